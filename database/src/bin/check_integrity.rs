@@ -0,0 +1,57 @@
+use std::path::Path;
+use std::process::ExitCode;
+
+use database::{PagedFileManager, PagedFileManagerConfigBuilder};
+
+// Prints every problem found in the file. Exits with 0 if there were none, 1 if there were and 2 if
+// the file couldn't be checked at all.
+//
+// The file is opened like any other open would, so it gets recovered from its write-ahead log and
+// closing it writes every page back and empties the log.
+const USAGE: &str = "usage: check_integrity <database file>
+
+Recovers the file from its write-ahead log (<database file>.wal) and checkpoints it before exiting,
+so both get written to. Check a copy of both to leave the originals untouched.";
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let [path] = args.as_slice() else {
+        eprintln!("{USAGE}");
+        return ExitCode::from(2);
+    };
+    // Opening a file that isn't there would create an empty database
+    if !Path::new(path).exists() {
+        eprintln!("{path} doesn't exist");
+        return ExitCode::from(2);
+    }
+
+    // No page size in the config means the file's own is used
+    let manager = match PagedFileManager::new(path, PagedFileManagerConfigBuilder::new().build()) {
+        Ok(manager) => manager,
+        Err(err) => {
+            eprintln!("can't open {path}: {err}");
+            return ExitCode::from(2);
+        }
+    };
+    match manager.check_integrity() {
+        Ok(report) => {
+            for problem in &report.problems {
+                println!("{problem}");
+            }
+            println!(
+                "checked {} pages, found {} problems",
+                report.pages_checked,
+                report.problems.len()
+            );
+            if report.is_ok() {
+                ExitCode::SUCCESS
+            } else {
+                ExitCode::from(1)
+            }
+        }
+        Err(err) => {
+            eprintln!("can't check {path}: {err}");
+            ExitCode::from(2)
+        }
+    }
+}
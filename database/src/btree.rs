@@ -0,0 +1,1679 @@
+use crate::buffer_pool::{LatchMode, PageLatch};
+use crate::error::{DbError, Result};
+use crate::key_codec::{decode_key, encode_key, KeyValue, SortOrder};
+use crate::{IndexPage, MetadataPage, PageHeader, PageType, PagedFileManager, PinnedPage};
+
+// B+Tree built out of IndexPages. The root lives in MetadataPage.root_page_id so there's a single
+// tree per file.
+//
+// Leaves hold the entries: keys[i] maps to child_pointers[i], so keys and child_pointers are the
+// same length, and leaves are chained both ways through next_leaf and prev_leaf in key order.
+// Internal nodes have one more child than keys. keys[i] separates the children either side of it:
+// everything under child_pointers[i] is smaller than it and everything under child_pointers[i + 1]
+// is at least it.
+//
+// Nodes are deserialized, changed in memory and written back whole. A node that no longer fits in a
+// page after an insert gets split in half and a separator is pushed up into the parent, which may
+// split in turn. Splitting the root grows the tree by one level. Separators coming out of leaves
+// are suffix truncated: rather than the right half's whole first key, they're the shortest prefix
+// of it that's still bigger than the left half's last key. On top of IndexPage only storing the
+// prefix its keys share once, that keeps internal nodes small and their fan-out high.
+//
+// Deletes go the other way. A node left less than half full takes entries from a sibling, or is
+// merged with it when both fit in one page (freeing the emptied page). When the root is left with a
+// single child that child becomes the new root, shrinking the tree by one level.
+//
+// Bulk loading skips all of that for sorted input. Leaves are packed left to right up to the fill
+// factor, then the (separator, page id) of every leaf becomes the input for the level above, and
+// so on until a level fits in a single node, which becomes the root.
+//
+// Everything above works on the keys entries are stored under, which are only unique because
+// non-unique trees store every (key, value) pair under the pair encoded with key_codec. Sorting by
+// those is sorting by key then value, and every stored key of a given key starts with the key's
+// own encoding, so finding all of a key's values is a prefix scan.
+//
+// Several threads can use the tree at once, every node is latched before it's read or written.
+// Lookups crab down from the root with shared latches, letting go of a node once its child is
+// latched. Inserts and deletes first try the same way, only latching the leaf exclusively, which
+// is enough when the leaf doesn't need to split or merge. When it does they start over holding the
+// metadata page exclusively, which keeps them to one at a time and protects page allocation, and
+// crab down with exclusive latches. Once a node is reached that can take the change without
+// splitting or merging, the latches above it are let go since nothing above it will change.
+// Latches are only waited for going down the tree, for a sibling whose parent is already latched
+// exclusively, or for the leaf after one being split or merged, so nobody ends up waiting in a
+// circle. Cursors moving between leaves only take a neighbour's latch if it's free. Anything that
+// changed stays latched until the transaction that changed it has committed (or rolled back), so
+// nobody reads a change that might still be undone.
+
+/// Index mapping byte string keys to u64 values (page ids, record ids, ...). Keys compare as plain
+/// byte strings so they need to be encoded in an order preserving way.
+///
+/// Every method takes `&self` so the tree can be shared between threads. Each call is its own
+/// transaction, so while other threads use the tree don't group calls with
+/// `PagedFileManager::atomically`: latches are let go when a call returns, before the outer
+/// transaction commits
+pub struct BTree<'a> {
+    manager: &'a PagedFileManager,
+    uniqueness: Uniqueness,
+}
+
+/// Whether a tree lets a key map to more than one value. Fixed when the tree is created
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Uniqueness {
+    /// Every key maps to a single value, inserting a key that's already there is an error
+    #[default]
+    Unique,
+    /// A key maps to any number of values, like a secondary index mapping a column value to
+    /// every record holding it
+    NonUnique,
+}
+
+/// What an insert into a subtree hands back to the parent when the subtree's root had to split:
+/// the separator key and the page id of the new right sibling
+type Split = Option<(Vec<u8>, u64)>;
+
+/// A key and its value (leaves) or child page id (internal nodes)
+type Entry = (Vec<u8>, u64);
+
+/// One level of nodes being built by `bulk_load`. Entries are (key, value) pairs for leaves and
+/// (separator, child page id) pairs for internal nodes
+struct LevelBuilder {
+    is_leaf: bool,
+    // Body bytes a node gets filled to before the next one is started
+    target_size: usize,
+    current: Vec<Entry>,
+    // Total length of the keys the current node will actually store
+    key_bytes: usize,
+    // Last finished node. It isn't written until the next one is finished, both because a leaf
+    // needs the page id of the one after it and so an underfull last node can borrow from it
+    pending: Option<(u64, Vec<Entry>)>,
+    // Last key of the last leaf written, the separator for the next one is cut down from it
+    previous_last_key: Option<Vec<u8>>,
+    // (separator, page id) of every node written so far, the entries for the level above
+    written: Vec<Entry>,
+}
+
+impl LevelBuilder {
+    fn new(is_leaf: bool, target_size: usize) -> Self {
+        LevelBuilder {
+            is_leaf,
+            target_size,
+            current: Vec::new(),
+            key_bytes: 0,
+            pending: None,
+            previous_last_key: None,
+            written: Vec::new(),
+        }
+    }
+
+    /// The first entry of an internal node is only a child pointer, its key is the separator for
+    /// the node in the level above
+    fn stores_key(&self) -> bool {
+        self.is_leaf || !self.current.is_empty()
+    }
+
+    /// Body size of the current node once the entry for `key` is added, worked out the same way
+    /// as `IndexPage::calc_size`. Entries come in sorted so the prefix every key shares is just
+    /// the one shared by the first key and the new one
+    fn size_with(&self, key: &[u8]) -> usize {
+        let values_size = (self.current.len() + 1) * BTree::VALUE_SIZE;
+        if !self.stores_key() {
+            return IndexPage::EXCLUDING_VEC_SIZE + values_size;
+        }
+        let stored = &self.current[if self.is_leaf { 0 } else { 1 }..];
+        let num_keys = stored.len() + 1;
+        let first = stored.first().map_or(key, |(first, _)| first.as_slice());
+        let prefix_savings = (num_keys - 1) * crate::common_prefix_len(first, key);
+        IndexPage::EXCLUDING_VEC_SIZE
+            + num_keys * IndexPage::KEY_SLOT_SIZE
+            + values_size
+            + self.key_bytes
+            + key.len()
+            - prefix_savings
+    }
+
+    fn node(&self, entries: Vec<Entry>) -> IndexPage {
+        let (mut keys, child_pointers): (Vec<_>, Vec<_>) = entries.into_iter().unzip();
+        if !self.is_leaf && !keys.is_empty() {
+            keys.remove(0);
+        }
+        IndexPage {
+            is_leaf: self.is_leaf,
+            next_leaf: 0,
+            prev_leaf: 0,
+            keys,
+            child_pointers,
+        }
+    }
+}
+
+impl<'a> BTree<'a> {
+    const VALUE_SIZE: usize = size_of::<u64>();
+    // Bytes of an index page that can never hold keys: the fixed fields and the extra child
+    // pointer in internal nodes
+    const NODE_OVERHEAD: usize = IndexPage::EXCLUDING_VEC_SIZE + Self::VALUE_SIZE;
+
+    /// Opens the tree rooted at the metadata page's root_page_id, as whichever kind of tree it was
+    /// created as. Creates an empty unique tree if the file doesn't have a tree yet
+    pub fn open(manager: &'a PagedFileManager) -> Result<Self> {
+        Self::open_as(manager, None)
+    }
+
+    /// Same as `open` but the tree has to be (or is created as) the given kind
+    pub fn open_with(manager: &'a PagedFileManager, uniqueness: Uniqueness) -> Result<Self> {
+        Self::open_as(manager, Some(uniqueness))
+    }
+
+    fn open_as(manager: &'a PagedFileManager, requested: Option<Uniqueness>) -> Result<Self> {
+        // Stops two threads opening a new file from both creating a root
+        let _metadata_latch =
+            manager.latch_page(PagedFileManager::METADATA_PAGE_ID, LatchMode::Exclusive)?;
+        let (root_page_id, stored_is_unique) = manager.with_window::<MetadataPage, _, _>(
+            PagedFileManager::METADATA_PAGE_ID,
+            |metadata_page_window| {
+                Ok((
+                    metadata_page_window.read_root_page_id(),
+                    metadata_page_window.read_tree_is_unique(),
+                ))
+            },
+        )?;
+
+        if root_page_id != 0 {
+            let stored = if stored_is_unique {
+                Uniqueness::Unique
+            } else {
+                Uniqueness::NonUnique
+            };
+            if let Some(requested) = requested.filter(|requested| *requested != stored) {
+                return Err(DbError::UniquenessMismatch { requested, stored });
+            }
+            return Ok(BTree {
+                manager,
+                uniqueness: stored,
+            });
+        }
+
+        let uniqueness = requested.unwrap_or_default();
+        manager.atomically(|manager| {
+            let root_page_id = manager.create_index_page(true)?;
+            manager.with_window_mut::<MetadataPage, _, _>(
+                PagedFileManager::METADATA_PAGE_ID,
+                |metadata_page_window| {
+                    metadata_page_window.update_root_page_id(root_page_id);
+                    metadata_page_window.update_tree_is_unique(uniqueness == Uniqueness::Unique);
+                    Ok(())
+                },
+            )
+        })?;
+        Ok(BTree {
+            manager,
+            uniqueness,
+        })
+    }
+
+    pub fn uniqueness(&self) -> Uniqueness {
+        self.uniqueness
+    }
+
+    /// Biggest key the tree accepts for the page size. Small enough that every node can hold at
+    /// least 4 entries, so splitting a node always leaves at least 2 on each side. In non-unique
+    /// trees this applies to the stored key, which is the key escaped and with its value appended
+    pub fn max_key_size(page_size: u32) -> usize {
+        (page_size as usize - PageHeader::SIZE - Self::NODE_OVERHEAD) / 4
+            - Self::VALUE_SIZE
+            - IndexPage::KEY_SLOT_SIZE
+    }
+
+    pub fn root_page_id(&self) -> Result<u64> {
+        self.manager.with_window::<MetadataPage, _, _>(
+            PagedFileManager::METADATA_PAGE_ID,
+            |metadata_page_window| Ok(metadata_page_window.read_root_page_id()),
+        )
+    }
+
+    fn set_root_page_id(&self, root_page_id: u64) -> Result<()> {
+        self.manager.with_window_mut::<MetadataPage, _, _>(
+            PagedFileManager::METADATA_PAGE_ID,
+            |metadata_page_window| {
+                metadata_page_window.update_root_page_id(root_page_id);
+                Ok(())
+            },
+        )
+    }
+
+    /// Latches the root in `mode`. The root can change between reading its id and the latch being
+    /// granted, so that's retried until the latched page is still the root once it's held
+    fn latch_root(&self, mode: LatchMode) -> Result<(u64, PageLatch<'a>)> {
+        loop {
+            let root_page_id = self.root_page_id()?;
+            let latch = self.manager.latch_page(root_page_id, mode)?;
+            if self.root_page_id()? == root_page_id {
+                return Ok((root_page_id, latch));
+            }
+        }
+    }
+
+    /// Walks down from the root to the leaf `choose` picks the children towards, holding a shared
+    /// latch on a node only until its child's is held. Returns a copy of the leaf, nothing is
+    /// latched anymore once this returns
+    pub(crate) fn find_leaf(&self, choose: impl Fn(&IndexPage) -> usize) -> Result<IndexPage> {
+        let (page_id, _latch) = self.latch_leaf(choose)?;
+        self.read_node(page_id)
+    }
+
+    /// Same walk as `find_leaf` but the leaf is left latched and isn't read
+    pub(crate) fn latch_leaf(
+        &self,
+        choose: impl Fn(&IndexPage) -> usize,
+    ) -> Result<(u64, PageLatch<'a>)> {
+        let (mut page_id, mut latch) = self.latch_root(LatchMode::Shared)?;
+        loop {
+            let is_leaf = self
+                .manager
+                .with_window::<IndexPage, _, _>(page_id, |window| Ok(window.is_leaf()))?;
+            if is_leaf {
+                return Ok((page_id, latch));
+            }
+            let node = self.read_node(page_id)?;
+            page_id = node.child_pointers[choose(&node)];
+            latch = self.manager.latch_page(page_id, LatchMode::Shared)?;
+        }
+    }
+
+    pub(crate) fn read_node(&self, page_id: u64) -> Result<IndexPage> {
+        let (header, node) = self.manager.read_page_as::<IndexPage>(page_id)?;
+        if header.page_type != PageType::Index {
+            return Err(DbError::Corruption {
+                page_id,
+                reason: format!("expected a B+Tree node but found {:?}", header.page_type),
+            });
+        }
+        Ok(node)
+    }
+
+    /// Latches the page in shared mode, or None if somebody else has it latched exclusively
+    pub(crate) fn try_latch_shared(&self, page_id: u64) -> Result<Option<PageLatch<'a>>> {
+        self.manager.try_latch_page(page_id, LatchMode::Shared)
+    }
+
+    pub(crate) fn latch_shared(&self, page_id: u64) -> Result<PageLatch<'a>> {
+        self.manager.latch_page(page_id, LatchMode::Shared)
+    }
+
+    pub(crate) fn pin(&self, page_id: u64) -> Result<PinnedPage<'a>> {
+        self.manager.pin(page_id)
+    }
+
+    /// Points the leaf at `page_id` back at `prev_leaf`, for when the leaf before it changes
+    fn set_prev_leaf(
+        &self,
+        page_id: u64,
+        prev_leaf: u64,
+        latches: &mut Vec<PageLatch<'a>>,
+    ) -> Result<()> {
+        latches.push(self.manager.latch_page(page_id, LatchMode::Exclusive)?);
+        let mut leaf = self.read_node(page_id)?;
+        leaf.prev_leaf = prev_leaf;
+        self.manager.write_index_page(page_id, &leaf)
+    }
+
+    fn fits(&self, node: &IndexPage) -> bool {
+        self.fits_size(node.calc_size())
+    }
+
+    /// Whether a node whose `calc_size` is `size` fits in a page
+    fn fits_size(&self, size: usize) -> bool {
+        PageHeader::SIZE + size <= self.manager.page_size as usize
+    }
+
+    /// The key an entry is stored under, see the comment at the top
+    fn stored_key(&self, key: &[u8], value: u64) -> Vec<u8> {
+        match self.uniqueness {
+            Uniqueness::Unique => key.to_vec(),
+            Uniqueness::NonUnique => {
+                encode_key(&[KeyValue::Bytes(key.to_vec()), KeyValue::UInt(value)])
+            }
+        }
+    }
+
+    /// What every stored key of the key's entries in a non-unique tree starts with
+    pub(crate) fn stored_key_prefix(key: &[u8]) -> Vec<u8> {
+        encode_key(&[KeyValue::Bytes(key.to_vec())])
+    }
+
+    /// Turns a stored key back into the key it was stored for
+    pub(crate) fn user_key(&self, stored_key: Vec<u8>) -> Result<Vec<u8>> {
+        if self.uniqueness == Uniqueness::Unique {
+            return Ok(stored_key);
+        }
+        let orders = [SortOrder::Ascending, SortOrder::Ascending];
+        match decode_key(&stored_key, &orders)?.as_mut_slice() {
+            [KeyValue::Bytes(key), KeyValue::UInt(_)] => Ok(std::mem::take(key)),
+            _ => Err(DbError::InvalidKey(
+                "non-unique tree entry isn't a (key, value) pair".to_string(),
+            )),
+        }
+    }
+
+    /// Looks up the value stored for the key. For a key with several values in a non-unique tree
+    /// that's the smallest one
+    pub fn get(&self, key: &[u8]) -> Result<Option<u64>> {
+        match self.uniqueness {
+            Uniqueness::Unique => self.get_stored(key),
+            Uniqueness::NonUnique => self
+                .exact(key)?
+                .next()
+                .transpose()
+                .map(|entry| entry.map(|(_, value)| value)),
+        }
+    }
+
+    /// Every value stored for the key, smallest first
+    pub fn get_all(&self, key: &[u8]) -> Result<Vec<u64>> {
+        match self.uniqueness {
+            Uniqueness::Unique => Ok(self.get_stored(key)?.into_iter().collect()),
+            Uniqueness::NonUnique => self
+                .exact(key)?
+                .map(|entry| entry.map(|(_, value)| value))
+                .collect(),
+        }
+    }
+
+    fn get_stored(&self, key: &[u8]) -> Result<Option<u64>> {
+        let leaf = self.find_leaf(|node| node.child_index(key))?;
+        Ok(leaf
+            .search(key)
+            .ok()
+            .map(|index| leaf.child_pointers[index]))
+    }
+
+    /// Adds the entry. Unique trees refuse keys they already have with `DuplicateKey`, non-unique
+    /// trees add the value next to the key's others (and do nothing if it's already one of them)
+    pub fn insert(&self, key: &[u8], value: u64) -> Result<()> {
+        let existing = self.insert_entry(key, value, false)?;
+        if existing.is_some() && self.uniqueness == Uniqueness::Unique {
+            return Err(DbError::DuplicateKey(key.to_vec()));
+        }
+        Ok(())
+    }
+
+    /// Inserts the key, or replaces its value if it's already in the tree, returning the value it
+    /// replaced. In a non-unique tree every value is an entry of its own, so this only ever adds
+    pub fn upsert(&self, key: &[u8], value: u64) -> Result<Option<u64>> {
+        self.insert_entry(key, value, true)
+    }
+
+    /// Returns the value already stored under the entry's stored key, which is only replaced with
+    /// the new one if `replace` is set
+    fn insert_entry(&self, key: &[u8], value: u64, replace: bool) -> Result<Option<u64>> {
+        let stored_key = self.stored_key(key, value);
+        let max = Self::max_key_size(self.manager.page_size);
+        if stored_key.len() > max {
+            return Err(DbError::KeyTooLarge {
+                size: stored_key.len(),
+                max,
+            });
+        }
+
+        let in_leaf = self.change_leaf(&stored_key, |leaf, _| match leaf.search(&stored_key) {
+            Ok(index) => {
+                let old_value = leaf.child_pointers[index];
+                if replace {
+                    leaf.child_pointers[index] = value;
+                }
+                Some((Some(old_value), replace))
+            }
+            Err(index) => {
+                leaf.keys.insert(index, stored_key.clone());
+                leaf.child_pointers.insert(index, value);
+                self.fits(leaf).then_some((None, true))
+            }
+        })?;
+        if let Some(replaced) = in_leaf {
+            return Ok(replaced);
+        }
+
+        let mut latches = Vec::new();
+        let _metadata_latch = self
+            .manager
+            .latch_page(PagedFileManager::METADATA_PAGE_ID, LatchMode::Exclusive)?;
+        self.manager.atomically(|_| {
+            let root_page_id = self.root_page_id()?;
+            latches.push(
+                self.manager
+                    .latch_page(root_page_id, LatchMode::Exclusive)?,
+            );
+            let (replaced, split) =
+                self.insert_into(root_page_id, &stored_key, value, replace, &mut latches)?;
+
+            // The root split so the tree grows a level
+            if let Some((separator, right_page_id)) = split {
+                let new_root_page_id = self.manager.allocate_page()?;
+                let new_root = IndexPage {
+                    is_leaf: false,
+                    next_leaf: 0,
+                    prev_leaf: 0,
+                    keys: vec![separator],
+                    child_pointers: vec![root_page_id, right_page_id],
+                };
+                self.manager.write_index_page(new_root_page_id, &new_root)?;
+                self.set_root_page_id(new_root_page_id)?;
+            }
+            Ok(replaced)
+        })
+    }
+
+    /// Makes a change that only touches the leaf the key belongs in, for the common case of an
+    /// insert or delete that doesn't split or merge anything. Shared latches are crabbed down to
+    /// the leaf, which is latched exclusively while its parent is still latched so nobody can move
+    /// it around in between. Only that leaf is held while it's changed.
+    ///
+    /// `change` gets the leaf and whether it's the root, and returns what to hand back along with
+    /// whether it changed the leaf. None means the change needs more than the one leaf, nothing is
+    /// written and None is returned so the caller can do it the slow way
+    fn change_leaf<T>(
+        &self,
+        key: &[u8],
+        change: impl FnOnce(&mut IndexPage, bool) -> Option<(T, bool)>,
+    ) -> Result<Option<T>> {
+        let (mut page_id, mut latch) = self.latch_root(LatchMode::Shared)?;
+        let mut parent_latch = None;
+        let mut node = self.read_node(page_id)?;
+        while !node.is_leaf {
+            let child_page_id = node.child_pointers[node.child_index(key)];
+            let child_latch = self.manager.latch_page(child_page_id, LatchMode::Shared)?;
+            parent_latch = Some(std::mem::replace(&mut latch, child_latch));
+            page_id = child_page_id;
+            node = self.read_node(page_id)?;
+        }
+        drop(latch);
+
+        let is_root = parent_latch.is_none();
+        let _leaf_latch = if is_root {
+            let (root_page_id, root_latch) = self.latch_root(LatchMode::Exclusive)?;
+            if root_page_id != page_id {
+                return Ok(None);
+            }
+            root_latch
+        } else {
+            self.manager.latch_page(page_id, LatchMode::Exclusive)?
+        };
+        drop(parent_latch);
+
+        // Read again, something else could have changed it while it wasn't latched. A root could
+        // even have been freed and its page reused for a new internal root
+        let mut leaf = self.read_node(page_id)?;
+        if !leaf.is_leaf {
+            return Ok(None);
+        }
+        let Some((result, changed)) = change(&mut leaf, is_root) else {
+            return Ok(None);
+        };
+        if changed {
+            self.manager
+                .atomically(|manager| manager.write_index_page(page_id, &leaf))?;
+        }
+        Ok(Some(result))
+    }
+
+    /// Keeps only the newest latch, once a node that won't change the nodes above it is reached
+    fn release_ancestors(latches: &mut Vec<PageLatch<'a>>) {
+        let newest = latches.pop();
+        latches.clear();
+        latches.extend(newest);
+    }
+
+    /// Can take another entry without splitting, whatever its key
+    fn can_absorb_entry(&self, node: &IndexPage) -> bool {
+        // A new key can make the shared prefix shorter, at worst down to nothing
+        let uncompressed_size =
+            node.calc_size() + node.keys.len().saturating_sub(1) * node.prefix_len();
+        PageHeader::SIZE
+            + uncompressed_size
+            + Self::max_key_size(self.manager.page_size)
+            + IndexPage::KEY_SLOT_SIZE
+            + Self::VALUE_SIZE
+            <= self.manager.page_size as usize
+    }
+
+    /// Stays at least half full after losing any one of its entries. Losing the first or last key
+    /// can make the shared prefix longer, anything in between just takes its own bytes with it
+    fn can_spare_entry(&self, node: &IndexPage) -> bool {
+        let len = node.keys.len();
+        if len < 2 {
+            return false;
+        }
+        let longest_middle = (1..len - 1).max_by_key(|&index| node.keys[index].len());
+        [Some(0), Some(len - 1), longest_middle]
+            .into_iter()
+            .flatten()
+            .all(|index| {
+                let mut smaller = node.clone();
+                smaller.keys.remove(index);
+                smaller.child_pointers.pop();
+                !self.is_underfull(&smaller)
+            })
+    }
+
+    /// Inserts into the subtree at `page_id`, whose latch is the last one in `latches`
+    fn insert_into(
+        &self,
+        page_id: u64,
+        key: &[u8],
+        value: u64,
+        replace: bool,
+        latches: &mut Vec<PageLatch<'a>>,
+    ) -> Result<(Option<u64>, Split)> {
+        let mut node = self.read_node(page_id)?;
+        if self.can_absorb_entry(&node) {
+            Self::release_ancestors(latches);
+        }
+
+        let replaced = if node.is_leaf {
+            match node.search(key) {
+                Ok(index) => {
+                    let old_value = node.child_pointers[index];
+                    if !replace {
+                        return Ok((Some(old_value), None));
+                    }
+                    node.child_pointers[index] = value;
+                    Some(old_value)
+                }
+                Err(index) => {
+                    node.keys.insert(index, key.to_vec());
+                    node.child_pointers.insert(index, value);
+                    None
+                }
+            }
+        } else {
+            let child_index = node.child_index(key);
+            let child_page_id = node.child_pointers[child_index];
+            latches.push(
+                self.manager
+                    .latch_page(child_page_id, LatchMode::Exclusive)?,
+            );
+            let (replaced, split) =
+                self.insert_into(child_page_id, key, value, replace, latches)?;
+            match split {
+                Some((separator, right_page_id)) => {
+                    node.keys.insert(child_index, separator);
+                    node.child_pointers.insert(child_index + 1, right_page_id);
+                }
+                // Nothing changed in this node
+                None => return Ok((replaced, None)),
+            }
+            replaced
+        };
+
+        if self.fits(&node) {
+            self.manager.write_index_page(page_id, &node)?;
+            return Ok((replaced, None));
+        }
+        let split = self.split(page_id, node, latches)?;
+        Ok((replaced, Some(split)))
+    }
+
+    /// Splits the overfull node in half by bytes, writing the left half back to `page_id` and the
+    /// right half to a new page. Returns the separator for the parent and the new page's id
+    fn split(
+        &self,
+        page_id: u64,
+        mut node: IndexPage,
+        latches: &mut Vec<PageLatch<'a>>,
+    ) -> Result<(Vec<u8>, u64)> {
+        let right_page_id = self.manager.allocate_page()?;
+        latches.push(
+            self.manager
+                .latch_page(right_page_id, LatchMode::Exclusive)?,
+        );
+        let keys = &node.keys;
+        let halves = |mid: usize| {
+            if node.is_leaf {
+                (
+                    IndexPage::size_for(&keys[..mid], mid),
+                    IndexPage::size_for(&keys[mid..], keys.len() - mid),
+                )
+            } else {
+                (
+                    IndexPage::size_for(&keys[..mid], mid + 1),
+                    IndexPage::size_for(&keys[mid + 1..], keys.len() - mid),
+                )
+            }
+        };
+        let mid = self
+            .split_point(keys.len(), halves)
+            .ok_or_else(|| DbError::Corruption {
+                page_id,
+                reason: "node is too full to split into two halves that fit".to_string(),
+            })?;
+
+        let (separator, right) = if node.is_leaf {
+            let right = IndexPage {
+                is_leaf: true,
+                next_leaf: node.next_leaf,
+                prev_leaf: page_id,
+                keys: node.keys.split_off(mid),
+                child_pointers: node.child_pointers.split_off(mid),
+            };
+            if node.next_leaf != 0 {
+                self.set_prev_leaf(node.next_leaf, right_page_id, latches)?;
+            }
+            node.next_leaf = right_page_id;
+            // Leaves keep every entry so the separator only has to fall between the halves
+            let left_last_key = node.keys.last().expect("split leaves aren't empty");
+            (
+                Self::shortest_separator(left_last_key, &right.keys[0]),
+                right,
+            )
+        } else {
+            let mut right_keys = node.keys.split_off(mid);
+            let right = IndexPage {
+                is_leaf: false,
+                next_leaf: 0,
+                prev_leaf: 0,
+                child_pointers: node.child_pointers.split_off(mid + 1),
+                keys: right_keys.split_off(1),
+            };
+            // Internal nodes move the middle key up rather than keeping a copy
+            (right_keys.remove(0), right)
+        };
+
+        self.manager.write_index_page(page_id, &node)?;
+        self.manager.write_index_page(right_page_id, &right)?;
+        Ok((separator, right_page_id))
+    }
+
+    /// Index of the first key that goes to the right half (or up to the parent, for internal
+    /// nodes). Both sides get at least one key (plus the middle one for internal nodes) and out of
+    /// the points where both halves fit, the one leaving the bigger half smallest wins. Halves are
+    /// measured the way they're stored, since a half can lose the prefix its keys shared in the
+    /// whole node.
+    ///
+    /// There's always such a point for a node that fit before it gained its one new entry. A new
+    /// key that breaks the shared prefix sorts before or after every other key, so it can go in a
+    /// half with just its neighbour and leave the rest as they were.
+    ///
+    /// `halves` gives the `calc_size` of both halves when splitting at a given index
+    fn split_point(&self, len: usize, halves: impl Fn(usize) -> (usize, usize)) -> Option<usize> {
+        (1..len.saturating_sub(1))
+            .map(|mid| (mid, halves(mid)))
+            .filter(|&(_, (left, right))| self.fits_size(left) && self.fits_size(right))
+            .min_by_key(|&(_, (left, right))| left.max(right))
+            .map(|(mid, _)| mid)
+    }
+
+    /// Shortest key that's bigger than `left` and no bigger than `right`, which has to be bigger
+    /// than `left`. That's `right` cut off one byte past where the two first differ
+    fn shortest_separator(left: &[u8], right: &[u8]) -> Vec<u8> {
+        right[..crate::common_prefix_len(left, right) + 1].to_vec()
+    }
+
+    /// Removes the key from the tree, returning its value if it was there. A key with several
+    /// values in a non-unique tree only loses its smallest one
+    pub fn delete(&self, key: &[u8]) -> Result<Option<u64>> {
+        if self.uniqueness == Uniqueness::Unique {
+            return self.delete_stored(key, None);
+        }
+        // The value is part of the stored key so deleting it only removes that one entry. If
+        // somebody else deleted it first, the key's next smallest value is the one to go
+        while let Some(value) = self.get(key)? {
+            if let Some(removed) = self.delete_stored(&self.stored_key(key, value), None)? {
+                return Ok(Some(removed));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Removes the key's entry only if it has the given value, returning whether it did. How a
+    /// secondary index drops the entry for one particular record
+    pub fn delete_entry(&self, key: &[u8], value: u64) -> Result<bool> {
+        let removed = match self.uniqueness {
+            Uniqueness::Unique => self.delete_stored(key, Some(value))?,
+            Uniqueness::NonUnique => self.delete_stored(&self.stored_key(key, value), None)?,
+        };
+        Ok(removed.is_some())
+    }
+
+    /// Removes the entry stored under `key`, as long as its value is `expected` if that's given.
+    /// The value is checked under the same latches the entry is removed under
+    fn delete_stored(&self, key: &[u8], expected: Option<u64>) -> Result<Option<u64>> {
+        let in_leaf = self.change_leaf(key, |leaf, is_root| {
+            let Some(index) = Self::entry_index(leaf, key, expected) else {
+                return Some((None, false));
+            };
+            leaf.keys.remove(index);
+            let removed = leaf.child_pointers.remove(index);
+            (is_root || !self.is_underfull(leaf)).then_some((Some(removed), true))
+        })?;
+        if let Some(removed) = in_leaf {
+            return Ok(removed);
+        }
+
+        let mut latches = Vec::new();
+        let _metadata_latch = self
+            .manager
+            .latch_page(PagedFileManager::METADATA_PAGE_ID, LatchMode::Exclusive)?;
+        self.manager.atomically(|_| {
+            let root_page_id = self.root_page_id()?;
+            latches.push(
+                self.manager
+                    .latch_page(root_page_id, LatchMode::Exclusive)?,
+            );
+            let (removed, _) = self.delete_from(root_page_id, key, expected, &mut latches)?;
+
+            // A root with a single child is pointless, the child takes its place. The root can only
+            // have lost a child if it was changed, in which case it's still latched
+            if latches.first().map(PageLatch::page_id) == Some(root_page_id) {
+                let root = self.read_node(root_page_id)?;
+                if !root.is_leaf && root.keys.is_empty() {
+                    self.set_root_page_id(root.child_pointers[0])?;
+                    self.manager.free_page(root_page_id)?;
+                }
+            }
+            Ok(removed)
+        })
+    }
+
+    /// Index of the leaf's entry for `key`, None if there isn't one or its value isn't `expected`
+    fn entry_index(leaf: &IndexPage, key: &[u8], expected: Option<u64>) -> Option<usize> {
+        leaf.search(key)
+            .ok()
+            .filter(|&index| expected.is_none_or(|value| leaf.child_pointers[index] == value))
+    }
+
+    /// Deletes from the subtree at `page_id`, whose latch is the last one in `latches`. Returns
+    /// the removed value and whether the node is now underfull
+    fn delete_from(
+        &self,
+        page_id: u64,
+        key: &[u8],
+        expected: Option<u64>,
+        latches: &mut Vec<PageLatch<'a>>,
+    ) -> Result<(Option<u64>, bool)> {
+        let mut node = self.read_node(page_id)?;
+        if self.can_spare_entry(&node) {
+            Self::release_ancestors(latches);
+        }
+
+        let removed = if node.is_leaf {
+            let Some(index) = Self::entry_index(&node, key, expected) else {
+                return Ok((None, false));
+            };
+            node.keys.remove(index);
+            Some(node.child_pointers.remove(index))
+        } else {
+            let child_index = node.child_index(key);
+            let child_page_id = node.child_pointers[child_index];
+            latches.push(
+                self.manager
+                    .latch_page(child_page_id, LatchMode::Exclusive)?,
+            );
+            let (removed, child_underfull) =
+                self.delete_from(child_page_id, key, expected, latches)?;
+            if !child_underfull {
+                return Ok((removed, false));
+            }
+            self.rebalance(&mut node, child_index, latches)?;
+            removed
+        };
+
+        self.manager.write_index_page(page_id, &node)?;
+        Ok((removed, self.is_underfull(&node)))
+    }
+
+    /// Less than half full. Sizes are in bytes rather than entries since keys can be any size
+    fn is_underfull(&self, node: &IndexPage) -> bool {
+        (PageHeader::SIZE + node.calc_size()) * 2 < self.manager.page_size as usize
+    }
+
+    /// Fixes up the underfull child at `child_index` using one of its siblings. If the two fit in
+    /// one page they're merged and the right one is freed, otherwise entries are moved over from
+    /// the fuller one until they're balanced. Only `parent` is left for the caller to write.
+    ///
+    /// Moving entries changes the separator in `parent`, and a longer separator could overflow it.
+    /// In that case the child is just left underfull, a later delete will get another go at it
+    fn rebalance(
+        &self,
+        parent: &mut IndexPage,
+        child_index: usize,
+        latches: &mut Vec<PageLatch<'a>>,
+    ) -> Result<()> {
+        // Only a root that's about to be collapsed can have a single child
+        if parent.child_pointers.len() < 2 {
+            return Ok(());
+        }
+        // Always work on a (left, right) pair, preferring the left sibling
+        let left_index = child_index.saturating_sub(1);
+        let right_index = left_index + 1;
+        let left_page_id = parent.child_pointers[left_index];
+        let right_page_id = parent.child_pointers[right_index];
+        // The child itself is already latched
+        let sibling_page_id = if left_index == child_index {
+            right_page_id
+        } else {
+            left_page_id
+        };
+        latches.push(
+            self.manager
+                .latch_page(sibling_page_id, LatchMode::Exclusive)?,
+        );
+        let mut left = self.read_node(left_page_id)?;
+        let mut right = self.read_node(right_page_id)?;
+        let mut separator = parent.keys[left_index].clone();
+
+        let merged = Self::merge(left.clone(), separator.clone(), right.clone());
+        if self.fits(&merged) {
+            if merged.is_leaf && merged.next_leaf != 0 {
+                self.set_prev_leaf(merged.next_leaf, left_page_id, latches)?;
+            }
+            self.manager.write_index_page(left_page_id, &merged)?;
+            self.manager.free_page(right_page_id)?;
+            parent.keys.remove(left_index);
+            parent.child_pointers.remove(right_index);
+            return Ok(());
+        }
+
+        if left.calc_size() < right.calc_size() {
+            while left.calc_size() < right.calc_size() {
+                Self::shift_left(&mut left, &mut separator, &mut right);
+            }
+        } else {
+            while right.calc_size() < left.calc_size() {
+                Self::shift_right(&mut left, &mut separator, &mut right);
+            }
+        }
+        // A key moved over can break the prefix the receiving node's keys shared and leave it too
+        // big, that's left underfull too
+        if !self.fits(&left) || !self.fits(&right) {
+            return Ok(());
+        }
+        let old_separator = std::mem::replace(&mut parent.keys[left_index], separator);
+        if !self.fits(parent) {
+            parent.keys[left_index] = old_separator;
+            return Ok(());
+        }
+        self.manager.write_index_page(left_page_id, &left)?;
+        self.manager.write_index_page(right_page_id, &right)
+    }
+
+    /// Combines two siblings into one node. Internal nodes pull the separator between them back
+    /// down, leaves just drop it since they keep every key anyway
+    fn merge(mut left: IndexPage, separator: Vec<u8>, right: IndexPage) -> IndexPage {
+        if left.is_leaf {
+            left.next_leaf = right.next_leaf;
+        } else {
+            left.keys.push(separator);
+        }
+        left.keys.extend(right.keys);
+        left.child_pointers.extend(right.child_pointers);
+        left
+    }
+
+    /// Moves the first entry of `right` to the end of `left`, keeping the separator between them
+    /// up to date
+    fn shift_left(left: &mut IndexPage, separator: &mut Vec<u8>, right: &mut IndexPage) {
+        let child_pointer = right.child_pointers.remove(0);
+        let key = right.keys.remove(0);
+        left.child_pointers.push(child_pointer);
+        if left.is_leaf {
+            *separator = Self::shortest_separator(&key, &right.keys[0]);
+            left.keys.push(key);
+        } else {
+            // Rotates through the parent: the separator comes down and right's first key goes up
+            left.keys.push(std::mem::replace(separator, key));
+        }
+    }
+
+    /// Moves the last entry of `left` to the start of `right`, keeping the separator between them
+    /// up to date
+    fn shift_right(left: &mut IndexPage, separator: &mut Vec<u8>, right: &mut IndexPage) {
+        let child_pointer = left.child_pointers.pop().expect("donor can't be empty");
+        let key = left.keys.pop().expect("donor can't be empty");
+        right.child_pointers.insert(0, child_pointer);
+        if right.is_leaf {
+            let left_last_key = left.keys.last().expect("donor keeps at least one entry");
+            *separator = Self::shortest_separator(left_last_key, &key);
+            right.keys.insert(0, key);
+        } else {
+            right.keys.insert(0, std::mem::replace(separator, key));
+        }
+    }
+}
+
+impl<'a> BTree<'a> {
+    /// Replaces everything in the tree with the entries, which have to be sorted by key with no
+    /// duplicates (for non-unique trees: by key then value, with no duplicate pairs). Builds the
+    /// tree bottom up rather than inserting one entry at a time so every page is written once and
+    /// nodes are packed to `fill_factor` of a page. The fill factor is clamped to between 0.5
+    /// (anything less would leave nodes underfull) and 1.0.
+    ///
+    /// Runs as one atomic change, so bad input leaves the old tree untouched. Every node of the old
+    /// tree stays latched until then, so other threads wait for the load rather than seeing half of
+    /// it
+    pub fn bulk_load<I>(&self, entries: I, fill_factor: f64) -> Result<()>
+    where
+        I: IntoIterator<Item = (Vec<u8>, u64)>,
+    {
+        let max = Self::max_key_size(self.manager.page_size);
+        let body_size = self.manager.page_size as usize - PageHeader::SIZE;
+        let target_size = (body_size as f64 * fill_factor.clamp(0.5, 1.0)) as usize;
+
+        let mut latches = Vec::new();
+        let _metadata_latch = self
+            .manager
+            .latch_page(PagedFileManager::METADATA_PAGE_ID, LatchMode::Exclusive)?;
+        self.manager.atomically(|_| {
+            let (old_root_page_id, old_root_latch) = self.latch_root(LatchMode::Exclusive)?;
+            latches.push(old_root_latch);
+            self.free_subtree(old_root_page_id, &mut latches)?;
+
+            let mut level = LevelBuilder::new(true, target_size);
+            let mut previous: Option<Vec<u8>> = None;
+            for (key, value) in entries {
+                let key = self.stored_key(&key, value);
+                if key.len() > max {
+                    return Err(DbError::KeyTooLarge {
+                        size: key.len(),
+                        max,
+                    });
+                }
+                if let Some(previous) = previous.take().filter(|previous| *previous >= key) {
+                    return Err(DbError::UnsortedKeys {
+                        previous: self.user_key(previous)?,
+                        next: self.user_key(key)?,
+                    });
+                }
+                previous = Some(key.clone());
+                self.push_entry(&mut level, key, value)?;
+            }
+
+            let mut nodes = self.finish_level(&mut level)?;
+            while nodes.len() > 1 {
+                let mut level = LevelBuilder::new(false, target_size);
+                for (key, page_id) in nodes {
+                    self.push_entry(&mut level, key, page_id)?;
+                }
+                nodes = self.finish_level(&mut level)?;
+            }
+
+            // Everything new hangs off the new root, so latching it keeps the new tree to ourselves
+            // until it's committed. It can reuse the page of an old node, which is latched already
+            let new_root_page_id = nodes[0].1;
+            if !latches
+                .iter()
+                .any(|latch| latch.page_id() == new_root_page_id)
+            {
+                latches.push(
+                    self.manager
+                        .latch_page(new_root_page_id, LatchMode::Exclusive)?,
+                );
+            }
+            self.set_root_page_id(new_root_page_id)
+        })
+    }
+
+    /// Frees the node and everything under it, latching each node first. The latches are added to
+    /// `latches`, where the node's own already is
+    fn free_subtree(&self, page_id: u64, latches: &mut Vec<PageLatch<'a>>) -> Result<()> {
+        let node = self.read_node(page_id)?;
+        if !node.is_leaf {
+            for child_page_id in node.child_pointers {
+                latches.push(
+                    self.manager
+                        .latch_page(child_page_id, LatchMode::Exclusive)?,
+                );
+                self.free_subtree(child_page_id, latches)?;
+            }
+        }
+        self.manager.free_page(page_id)
+    }
+
+    fn push_entry(&self, level: &mut LevelBuilder, key: Vec<u8>, value: u64) -> Result<()> {
+        // At least two entries per node, so internal nodes always have a key to separate them
+        if level.current.len() >= 2 && level.size_with(&key) > level.target_size {
+            self.finish_node(level)?;
+        }
+        if level.stores_key() {
+            level.key_bytes += key.len();
+        }
+        level.current.push((key, value));
+        Ok(())
+    }
+
+    /// Gives the current node a page and writes out the pending one now that it knows what comes
+    /// after it
+    fn finish_node(&self, level: &mut LevelBuilder) -> Result<()> {
+        let page_id = self.manager.allocate_page()?;
+        let entries = std::mem::take(&mut level.current);
+        level.key_bytes = 0;
+        if let Some((pending_page_id, pending)) = level.pending.replace((page_id, entries)) {
+            self.write_built_node(level, pending_page_id, pending, page_id)?;
+        }
+        Ok(())
+    }
+
+    fn write_built_node(
+        &self,
+        level: &mut LevelBuilder,
+        page_id: u64,
+        entries: Vec<Entry>,
+        next_leaf: u64,
+    ) -> Result<()> {
+        let first_key = entries
+            .first()
+            .map(|(key, _)| key.clone())
+            .unwrap_or_default();
+        let separator = match level.previous_last_key.take() {
+            Some(previous_last_key) => Self::shortest_separator(&previous_last_key, &first_key),
+            None => first_key,
+        };
+        // Nodes are written in order so the last one written is the one before this
+        let prev_leaf = level.written.last().map_or(0, |(_, page_id)| *page_id);
+        level.written.push((separator, page_id));
+        if level.is_leaf {
+            level.previous_last_key = entries.last().map(|(key, _)| key.clone());
+        }
+        let mut node = level.node(entries);
+        if node.is_leaf {
+            node.next_leaf = next_leaf;
+            node.prev_leaf = prev_leaf;
+        }
+        self.manager.write_index_page(page_id, &node)
+    }
+
+    /// Writes whatever is left of the level, returning the entries for the level above. A last
+    /// node that would be underfull gets evened out with the one before it
+    fn finish_level(&self, level: &mut LevelBuilder) -> Result<Vec<Entry>> {
+        let mut last = std::mem::take(&mut level.current);
+        let Some((pending_page_id, mut pending)) = level.pending.take() else {
+            // Everything fit in one node (which is an empty leaf for an empty tree)
+            let page_id = self.manager.allocate_page()?;
+            self.write_built_node(level, page_id, last, 0)?;
+            return Ok(std::mem::take(&mut level.written));
+        };
+
+        if self.is_underfull(&level.node(last.clone())) {
+            pending.append(&mut last);
+            if self.fits(&level.node(pending.clone())) {
+                self.write_built_node(level, pending_page_id, pending, 0)?;
+                return Ok(std::mem::take(&mut level.written));
+            }
+            let size = |entries: &[Entry]| level.node(entries.to_vec()).calc_size();
+            let mid = self
+                .split_point(pending.len(), |mid| {
+                    (size(&pending[..mid]), size(&pending[mid..]))
+                })
+                .ok_or_else(|| DbError::Corruption {
+                    page_id: pending_page_id,
+                    reason: "last two nodes of the level don't fit in two pages".to_string(),
+                })?;
+            last = pending.split_off(mid);
+        }
+        let last_page_id = self.manager.allocate_page()?;
+        self.write_built_node(level, pending_page_id, pending, last_page_id)?;
+        self.write_built_node(level, last_page_id, last, 0)?;
+        Ok(std::mem::take(&mut level.written))
+    }
+}
+
+impl IndexPage {
+    /// Binary search over a node's keys. Same contract as `slice::binary_search`
+    pub fn search(&self, key: &[u8]) -> std::result::Result<usize, usize> {
+        self.keys
+            .binary_search_by(|probe| probe.as_slice().cmp(key))
+    }
+
+    /// Which child of an internal node the key belongs under
+    pub fn child_index(&self, key: &[u8]) -> usize {
+        self.keys
+            .partition_point(|separator| separator.as_slice() <= key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::memory_manager;
+    use crate::{BTreeCursor, PagedFileManagerConfigBuilder};
+    use proptest::prelude::*;
+    use std::ops::Bound;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+
+    /// Every key from 0 to n exactly once, in a scrambled order
+    fn scrambled_keys(n: u32) -> Vec<u32> {
+        // 7919 is prime so stepping by it visits every value mod n once for any n it doesn't divide
+        (0..n).map(|i| (i * 7919) % n).collect()
+    }
+
+    #[test]
+    fn inserted_keys_can_be_found_after_many_splits() {
+        let manager = memory_manager(256);
+        let tree = BTree::open(&manager).unwrap();
+        let first_root = tree.root_page_id().unwrap();
+
+        for key in scrambled_keys(2000) {
+            tree.insert(&key.to_be_bytes(), key as u64 * 10).unwrap();
+        }
+        // Plenty of root splits with 256 byte pages
+        assert_ne!(tree.root_page_id().unwrap(), first_root);
+
+        for key in 0..2000u32 {
+            assert_eq!(tree.get(&key.to_be_bytes()).unwrap(), Some(key as u64 * 10));
+        }
+        assert_eq!(tree.get(&5000u32.to_be_bytes()).unwrap(), None);
+    }
+
+    #[test]
+    fn existing_keys_are_rejected_unless_upserted() {
+        let manager = memory_manager(256);
+        let tree = BTree::open(&manager).unwrap();
+
+        tree.insert(b"key1", 1).unwrap();
+        assert!(matches!(
+            tree.insert(b"key1", 2),
+            Err(DbError::DuplicateKey(key)) if key == b"key1"
+        ));
+        assert_eq!(tree.get(b"key1").unwrap(), Some(1));
+        assert_eq!(tree.upsert(b"key1", 2).unwrap(), Some(1));
+        assert_eq!(tree.upsert(b"key2", 3).unwrap(), None);
+        assert_eq!(tree.get(b"key1").unwrap(), Some(2));
+
+        assert!(!tree.delete_entry(b"key1", 1).unwrap());
+        assert!(tree.delete_entry(b"key1", 2).unwrap());
+        assert_eq!(tree.get(b"key1").unwrap(), None);
+
+        let too_big = vec![0u8; BTree::max_key_size(256) + 1];
+        assert!(matches!(
+            tree.insert(&too_big, 3),
+            Err(DbError::KeyTooLarge { .. })
+        ));
+    }
+
+    #[test]
+    fn non_unique_trees_keep_every_value_of_a_key() {
+        let manager = memory_manager(256);
+        let tree = BTree::open_with(&manager, Uniqueness::NonUnique).unwrap();
+        // "k1" is a prefix of "k10" and friends, their entries still mustn't interleave
+        let key = |i: u32| format!("k{i}").into_bytes();
+        let values = |i: u32| (0..20).map(move |j| i as u64 * 1000 + j);
+        for j in scrambled_keys(20) {
+            for i in scrambled_keys(50) {
+                tree.insert(&key(i), i as u64 * 1000 + j as u64).unwrap();
+            }
+        }
+        // Adding a pair that's already there changes nothing
+        tree.insert(&key(3), 3000).unwrap();
+
+        for i in 0..50 {
+            assert_eq!(
+                tree.get_all(&key(i)).unwrap(),
+                values(i).collect::<Vec<_>>()
+            );
+            assert_eq!(tree.get(&key(i)).unwrap(), Some(i as u64 * 1000));
+        }
+        assert_eq!(tree.get_all(b"k50").unwrap(), Vec::<u64>::new());
+
+        let entries = |cursor: BTreeCursor| -> Vec<(Vec<u8>, u64)> {
+            cursor.map(|entry| entry.unwrap()).collect()
+        };
+        let expected: Vec<(Vec<u8>, u64)> = [1, 10]
+            .into_iter()
+            .flat_map(|i| values(i).map(move |value| (key(i), value)))
+            .collect();
+        let range = tree.range(Bound::Included(b"k1"), Bound::Included(b"k10"));
+        assert_eq!(entries(range.unwrap()), expected);
+        let range = tree.range(Bound::Excluded(b"k0"), Bound::Excluded(b"k11"));
+        assert_eq!(entries(range.unwrap()), expected);
+        assert_eq!(entries(tree.prefix(b"k1").unwrap()).len(), 11 * 20);
+        assert_eq!(entries(tree.iter().unwrap()).len(), 50 * 20);
+
+        assert!(tree.delete_entry(&key(5), 5003).unwrap());
+        assert!(!tree.delete_entry(&key(5), 5003).unwrap());
+        assert_eq!(tree.delete(&key(5)).unwrap(), Some(5000));
+        let left: Vec<u64> = values(5)
+            .filter(|value| ![5000, 5003].contains(value))
+            .collect();
+        assert_eq!(tree.get_all(&key(5)).unwrap(), left);
+    }
+
+    #[test]
+    fn uniqueness_is_fixed_when_the_tree_is_created() {
+        let manager = memory_manager(256);
+        BTree::open_with(&manager, Uniqueness::NonUnique)
+            .unwrap()
+            .insert(b"key", 1)
+            .unwrap();
+
+        let tree = BTree::open(&manager).unwrap();
+        assert_eq!(tree.uniqueness(), Uniqueness::NonUnique);
+        tree.insert(b"key", 2).unwrap();
+        assert_eq!(tree.get_all(b"key").unwrap(), [1, 2]);
+
+        assert!(matches!(
+            BTree::open_with(&manager, Uniqueness::Unique),
+            Err(DbError::UniquenessMismatch {
+                requested: Uniqueness::Unique,
+                stored: Uniqueness::NonUnique
+            })
+        ));
+    }
+
+    /// Every key in the tree in the order the leaf chain visits them. Also checks that walking the
+    /// chain backwards from the last leaf visits the same leaves
+    fn keys_via_leaf_chain(tree: &BTree) -> Vec<Vec<u8>> {
+        let mut page_id = tree.root_page_id().unwrap();
+        let mut node = tree.read_node(page_id).unwrap();
+        while !node.is_leaf {
+            page_id = node.child_pointers[0];
+            node = tree.read_node(page_id).unwrap();
+        }
+        assert_eq!(node.prev_leaf, 0);
+
+        let mut leaves = vec![page_id];
+        let mut keys = node.keys.clone();
+        while node.next_leaf != 0 {
+            leaves.push(node.next_leaf);
+            node = tree.read_node(node.next_leaf).unwrap();
+            keys.extend(node.keys.iter().cloned());
+        }
+
+        let mut backwards = vec![*leaves.last().unwrap()];
+        while node.prev_leaf != 0 {
+            backwards.push(node.prev_leaf);
+            node = tree.read_node(node.prev_leaf).unwrap();
+        }
+        backwards.reverse();
+        assert_eq!(backwards, leaves);
+        keys
+    }
+
+    fn total_pages(tree: &BTree) -> u64 {
+        tree.manager
+            .with_window::<MetadataPage, _, _>(PagedFileManager::METADATA_PAGE_ID, |window| {
+                Ok(window.read_total_pages())
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn deleted_keys_are_gone_and_the_rest_stay_linked() {
+        let manager = memory_manager(256);
+        let tree = BTree::open(&manager).unwrap();
+        for key in scrambled_keys(2000) {
+            tree.insert(&key.to_be_bytes(), key as u64).unwrap();
+        }
+
+        for key in scrambled_keys(2000).into_iter().filter(|key| key % 3 != 0) {
+            assert_eq!(tree.delete(&key.to_be_bytes()).unwrap(), Some(key as u64));
+        }
+        assert_eq!(tree.delete(&1u32.to_be_bytes()).unwrap(), None);
+
+        for key in 0..2000u32 {
+            let expected = (key % 3 == 0).then_some(key as u64);
+            assert_eq!(tree.get(&key.to_be_bytes()).unwrap(), expected);
+        }
+        let remaining: Vec<Vec<u8>> = (0..2000u32)
+            .filter(|key| key % 3 == 0)
+            .map(|key| key.to_be_bytes().to_vec())
+            .collect();
+        assert_eq!(keys_via_leaf_chain(&tree), remaining);
+    }
+
+    #[test]
+    fn emptying_the_tree_shrinks_it_and_frees_its_pages() {
+        let manager = memory_manager(256);
+        let tree = BTree::open(&manager).unwrap();
+        for key in scrambled_keys(1000) {
+            tree.insert(&key.to_be_bytes(), key as u64).unwrap();
+        }
+        let pages_when_full = total_pages(&tree);
+
+        for key in scrambled_keys(1000) {
+            tree.delete(&key.to_be_bytes()).unwrap();
+        }
+        let root_page_id = tree.root_page_id().unwrap();
+        let root = tree.read_node(root_page_id).unwrap();
+        assert!(root.is_leaf && root.keys.is_empty());
+
+        // Everything freed by the deletes gets reused so the file doesn't grow
+        for key in scrambled_keys(1000) {
+            tree.insert(&key.to_be_bytes(), key as u64).unwrap();
+        }
+        assert_eq!(total_pages(&tree), pages_when_full);
+    }
+
+    #[test]
+    fn keys_of_different_lengths_share_nodes() {
+        let manager = memory_manager(256);
+        let tree = BTree::open(&manager).unwrap();
+        let max = BTree::max_key_size(256);
+        // Anywhere from 4 bytes up to the max so nodes end up mixing tiny and huge keys
+        let key = |i: u32| {
+            let mut key = i.to_be_bytes().to_vec();
+            key.resize(4 + (i as usize * 7) % (max - 3), b'x');
+            key
+        };
+
+        for i in scrambled_keys(500) {
+            tree.insert(&key(i), i as u64).unwrap();
+        }
+        for i in scrambled_keys(500).into_iter().filter(|i| i % 2 == 1) {
+            assert_eq!(tree.delete(&key(i)).unwrap(), Some(i as u64));
+        }
+
+        for i in 0..500u32 {
+            let expected = (i % 2 == 0).then_some(i as u64);
+            assert_eq!(tree.get(&key(i)).unwrap(), expected);
+        }
+        let remaining: Vec<Vec<u8>> = (0..500).filter(|i| i % 2 == 0).map(key).collect();
+        assert_eq!(keys_via_leaf_chain(&tree), remaining);
+    }
+
+    #[test]
+    fn separators_are_only_as_long_as_they_need_to_be() {
+        let manager = memory_manager(256);
+        let tree = BTree::open(&manager).unwrap();
+        // Long shared prefix, then a few bytes telling keys apart, then bytes separators can drop
+        let key = |i: u32| [b"tenant-42/2024T".as_slice(), &i.to_be_bytes(), b"/pay"].concat();
+        for i in scrambled_keys(1000) {
+            tree.insert(&key(i), i as u64).unwrap();
+        }
+
+        let root_page_id = tree.root_page_id().unwrap();
+        let root = tree.read_node(root_page_id).unwrap();
+        assert!(!root.is_leaf);
+        assert!(root
+            .keys
+            .iter()
+            .all(|separator| separator.len() < key(0).len()));
+        for i in 0..1000u32 {
+            assert_eq!(tree.get(&key(i)).unwrap(), Some(i as u64));
+        }
+        let scanned: Vec<u64> = tree
+            .prefix(b"tenant-42/")
+            .unwrap()
+            .map(|entry| entry.unwrap().1)
+            .collect();
+        assert_eq!(scanned, (0..1000).collect::<Vec<_>>());
+    }
+
+    fn sorted_entries(n: u32) -> impl Iterator<Item = (Vec<u8>, u64)> {
+        (0..n).map(|key| (key.to_be_bytes().to_vec(), key as u64))
+    }
+
+    #[test]
+    fn bulk_loaded_trees_are_packed_and_behave_like_any_other() {
+        let manager = memory_manager(256);
+        let tree = BTree::open(&manager).unwrap();
+        for key in scrambled_keys(2000) {
+            tree.insert(&key.to_be_bytes(), key as u64).unwrap();
+        }
+        let pages_when_inserted = total_pages(&tree);
+
+        // Replaces the inserted tree, reusing its pages
+        tree.bulk_load(sorted_entries(2000), 1.0).unwrap();
+        assert_eq!(total_pages(&tree), pages_when_inserted);
+        let expected: Vec<Vec<u8>> = sorted_entries(2000).map(|(key, _)| key).collect();
+        assert_eq!(keys_via_leaf_chain(&tree), expected);
+
+        // Packed full pages take far fewer than half full ones
+        let packed = memory_manager(256);
+        let packed_tree = BTree::open(&packed).unwrap();
+        packed_tree.bulk_load(sorted_entries(2000), 1.0).unwrap();
+        let sparse = memory_manager(256);
+        let sparse_tree = BTree::open(&sparse).unwrap();
+        sparse_tree.bulk_load(sorted_entries(2000), 0.5).unwrap();
+        assert!(total_pages(&packed_tree) * 3 < total_pages(&sparse_tree) * 2);
+
+        for key in (0..2000u32).step_by(2) {
+            assert_eq!(tree.delete(&key.to_be_bytes()).unwrap(), Some(key as u64));
+        }
+        tree.insert(&5000u32.to_be_bytes(), 5000).unwrap();
+        for key in 0..2000u32 {
+            let expected = (key % 2 == 1).then_some(key as u64);
+            assert_eq!(tree.get(&key.to_be_bytes()).unwrap(), expected);
+        }
+        assert_eq!(tree.get(&5000u32.to_be_bytes()).unwrap(), Some(5000));
+    }
+
+    #[test]
+    fn bad_bulk_load_input_leaves_the_tree_alone() {
+        let manager = memory_manager(256);
+        let tree = BTree::open(&manager).unwrap();
+        tree.bulk_load(sorted_entries(100), 0.9).unwrap();
+
+        let unsorted = sorted_entries(500).chain(sorted_entries(1));
+        assert!(matches!(
+            tree.bulk_load(unsorted, 0.9),
+            Err(DbError::UnsortedKeys { .. })
+        ));
+        let expected: Vec<Vec<u8>> = sorted_entries(100).map(|(key, _)| key).collect();
+        assert_eq!(keys_via_leaf_chain(&tree), expected);
+
+        tree.bulk_load(std::iter::empty(), 0.9).unwrap();
+        let root_page_id = tree.root_page_id().unwrap();
+        let root = tree.read_node(root_page_id).unwrap();
+        assert!(root.is_leaf && root.keys.is_empty());
+    }
+
+    #[test]
+    fn tree_survives_reopening_the_file() {
+        let path = crate::tests::temp_db_path("btree_reopen");
+        let config = || PagedFileManagerConfigBuilder::new().page_size(256).build();
+        {
+            let manager = PagedFileManager::new(&path, config()).unwrap();
+            let tree = BTree::open(&manager).unwrap();
+            for key in scrambled_keys(300) {
+                tree.insert(&key.to_be_bytes(), key as u64).unwrap();
+            }
+        }
+
+        let manager = PagedFileManager::new(&path, config()).unwrap();
+        let tree = BTree::open(&manager).unwrap();
+        for key in 0..300u32 {
+            assert_eq!(tree.get(&key.to_be_bytes()).unwrap(), Some(key as u64));
+        }
+
+        drop(manager);
+        crate::tests::remove_db(&path);
+    }
+
+    #[test]
+    fn delete_entry_only_removes_the_value_it_was_given() {
+        let manager = memory_manager(256);
+        let tree = BTree::open(&manager).unwrap();
+        let key = |i: u64| (i % 8).to_be_bytes();
+
+        // Every value goes in once, so each has to come out exactly once: replaced by an upsert,
+        // removed by its own delete_entry or still in the tree at the end
+        let gone: Vec<u64> = thread::scope(|scope| {
+            let workers: Vec<_> = (0..4u64)
+                .map(|worker| {
+                    let tree = &tree;
+                    scope.spawn(move || {
+                        let mut gone = Vec::new();
+                        for i in 0..2000 {
+                            let value = worker * 10_000 + i;
+                            gone.extend(tree.upsert(&key(i), value).unwrap());
+                            if tree.delete_entry(&key(i), value).unwrap() {
+                                gone.push(value);
+                            }
+                        }
+                        gone
+                    })
+                })
+                .collect();
+            workers
+                .into_iter()
+                .flat_map(|worker| worker.join().unwrap())
+                .collect()
+        });
+
+        let mut seen: Vec<u64> = (0..8)
+            .filter_map(|i| tree.get(&key(i)).unwrap())
+            .chain(gone)
+            .collect();
+        seen.sort();
+        let expected: Vec<u64> = (0..4u64)
+            .flat_map(|worker| (0..2000).map(move |i| worker * 10_000 + i))
+            .collect();
+        assert_eq!(seen, expected);
+    }
+
+    #[test]
+    fn readers_and_writers_can_share_the_tree_across_threads() {
+        let manager = memory_manager(256);
+        let tree = BTree::open(&manager).unwrap();
+        // Multiples of 5 are never touched by the writers so readers can check they're always
+        // there, every writer owns the keys with one of the other remainders
+        let stable: Vec<u32> = (0..400).map(|i| i * 5).collect();
+        for &key in &stable {
+            tree.insert(&key.to_be_bytes(), key as u64).unwrap();
+        }
+        let owned = |writer: u32| {
+            scrambled_keys(400)
+                .into_iter()
+                .map(move |i| i * 5 + writer + 1)
+        };
+        let kept = |key: &u32| key.is_multiple_of(2);
+
+        let writers_done = AtomicUsize::new(0);
+        thread::scope(|scope| {
+            for writer in 0..4 {
+                let (tree, writers_done) = (&tree, &writers_done);
+                scope.spawn(move || {
+                    for key in owned(writer) {
+                        tree.insert(&key.to_be_bytes(), key as u64).unwrap();
+                    }
+                    for key in owned(writer).filter(|key| !kept(key)) {
+                        assert_eq!(tree.delete(&key.to_be_bytes()).unwrap(), Some(key as u64));
+                    }
+                    writers_done.fetch_add(1, Ordering::SeqCst);
+                });
+            }
+            for reader in 0..4 {
+                let (tree, stable, writers_done) = (&tree, &stable, &writers_done);
+                scope.spawn(move || loop {
+                    let finished = writers_done.load(Ordering::SeqCst) == 4;
+                    for &key in stable.iter().skip(reader).step_by(37) {
+                        assert_eq!(tree.get(&key.to_be_bytes()).unwrap(), Some(key as u64));
+                    }
+                    let decode = |entry: Result<(Vec<u8>, u64)>| {
+                        u32::from_be_bytes(entry.unwrap().0.try_into().unwrap())
+                    };
+                    let forward: Vec<u32> = tree.iter().unwrap().map(decode).collect();
+                    assert!(forward.is_sorted_by(|a, b| a < b));
+                    let mut backward: Vec<u32> =
+                        tree.iter_back().unwrap().backwards().map(decode).collect();
+                    backward.reverse();
+                    assert!(backward.is_sorted_by(|a, b| a < b));
+                    for keys in [forward, backward] {
+                        let seen = keys.iter().filter(|key| key.is_multiple_of(5)).count();
+                        assert_eq!(seen, stable.len());
+                    }
+                    if finished {
+                        break;
+                    }
+                });
+            }
+        });
+
+        let mut expected: Vec<u32> = (0..4).flat_map(owned).filter(kept).collect();
+        expected.extend(&stable);
+        expected.sort();
+        let expected: Vec<Vec<u8>> = expected
+            .iter()
+            .map(|key| key.to_be_bytes().to_vec())
+            .collect();
+        assert_eq!(keys_via_leaf_chain(&tree), expected);
+        for key in &expected {
+            assert!(tree.get(key).unwrap().is_some());
+        }
+    }
+
+    #[test]
+    fn a_key_breaking_the_shared_prefix_still_splits_into_halves_that_fit() {
+        let manager = memory_manager(4096);
+        let tree = BTree::open(&manager).unwrap();
+        // 48 byte keys that only differ in their last few bytes, so a leaf holds far more of them
+        // than it could if the prefix weren't shared
+        let key = |i: u32| format!("tenant-0042|2024-06-01T00:00:00.000000|{i:>09}").into_bytes();
+        // As many keys as the root leaf takes before it splits, so it's full when the last comes
+        let full = {
+            let manager = memory_manager(4096);
+            let tree = BTree::open(&manager).unwrap();
+            (0..)
+                .find(|&i| {
+                    tree.insert(&key(i), i as u64).unwrap();
+                    let root_page_id = tree.root_page_id().unwrap();
+                    !tree.read_node(root_page_id).unwrap().is_leaf
+                })
+                .unwrap()
+        };
+        for i in 0..full {
+            tree.insert(&key(i), i as u64).unwrap();
+        }
+        let last = format!("tenant-0043|{}", "0".repeat(36)).into_bytes();
+        tree.insert(&last, full as u64).unwrap();
+
+        for i in 0..full {
+            assert_eq!(tree.get(&key(i)).unwrap(), Some(i as u64));
+        }
+        assert_eq!(tree.get(&last).unwrap(), Some(full as u64));
+        let mut expected: Vec<Vec<u8>> = (0..full).map(key).collect();
+        expected.push(last);
+        assert_eq!(keys_via_leaf_chain(&tree), expected);
+    }
+
+    #[test]
+    fn a_short_key_next_to_long_ones_still_splits_into_halves_that_fit() {
+        // Once before all the long keys and once after them
+        for short in [b"a", b"b"] {
+            let manager = memory_manager(512);
+            let tree = BTree::open(&manager).unwrap();
+            let key = |i: u32| [[b'a'; 80].as_slice(), &i.to_be_bytes()].concat();
+            for i in 0..200 {
+                tree.insert(&key(i), i as u64).unwrap();
+            }
+            tree.insert(short, 200).unwrap();
+
+            for i in 0..200u32 {
+                assert_eq!(tree.get(&key(i)).unwrap(), Some(i as u64));
+            }
+            assert_eq!(tree.get(short).unwrap(), Some(200));
+        }
+    }
+
+    /// Long keys from a few families with different prefixes, so nodes keep gaining and losing
+    /// the prefix their keys share
+    fn long_key(i: u16) -> Vec<u8> {
+        let family = [b'a', b'b', b'c'][i as usize % 3];
+        let mut key = vec![family; 40 + (i as usize * 13) % 40];
+        key.extend_from_slice(&i.to_be_bytes());
+        key
+    }
+
+    proptest! {
+        #[test]
+        fn tree_matches_a_btreemap_under_inserts_and_deletes(
+            ops in proptest::collection::vec((any::<bool>(), 0..300u16), 1..600)
+        ) {
+            let manager = memory_manager(512);
+            let tree = BTree::open(&manager).unwrap();
+            let mut model = std::collections::BTreeMap::new();
+            for (insert, i) in ops {
+                let key = long_key(i);
+                if insert {
+                    prop_assert_eq!(tree.upsert(&key, i as u64).unwrap(), model.insert(key, i as u64));
+                } else {
+                    prop_assert_eq!(tree.delete(&key).unwrap(), model.remove(&key));
+                }
+            }
+            for (key, value) in &model {
+                prop_assert_eq!(tree.get(key).unwrap(), Some(*value));
+            }
+            prop_assert_eq!(keys_via_leaf_chain(&tree), model.into_keys().collect::<Vec<_>>());
+        }
+    }
+}
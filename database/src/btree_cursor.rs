@@ -0,0 +1,604 @@
+use std::cmp::Ordering;
+use std::ops::Bound;
+
+use crate::btree::{BTree, Uniqueness};
+use crate::error::{DbError, Result};
+use crate::key_codec::bytes_column_prefix;
+use crate::{IndexPage, PageType, PinnedPage};
+
+/// Walks the entries of a `BTree` in key order between a lower and an upper bound, in either
+/// direction. The current leaf stays pinned in the buffer pool and each entry's key is copied
+/// straight out of it as it's handed out; running off either end of the leaf follows next_leaf
+/// or prev_leaf, so dropping the cursor early never reads leaves past where it stopped.
+///
+/// The cursor sits between two entries. `next` hands out the entry after it and `prev` the one
+/// before it, so calling one right after the other gives the same entry twice. In non-unique trees
+/// a key with several values comes up once per value, ordered by value.
+///
+/// No latches are held between calls, so other threads can change the tree while a cursor is open.
+/// Every read of the leaf checks its lsn is still the one it had when the cursor got there, and if
+/// it isn't the cursor finds its place again from the root. Entries are never skipped or repeated
+/// because of that, but the cursor can see a mix of entries from before and after a change
+pub struct BTreeCursor<'t, 'a> {
+    tree: &'t BTree<'a>,
+    leaf: Leaf<'a>,
+    // Position in the leaf, the entry at `index` is the next one and the one before it the previous
+    index: usize,
+    // Where the cursor is in terms of keys, as the bound and end of a range a new cursor would
+    // start at to be in the same place. Used to find the place again when the leaf changes
+    place: (Bound<Vec<u8>>, End),
+    lower: Bound<Vec<u8>>,
+    upper: Bound<Vec<u8>>,
+    // Set after the first error, the cursor can't tell where it is after that
+    failed: bool,
+}
+
+/// The leaf a cursor is in, pinned for as long as the cursor stays there. The rest is as it was
+/// when the leaf was pinned and only holds while the page's lsn is still `lsn`
+struct Leaf<'a> {
+    page: PinnedPage<'a>,
+    lsn: u64,
+    keys_len: usize,
+    prev_leaf: u64,
+    next_leaf: u64,
+}
+
+/// Iterator over a cursor's entries in descending key order, see `BTreeCursor::backwards`
+pub struct Backwards<'t, 'a>(BTreeCursor<'t, 'a>);
+
+/// Which end of its range a new cursor starts at
+#[derive(Clone, Copy, PartialEq)]
+enum End {
+    Front,
+    Back,
+}
+
+impl End {
+    fn opposite(self) -> End {
+        match self {
+            End::Front => End::Back,
+            End::Back => End::Front,
+        }
+    }
+}
+
+impl<'a> Leaf<'a> {
+    /// Pins the leaf and reads what the cursor needs to know about it. The caller has to hold its
+    /// latch so nothing is halfway through changing it
+    fn pin(tree: &BTree<'a>, page_id: u64) -> Result<Self> {
+        let page = tree.pin(page_id)?;
+        let (lsn, keys_len, prev_leaf, next_leaf) =
+            page.with_window::<IndexPage, _, _>(|window| {
+                if window.read_page_type()? != PageType::Index || !window.is_leaf() {
+                    return Err(DbError::Corruption {
+                        page_id,
+                        reason: "expected a B+Tree leaf".to_string(),
+                    });
+                }
+                Ok((
+                    window.read_lsn(),
+                    window.keys_len(),
+                    window.prev_leaf(),
+                    window.next_leaf(),
+                ))
+            })?;
+        Ok(Leaf {
+            page,
+            lsn,
+            keys_len,
+            prev_leaf,
+            next_leaf,
+        })
+    }
+
+    fn is_current(&self) -> Result<bool> {
+        self.page
+            .with_window::<IndexPage, _, _>(|window| Ok(window.read_lsn() == self.lsn))
+    }
+
+    /// Stored key and value of the entry at `index`, None if the leaf has changed
+    fn entry(&self, index: usize) -> Result<Option<(Vec<u8>, u64)>> {
+        self.page.with_window::<IndexPage, _, _>(|window| {
+            if window.read_lsn() != self.lsn {
+                return Ok(None);
+            }
+            Ok(Some((window.key(index)?, window.child_pointer(index)?)))
+        })
+    }
+
+    /// Stored key of the entry at the leaf's `end`, None if the leaf has changed or is empty
+    fn edge_key(&self, end: End) -> Result<Option<Vec<u8>>> {
+        let index = match end {
+            End::Front => 0,
+            End::Back => self.keys_len.wrapping_sub(1),
+        };
+        if index >= self.keys_len {
+            return Ok(None);
+        }
+        Ok(self.entry(index)?.map(|(key, _)| key))
+    }
+
+    /// Number of keys at the start of the leaf that `before` holds for when compared to `key`
+    fn partition_point(&self, key: &[u8], before: impl Fn(Ordering) -> bool) -> Result<usize> {
+        self.page.with_window::<IndexPage, _, _>(|window| {
+            let (mut low, mut high) = (0, self.keys_len);
+            while low < high {
+                let middle = low + (high - low) / 2;
+                if before(window.compare_key(middle, key)?) {
+                    low = middle + 1;
+                } else {
+                    high = middle;
+                }
+            }
+            Ok(low)
+        })
+    }
+}
+
+impl<'a> BTree<'a> {
+    /// Cursor over every entry with a key between the two bounds, starting before the first one
+    pub fn range(&self, lower: Bound<&[u8]>, upper: Bound<&[u8]>) -> Result<BTreeCursor<'_, 'a>> {
+        let (lower, upper) = self.stored_bounds(lower, upper);
+        self.seek(lower, upper, End::Front)
+    }
+
+    /// Same as `range` but starting after the last entry, for walking it backwards
+    pub fn range_back(
+        &self,
+        lower: Bound<&[u8]>,
+        upper: Bound<&[u8]>,
+    ) -> Result<BTreeCursor<'_, 'a>> {
+        let (lower, upper) = self.stored_bounds(lower, upper);
+        self.seek(lower, upper, End::Back)
+    }
+
+    /// Cursor over every entry in the tree, starting before the first one
+    pub fn iter(&self) -> Result<BTreeCursor<'_, 'a>> {
+        self.range(Bound::Unbounded, Bound::Unbounded)
+    }
+
+    /// Cursor over every entry in the tree, starting after the last one
+    pub fn iter_back(&self) -> Result<BTreeCursor<'_, 'a>> {
+        self.range_back(Bound::Unbounded, Bound::Unbounded)
+    }
+
+    /// Cursor over every entry whose key starts with `prefix`, starting before the first one
+    pub fn prefix(&self, prefix: &[u8]) -> Result<BTreeCursor<'_, 'a>> {
+        let prefix = self.stored_key_prefix_of(prefix);
+        self.stored_prefix(prefix, End::Front)
+    }
+
+    /// Same as `prefix` but starting after the last entry, for walking it backwards
+    pub fn prefix_back(&self, prefix: &[u8]) -> Result<BTreeCursor<'_, 'a>> {
+        let prefix = self.stored_key_prefix_of(prefix);
+        self.stored_prefix(prefix, End::Back)
+    }
+
+    /// Cursor over every entry of the key in a non-unique tree
+    pub(crate) fn exact(&self, key: &[u8]) -> Result<BTreeCursor<'_, 'a>> {
+        self.stored_prefix(BTree::stored_key_prefix(key), End::Front)
+    }
+
+    /// What every stored key of a key starting with `prefix` starts with
+    fn stored_key_prefix_of(&self, prefix: &[u8]) -> Vec<u8> {
+        match self.uniqueness() {
+            Uniqueness::Unique => prefix.to_vec(),
+            Uniqueness::NonUnique => bytes_column_prefix(prefix),
+        }
+    }
+
+    /// Bounds on stored keys that take in exactly the entries whose keys are between the two
+    /// bounds
+    fn stored_bounds(
+        &self,
+        lower: Bound<&[u8]>,
+        upper: Bound<&[u8]>,
+    ) -> (Bound<Vec<u8>>, Bound<Vec<u8>>) {
+        if self.uniqueness() == Uniqueness::Unique {
+            return (lower.map(|key| key.to_vec()), upper.map(|key| key.to_vec()));
+        }
+
+        // Every stored key of a key starts with the same prefix, and everything starting with the
+        // prefix sorts before its successor
+        let stored_prefix = |key: &[u8]| BTree::stored_key_prefix(key);
+        let past_stored_prefix = |key: &[u8]| {
+            prefix_successor(&BTree::stored_key_prefix(key))
+                .expect("encoded keys end in a terminator, which can be incremented")
+        };
+        let lower = match lower {
+            Bound::Included(key) => Bound::Included(stored_prefix(key)),
+            Bound::Excluded(key) => Bound::Included(past_stored_prefix(key)),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        let upper = match upper {
+            Bound::Included(key) => Bound::Excluded(past_stored_prefix(key)),
+            Bound::Excluded(key) => Bound::Excluded(stored_prefix(key)),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        (lower, upper)
+    }
+
+    /// Cursor over every entry whose stored key starts with `prefix`
+    fn stored_prefix(&self, prefix: Vec<u8>, end: End) -> Result<BTreeCursor<'_, 'a>> {
+        let upper = match prefix_successor(&prefix) {
+            Some(successor) => Bound::Excluded(successor),
+            None => Bound::Unbounded,
+        };
+        self.seek(Bound::Included(prefix), upper, end)
+    }
+
+    /// Cursor over every entry with a stored key between the two bounds, starting at `end`
+    fn seek(
+        &self,
+        lower: Bound<Vec<u8>>,
+        upper: Bound<Vec<u8>>,
+        end: End,
+    ) -> Result<BTreeCursor<'_, 'a>> {
+        let bound = match end {
+            End::Front => lower.clone(),
+            End::Back => upper.clone(),
+        };
+        let (leaf, index) = self.position(bound.as_ref().map(Vec::as_slice), end)?;
+        Ok(BTreeCursor {
+            tree: self,
+            leaf,
+            index,
+            place: (bound, end),
+            lower,
+            upper,
+            failed: false,
+        })
+    }
+
+    /// Leaf and index a cursor starting at `bound` goes to, `end` saying which end of the range
+    /// the bound is. That's found by descending towards the bound, the leaf it lands in might not
+    /// hold any entry in the range but the cursor steps over to its neighbour when asked for one
+    fn position(&self, bound: Bound<&[u8]>, end: End) -> Result<(Leaf<'a>, usize)> {
+        let (page_id, _latch) = self.latch_leaf(|node| match bound {
+            Bound::Included(key) | Bound::Excluded(key) => node.child_index(key),
+            Bound::Unbounded if end == End::Front => 0,
+            Bound::Unbounded => node.child_pointers.len() - 1,
+        })?;
+
+        let leaf = Leaf::pin(self, page_id)?;
+        let index = match (end, bound) {
+            (End::Front, Bound::Included(key)) | (End::Back, Bound::Excluded(key)) => {
+                leaf.partition_point(key, |probe| probe == Ordering::Less)?
+            }
+            (End::Front, Bound::Excluded(key)) | (End::Back, Bound::Included(key)) => {
+                leaf.partition_point(key, |probe| probe != Ordering::Greater)?
+            }
+            (End::Front, Bound::Unbounded) => 0,
+            (End::Back, Bound::Unbounded) => leaf.keys_len,
+        };
+        Ok((leaf, index))
+    }
+}
+
+/// Smallest key bigger than every key starting with `prefix`. None if there isn't one, which is
+/// the case when the prefix is all 0xff bytes
+fn prefix_successor(prefix: &[u8]) -> Option<Vec<u8>> {
+    let last_incrementable = prefix.iter().rposition(|&byte| byte != 0xff)?;
+    let mut successor = prefix[..=last_incrementable].to_vec();
+    successor[last_incrementable] += 1;
+    Some(successor)
+}
+
+impl<'t, 'a> BTreeCursor<'t, 'a> {
+    /// The entry before the cursor, moving the cursor back over it. None once the cursor is at
+    /// the start of its range
+    pub fn prev(&mut self) -> Option<Result<(Vec<u8>, u64)>> {
+        self.advance(End::Front)
+    }
+
+    /// Turns the cursor into an iterator walking backwards from where it is, like repeatedly
+    /// calling `prev`
+    pub fn backwards(self) -> Backwards<'t, 'a> {
+        Backwards(self)
+    }
+
+    fn advance(&mut self, towards: End) -> Option<Result<(Vec<u8>, u64)>> {
+        if self.failed {
+            return None;
+        }
+        let entry = self.try_advance(towards);
+        if entry.is_err() {
+            self.failed = true;
+        }
+        entry.transpose()
+    }
+
+    /// Moves over the entry next to the cursor on its `towards` side and returns it, None if
+    /// that's past the end of the range
+    fn try_advance(&mut self, towards: End) -> Result<Option<(Vec<u8>, u64)>> {
+        loop {
+            let index = match towards {
+                End::Front if self.index > 0 => self.index - 1,
+                End::Back if self.index < self.leaf.keys_len => self.index,
+                _ => {
+                    if !self.step(towards)? {
+                        return Ok(None);
+                    }
+                    continue;
+                }
+            };
+            let Some((key, value)) = self.leaf.entry(index)? else {
+                self.find_place()?;
+                continue;
+            };
+            let out_of_range = match towards {
+                End::Front => self.before_lower(&key),
+                End::Back => self.past_upper(&key),
+            };
+            if out_of_range {
+                return Ok(None);
+            }
+
+            self.index = match towards {
+                End::Front => index,
+                End::Back => index + 1,
+            };
+            self.place = (Bound::Excluded(key.clone()), towards.opposite());
+            return Ok(Some((self.tree.user_key(key)?, value)));
+        }
+    }
+
+    /// Moves on to the leaf past the current one's `towards` end, returning false if there isn't
+    /// one. Only done when the cursor is at that end of its leaf
+    fn step(&mut self, towards: End) -> Result<bool> {
+        {
+            let _latch = self.tree.latch_shared(self.leaf.page.page_id())?;
+            if self.leaf.is_current()? {
+                let neighbour_page_id = match towards {
+                    End::Front => self.leaf.prev_leaf,
+                    End::Back => self.leaf.next_leaf,
+                };
+                if neighbour_page_id == 0 {
+                    return Ok(false);
+                }
+                // Latches are only ever waited on going down the tree, so the neighbour is only
+                // taken if it's free right away
+                if let Some(_neighbour_latch) = self.tree.try_latch_shared(neighbour_page_id)? {
+                    let neighbour = Leaf::pin(self.tree, neighbour_page_id)?;
+                    self.index = match towards {
+                        End::Front => neighbour.keys_len,
+                        End::Back => 0,
+                    };
+                    self.leaf = neighbour;
+                    return Ok(true);
+                }
+                // Otherwise it's found from the root, aiming just past this leaf's entries
+                if let Some(edge_key) = self.leaf.edge_key(towards)? {
+                    self.place = (Bound::Excluded(edge_key), towards.opposite());
+                }
+            }
+        }
+        self.find_place()?;
+        Ok(true)
+    }
+
+    /// Finds the cursor's place again from the root, for when its leaf has changed
+    fn find_place(&mut self) -> Result<()> {
+        let (bound, end) = &self.place;
+        let (leaf, index) = self
+            .tree
+            .position(bound.as_ref().map(Vec::as_slice), *end)?;
+        self.leaf = leaf;
+        self.index = index;
+        Ok(())
+    }
+
+    fn before_lower(&self, key: &[u8]) -> bool {
+        match &self.lower {
+            Bound::Included(lower) => key < lower.as_slice(),
+            Bound::Excluded(lower) => key <= lower.as_slice(),
+            Bound::Unbounded => false,
+        }
+    }
+
+    fn past_upper(&self, key: &[u8]) -> bool {
+        match &self.upper {
+            Bound::Included(upper) => key > upper.as_slice(),
+            Bound::Excluded(upper) => key >= upper.as_slice(),
+            Bound::Unbounded => false,
+        }
+    }
+}
+
+impl Iterator for BTreeCursor<'_, '_> {
+    type Item = Result<(Vec<u8>, u64)>;
+
+    /// The entry after the cursor, moving the cursor forward over it. None once the cursor is at
+    /// the end of its range
+    fn next(&mut self) -> Option<Self::Item> {
+        self.advance(End::Back)
+    }
+}
+
+impl Iterator for Backwards<'_, '_> {
+    type Item = Result<(Vec<u8>, u64)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.prev()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::memory_manager;
+
+    fn keys_of(cursor: BTreeCursor) -> Vec<u32> {
+        cursor
+            .map(|entry| {
+                let (key, value) = entry.unwrap();
+                let key = u32::from_be_bytes(key.try_into().unwrap());
+                assert_eq!(value, key as u64);
+                key
+            })
+            .collect()
+    }
+
+    fn keys_of_backwards(cursor: BTreeCursor) -> Vec<u32> {
+        cursor
+            .backwards()
+            .map(|entry| u32::from_be_bytes(entry.unwrap().0.try_into().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn ranges_respect_their_bounds_across_leaves() {
+        let manager = memory_manager(256);
+        let tree = BTree::open(&manager).unwrap();
+        // Even keys only so bounds can fall between keys too
+        for key in (0..1000u32).rev().map(|key| key * 2) {
+            tree.insert(&key.to_be_bytes(), key as u64).unwrap();
+        }
+        let (lo, hi) = (100u32.to_be_bytes(), 900u32.to_be_bytes());
+
+        let all = keys_of(tree.iter().unwrap());
+        assert_eq!(all, (0..1000).map(|key| key * 2).collect::<Vec<_>>());
+
+        let inclusive = tree.range(Bound::Included(&lo), Bound::Included(&hi));
+        assert_eq!(
+            keys_of(inclusive.unwrap()),
+            (50..=450).map(|key| key * 2).collect::<Vec<_>>()
+        );
+        let exclusive = tree.range(Bound::Excluded(&lo), Bound::Excluded(&hi));
+        assert_eq!(
+            keys_of(exclusive.unwrap()),
+            (51..450).map(|key| key * 2).collect::<Vec<_>>()
+        );
+
+        let odd = 101u32.to_be_bytes();
+        let from_odd = tree.range(Bound::Included(&odd), Bound::Unbounded).unwrap();
+        assert_eq!(keys_of(from_odd).first(), Some(&102));
+
+        let past_the_end = 5000u32.to_be_bytes();
+        let empty = tree.range(Bound::Included(&past_the_end), Bound::Unbounded);
+        assert!(keys_of(empty.unwrap()).is_empty());
+
+        // Stopping early is just not asking for more
+        let first_three = tree.iter().unwrap().take(3);
+        assert_eq!(
+            first_three
+                .map(|entry| entry.unwrap().1)
+                .collect::<Vec<_>>(),
+            [0, 2, 4]
+        );
+    }
+
+    #[test]
+    fn backward_scans_mirror_forward_ones() {
+        let manager = memory_manager(256);
+        let tree = BTree::open(&manager).unwrap();
+        for key in (0..1000u32).map(|key| key * 2) {
+            tree.insert(&key.to_be_bytes(), key as u64).unwrap();
+        }
+        let (lo, hi) = (100u32.to_be_bytes(), 900u32.to_be_bytes());
+
+        let mut forward = keys_of(tree.iter().unwrap());
+        forward.reverse();
+        assert_eq!(keys_of_backwards(tree.iter_back().unwrap()), forward);
+
+        let inclusive = tree.range_back(Bound::Included(&lo), Bound::Included(&hi));
+        assert_eq!(
+            keys_of_backwards(inclusive.unwrap()),
+            (50..=450).rev().map(|key| key * 2).collect::<Vec<_>>()
+        );
+        let exclusive = tree.range_back(Bound::Excluded(&lo), Bound::Excluded(&hi));
+        assert_eq!(
+            keys_of_backwards(exclusive.unwrap()),
+            (51..450).rev().map(|key| key * 2).collect::<Vec<_>>()
+        );
+
+        // The latest few entries before a point without touching anything before them
+        let odd = 901u32.to_be_bytes();
+        let latest = tree.range_back(Bound::Unbounded, Bound::Excluded(&odd));
+        let latest: Vec<u32> = keys_of_backwards(latest.unwrap())
+            .into_iter()
+            .take(3)
+            .collect();
+        assert_eq!(latest, [900, 898, 896]);
+
+        let before_the_start = tree.range_back(Bound::Unbounded, Bound::Excluded(&[0u8; 4]));
+        assert!(keys_of_backwards(before_the_start.unwrap()).is_empty());
+    }
+
+    #[test]
+    fn cursors_can_change_direction() {
+        let manager = memory_manager(256);
+        let tree = BTree::open_with(&manager, Uniqueness::NonUnique).unwrap();
+        for key in 0..300u32 {
+            for value in 0..3 {
+                tree.insert(&key.to_be_bytes(), (key * 3 + value) as u64)
+                    .unwrap();
+            }
+        }
+        let value = |entry: Option<Result<(Vec<u8>, u64)>>| entry.unwrap().unwrap().1;
+
+        let mut cursor = tree.iter().unwrap();
+        assert!(cursor.prev().is_none());
+        assert_eq!(value(cursor.next()), 0);
+        assert_eq!(value(cursor.next()), 1);
+        assert_eq!(value(cursor.prev()), 1);
+        assert_eq!(value(cursor.prev()), 0);
+        assert!(cursor.prev().is_none());
+
+        // Turning around in the middle works across leaf boundaries too
+        let mut cursor = tree.iter().unwrap();
+        for expected in 0..500 {
+            assert_eq!(value(cursor.next()), expected);
+        }
+        for expected in (0..500).rev() {
+            assert_eq!(value(cursor.prev()), expected);
+        }
+        let key = 42u32.to_be_bytes();
+        let mut cursor = tree
+            .range_back(Bound::Included(&key), Bound::Included(&key))
+            .unwrap();
+        assert_eq!(value(cursor.prev()), 128);
+        assert_eq!(value(cursor.next()), 128);
+        assert!(cursor.next().is_none());
+        let values: Vec<u64> = cursor.backwards().map(|entry| entry.unwrap().1).collect();
+        assert_eq!(values, [128, 127, 126]);
+
+        // Keys 256 to 299 are the ones starting with [0, 0, 1]
+        let last_with_prefix = tree.prefix_back(&[0, 0, 1]).unwrap().backwards().next();
+        assert_eq!(value(last_with_prefix), 299 * 3 + 2);
+    }
+
+    #[test]
+    fn prefix_scans_only_return_matching_keys() {
+        let manager = memory_manager(256);
+        let tree = BTree::open(&manager).unwrap();
+        for (value, key) in [
+            b"aa01",
+            b"ab01",
+            b"ab02",
+            b"ab\xff\xff",
+            b"ac01",
+            b"b\xff\xff\xff",
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            tree.insert(key, value as u64).unwrap();
+        }
+
+        let prefixed = |tree: &BTree, prefix: &[u8]| -> Vec<Vec<u8>> {
+            tree.prefix(prefix)
+                .unwrap()
+                .map(|entry| entry.unwrap().0)
+                .collect()
+        };
+        assert_eq!(
+            prefixed(&tree, b"ab"),
+            [b"ab01".to_vec(), b"ab02".to_vec(), b"ab\xff\xff".to_vec()]
+        );
+        assert_eq!(prefixed(&tree, b"b\xff"), [b"b\xff\xff\xff".to_vec()]);
+        assert!(prefixed(&tree, b"zz").is_empty());
+        assert_eq!(prefix_successor(b"a\xff"), Some(b"b".to_vec()));
+        assert_eq!(prefix_successor(b"\xff\xff"), None);
+    }
+}
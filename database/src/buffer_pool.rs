@@ -0,0 +1,670 @@
+use std::collections::HashMap;
+use std::mem;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::thread::{self, ThreadId};
+
+use crate::error::{DbError, Result};
+use crate::page_store::SharedPageStore;
+use crate::wal::Wal;
+use crate::{read_be_u64, PageHeader};
+
+/// Index of a frame within the buffer pool
+pub type FrameId = usize;
+
+/// Bookkeeping for a single slot in the buffer pool that can hold one page worth of bytes. The
+/// bytes themselves live next to it in `BufferPool::frames` so they can be locked on their own.
+///
+/// `referenced` is the "second chance" bit used by the clock replacer. It gets set every time the
+/// frame is pinned and cleared as the clock hand sweeps past it.
+///
+/// `loading` is set while the page is being read in from disk without the pool's state locked.
+/// The frame is already in the page table so nobody reads the page in twice, but nobody else gets
+/// to pin it until the read is done.
+struct Frame {
+    page_id: Option<u64>,
+    pin_count: u32,
+    is_dirty: bool,
+    referenced: bool,
+    loading: bool,
+}
+
+impl Frame {
+    fn new() -> Self {
+        Frame {
+            page_id: None,
+            pin_count: 0,
+            is_dirty: false,
+            referenced: false,
+            loading: false,
+        }
+    }
+}
+
+/// Everything about the pool that changes when pages are pinned, unpinned or evicted
+struct PoolState {
+    frames: Vec<Frame>,
+    page_table: HashMap<u64, FrameId>, // pageId -> frame holding it
+    free_frames: Vec<FrameId>,
+    clock_hand: FrameId,
+}
+
+/// Fixed size pool of page frames sitting in front of the database's page store.
+///
+/// Pages are pinned while in use and can only be evicted once their pin count drops back to 0.
+/// Dirty pages are only written back to disk when they get evicted or when a flush is requested,
+/// so edits made to the bytes of a pinned frame (through a `PageWindow` for example) will persist
+/// as long as the frame was marked dirty when unpinning.
+///
+/// Eviction uses the clock algorithm. It's not as good as LRU-K or anything fancy but it never
+/// picks a pinned page and it's cheap.
+///
+/// The pool is also where page checksums live. Every page written back gets its checksum stamped
+/// and every page read from disk gets verified before anyone can see its bytes.
+///
+/// When given a write-ahead log the pool enforces the WAL-before-data rule: a page is never
+/// written back until the log is durable up to the lsn stamped in the page's header.
+///
+/// The pool can be shared between threads. Pinning and eviction go through one lock, while each
+/// frame's bytes have a lock of their own that's only held for as long as a single read or change
+/// of the page takes. Anything that has to stay consistent across several pages uses the page
+/// latches in `latches` on top of that.
+pub struct BufferPool {
+    store: SharedPageStore,
+    page_size: u32,
+    checksums: bool,
+    wal: Option<Arc<Mutex<Wal>>>,
+    state: Mutex<PoolState>,
+    // Signalled whenever a frame stops loading
+    loaded: Condvar,
+    frames: Vec<RwLock<Vec<u8>>>,
+    latches: PageLatches,
+}
+
+impl BufferPool {
+    pub fn new(store: SharedPageStore, page_size: u32, pool_size: usize, checksums: bool) -> Self {
+        // Always need at least one frame otherwise nothing can ever be loaded
+        let pool_size = pool_size.max(1);
+        BufferPool {
+            store,
+            page_size,
+            checksums,
+            wal: None,
+            state: Mutex::new(PoolState {
+                frames: (0..pool_size).map(|_| Frame::new()).collect(),
+                page_table: HashMap::new(),
+                // Reversed so frames get handed out starting from 0. Doesn't matter, just nicer to
+                // debug
+                free_frames: (0..pool_size).rev().collect(),
+                clock_hand: 0,
+            }),
+            loaded: Condvar::new(),
+            frames: (0..pool_size)
+                .map(|_| RwLock::new(vec![0u8; page_size as usize]))
+                .collect(),
+            latches: PageLatches::new(),
+        }
+    }
+
+    pub fn with_wal(mut self, wal: Arc<Mutex<Wal>>) -> Self {
+        self.wal = Some(wal);
+        self
+    }
+
+    pub fn pool_size(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn contains(&self, page_id: u64) -> Result<bool> {
+        Ok(self.state.lock()?.page_table.contains_key(&page_id))
+    }
+
+    /// The latches guarding the pages in this pool
+    pub fn latches(&self) -> &PageLatches {
+        &self.latches
+    }
+
+    /// Pins the given page, reading it from disk if it isn't already resident.
+    /// Every call to this needs to be matched with a call to `unpin_page`
+    pub fn pin_page(&self, page_id: u64) -> Result<FrameId> {
+        let (mut state, resident) = self.lookup(self.state.lock()?, page_id)?;
+        if let Some(frame_id) = resident {
+            let frame = &mut state.frames[frame_id];
+            frame.pin_count += 1;
+            frame.referenced = true;
+            return Ok(frame_id);
+        }
+
+        // The frame is reserved for the page before letting go of the state, so the rest of the
+        // pool can be used while the read happens
+        let frame_id = self.claim_frame(&mut state)?;
+        Self::install(&mut state, frame_id, page_id, false);
+        state.frames[frame_id].loading = true;
+        drop(state);
+
+        let read = self.read_from_disk(page_id, frame_id);
+
+        let mut state = self.state.lock()?;
+        let frame = &mut state.frames[frame_id];
+        frame.loading = false;
+        if read.is_err() {
+            // Give the frame back so a failed read doesn't leak it
+            frame.page_id = None;
+            frame.pin_count = 0;
+            state.page_table.remove(&page_id);
+            state.free_frames.push(frame_id);
+        }
+        drop(state);
+        self.loaded.notify_all();
+        read.map(|()| frame_id)
+    }
+
+    /// Pins a frame for a page that doesn't exist on disk yet. The frame is zeroed and marked dirty
+    /// so it'll be written out at some point even if the caller never modifies it.
+    pub fn pin_new_page(&self, page_id: u64) -> Result<FrameId> {
+        let (mut state, resident) = self.lookup(self.state.lock()?, page_id)?;
+        let frame_id = match resident {
+            Some(frame_id) => {
+                state.frames[frame_id].pin_count += 1;
+                frame_id
+            }
+            None => {
+                let frame_id = self.claim_frame(&mut state)?;
+                Self::install(&mut state, frame_id, page_id, true);
+                frame_id
+            }
+        };
+        state.frames[frame_id].is_dirty = true;
+        drop(state);
+
+        self.frames[frame_id].write()?.fill(0);
+        Ok(frame_id)
+    }
+
+    /// Drops one pin on the page. If `is_dirty` is true the page will be written back before its
+    /// frame is reused. Passing false never clears a dirty bit set by someone else.
+    pub fn unpin_page(&self, page_id: u64, is_dirty: bool) -> Result<()> {
+        let mut state = self.state.lock()?;
+        let frame_id = *state
+            .page_table
+            .get(&page_id)
+            .ok_or(DbError::PageNotPinned(page_id))?;
+        let frame = &mut state.frames[frame_id];
+        if frame.pin_count == 0 {
+            return Err(DbError::PageNotPinned(page_id));
+        }
+        frame.pin_count -= 1;
+        frame.is_dirty |= is_dirty;
+        Ok(())
+    }
+
+    /// A pinned frame's bytes. Locks the frame so don't hold on to it for longer than needed, and
+    /// don't pin or flush anything while holding it
+    pub fn frame(&self, frame_id: FrameId) -> Result<RwLockReadGuard<'_, Vec<u8>>> {
+        Ok(self.frames[frame_id].read()?)
+    }
+
+    /// Mutable access to a pinned frame's bytes. Callers still need to pass `is_dirty = true` when
+    /// unpinning for changes to be written back
+    pub fn frame_mut(&self, frame_id: FrameId) -> Result<RwLockWriteGuard<'_, Vec<u8>>> {
+        Ok(self.frames[frame_id].write()?)
+    }
+
+    /// Throws away the page's frame without writing it back, dirty or not. Used for pages whose
+    /// allocation got rolled back so they never reach the file
+    pub fn discard_page(&self, page_id: u64) -> Result<()> {
+        let mut state = self.state.lock()?;
+        if let Some(frame_id) = state.page_table.remove(&page_id) {
+            let frame = &mut state.frames[frame_id];
+            if frame.pin_count > 0 {
+                state.page_table.insert(page_id, frame_id);
+                return Err(DbError::InvalidPageId(page_id));
+            }
+            frame.page_id = None;
+            frame.is_dirty = false;
+            state.free_frames.push(frame_id);
+        }
+        Ok(())
+    }
+
+    /// Writes the page back to disk if it is resident and dirty. Does not sync the file.
+    pub fn flush_page(&self, page_id: u64) -> Result<()> {
+        let mut state = self.state.lock()?;
+        if let Some(&frame_id) = state.page_table.get(&page_id) {
+            self.write_back(&mut state, frame_id)?;
+        }
+        Ok(())
+    }
+
+    /// Writes every dirty page back to disk and then syncs the file
+    pub fn flush_all(&self) -> Result<()> {
+        let mut state = self.state.lock()?;
+        for frame_id in 0..self.frames.len() {
+            self.write_back(&mut state, frame_id)?;
+        }
+        self.store.lock()?.sync()?;
+        Ok(())
+    }
+
+    /// The frame holding the page, if there is one. Waits for a page that's still being read in,
+    /// it can turn out not to be resident after all if the read failed
+    fn lookup<'s>(
+        &self,
+        mut state: MutexGuard<'s, PoolState>,
+        page_id: u64,
+    ) -> Result<(MutexGuard<'s, PoolState>, Option<FrameId>)> {
+        loop {
+            match state.page_table.get(&page_id).copied() {
+                Some(frame_id) if state.frames[frame_id].loading => {
+                    state = self.loaded.wait(state)?;
+                }
+                resident => return Ok((state, resident)),
+            }
+        }
+    }
+
+    fn install(state: &mut PoolState, frame_id: FrameId, page_id: u64, is_dirty: bool) {
+        let frame = &mut state.frames[frame_id];
+        frame.page_id = Some(page_id);
+        frame.pin_count = 1;
+        frame.is_dirty = is_dirty;
+        frame.referenced = true;
+        state.page_table.insert(page_id, frame_id);
+    }
+
+    /// Finds a frame that can be (re)used. Free frames are used first, otherwise the clock hand
+    /// sweeps over the frames looking for one that is unpinned and hasn't been referenced since the
+    /// last sweep. Dirty victims are written back before being handed out.
+    fn claim_frame(&self, state: &mut PoolState) -> Result<FrameId> {
+        if let Some(frame_id) = state.free_frames.pop() {
+            return Ok(frame_id);
+        }
+
+        // Two full sweeps is enough: the first one clears every reference bit so if nothing is
+        // found by the end of the second every frame must be pinned
+        for _ in 0..self.frames.len() * 2 {
+            let frame_id = state.clock_hand;
+            state.clock_hand = (state.clock_hand + 1) % self.frames.len();
+
+            let frame = &mut state.frames[frame_id];
+            if frame.pin_count > 0 {
+                continue;
+            }
+            if frame.referenced {
+                frame.referenced = false;
+                continue;
+            }
+
+            self.write_back(state, frame_id)?;
+            if let Some(old_page_id) = state.frames[frame_id].page_id.take() {
+                state.page_table.remove(&old_page_id);
+            }
+            return Ok(frame_id);
+        }
+
+        Err(DbError::BufferPoolFull)
+    }
+
+    fn write_back(&self, state: &mut PoolState, frame_id: FrameId) -> Result<()> {
+        let frame = &mut state.frames[frame_id];
+        if let (Some(page_id), true) = (frame.page_id, frame.is_dirty) {
+            let mut data = self.frames[frame_id].write()?;
+            if let Some(wal) = &self.wal {
+                let lsn = read_be_u64(&data[PageHeader::lsn_span()]);
+                wal.lock()?.flush_to(lsn)?;
+            }
+            if self.checksums {
+                PageHeader::stamp_checksum(&mut data);
+            }
+            self.store
+                .lock()?
+                .write_at(page_id * self.page_size as u64, &data)?;
+            frame.is_dirty = false;
+        }
+        Ok(())
+    }
+
+    fn read_from_disk(&self, page_id: u64, frame_id: FrameId) -> Result<()> {
+        let mut buffer = self.frames[frame_id].write()?;
+        self.store
+            .lock()?
+            .read_at(page_id * self.page_size as u64, &mut buffer)?;
+
+        if self.checksums {
+            let (stored, computed) = PageHeader::read_checksums(&buffer);
+            if stored != computed {
+                return Err(DbError::Corruption {
+                    page_id,
+                    reason: format!("stored checksum {stored:#010x} but computed {computed:#010x}"),
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Whether a latch lets others in too
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LatchMode {
+    /// Any number of holders, for reading the page
+    Shared,
+    /// A single holder, for changing the page
+    Exclusive,
+}
+
+/// Read/write latches for pages, by page id. A latch doesn't care whether its page is in the pool
+/// and is held for as long as some operation needs a page to not change under it, which can span
+/// many reads and writes of the page (and of others).
+///
+/// Latches are advisory: reading or changing a page doesn't take its latch by itself, it's up to
+/// whatever touches pages from several threads (the B+Tree) to take them. A thread holding a latch
+/// exclusively can take it exclusively again, it's released once every guard is gone. Other than
+/// that they're not reentrant, latching a page shared that the same thread holds exclusively (or
+/// exclusively that it holds shared) blocks forever.
+pub struct PageLatches {
+    held: Mutex<HashMap<u64, LatchState>>,
+    released: Condvar,
+}
+
+#[derive(Default)]
+struct LatchState {
+    readers: usize,
+    // Thread holding the latch exclusively and how many times it has taken it
+    writer: Option<(ThreadId, usize)>,
+}
+
+impl LatchState {
+    fn allows(&self, mode: LatchMode) -> bool {
+        match (mode, self.writer) {
+            (LatchMode::Shared, writer) => writer.is_none(),
+            (LatchMode::Exclusive, Some((owner, _))) => owner == thread::current().id(),
+            (LatchMode::Exclusive, None) => self.readers == 0,
+        }
+    }
+}
+
+impl PageLatches {
+    fn new() -> Self {
+        PageLatches {
+            held: Mutex::new(HashMap::new()),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Waits until the page's latch can be taken in `mode`, then takes it. The latch is released
+    /// when the returned guard is dropped
+    pub fn acquire(&self, page_id: u64, mode: LatchMode) -> Result<PageLatch<'_>> {
+        let mut held = self.held.lock()?;
+        while !held.get(&page_id).is_none_or(|state| state.allows(mode)) {
+            held = self.released.wait(held)?;
+        }
+        Self::take(&mut held, page_id, mode);
+        Ok(PageLatch {
+            latches: self,
+            page_id,
+            mode,
+        })
+    }
+
+    /// Same as `acquire` but gives up straight away rather than waiting
+    pub fn try_acquire(&self, page_id: u64, mode: LatchMode) -> Result<Option<PageLatch<'_>>> {
+        let mut held = self.held.lock()?;
+        if !held.get(&page_id).is_none_or(|state| state.allows(mode)) {
+            return Ok(None);
+        }
+        Self::take(&mut held, page_id, mode);
+        Ok(Some(PageLatch {
+            latches: self,
+            page_id,
+            mode,
+        }))
+    }
+
+    fn take(held: &mut HashMap<u64, LatchState>, page_id: u64, mode: LatchMode) {
+        let state = held.entry(page_id).or_default();
+        match mode {
+            LatchMode::Shared => state.readers += 1,
+            LatchMode::Exclusive => {
+                let depth = state.writer.map_or(0, |(_, depth)| depth);
+                state.writer = Some((thread::current().id(), depth + 1));
+            }
+        }
+    }
+
+    /// Lets go of a latch taken once by a guard that was given up with `PageLatch::keep`
+    pub(crate) fn release(&self, page_id: u64, mode: LatchMode) {
+        // A poisoned map still has the right counts in it, the panic happened somewhere else
+        let mut held = self
+            .held
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if let Some(state) = held.get_mut(&page_id) {
+            match mode {
+                LatchMode::Shared => state.readers -= 1,
+                LatchMode::Exclusive => {
+                    state.writer = state
+                        .writer
+                        .and_then(|(owner, depth)| (depth > 1).then_some((owner, depth - 1)));
+                }
+            }
+            if state.readers == 0 && state.writer.is_none() {
+                held.remove(&page_id);
+            }
+        }
+        self.released.notify_all();
+    }
+}
+
+/// A held page latch, released on drop
+pub struct PageLatch<'l> {
+    latches: &'l PageLatches,
+    page_id: u64,
+    mode: LatchMode,
+}
+
+impl PageLatch<'_> {
+    pub fn page_id(&self) -> u64 {
+        self.page_id
+    }
+
+    pub fn mode(&self) -> LatchMode {
+        self.mode
+    }
+
+    /// Gives up the guard without releasing the latch, which then stays held until
+    /// `PageLatches::release` is called for it
+    pub(crate) fn keep(self) {
+        mem::forget(self);
+    }
+}
+
+impl Drop for PageLatch<'_> {
+    fn drop(&mut self) {
+        self.latches.release(self.page_id, self.mode);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::page_store::{MemoryStore, PageStore};
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::thread;
+    use std::time::Duration;
+
+    fn memory_store() -> SharedPageStore {
+        Arc::new(Mutex::new(Box::new(MemoryStore::new())))
+    }
+
+    #[test]
+    fn dirty_pages_written_back_on_eviction() {
+        let pool = BufferPool::new(memory_store(), 64, 2, true);
+
+        for page_id in 0..3u64 {
+            let frame_id = pool.pin_new_page(page_id).unwrap();
+            pool.frame_mut(frame_id).unwrap()[0] = page_id as u8 + 10;
+            pool.unpin_page(page_id, true).unwrap();
+        }
+        // Only 2 frames so page 0 had to be evicted (and written) to make room for page 2
+        assert!(!pool.contains(0).unwrap());
+
+        let frame_id = pool.pin_page(0).unwrap();
+        assert_eq!(pool.frame(frame_id).unwrap()[0], 10);
+        pool.unpin_page(0, false).unwrap();
+    }
+
+    #[test]
+    fn corrupt_page_reports_page_id() {
+        let store = memory_store();
+        let pool = BufferPool::new(store.clone(), 64, 1, true);
+
+        for page_id in 0..2u64 {
+            pool.pin_new_page(page_id).unwrap();
+            pool.unpin_page(page_id, true).unwrap();
+        }
+        pool.flush_all().unwrap();
+
+        // Flip a byte in page 1 behind the pool's back. Page 1 is still resident so load page 0
+        // to push it out first
+        store.lock().unwrap().write_at(64 + 40, &[0xff]).unwrap();
+        let frame_id = pool.pin_page(0).unwrap();
+        assert_eq!(pool.frame(frame_id).unwrap().len(), 64);
+        pool.unpin_page(0, false).unwrap();
+
+        assert!(matches!(
+            pool.pin_page(1),
+            Err(DbError::Corruption { page_id: 1, .. })
+        ));
+
+        // Same bytes are fine with checksums turned off
+        let unchecked_pool = BufferPool::new(store, 64, 1, false);
+        unchecked_pool.pin_page(1).unwrap();
+    }
+
+    #[test]
+    fn pinned_pages_are_never_evicted() {
+        let pool = BufferPool::new(memory_store(), 64, 2, true);
+
+        pool.pin_new_page(0).unwrap();
+        pool.pin_new_page(1).unwrap();
+        assert!(pool.pin_new_page(2).is_err());
+
+        pool.unpin_page(1, true).unwrap();
+        pool.pin_new_page(2).unwrap();
+        assert!(pool.contains(0).unwrap());
+        assert!(!pool.contains(1).unwrap());
+    }
+
+    #[test]
+    fn exclusive_latches_wait_for_everyone_else() {
+        let latches = PageLatches::new();
+
+        let first = latches.acquire(7, LatchMode::Shared).unwrap();
+        let second = latches.try_acquire(7, LatchMode::Shared).unwrap();
+        assert!(second.is_some());
+        assert!(latches
+            .try_acquire(7, LatchMode::Exclusive)
+            .unwrap()
+            .is_none());
+        // Other pages aren't affected
+        assert!(latches
+            .try_acquire(8, LatchMode::Exclusive)
+            .unwrap()
+            .is_some());
+
+        let released = AtomicBool::new(false);
+        thread::scope(|scope| {
+            let waiter = scope.spawn(|| {
+                let latch = latches.acquire(7, LatchMode::Exclusive).unwrap();
+                assert!(released.load(Ordering::SeqCst));
+                assert_eq!(latch.mode(), LatchMode::Exclusive);
+            });
+            thread::sleep(Duration::from_millis(20));
+            released.store(true, Ordering::SeqCst);
+            drop(first);
+            drop(second);
+            waiter.join().unwrap();
+        });
+        assert!(latches.held.lock().unwrap().is_empty());
+    }
+
+    /// Store whose reads wait until `open` is set, so a test can hold a read in the middle
+    struct GatedStore {
+        inner: MemoryStore,
+        open: Arc<(Mutex<bool>, Condvar)>,
+        reads: Arc<AtomicUsize>,
+    }
+
+    impl PageStore for GatedStore {
+        fn len(&self) -> Result<u64> {
+            self.inner.len()
+        }
+
+        fn read_at(&mut self, offset: u64, buffer: &mut [u8]) -> Result<()> {
+            self.reads.fetch_add(1, Ordering::SeqCst);
+            let (open, opened) = &*self.open;
+            let mut open = open.lock()?;
+            while !*open {
+                open = opened.wait(open)?;
+            }
+            self.inner.read_at(offset, buffer)
+        }
+
+        fn write_at(&mut self, offset: u64, data: &[u8]) -> Result<()> {
+            self.inner.write_at(offset, data)
+        }
+
+        fn set_len(&mut self, len: u64) -> Result<()> {
+            self.inner.set_len(len)
+        }
+
+        fn sync(&mut self) -> Result<()> {
+            self.inner.sync()
+        }
+    }
+
+    #[test]
+    fn reading_a_page_in_doesnt_hold_up_the_rest_of_the_pool() {
+        let open = Arc::new((Mutex::new(false), Condvar::new()));
+        let reads = Arc::new(AtomicUsize::new(0));
+        let mut inner = MemoryStore::new();
+        inner.write_at(64, &[7u8; 64]).unwrap();
+        let store: SharedPageStore = Arc::new(Mutex::new(Box::new(GatedStore {
+            inner,
+            open: open.clone(),
+            reads: reads.clone(),
+        })));
+        let pool = BufferPool::new(store, 64, 2, false);
+        pool.pin_new_page(0).unwrap();
+        pool.unpin_page(0, true).unwrap();
+
+        let opened = AtomicBool::new(false);
+        thread::scope(|scope| {
+            let loaders: Vec<_> = (0..2)
+                .map(|_| scope.spawn(|| pool.pin_page(1).unwrap()))
+                .collect();
+            scope.spawn(|| {
+                thread::sleep(Duration::from_millis(200));
+                opened.store(true, Ordering::SeqCst);
+                *open.0.lock().unwrap() = true;
+                open.1.notify_all();
+            });
+            while reads.load(Ordering::SeqCst) == 0 {
+                thread::yield_now();
+            }
+
+            // Page 1 is stuck being read but page 0 is right there
+            pool.pin_page(0).unwrap();
+            pool.unpin_page(0, false).unwrap();
+            assert!(!opened.load(Ordering::SeqCst));
+
+            // Both loaders end up with the same frame, read once
+            let frame_ids: Vec<FrameId> = loaders.into_iter().map(|l| l.join().unwrap()).collect();
+            assert_eq!(frame_ids[0], frame_ids[1]);
+            assert_eq!(*pool.frame(frame_ids[0]).unwrap(), vec![7u8; 64]);
+            assert_eq!(reads.load(Ordering::SeqCst), 1);
+        });
+        pool.unpin_page(1, false).unwrap();
+        pool.unpin_page(1, false).unwrap();
+        assert!(pool.unpin_page(1, false).is_err());
+    }
+}
@@ -0,0 +1,180 @@
+use std::fmt;
+use std::io;
+use std::sync::PoisonError;
+
+use crate::btree::Uniqueness;
+use crate::slotted_page::SlotId;
+
+/// Every fallible operation in the crate returns this. The idea is a bad file or a bad buffer
+/// should turn into an error the caller can handle rather than taking the whole process down.
+#[derive(Debug)]
+pub enum DbError {
+    Io(io::Error),
+    /// The bytes of a page don't make sense. Either the checksum didn't match or some field
+    /// holds a value that can't be valid
+    Corruption {
+        page_id: u64,
+        reason: String,
+    },
+    /// Tried to (de)serialize into/out of a buffer that can't hold the value
+    BufferTooSmall {
+        needed: usize,
+        available: usize,
+    },
+    UnknownPageType(u8),
+    /// Somebody panicked while holding a lock so whatever it protects can't be trusted
+    LockPoisoned,
+    /// The page id doesn't refer to a page that can be used for the requested operation
+    InvalidPageId(u64),
+    /// Every frame in the buffer pool is pinned so nothing can be evicted
+    BufferPoolFull,
+    /// Unpinned a page that wasn't pinned (or isn't in the pool at all)
+    PageNotPinned(u64),
+    /// The file being opened wasn't created by this crate (or its first page is too mangled to
+    /// tell)
+    NotADatabase(String),
+    /// The file was written by a version of the crate with a different on-disk format
+    UnsupportedVersion {
+        expected: u32,
+        found: u32,
+    },
+    /// The config asked for a page size that doesn't match the one the file was created with
+    PageSizeMismatch {
+        configured: u32,
+        stored: u32,
+    },
+    /// The config asked for pages too small to hold the page layouts, see
+    /// `PagedFileManager::MIN_PAGE_SIZE`
+    PageSizeTooSmall {
+        configured: u32,
+        min: u32,
+    },
+    /// The write-ahead log can't be used to recover the database file
+    CorruptLog(String),
+    /// Bytes handed to the key codec aren't a key it encoded
+    InvalidKey(String),
+    /// Bulk loading needs its keys in strictly increasing order
+    UnsortedKeys {
+        previous: Vec<u8>,
+        next: Vec<u8>,
+    },
+    /// Inserted a key that's already in a unique tree
+    DuplicateKey(Vec<u8>),
+    /// The tree was opened as one kind of tree but the file holds the other
+    UniquenessMismatch {
+        requested: Uniqueness,
+        stored: Uniqueness,
+    },
+    /// Keys have to be small enough that a B+Tree node can always hold a few of them
+    KeyTooLarge {
+        size: usize,
+        max: usize,
+    },
+    /// A row handed to the tuple codec doesn't fit the table's schema, or a column was read as
+    /// something it isn't
+    SchemaMismatch(String),
+    /// Bytes handed to the tuple codec aren't a tuple it encoded with that schema
+    InvalidTuple(String),
+    /// A page was changed outside `PagedFileManager::atomically`, so there's no transaction to
+    /// log the change in
+    NotInTransaction,
+    /// The slot holds a forwarding stub left by `HeapFile::update`, the record it points at has to
+    /// be read through the heap
+    Forwarded {
+        page_id: u64,
+        slot: SlotId,
+    },
+}
+
+pub type Result<T> = std::result::Result<T, DbError>;
+
+impl fmt::Display for DbError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DbError::Io(err) => write!(f, "io error: {err}"),
+            DbError::Corruption { page_id, reason } => {
+                write!(f, "page {page_id} is corrupt: {reason}")
+            }
+            DbError::BufferTooSmall { needed, available } => write!(
+                f,
+                "buffer too small: needed {needed} bytes but only {available} available"
+            ),
+            DbError::UnknownPageType(page_type) => write!(f, "unknown page type {page_type}"),
+            DbError::LockPoisoned => write!(f, "lock poisoned"),
+            DbError::InvalidPageId(page_id) => write!(f, "invalid page id {page_id}"),
+            DbError::BufferPoolFull => write!(f, "all frames in the buffer pool are pinned"),
+            DbError::PageNotPinned(page_id) => write!(f, "page {page_id} is not pinned"),
+            DbError::NotADatabase(reason) => write!(f, "not a database file: {reason}"),
+            DbError::UnsupportedVersion { expected, found } => write!(
+                f,
+                "database file is version {found} but only version {expected} is supported"
+            ),
+            DbError::PageSizeMismatch { configured, stored } => write!(
+                f,
+                "configured page size {configured} doesn't match the file's page size {stored}"
+            ),
+            DbError::PageSizeTooSmall { configured, min } => write!(
+                f,
+                "configured page size {configured} is smaller than the {min} byte minimum"
+            ),
+            DbError::CorruptLog(reason) => write!(f, "write-ahead log is corrupt: {reason}"),
+            DbError::InvalidKey(reason) => write!(f, "invalid encoded key: {reason}"),
+            DbError::UnsortedKeys { previous, next } => write!(
+                f,
+                "keys must be strictly increasing but {next:?} came after {previous:?}"
+            ),
+            DbError::DuplicateKey(key) => write!(f, "key {key:?} is already in the unique tree"),
+            DbError::UniquenessMismatch { requested, stored } => write!(
+                f,
+                "tree was opened as {requested:?} but the file holds a {stored:?} tree"
+            ),
+            DbError::KeyTooLarge { size, max } => {
+                write!(
+                    f,
+                    "key of {size} bytes is bigger than the {max} byte maximum"
+                )
+            }
+            DbError::SchemaMismatch(reason) => write!(f, "row doesn't match the schema: {reason}"),
+            DbError::InvalidTuple(reason) => write!(f, "invalid encoded tuple: {reason}"),
+            DbError::NotInTransaction => {
+                write!(f, "pages can only be modified inside a transaction")
+            }
+            DbError::Forwarded { page_id, slot } => write!(
+                f,
+                "slot {slot} of page {page_id} forwards to a record its heap file moved"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for DbError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DbError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for DbError {
+    fn from(err: io::Error) -> Self {
+        DbError::Io(err)
+    }
+}
+
+impl<T> From<PoisonError<T>> for DbError {
+    fn from(_: PoisonError<T>) -> Self {
+        DbError::LockPoisoned
+    }
+}
+
+/// Helper for the common "make sure the buffer is big enough before touching it" check
+pub(crate) fn check_buffer_size(buffer: &[u8], needed: usize) -> Result<()> {
+    if buffer.len() < needed {
+        return Err(DbError::BufferTooSmall {
+            needed,
+            available: buffer.len(),
+        });
+    }
+    Ok(())
+}
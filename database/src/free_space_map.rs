@@ -0,0 +1,418 @@
+use std::collections::BTreeMap;
+use std::ops::Range;
+
+use struct_layout::StructLayout;
+
+use crate::buffer_pool::{LatchMode, PageLatch};
+use crate::error::{check_buffer_size, DbError, Result};
+use crate::{
+    padding_needed_from_type, read_be_u32, read_be_u64, MyDeserialize, MySerialize, PageHeader,
+    PageType, PagedFileManager,
+};
+
+// Free space map, the list of a heap file's data pages along with roughly how much room each one
+// has, so inserts can go straight to a page with room rather than trying every page.
+//
+// Free space is kept as one byte per page, in 256ths of the page size rounded down. A page is only
+// picked for a record if its byte says there's room, so the rounding means a page can be passed
+// over when the record would only just fit but is never picked when it can't. The byte is only as
+// fresh as the last change the heap made to the page, changes made to it any other way (say
+// `PagedFileManager::insert_record` on the page directly) aren't seen until the heap next
+// touches it.
+//
+// The map is a chain of pages. New data pages are added to the end of the last one and never
+// leave the map, so once a data page is found in a map page it can always be found there. The
+// first page keeps the id of the last one so adding pages doesn't have to walk the chain.
+//
+// Map pages are latched after the data pages whose entries change, and in page id order when
+// several are, so a map latch is never held while waiting for a data page's latch.
+
+// Free space map page structure
+//
+// | next_page_id | last_page_id | entries_len | page_ids | free_space |
+//
+// The entries are stored as two arrays so the page ids stay aligned, the free space of the page at
+// page_ids[i] is free_space[i]
+#[repr(C)]
+#[derive(StructLayout, Debug, Clone, PartialEq)]
+pub struct FreeSpaceMapPage {
+    // Next page of the map, 0 for the last one
+    pub next_page_id: u64,
+    // Last page of the map. Only kept up to date in the first page, 0 in the others
+    pub last_page_id: u64,
+    // Data page ids and how much room each has, in 256ths of a page
+    pub entries: Vec<(u64, u8)>,
+}
+
+impl MySerialize for FreeSpaceMapPage {
+    fn serialize(&self, buffer: &mut [u8]) -> Result<usize> {
+        let size = self.size();
+        check_buffer_size(buffer, size)?;
+
+        buffer[Self::next_page_id_span()].copy_from_slice(&self.next_page_id.to_be_bytes());
+        buffer[Self::last_page_id_span()].copy_from_slice(&self.last_page_id.to_be_bytes());
+        buffer[Self::entries_len_span()]
+            .copy_from_slice(&(self.entries.len() as u32).to_be_bytes());
+        let free_space_offset = self.free_space_offset();
+        for (index, &(page_id, free_space)) in self.entries.iter().enumerate() {
+            let page_id_offset = Self::PAGE_IDS_OFFSET + index * Self::PAGE_ID_SIZE;
+            buffer[page_id_offset..page_id_offset + Self::PAGE_ID_SIZE]
+                .copy_from_slice(&page_id.to_be_bytes());
+            buffer[free_space_offset + index] = free_space;
+        }
+
+        Ok(size)
+    }
+}
+
+impl MyDeserialize for FreeSpaceMapPage {
+    fn deserialize(buffer: &[u8]) -> Result<Self> {
+        check_buffer_size(buffer, Self::MIN_SIZE)?;
+
+        let next_page_id = read_be_u64(&buffer[Self::next_page_id_span()]);
+        let last_page_id = read_be_u64(&buffer[Self::last_page_id_span()]);
+        let entries_len = read_be_u32(&buffer[Self::entries_len_span()]) as usize;
+        let free_space_offset = Self::PAGE_IDS_OFFSET + Self::PAGE_ID_SIZE * entries_len;
+        check_buffer_size(buffer, free_space_offset + entries_len)?;
+
+        let entries = buffer[Self::PAGE_IDS_OFFSET..free_space_offset]
+            .chunks_exact(Self::PAGE_ID_SIZE)
+            .map(read_be_u64)
+            .zip(
+                buffer[free_space_offset..free_space_offset + entries_len]
+                    .iter()
+                    .copied(),
+            )
+            .collect();
+
+        Ok(FreeSpaceMapPage {
+            next_page_id,
+            last_page_id,
+            entries,
+        })
+    }
+}
+
+impl FreeSpaceMapPage {
+    const ENTRIES_LEN_SIZE: usize = size_of::<u32>();
+    const PAGE_ID_SIZE: usize = size_of::<u64>();
+    const FREE_SPACE_SIZE: usize = size_of::<u8>();
+
+    const ENTRIES_LEN_OFFSET: usize = Self::LAST_PAGE_ID_OFFSET + Self::LAST_PAGE_ID_SIZE;
+    const PAGE_IDS_OFFSET: usize =
+        padding_needed_from_type::<u64>(Self::ENTRIES_LEN_OFFSET + Self::ENTRIES_LEN_SIZE)
+            + Self::ENTRIES_LEN_OFFSET
+            + Self::ENTRIES_LEN_SIZE;
+
+    const MIN_SIZE: usize = Self::PAGE_IDS_OFFSET;
+
+    fn entries_len_span() -> Range<usize> {
+        Self::ENTRIES_LEN_OFFSET..Self::ENTRIES_LEN_OFFSET + Self::ENTRIES_LEN_SIZE
+    }
+
+    fn free_space_offset(&self) -> usize {
+        Self::PAGE_IDS_OFFSET + Self::PAGE_ID_SIZE * self.entries.len()
+    }
+
+    pub fn new() -> Self {
+        FreeSpaceMapPage {
+            next_page_id: 0,
+            last_page_id: 0,
+            entries: Vec::new(),
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        Self::MIN_SIZE + (Self::PAGE_ID_SIZE + Self::FREE_SPACE_SIZE) * self.entries.len()
+    }
+
+    /// How many data pages one map page can hold for the given page size
+    pub fn capacity(page_size: u32) -> usize {
+        (page_size as usize - PageHeader::SIZE - Self::MIN_SIZE)
+            / (Self::PAGE_ID_SIZE + Self::FREE_SPACE_SIZE)
+    }
+
+    /// What gets stored for a page with `free_bytes` of room
+    pub fn free_space(free_bytes: usize, page_size: u32) -> u8 {
+        (free_bytes * 256 / page_size as usize).min(u8::MAX as usize) as u8
+    }
+
+    /// Least a page's stored free space can be for it to certainly have `needed` bytes of room.
+    /// Can be more than any page stores if `needed` is most of a page
+    pub fn free_space_needed(needed: usize, page_size: u32) -> usize {
+        (needed * 256).div_ceil(page_size as usize)
+    }
+}
+
+impl Default for FreeSpaceMapPage {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// How many bytes of room a data page has now, for `FreeSpaceMap::update`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct PageRoom {
+    pub(crate) page_id: u64,
+    // The map page the data page is listed in, if it's known
+    pub(crate) map_page_id: Option<u64>,
+    pub(crate) free_bytes: usize,
+}
+
+/// The free space map of one heap file, known by the page id of its first page
+pub(crate) struct FreeSpaceMap<'a> {
+    manager: &'a PagedFileManager,
+    first_page_id: u64,
+}
+
+impl<'a> FreeSpaceMap<'a> {
+    /// Makes a new empty map. The caller has to hold the metadata page's latch exclusively
+    pub(crate) fn create(manager: &'a PagedFileManager) -> Result<Self> {
+        manager.atomically(|_| {
+            let map = FreeSpaceMap {
+                manager,
+                first_page_id: manager.allocate_page()?,
+            };
+            let mut first_page = FreeSpaceMapPage::new();
+            first_page.last_page_id = map.first_page_id;
+            map.write_page(map.first_page_id, &first_page)?;
+            Ok(map)
+        })
+    }
+
+    pub(crate) fn open(manager: &'a PagedFileManager, first_page_id: u64) -> Result<Self> {
+        let map = FreeSpaceMap {
+            manager,
+            first_page_id,
+        };
+        map.read_page(first_page_id)?;
+        Ok(map)
+    }
+
+    pub(crate) fn first_page_id(&self) -> u64 {
+        self.first_page_id
+    }
+
+    /// Reads one page of the map, latching it shared while it's read
+    pub(crate) fn read(&self, page_id: u64) -> Result<FreeSpaceMapPage> {
+        let _latch = self.manager.latch_page(page_id, LatchMode::Shared)?;
+        self.read_page(page_id)
+    }
+
+    /// Data pages the map says have at least `needed` bytes of room, each with the map page it's
+    /// listed in. Map pages are read in order until one lists some, so finding room near the start
+    /// of a big map doesn't read all of it
+    pub(crate) fn pages_with_room(&self, needed: usize) -> Result<Vec<(u64, u64)>> {
+        let free_space_needed = FreeSpaceMapPage::free_space_needed(needed, self.manager.page_size);
+        let mut page_id = self.first_page_id;
+        while page_id != 0 {
+            let map_page = self.read(page_id)?;
+            let pages: Vec<_> = map_page
+                .entries
+                .iter()
+                .filter(|(_, free_space)| *free_space as usize >= free_space_needed)
+                .map(|(data_page_id, _)| (*data_page_id, page_id))
+                .collect();
+            if !pages.is_empty() {
+                return Ok(pages);
+            }
+            page_id = map_page.next_page_id;
+        }
+        Ok(Vec::new())
+    }
+
+    /// Stores how much room the `changed` data pages now have and adds the `added` ones to the end
+    /// of the map. Pages in `changed` that aren't in the map are ignored. The map pages that get
+    /// written stay latched, their latches are added to `latches`.
+    ///
+    /// Only the map pages that change are latched exclusively. Ones a `changed` page doesn't know
+    /// are found by reading the map until all of them are.
+    ///
+    /// Adding pages can need another map page, so the caller has to hold the metadata page's
+    /// latch exclusively if `added` isn't empty
+    pub(crate) fn update(
+        &self,
+        changed: &[PageRoom],
+        added: &[(u64, usize)],
+        latches: &mut Vec<PageLatch<'a>>,
+    ) -> Result<()> {
+        let page_size = self.manager.page_size;
+        // New free space of the changed data pages, by the map page they're listed in
+        let mut updates: BTreeMap<u64, Vec<(u64, u8)>> = BTreeMap::new();
+        let mut unknown = Vec::new();
+        for room in changed {
+            let free_space = FreeSpaceMapPage::free_space(room.free_bytes, page_size);
+            match room.map_page_id {
+                Some(map_page_id) => updates
+                    .entry(map_page_id)
+                    .or_default()
+                    .push((room.page_id, free_space)),
+                None => unknown.push((room.page_id, free_space)),
+            }
+        }
+
+        // Entries never move once they're in the map, so where they are can be looked up before
+        // latching anything exclusively
+        let mut page_id = self.first_page_id;
+        while page_id != 0 && !unknown.is_empty() {
+            let map_page = self.read_unless_held(page_id, latches)?;
+            unknown.retain(|&(data_page_id, free_space)| {
+                let listed = map_page.entries.iter().any(|&(id, _)| id == data_page_id);
+                if listed {
+                    updates
+                        .entry(page_id)
+                        .or_default()
+                        .push((data_page_id, free_space));
+                }
+                !listed
+            });
+            page_id = map_page.next_page_id;
+        }
+
+        // Only a caller holding the metadata latch adds pages, so the last page can't change
+        // between reading it here and latching it below
+        let last_page_id = match added.is_empty() {
+            true => 0,
+            false => {
+                let last_page_id = self
+                    .read_unless_held(self.first_page_id, latches)?
+                    .last_page_id;
+                updates.entry(self.first_page_id).or_default();
+                updates.entry(last_page_id).or_default();
+                last_page_id
+            }
+        };
+
+        let mut map_pages = BTreeMap::new();
+        for (&page_id, entries) in &updates {
+            let latch = match latches.iter().any(|latch| latch.page_id() == page_id) {
+                true => None,
+                false => Some(self.manager.latch_page(page_id, LatchMode::Exclusive)?),
+            };
+            let mut map_page = self.read_page(page_id)?;
+            let mut modified = false;
+            for (data_page_id, stored) in map_page.entries.iter_mut() {
+                if let Some(&(_, free_space)) = entries.iter().find(|(id, _)| id == data_page_id) {
+                    modified |= *stored != free_space;
+                    *stored = free_space;
+                }
+            }
+            map_pages.insert(page_id, (map_page, modified, latch));
+        }
+
+        if !added.is_empty() {
+            let mut page_id = last_page_id;
+            let mut rest = added;
+            loop {
+                let (map_page, modified, _) = map_pages
+                    .get_mut(&page_id)
+                    .expect("the last map page is latched");
+                let room =
+                    FreeSpaceMapPage::capacity(page_size).saturating_sub(map_page.entries.len());
+                let (fits, more) = rest.split_at(room.min(rest.len()));
+                map_page
+                    .entries
+                    .extend(fits.iter().map(|&(id, free_bytes)| {
+                        (id, FreeSpaceMapPage::free_space(free_bytes, page_size))
+                    }));
+                *modified = true;
+                rest = more;
+                if rest.is_empty() {
+                    break;
+                }
+                // Nobody can get to the new page before the one linking to it is written, so it's
+                // latched without waiting
+                let next_page_id = self.manager.allocate_page()?;
+                map_page.next_page_id = next_page_id;
+                let latch = self
+                    .manager
+                    .latch_page(next_page_id, LatchMode::Exclusive)?;
+                map_pages.insert(next_page_id, (FreeSpaceMapPage::new(), true, Some(latch)));
+                page_id = next_page_id;
+            }
+            if page_id != last_page_id {
+                let (first_page, modified, _) = map_pages
+                    .get_mut(&self.first_page_id)
+                    .expect("the first map page is latched");
+                first_page.last_page_id = page_id;
+                *modified = true;
+            }
+        }
+
+        for (page_id, (map_page, modified, latch)) in map_pages {
+            if modified {
+                self.write_page(page_id, &map_page)?;
+                latches.extend(latch);
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads one page of the map, latching it shared unless `latches` already holds it
+    fn read_unless_held(
+        &self,
+        page_id: u64,
+        latches: &[PageLatch<'a>],
+    ) -> Result<FreeSpaceMapPage> {
+        match latches.iter().any(|latch| latch.page_id() == page_id) {
+            true => self.read_page(page_id),
+            false => self.read(page_id),
+        }
+    }
+
+    fn read_page(&self, page_id: u64) -> Result<FreeSpaceMapPage> {
+        let (header, map_page) = self.manager.read_page_as::<FreeSpaceMapPage>(page_id)?;
+        if header.page_type != PageType::FreeSpaceMap {
+            return Err(DbError::Corruption {
+                page_id,
+                reason: format!(
+                    "expected a FreeSpaceMap page but found {:?}",
+                    header.page_type
+                ),
+            });
+        }
+        Ok(map_page)
+    }
+
+    fn write_page(&self, page_id: u64, map_page: &FreeSpaceMapPage) -> Result<()> {
+        let mut header = PageHeader::new(page_id, PageType::FreeSpaceMap);
+        header.free_space_pointer = (PageHeader::SIZE + map_page.size()) as u32;
+        let mut page_buffer = vec![0u8; self.manager.page_size as usize];
+        header.serialize(&mut page_buffer)?;
+        map_page.serialize(&mut page_buffer[PageHeader::SIZE..])?;
+        self.manager.write_page(page_id, page_buffer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    proptest! {
+        #[test]
+        fn free_space_map_page_round_trips(
+            next_page_id: u64,
+            last_page_id: u64,
+            entries in prop::collection::vec(any::<(u64, u8)>(), 0..64),
+        ) {
+            let map_page = FreeSpaceMapPage { next_page_id, last_page_id, entries };
+            let mut buffer = vec![0u8; map_page.size()];
+            prop_assert_eq!(map_page.serialize(&mut buffer).unwrap(), map_page.size());
+            prop_assert_eq!(FreeSpaceMapPage::deserialize(&buffer).unwrap(), map_page);
+        }
+
+        #[test]
+        fn pages_are_only_picked_when_they_have_room(
+            page_size in prop::sample::select(vec![512u32, 4096, 8192]),
+            free_bytes in 0usize..8192,
+            needed in 0usize..8192,
+        ) {
+            let free_bytes = free_bytes.min(page_size as usize);
+            let stored = FreeSpaceMapPage::free_space(free_bytes, page_size);
+            if stored as usize >= FreeSpaceMapPage::free_space_needed(needed, page_size) {
+                prop_assert!(free_bytes >= needed);
+            }
+        }
+    }
+}
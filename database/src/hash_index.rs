@@ -0,0 +1,799 @@
+use std::ops::Range;
+
+use struct_layout::StructLayout;
+
+use crate::buffer_pool::{LatchMode, PageLatch};
+use crate::error::{check_buffer_size, DbError, Result};
+use crate::{
+    padding_needed_from_type, read_be_u32, read_be_u64, MyDeserialize, MySerialize, PageHeader,
+    PageType, PagedFileManager,
+};
+
+// Extendible hash index, for when all that's needed is looking keys up exactly.
+//
+// A directory page holds 2^global_depth bucket page ids and a key lives in the bucket at the low
+// global_depth bits of its hash. Buckets have a local depth of their own, at most the global one: a
+// bucket of local depth d holds every key whose hash ends in the same d bits, so it's pointed at by
+// every directory slot ending in those bits. A bucket that no longer fits in its page is split in
+// two on one more bit of the hash and only the slots pointing at it change. When its local depth
+// is already the global depth there's no slot to point at the new bucket, so the directory
+// doubles first by copying itself, which points every bucket at from twice as many slots.
+//
+// The directory is a single page. Once it's as big as a page allows (or the keys of a bucket all
+// hash the same as far as the directory can see), a full bucket gets a chain of overflow bucket
+// pages instead of splitting. Buckets aren't merged back together and the directory never shrinks.
+//
+// Hashes are crc32c of the key. They decide where keys are on disk, so they can't ever change.
+//
+// Lookups latch the directory shared, then the bucket's first page, then let go of the directory.
+// Changes that fit in the pages a bucket already has only latch the bucket exclusively, the
+// directory stays latched shared so the bucket can't be split in the meantime. Changes that need
+// pages allocated or freed latch the metadata page exclusively first like the B+Tree does, then the
+// directory and the bucket.
+
+/// Index mapping byte string keys to u64 values, like a unique `BTree` but for point lookups only.
+/// Several indexes can live in a file, each is known by the page id of its directory
+pub struct HashIndex<'a> {
+    manager: &'a PagedFileManager,
+    directory_page_id: u64,
+}
+
+/// A bucket read from its chain of pages
+struct Bucket {
+    // The first is the page the directory points at, the rest are its overflow pages
+    page_ids: Vec<u64>,
+    local_depth: u32,
+    entries: Vec<(Vec<u8>, u64)>,
+}
+
+// Hash directory page structure
+//
+// | global_depth | bucket_page_ids_len | bucket_page_ids |
+#[repr(C)]
+#[derive(StructLayout, Debug, Clone, PartialEq)]
+pub struct HashDirectoryPage {
+    pub global_depth: u32,
+    // Always 2^global_depth of them
+    pub bucket_page_ids: Vec<u64>,
+}
+
+impl MySerialize for HashDirectoryPage {
+    fn serialize(&self, buffer: &mut [u8]) -> Result<usize> {
+        let size = self.size();
+        check_buffer_size(buffer, size)?;
+
+        buffer[Self::global_depth_span()].copy_from_slice(&self.global_depth.to_be_bytes());
+        buffer[Self::bucket_page_ids_len_span()]
+            .copy_from_slice(&(self.bucket_page_ids.len() as u32).to_be_bytes());
+        let mut bucket_page_id_offset = Self::BUCKET_PAGE_IDS_FIRST_VALUE_OFFSET;
+        for bucket_page_id in &self.bucket_page_ids {
+            buffer[bucket_page_id_offset..bucket_page_id_offset + Self::BUCKET_PAGE_ID_SIZE]
+                .copy_from_slice(&bucket_page_id.to_be_bytes());
+            bucket_page_id_offset += Self::BUCKET_PAGE_ID_SIZE;
+        }
+
+        assert!(size == bucket_page_id_offset);
+        Ok(size)
+    }
+}
+
+impl MyDeserialize for HashDirectoryPage {
+    fn deserialize(buffer: &[u8]) -> Result<Self> {
+        check_buffer_size(buffer, Self::MIN_SIZE)?;
+
+        let global_depth = read_be_u32(&buffer[Self::global_depth_span()]);
+        let bucket_page_ids_len = read_be_u32(&buffer[Self::bucket_page_ids_len_span()]) as usize;
+        check_buffer_size(
+            buffer,
+            Self::MIN_SIZE + Self::BUCKET_PAGE_ID_SIZE * bucket_page_ids_len,
+        )?;
+
+        let bucket_page_ids = buffer[Self::BUCKET_PAGE_IDS_FIRST_VALUE_OFFSET..]
+            .chunks_exact(Self::BUCKET_PAGE_ID_SIZE)
+            .take(bucket_page_ids_len)
+            .map(read_be_u64)
+            .collect();
+
+        Ok(HashDirectoryPage {
+            global_depth,
+            bucket_page_ids,
+        })
+    }
+}
+
+impl HashDirectoryPage {
+    const BUCKET_PAGE_IDS_LEN_SIZE: usize = size_of::<u32>();
+    const BUCKET_PAGE_ID_SIZE: usize = size_of::<u64>();
+
+    const BUCKET_PAGE_IDS_LEN_OFFSET: usize = Self::GLOBAL_DEPTH_OFFSET + Self::GLOBAL_DEPTH_SIZE;
+    const BUCKET_PAGE_IDS_FIRST_VALUE_OFFSET: usize = padding_needed_from_type::<u64>(
+        Self::BUCKET_PAGE_IDS_LEN_OFFSET + Self::BUCKET_PAGE_IDS_LEN_SIZE,
+    ) + Self::BUCKET_PAGE_IDS_LEN_OFFSET
+        + Self::BUCKET_PAGE_IDS_LEN_SIZE;
+
+    const MIN_SIZE: usize = Self::BUCKET_PAGE_IDS_FIRST_VALUE_OFFSET;
+
+    fn bucket_page_ids_len_span() -> Range<usize> {
+        Self::BUCKET_PAGE_IDS_LEN_OFFSET
+            ..Self::BUCKET_PAGE_IDS_LEN_OFFSET + Self::BUCKET_PAGE_IDS_LEN_SIZE
+    }
+
+    pub fn size(&self) -> usize {
+        Self::MIN_SIZE + Self::BUCKET_PAGE_ID_SIZE * self.bucket_page_ids.len()
+    }
+
+    /// Deepest the directory can get before it no longer fits in a page of the given size. Hashes
+    /// are 32 bits so it can't usefully go past that either
+    pub fn max_global_depth(page_size: u32) -> u32 {
+        let capacity =
+            (page_size as usize - PageHeader::SIZE - Self::MIN_SIZE) / Self::BUCKET_PAGE_ID_SIZE;
+        capacity.ilog2().min(u32::BITS)
+    }
+
+    /// Page id of the bucket a key with the given hash belongs in
+    pub fn bucket_page_id(&self, hash: u32) -> u64 {
+        let mask = (1u64 << self.global_depth) - 1;
+        self.bucket_page_ids[(hash as u64 & mask) as usize]
+    }
+
+    /// Doubles the number of slots, each new slot pointing at the same bucket as the slot it's a
+    /// copy of
+    fn double(&mut self) {
+        self.bucket_page_ids.extend_from_within(..);
+        self.global_depth += 1;
+    }
+}
+
+// Hash bucket page structure
+//
+// | next_page_id | local_depth | entries_len | entries |
+//
+// Every entry is | key_len | value | key |. Entries aren't in any order, a bucket is small enough
+// that it's just searched from the start
+#[repr(C)]
+#[derive(StructLayout, Debug, Clone, PartialEq)]
+pub struct HashBucketPage {
+    // Next overflow page of the bucket, 0 for the last one
+    pub next_page_id: u64,
+    pub local_depth: u32,
+    pub entries: Vec<(Vec<u8>, u64)>,
+}
+
+impl MySerialize for HashBucketPage {
+    fn serialize(&self, buffer: &mut [u8]) -> Result<usize> {
+        let size = self.size();
+        check_buffer_size(buffer, size)?;
+
+        buffer[Self::next_page_id_span()].copy_from_slice(&self.next_page_id.to_be_bytes());
+        buffer[Self::local_depth_span()].copy_from_slice(&self.local_depth.to_be_bytes());
+        buffer[Self::entries_len_span()]
+            .copy_from_slice(&(self.entries.len() as u32).to_be_bytes());
+        let mut entry_offset = Self::ENTRIES_OFFSET;
+        for (key, value) in &self.entries {
+            let value_offset = entry_offset + Self::KEY_LEN_SIZE;
+            let key_offset = value_offset + Self::VALUE_SIZE;
+            buffer[entry_offset..value_offset].copy_from_slice(&(key.len() as u32).to_be_bytes());
+            buffer[value_offset..key_offset].copy_from_slice(&value.to_be_bytes());
+            buffer[key_offset..key_offset + key.len()].copy_from_slice(key);
+            entry_offset = key_offset + key.len();
+        }
+
+        assert!(size == entry_offset);
+        Ok(size)
+    }
+}
+
+impl MyDeserialize for HashBucketPage {
+    fn deserialize(buffer: &[u8]) -> Result<Self> {
+        check_buffer_size(buffer, Self::MIN_SIZE)?;
+
+        let next_page_id = read_be_u64(&buffer[Self::next_page_id_span()]);
+        let local_depth = read_be_u32(&buffer[Self::local_depth_span()]);
+        let entries_len = read_be_u32(&buffer[Self::entries_len_span()]) as usize;
+
+        let mut entry_offset = Self::ENTRIES_OFFSET;
+        let mut entries = Vec::new();
+        for _ in 0..entries_len {
+            let value_offset = entry_offset + Self::KEY_LEN_SIZE;
+            let key_offset = value_offset + Self::VALUE_SIZE;
+            check_buffer_size(buffer, key_offset)?;
+            let key_len = read_be_u32(&buffer[entry_offset..value_offset]) as usize;
+            check_buffer_size(buffer, key_offset + key_len)?;
+            let value = read_be_u64(&buffer[value_offset..key_offset]);
+            entries.push((buffer[key_offset..key_offset + key_len].to_vec(), value));
+            entry_offset = key_offset + key_len;
+        }
+
+        Ok(HashBucketPage {
+            next_page_id,
+            local_depth,
+            entries,
+        })
+    }
+}
+
+impl HashBucketPage {
+    const ENTRIES_LEN_SIZE: usize = size_of::<u32>();
+    const KEY_LEN_SIZE: usize = size_of::<u32>();
+    const VALUE_SIZE: usize = size_of::<u64>();
+    /// Bytes each entry takes up on top of its key
+    const ENTRY_OVERHEAD: usize = Self::KEY_LEN_SIZE + Self::VALUE_SIZE;
+
+    const ENTRIES_LEN_OFFSET: usize = Self::LOCAL_DEPTH_OFFSET + Self::LOCAL_DEPTH_SIZE;
+    const ENTRIES_OFFSET: usize = Self::ENTRIES_LEN_OFFSET + Self::ENTRIES_LEN_SIZE;
+
+    const MIN_SIZE: usize = Self::ENTRIES_OFFSET;
+
+    fn entries_len_span() -> Range<usize> {
+        Self::ENTRIES_LEN_OFFSET..Self::ENTRIES_LEN_OFFSET + Self::ENTRIES_LEN_SIZE
+    }
+
+    pub fn size(&self) -> usize {
+        Self::MIN_SIZE
+            + self
+                .entries
+                .iter()
+                .map(|(key, _)| Self::ENTRY_OVERHEAD + key.len())
+                .sum::<usize>()
+    }
+
+    /// Bytes of a page that entries can use
+    pub fn capacity(page_size: u32) -> usize {
+        page_size as usize - PageHeader::SIZE - Self::MIN_SIZE
+    }
+}
+
+impl<'a> HashIndex<'a> {
+    /// Makes a new empty index: a directory of depth 0 pointing at a single empty bucket
+    pub fn create(manager: &'a PagedFileManager) -> Result<Self> {
+        let _metadata_latch =
+            manager.latch_page(PagedFileManager::METADATA_PAGE_ID, LatchMode::Exclusive)?;
+        let directory_page_id = manager.atomically(|manager| {
+            let index = HashIndex {
+                manager,
+                directory_page_id: manager.allocate_page()?,
+            };
+            let mut bucket = Bucket {
+                page_ids: vec![manager.allocate_page()?],
+                local_depth: 0,
+                entries: Vec::new(),
+            };
+            index.write_bucket(&mut bucket)?;
+            index.write_directory(&HashDirectoryPage {
+                global_depth: 0,
+                bucket_page_ids: bucket.page_ids,
+            })?;
+            Ok(index.directory_page_id)
+        })?;
+        Ok(HashIndex {
+            manager,
+            directory_page_id,
+        })
+    }
+
+    /// Opens the index whose directory is at `directory_page_id`
+    pub fn open(manager: &'a PagedFileManager, directory_page_id: u64) -> Result<Self> {
+        let index = HashIndex {
+            manager,
+            directory_page_id,
+        };
+        index.read_directory()?;
+        Ok(index)
+    }
+
+    /// What the index has to be opened with again later
+    pub fn directory_page_id(&self) -> u64 {
+        self.directory_page_id
+    }
+
+    /// Biggest key the index accepts for the page size. Small enough that a bucket page holds at
+    /// least 2 entries
+    pub fn max_key_size(page_size: u32) -> usize {
+        HashBucketPage::capacity(page_size) / 2 - HashBucketPage::ENTRY_OVERHEAD
+    }
+
+    pub(crate) fn hash(key: &[u8]) -> u32 {
+        crc32c::crc32c(key)
+    }
+
+    pub fn get(&self, key: &[u8]) -> Result<Option<u64>> {
+        let (bucket_page_id, _bucket_latch, global_depth) = {
+            let _directory_latch = self.latch(self.directory_page_id, LatchMode::Shared)?;
+            let directory = self.read_directory()?;
+            let bucket_page_id = directory.bucket_page_id(Self::hash(key));
+            (
+                bucket_page_id,
+                self.latch(bucket_page_id, LatchMode::Shared)?,
+                directory.global_depth,
+            )
+        };
+        let bucket = self.read_bucket(bucket_page_id, global_depth)?;
+        Ok(bucket
+            .entries
+            .iter()
+            .find(|(entry_key, _)| entry_key == key)
+            .map(|(_, value)| *value))
+    }
+
+    /// Adds the entry. Refuses keys that are already in the index with `DuplicateKey`
+    pub fn insert(&self, key: &[u8], value: u64) -> Result<()> {
+        match self.insert_entry(key, value, false)? {
+            Some(_) => Err(DbError::DuplicateKey(key.to_vec())),
+            None => Ok(()),
+        }
+    }
+
+    /// Inserts the key, or replaces its value if it's already in the index, returning the value it
+    /// replaced
+    pub fn upsert(&self, key: &[u8], value: u64) -> Result<Option<u64>> {
+        self.insert_entry(key, value, true)
+    }
+
+    fn insert_entry(&self, key: &[u8], value: u64, replace: bool) -> Result<Option<u64>> {
+        let max = Self::max_key_size(self.manager.page_size);
+        if key.len() > max {
+            return Err(DbError::KeyTooLarge {
+                size: key.len(),
+                max,
+            });
+        }
+        self.change_bucket(key, |entries| {
+            match entries.iter_mut().find(|(entry_key, _)| entry_key == key) {
+                Some((_, existing)) => {
+                    let old_value = *existing;
+                    if replace {
+                        *existing = value;
+                    }
+                    (Some(old_value), replace)
+                }
+                None => {
+                    entries.push((key.to_vec(), value));
+                    (None, true)
+                }
+            }
+        })
+    }
+
+    /// Removes the key from the index, returning its value if it was there
+    pub fn delete(&self, key: &[u8]) -> Result<Option<u64>> {
+        self.change_bucket(key, |entries| {
+            let removed = entries
+                .iter()
+                .position(|(entry_key, _)| entry_key == key)
+                .map(|index| entries.swap_remove(index).1);
+            (removed, removed.is_some())
+        })
+    }
+
+    /// Runs `change` over the entries of the key's bucket and writes them back. `change` returns
+    /// what to hand back and whether it changed anything.
+    ///
+    /// The change is first made holding the directory shared, which works as long as the entries
+    /// still fit in the pages the bucket has. If they don't it's made again from scratch holding
+    /// everything exclusively, splitting the bucket or changing its chain of overflow pages
+    fn change_bucket<T>(
+        &self,
+        key: &[u8],
+        change: impl Fn(&mut Vec<(Vec<u8>, u64)>) -> (T, bool),
+    ) -> Result<T> {
+        let hash = Self::hash(key);
+        {
+            let _directory_latch = self.latch(self.directory_page_id, LatchMode::Shared)?;
+            let directory = self.read_directory()?;
+            let bucket_page_id = directory.bucket_page_id(hash);
+            let _bucket_latch = self.latch(bucket_page_id, LatchMode::Exclusive)?;
+            let mut bucket = self.read_bucket(bucket_page_id, directory.global_depth)?;
+            let (result, changed) = change(&mut bucket.entries);
+            if !changed {
+                return Ok(result);
+            }
+            if self.pages_needed(&bucket.entries) == bucket.page_ids.len() {
+                self.manager
+                    .atomically(|_| self.write_bucket(&mut bucket))?;
+                return Ok(result);
+            }
+        }
+
+        let _metadata_latch =
+            self.latch(PagedFileManager::METADATA_PAGE_ID, LatchMode::Exclusive)?;
+        let _directory_latch = self.latch(self.directory_page_id, LatchMode::Exclusive)?;
+        // Kept outside so the bucket stays latched until the change is committed
+        let mut latches = Vec::new();
+        self.manager.atomically(|_| {
+            let mut directory = self.read_directory()?;
+            let bucket_page_id = directory.bucket_page_id(hash);
+            latches.push(self.latch(bucket_page_id, LatchMode::Exclusive)?);
+            let mut bucket = self.read_bucket(bucket_page_id, directory.global_depth)?;
+            let (result, changed) = change(&mut bucket.entries);
+            if changed {
+                self.store_bucket(&mut directory, bucket)?;
+            }
+            Ok(result)
+        })
+    }
+
+    /// Writes the bucket back, splitting it for as long as it needs more than a page and
+    /// splitting is still possible. Writes the directory too if that changed it
+    fn store_bucket(&self, directory: &mut HashDirectoryPage, bucket: Bucket) -> Result<()> {
+        let max_global_depth = HashDirectoryPage::max_global_depth(self.manager.page_size);
+        let old_directory = directory.clone();
+        let mut pending = vec![bucket];
+        while let Some(mut bucket) = pending.pop() {
+            if self.pages_needed(&bucket.entries) <= 1 || bucket.local_depth == max_global_depth {
+                self.write_bucket(&mut bucket)?;
+                continue;
+            }
+            if bucket.local_depth == directory.global_depth {
+                directory.double();
+            }
+            let sibling = self.split(directory, &mut bucket)?;
+            pending.push(bucket);
+            pending.push(sibling);
+        }
+
+        if *directory != old_directory {
+            self.write_directory(directory)?;
+        }
+        Ok(())
+    }
+
+    /// Moves the bucket's entries with the next bit of their hash set to a new bucket, which the
+    /// directory slots ending in that bit are pointed at. Returns the new bucket, neither is
+    /// written
+    fn split(&self, directory: &mut HashDirectoryPage, bucket: &mut Bucket) -> Result<Bucket> {
+        let bit = 1u32 << bucket.local_depth;
+        bucket.local_depth += 1;
+        let (moved, kept) = std::mem::take(&mut bucket.entries)
+            .into_iter()
+            .partition(|(key, _)| Self::hash(key) & bit != 0);
+        bucket.entries = kept;
+        let sibling = Bucket {
+            page_ids: vec![self.manager.allocate_page()?],
+            local_depth: bucket.local_depth,
+            entries: moved,
+        };
+
+        for (slot, bucket_page_id) in directory.bucket_page_ids.iter_mut().enumerate() {
+            if *bucket_page_id == bucket.page_ids[0] && slot as u32 & bit != 0 {
+                *bucket_page_id = sibling.page_ids[0];
+            }
+        }
+        Ok(sibling)
+    }
+
+    /// How the entries get spread over a bucket's pages: filling each page before starting the
+    /// next. Gives the range of entries on each page, always at least one page even with no entries
+    fn pack(&self, entries: &[(Vec<u8>, u64)]) -> Vec<Range<usize>> {
+        let capacity = HashBucketPage::capacity(self.manager.page_size);
+        let mut pages = Vec::new();
+        let mut start = 0;
+        let mut used = 0;
+        for (index, (key, _)) in entries.iter().enumerate() {
+            let entry_size = HashBucketPage::ENTRY_OVERHEAD + key.len();
+            if used + entry_size > capacity {
+                pages.push(start..index);
+                start = index;
+                used = 0;
+            }
+            used += entry_size;
+        }
+        pages.push(start..entries.len());
+        pages
+    }
+
+    fn pages_needed(&self, entries: &[(Vec<u8>, u64)]) -> usize {
+        self.pack(entries).len()
+    }
+
+    /// Writes the bucket out over its pages, allocating more overflow pages or freeing the ones it
+    /// no longer needs
+    fn write_bucket(&self, bucket: &mut Bucket) -> Result<()> {
+        let pages = self.pack(&bucket.entries);
+        while bucket.page_ids.len() < pages.len() {
+            bucket.page_ids.push(self.manager.allocate_page()?);
+        }
+        for page_id in bucket.page_ids.split_off(pages.len()) {
+            self.manager.free_page(page_id)?;
+        }
+
+        for (index, entries) in pages.into_iter().enumerate() {
+            let bucket_page = HashBucketPage {
+                next_page_id: bucket.page_ids.get(index + 1).copied().unwrap_or(0),
+                local_depth: bucket.local_depth,
+                entries: bucket.entries[entries].to_vec(),
+            };
+            self.write_page(
+                bucket.page_ids[index],
+                PageType::HashBucket,
+                &bucket_page,
+                bucket_page.size(),
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Reads the bucket and its overflow pages. Its local depth has to be at most the directory's
+    /// `global_depth`, splitting only works out which slots to move for buckets that are
+    fn read_bucket(&self, page_id: u64, global_depth: u32) -> Result<Bucket> {
+        let first_page: HashBucketPage = self.read_page(page_id, PageType::HashBucket)?;
+        if first_page.local_depth > global_depth {
+            return Err(DbError::Corruption {
+                page_id,
+                reason: format!(
+                    "local depth {} is deeper than the directory's {global_depth}",
+                    first_page.local_depth
+                ),
+            });
+        }
+        let mut bucket = Bucket {
+            page_ids: vec![page_id],
+            local_depth: first_page.local_depth,
+            entries: first_page.entries,
+        };
+        let mut next_page_id = first_page.next_page_id;
+        while next_page_id != 0 {
+            if bucket.page_ids.contains(&next_page_id) {
+                return Err(DbError::Corruption {
+                    page_id,
+                    reason: "bucket's overflow pages loop back on themselves".to_string(),
+                });
+            }
+            let overflow_page: HashBucketPage =
+                self.read_page(next_page_id, PageType::HashBucket)?;
+            bucket.page_ids.push(next_page_id);
+            bucket.entries.extend(overflow_page.entries);
+            next_page_id = overflow_page.next_page_id;
+        }
+        Ok(bucket)
+    }
+
+    /// Reads the directory and makes sure its depth matches its slots, since a key's slot is found
+    /// by masking its hash with the depth
+    fn read_directory(&self) -> Result<HashDirectoryPage> {
+        let directory: HashDirectoryPage =
+            self.read_page(self.directory_page_id, PageType::HashDirectory)?;
+        let max_global_depth = HashDirectoryPage::max_global_depth(self.manager.page_size);
+        if directory.global_depth > max_global_depth
+            || directory.bucket_page_ids.len() as u64 != 1 << directory.global_depth
+        {
+            return Err(DbError::Corruption {
+                page_id: self.directory_page_id,
+                reason: format!(
+                    "global depth {} doesn't fit a directory of {} slots",
+                    directory.global_depth,
+                    directory.bucket_page_ids.len()
+                ),
+            });
+        }
+        Ok(directory)
+    }
+
+    fn write_directory(&self, directory: &HashDirectoryPage) -> Result<()> {
+        self.write_page(
+            self.directory_page_id,
+            PageType::HashDirectory,
+            directory,
+            directory.size(),
+        )
+    }
+
+    fn read_page<T: MyDeserialize>(&self, page_id: u64, page_type: PageType) -> Result<T> {
+        let (header, page) = self.manager.read_page_as::<T>(page_id)?;
+        if header.page_type != page_type {
+            return Err(DbError::Corruption {
+                page_id,
+                reason: format!(
+                    "expected a {page_type:?} page but found {:?}",
+                    header.page_type
+                ),
+            });
+        }
+        Ok(page)
+    }
+
+    fn write_page<T: MySerialize>(
+        &self,
+        page_id: u64,
+        page_type: PageType,
+        page: &T,
+        size: usize,
+    ) -> Result<()> {
+        let mut header = PageHeader::new(page_id, page_type);
+        header.free_space_pointer = (PageHeader::SIZE + size) as u32;
+        let mut page_buffer = vec![0u8; self.manager.page_size as usize];
+        header.serialize(&mut page_buffer)?;
+        page.serialize(&mut page_buffer[PageHeader::SIZE..])?;
+        self.manager.write_page(page_id, page_buffer)
+    }
+
+    fn latch(&self, page_id: u64, mode: LatchMode) -> Result<PageLatch<'a>> {
+        self.manager.latch_page(page_id, mode)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::memory_manager;
+    use std::thread;
+
+    /// Checks every slot points at a bucket whose local depth fits the slot, and that every key is
+    /// in the bucket its hash says
+    fn check_directory(index: &HashIndex) {
+        let directory = index.read_directory().unwrap();
+        assert_eq!(directory.bucket_page_ids.len(), 1 << directory.global_depth);
+        for (slot, &bucket_page_id) in directory.bucket_page_ids.iter().enumerate() {
+            let bucket = index
+                .read_bucket(bucket_page_id, directory.global_depth)
+                .unwrap();
+            assert!(bucket.local_depth <= directory.global_depth);
+            let mask = (1u32 << bucket.local_depth) - 1;
+            for (key, _) in &bucket.entries {
+                assert_eq!(HashIndex::hash(key) & mask, slot as u32 & mask);
+            }
+        }
+    }
+
+    #[test]
+    fn splits_and_doubling_keep_every_key_findable() {
+        let manager = memory_manager(1024);
+        let index = HashIndex::create(&manager).unwrap();
+        for key in 0..3000u32 {
+            index.insert(&key.to_be_bytes(), key as u64 * 10).unwrap();
+        }
+
+        let directory = index.read_directory().unwrap();
+        assert_eq!(
+            directory.global_depth,
+            HashDirectoryPage::max_global_depth(1024)
+        );
+        check_directory(&index);
+        for key in 0..3000u32 {
+            assert_eq!(
+                index.get(&key.to_be_bytes()).unwrap(),
+                Some(key as u64 * 10)
+            );
+        }
+        assert_eq!(index.get(&5000u32.to_be_bytes()).unwrap(), None);
+    }
+
+    #[test]
+    fn full_directories_chain_overflow_pages() {
+        // The directory of a 256 byte page can't get deep enough for this many keys
+        let manager = memory_manager(256);
+        let index = HashIndex::create(&manager).unwrap();
+        for key in 0..2000u32 {
+            index.insert(&key.to_be_bytes(), key as u64).unwrap();
+        }
+        let directory = index.read_directory().unwrap();
+        let longest_chain = directory
+            .bucket_page_ids
+            .iter()
+            .map(|&page_id| {
+                index
+                    .read_bucket(page_id, directory.global_depth)
+                    .unwrap()
+                    .page_ids
+                    .len()
+            })
+            .max();
+        assert!(longest_chain > Some(1));
+        check_directory(&index);
+
+        for key in (0..2000u32).filter(|key| key % 4 != 0) {
+            assert_eq!(index.delete(&key.to_be_bytes()).unwrap(), Some(key as u64));
+        }
+        for key in 0..2000u32 {
+            let expected = (key % 4 == 0).then_some(key as u64);
+            assert_eq!(index.get(&key.to_be_bytes()).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn existing_keys_are_rejected_unless_upserted() {
+        let manager = memory_manager(256);
+        let index = HashIndex::create(&manager).unwrap();
+
+        index.insert(b"key1", 1).unwrap();
+        assert!(matches!(
+            index.insert(b"key1", 2),
+            Err(DbError::DuplicateKey(key)) if key == b"key1"
+        ));
+        assert_eq!(index.upsert(b"key1", 2).unwrap(), Some(1));
+        assert_eq!(index.upsert(b"key2", 3).unwrap(), None);
+        assert_eq!(index.delete(b"key1").unwrap(), Some(2));
+        assert_eq!(index.delete(b"key1").unwrap(), None);
+
+        let too_big = vec![0u8; HashIndex::max_key_size(256) + 1];
+        assert!(matches!(
+            index.insert(&too_big, 4),
+            Err(DbError::KeyTooLarge { .. })
+        ));
+
+        // Reopening finds the same entries, opening anything but a directory is an error
+        let reopened = HashIndex::open(&manager, index.directory_page_id()).unwrap();
+        assert_eq!(reopened.get(b"key2").unwrap(), Some(3));
+        let bucket_page_id = index.read_directory().unwrap().bucket_page_ids[0];
+        assert!(matches!(
+            HashIndex::open(&manager, bucket_page_id),
+            Err(DbError::Corruption { .. })
+        ));
+    }
+
+    #[test]
+    fn directories_whose_depth_doesnt_fit_are_corruption() {
+        let manager = memory_manager(256);
+        let index = HashIndex::create(&manager).unwrap();
+        index.insert(b"key", 1).unwrap();
+        let directory = index.read_directory().unwrap();
+
+        for global_depth in [1, 64] {
+            index
+                .write_directory(&HashDirectoryPage {
+                    global_depth,
+                    ..directory.clone()
+                })
+                .unwrap();
+            assert!(matches!(index.get(b"key"), Err(DbError::Corruption { .. })));
+            assert!(matches!(
+                index.insert(b"other", 2),
+                Err(DbError::Corruption { .. })
+            ));
+            assert!(matches!(
+                index.delete(b"key"),
+                Err(DbError::Corruption { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn buckets_deeper_than_the_directory_are_corruption() {
+        let manager = memory_manager(256);
+        let index = HashIndex::create(&manager).unwrap();
+        index.insert(b"key", 1).unwrap();
+        let bucket_page_id = index.read_directory().unwrap().bucket_page_ids[0];
+
+        for local_depth in [1, 40] {
+            manager
+                .atomically(|_| {
+                    index.write_bucket(&mut Bucket {
+                        page_ids: vec![bucket_page_id],
+                        local_depth,
+                        entries: vec![(b"key".to_vec(), 1)],
+                    })
+                })
+                .unwrap();
+            assert!(matches!(index.get(b"key"), Err(DbError::Corruption { .. })));
+            assert!(matches!(
+                index.insert(b"other", 2),
+                Err(DbError::Corruption { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn threads_can_share_the_index() {
+        let manager = memory_manager(512);
+        let index = HashIndex::create(&manager).unwrap();
+        thread::scope(|scope| {
+            for writer in 0..4u32 {
+                let index = &index;
+                scope.spawn(move || {
+                    for key in (0..500u32).map(|i| i * 4 + writer) {
+                        index.insert(&key.to_be_bytes(), key as u64).unwrap();
+                        assert_eq!(index.get(&key.to_be_bytes()).unwrap(), Some(key as u64));
+                    }
+                    for key in (0..250u32).map(|i| i * 8 + writer) {
+                        assert_eq!(index.delete(&key.to_be_bytes()).unwrap(), Some(key as u64));
+                    }
+                });
+            }
+        });
+
+        check_directory(&index);
+        for key in 0..2000u32 {
+            let expected = (key % 8 >= 4).then_some(key as u64);
+            assert_eq!(index.get(&key.to_be_bytes()).unwrap(), expected);
+        }
+    }
+}
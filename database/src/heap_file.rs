@@ -0,0 +1,650 @@
+use std::thread;
+use std::vec;
+
+use crate::buffer_pool::{LatchMode, PageLatch};
+use crate::error::Result;
+use crate::free_space_map::{FreeSpaceMap, PageRoom};
+use crate::overflow::OverflowStub;
+use crate::{DataPage, PagedFileManager, Record, SlotId};
+
+// Heap file, the unordered records of one table spread over as many data pages as they need.
+//
+// The heap's pages are listed in its free space map along with roughly how much room each one has,
+// so an insert only tries pages that should fit the record. A new page is only added once none of
+// the ones it tried did. Pages stay in the heap once they're added, even after everything on them
+// is deleted, so their space can be reused by later inserts. Records too big for a page go in
+// overflow pages like they do with `PagedFileManager::insert_record`.
+//
+// Every change latches the data pages it touches exclusively and keeps them latched until the map
+// has been updated to match and the change has committed. Anything that might allocate or free
+// pages (a new page for the heap or overflow pages for a big record) latches the metadata page
+// exclusively before any page of the heap, like the B+Tree does.
+//
+// Pages changed some other way than through the heap are found out when an insert tries one and it
+// doesn't fit, which fixes its map entry.
+//
+// Records keep their `RecordId` for as long as they live, so indexes can point at them. When an
+// update makes one too big for the room left on its page it's moved to another page and a
+// forwarding stub is left in its slot, which reads, updates and deletes follow. The moved record
+// is marked as such on its new page so scans skip it there and only see it through the stub. It
+// moves back into its own slot once it fits there again, and a record that has to move again
+// just gets its stub pointed somewhere else, so there's never more than one stub to follow.
+//
+// Following a stub means latching a second data page while holding the first. Changes to a moved
+// record latch the metadata page before either of them, and readers only take the second page's
+// latch if it's free straight away and start over otherwise, so nobody waits for a data page
+// while holding one another might want.
+
+/// Where a record lives in a heap file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct RecordId {
+    pub page_id: u64,
+    pub slot: SlotId,
+}
+
+/// What a `RecordId` finds in its slot
+enum Slot {
+    Empty,
+    Here,
+    Forward(RecordId),
+}
+
+/// The records of a table. Several heaps can live in a file, each is known by the page id of the
+/// first page of its free space map
+pub struct HeapFile<'a> {
+    manager: &'a PagedFileManager,
+    map: FreeSpaceMap<'a>,
+}
+
+/// What a change to the heap has done so far, for updating the free space map once it's done
+struct Change<'a> {
+    // Pages the change has touched or will, kept latched until the change commits
+    latches: Vec<PageLatch<'a>>,
+    // Pages already in the map and how many bytes of room they have now
+    changed: Vec<PageRoom>,
+    // Pages the change added to the heap
+    added: Vec<(u64, usize)>,
+}
+
+impl<'a> Change<'a> {
+    fn new(latches: Vec<PageLatch<'a>>) -> Self {
+        Change {
+            latches,
+            changed: Vec::new(),
+            added: Vec::new(),
+        }
+    }
+
+    fn holds(&self, page_id: u64) -> bool {
+        self.latches.iter().any(|latch| latch.page_id() == page_id)
+    }
+
+    /// Records how much room the page has now, replacing anything recorded for it before.
+    /// `map_page_id` is the map page listing it, if it's known
+    fn note(&mut self, page_id: u64, map_page_id: Option<u64>, free_space: usize) {
+        if let Some(page) = self.added.iter_mut().find(|(id, _)| *id == page_id) {
+            page.1 = free_space;
+            return;
+        }
+        match self.changed.iter_mut().find(|room| room.page_id == page_id) {
+            Some(room) => {
+                room.map_page_id = room.map_page_id.or(map_page_id);
+                room.free_bytes = free_space;
+            }
+            None => self.changed.push(PageRoom {
+                page_id,
+                map_page_id,
+                free_bytes: free_space,
+            }),
+        }
+    }
+}
+
+impl<'a> HeapFile<'a> {
+    /// Makes a new empty heap. It has no data pages until the first insert
+    pub fn create(manager: &'a PagedFileManager) -> Result<Self> {
+        let _metadata_latch =
+            manager.latch_page(PagedFileManager::METADATA_PAGE_ID, LatchMode::Exclusive)?;
+        Ok(HeapFile {
+            manager,
+            map: FreeSpaceMap::create(manager)?,
+        })
+    }
+
+    /// Opens the heap whose free space map starts at `free_space_map_page_id`
+    pub fn open(manager: &'a PagedFileManager, free_space_map_page_id: u64) -> Result<Self> {
+        Ok(HeapFile {
+            manager,
+            map: FreeSpaceMap::open(manager, free_space_map_page_id)?,
+        })
+    }
+
+    pub fn free_space_map_page_id(&self) -> u64 {
+        self.map.first_page_id()
+    }
+
+    pub fn insert(&self, record: &[u8]) -> Result<RecordId> {
+        let mut change = Change::new(Vec::new());
+        self.manager.atomically(|_| {
+            // Spilling allocates overflow pages so those start with the metadata latch
+            if !self.spills(record) {
+                if let Some(record_id) = self.insert_into_existing(record, &mut change)? {
+                    self.finish(&mut change)?;
+                    return Ok(record_id);
+                }
+            }
+            change
+                .latches
+                .push(self.latch(PagedFileManager::METADATA_PAGE_ID, LatchMode::Exclusive)?);
+            let record_id = self.insert_holding_metadata(record, &mut change)?;
+            self.finish(&mut change)?;
+            Ok(record_id)
+        })
+    }
+
+    pub fn get(&self, record_id: RecordId) -> Result<Option<Vec<u8>>> {
+        loop {
+            let _latch = self.latch(record_id.page_id, LatchMode::Shared)?;
+            let moved_to = match self.slot(record_id)? {
+                Slot::Empty => return Ok(None),
+                Slot::Here => return self.manager.get_record(record_id.page_id, record_id.slot),
+                Slot::Forward(moved_to) => moved_to,
+            };
+            if let Some(_moved_latch) = self.try_latch(moved_to.page_id)? {
+                return self.manager.get_record(moved_to.page_id, moved_to.slot);
+            }
+            thread::yield_now();
+        }
+    }
+
+    /// Replaces the record, returning `record_id`. Records that no longer fit on their page are
+    /// moved and keep being found through `record_id`. None if there's no record there
+    pub fn update(&self, record_id: RecordId, record: &[u8]) -> Result<Option<RecordId>> {
+        {
+            let mut change = Change::new(self.latch_record(record_id, self.spills(record))?);
+            match self.slot(record_id)? {
+                Slot::Empty => return Ok(None),
+                Slot::Here => {
+                    if self.update_in_place(record_id, record, &mut change)? {
+                        return Ok(Some(record_id));
+                    }
+                }
+                // Moved records are dealt with below along with the ones that have to move
+                Slot::Forward(_) => {}
+            }
+        }
+
+        // Moving might need a page added so it starts over holding the metadata latch. The
+        // record's page is still latched while it goes in somewhere else so nobody can see it in
+        // both places
+        let mut change = Change::new(self.latch_record(record_id, true)?);
+        let moved_to = match self.slot(record_id)? {
+            Slot::Empty => return Ok(None),
+            Slot::Here => None,
+            Slot::Forward(moved_to) => {
+                change
+                    .latches
+                    .push(self.latch(moved_to.page_id, LatchMode::Exclusive)?);
+                Some(moved_to)
+            }
+        };
+        self.manager.atomically(|manager| {
+            // Back in its own slot if it fits there, otherwise where it was moved to before,
+            // otherwise somewhere new
+            let at_home = manager.update_record(record_id.page_id, record_id.slot, record)?;
+            let stays_moved = match (at_home, moved_to) {
+                (false, Some(moved_to)) => {
+                    manager.update_record(moved_to.page_id, moved_to.slot, record)?
+                }
+                _ => false,
+            };
+            if !at_home && !stays_moved {
+                let new_location = self.insert_holding_metadata(record, &mut change)?;
+                manager.with_data_window_mut(new_location.page_id, |window| {
+                    window.mark_moved(new_location.slot)
+                })?;
+                manager.forward_record(
+                    record_id.page_id,
+                    record_id.slot,
+                    new_location.page_id,
+                    new_location.slot,
+                )?;
+            }
+            if let Some(moved_to) = moved_to {
+                if !stays_moved {
+                    manager.delete_record(moved_to.page_id, moved_to.slot)?;
+                }
+                change.note(moved_to.page_id, None, self.free_space(moved_to.page_id)?);
+            }
+            change.note(record_id.page_id, None, self.free_space(record_id.page_id)?);
+            self.finish(&mut change)?;
+            Ok(Some(record_id))
+        })
+    }
+
+    /// Deletes the record, returning false if there wasn't one
+    pub fn delete(&self, record_id: RecordId) -> Result<bool> {
+        let mut change = Change::new(self.latch_record(record_id, false)?);
+        let moved_to = match self.slot(record_id)? {
+            Slot::Empty => return Ok(false),
+            Slot::Here => None,
+            Slot::Forward(moved_to) => {
+                change
+                    .latches
+                    .push(self.latch(moved_to.page_id, LatchMode::Exclusive)?);
+                Some(moved_to)
+            }
+        };
+        self.manager.atomically(|manager| {
+            for location in std::iter::once(record_id).chain(moved_to) {
+                manager.delete_record(location.page_id, location.slot)?;
+                change.note(location.page_id, None, self.free_space(location.page_id)?);
+            }
+            self.finish(&mut change)?;
+            Ok(true)
+        })
+    }
+
+    /// Every record in the heap, a page at a time. Pages are only latched while their records
+    /// are read so records changed while the scan runs may or may not be seen
+    pub fn scan(&self) -> HeapScan<'_, 'a> {
+        HeapScan {
+            heap: self,
+            next_map_page_id: self.map.first_page_id(),
+            page_ids: Vec::new().into_iter(),
+            records: Vec::new().into_iter(),
+            failed: false,
+        }
+    }
+
+    fn spills(&self, record: &[u8]) -> bool {
+        record.len() > DataPage::max_record_size(self.manager.page_size)
+    }
+
+    /// Replaces the record if it still fits on its page, which `change` has to hold
+    fn update_in_place(
+        &self,
+        record_id: RecordId,
+        record: &[u8],
+        change: &mut Change<'a>,
+    ) -> Result<bool> {
+        self.manager.atomically(|manager| {
+            if !manager.update_record(record_id.page_id, record_id.slot, record)? {
+                return Ok(false);
+            }
+            change.note(record_id.page_id, None, self.free_space(record_id.page_id)?);
+            self.finish(change)?;
+            Ok(true)
+        })
+    }
+
+    /// Tries the pages the map says have room, except ones the change already holds. The page the
+    /// record went in stays latched
+    fn insert_into_existing(
+        &self,
+        record: &[u8],
+        change: &mut Change<'a>,
+    ) -> Result<Option<RecordId>> {
+        let stored_len = match self.spills(record) {
+            true => OverflowStub::SIZE,
+            false => record.len(),
+        };
+        for (page_id, map_page_id) in self
+            .map
+            .pages_with_room(DataPage::max_space_needed(stored_len))?
+        {
+            if change.holds(page_id) {
+                continue;
+            }
+            let latch = self.latch(page_id, LatchMode::Exclusive)?;
+            let slot = self.manager.insert_record(page_id, record)?;
+            change.note(page_id, Some(map_page_id), self.free_space(page_id)?);
+            if let Some(slot) = slot {
+                change.latches.push(latch);
+                return Ok(Some(RecordId { page_id, slot }));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Inserts somewhere, adding a page to the heap if none has room. The change has to hold the
+    /// metadata latch
+    fn insert_holding_metadata(&self, record: &[u8], change: &mut Change<'a>) -> Result<RecordId> {
+        if let Some(record_id) = self.insert_into_existing(record, change)? {
+            return Ok(record_id);
+        }
+        // Nobody else can find the page until the map is updated, so it's latched without waiting
+        let page_id = self.manager.create_data_page()?;
+        change
+            .latches
+            .push(self.latch(page_id, LatchMode::Exclusive)?);
+        let slot = self
+            .manager
+            .insert_record(page_id, record)?
+            .expect("an empty page fits any record");
+        change.added.push((page_id, self.free_space(page_id)?));
+        Ok(RecordId { page_id, slot })
+    }
+
+    /// Brings the map up to date with the change. Its map pages stay latched along with the rest
+    fn finish(&self, change: &mut Change<'a>) -> Result<()> {
+        self.map
+            .update(&change.changed, &change.added, &mut change.latches)
+    }
+
+    /// Latches the record's page exclusively, after the metadata page if the change might
+    /// allocate or free pages or touch another data page. It will if `allocating` is set or the
+    /// record is in overflow pages or has been moved
+    fn latch_record(&self, record_id: RecordId, allocating: bool) -> Result<Vec<PageLatch<'a>>> {
+        loop {
+            // Checked before latching since the metadata latch has to come first, so it's checked
+            // again after in case the record changed in between
+            let needs_metadata = allocating || self.reaches_past_slot(record_id)?;
+            let mut latches = Vec::new();
+            if needs_metadata {
+                latches.push(self.latch(PagedFileManager::METADATA_PAGE_ID, LatchMode::Exclusive)?);
+            }
+            latches.push(self.latch(record_id.page_id, LatchMode::Exclusive)?);
+            if needs_metadata || !self.reaches_past_slot(record_id)? {
+                return Ok(latches);
+            }
+        }
+    }
+
+    fn reaches_past_slot(&self, record_id: RecordId) -> Result<bool> {
+        self.manager.with_data_window(record_id.page_id, |window| {
+            Ok(matches!(
+                window.get_record(record_id.slot)?,
+                Some(Record::Overflow(_) | Record::Forward { .. })
+            ))
+        })
+    }
+
+    /// What's in the slot as far as the heap's callers go. Records moved into the slot from
+    /// another page aren't there for them, they're found through their own slot
+    fn slot(&self, record_id: RecordId) -> Result<Slot> {
+        self.manager.with_data_window(record_id.page_id, |window| {
+            Ok(match window.get_record(record_id.slot)? {
+                None => Slot::Empty,
+                Some(Record::Forward { page_id, slot }) => {
+                    Slot::Forward(RecordId { page_id, slot })
+                }
+                Some(_) if window.is_moved(record_id.slot)? => Slot::Empty,
+                Some(_) => Slot::Here,
+            })
+        })
+    }
+
+    fn free_space(&self, page_id: u64) -> Result<usize> {
+        self.manager
+            .with_data_window(page_id, |window| window.free_space())
+    }
+
+    fn latch(&self, page_id: u64, mode: LatchMode) -> Result<PageLatch<'a>> {
+        self.manager.latch_page(page_id, mode)
+    }
+
+    /// Latches the page a moved record lives on in shared mode, None if it's latched exclusively
+    fn try_latch(&self, page_id: u64) -> Result<Option<PageLatch<'a>>> {
+        self.manager.try_latch_page(page_id, LatchMode::Shared)
+    }
+}
+
+/// Iterator over every record of a heap, made by `HeapFile::scan`. Stops after the first error
+pub struct HeapScan<'h, 'a> {
+    heap: &'h HeapFile<'a>,
+    // 0 once the last map page has been read
+    next_map_page_id: u64,
+    // Data pages of the last map page read that haven't been read yet
+    page_ids: vec::IntoIter<u64>,
+    records: vec::IntoIter<(RecordId, Vec<u8>)>,
+    failed: bool,
+}
+
+impl Iterator for HeapScan<'_, '_> {
+    type Item = Result<(RecordId, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(record) = self.records.next() {
+                return Some(Ok(record));
+            }
+            if self.failed {
+                return None;
+            }
+            let result = match self.page_ids.next() {
+                Some(page_id) => self.read_page(page_id),
+                None if self.next_map_page_id != 0 => self.read_next_map_page(),
+                None => return None,
+            };
+            if let Err(err) = result {
+                self.failed = true;
+                return Some(Err(err));
+            }
+        }
+    }
+}
+
+impl HeapScan<'_, '_> {
+    /// Copies out every record whose slot is on the page, following forwarding stubs. Starts
+    /// over if a page a record was moved to is busy
+    fn read_page(&mut self, page_id: u64) -> Result<()> {
+        let heap = self.heap;
+        'page: loop {
+            let latch = heap.latch(page_id, LatchMode::Shared)?;
+            let num_slots = heap
+                .manager
+                .with_data_window(page_id, |window| Ok(window.num_slots()))?;
+            let mut records = Vec::new();
+            for slot in 0..num_slots {
+                let record_id = RecordId { page_id, slot };
+                let location = match heap.slot(record_id)? {
+                    Slot::Empty => continue,
+                    Slot::Here => record_id,
+                    Slot::Forward(moved_to) => moved_to,
+                };
+                let _moved_latch = match location.page_id == page_id {
+                    true => None,
+                    false => match heap.try_latch(location.page_id)? {
+                        Some(moved_latch) => Some(moved_latch),
+                        None => {
+                            drop(latch);
+                            thread::yield_now();
+                            continue 'page;
+                        }
+                    },
+                };
+                if let Some(record) = heap.manager.get_record(location.page_id, location.slot)? {
+                    records.push((record_id, record));
+                }
+            }
+            self.records = records.into_iter();
+            return Ok(());
+        }
+    }
+
+    fn read_next_map_page(&mut self) -> Result<()> {
+        let map_page = self.heap.map.read(self.next_map_page_id)?;
+        self.page_ids = map_page
+            .entries
+            .into_iter()
+            .map(|(page_id, _)| page_id)
+            .collect::<Vec<_>>()
+            .into_iter();
+        self.next_map_page_id = map_page.next_page_id;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::memory_manager;
+    use crate::{DbError, MetadataPage};
+    use std::collections::HashMap;
+    use std::thread;
+
+    fn record(i: usize) -> Vec<u8> {
+        format!("record {i:04} ").repeat(i % 5 + 1).into_bytes()
+    }
+
+    fn scanned(heap: &HeapFile) -> HashMap<RecordId, Vec<u8>> {
+        heap.scan().map(|record| record.unwrap()).collect()
+    }
+
+    #[test]
+    fn records_spread_over_pages_and_come_back() {
+        let manager = memory_manager(512);
+        let heap = HeapFile::create(&manager).unwrap();
+        let mut expected: HashMap<_, _> = (0..300)
+            .map(|i| (heap.insert(&record(i)).unwrap(), record(i)))
+            .collect();
+        let big = vec![9u8; 2000];
+        expected.insert(heap.insert(&big).unwrap(), big);
+
+        let pages: std::collections::HashSet<_> = expected.keys().map(|rid| rid.page_id).collect();
+        assert!(pages.len() > 10);
+        for (&record_id, record) in &expected {
+            assert_eq!(heap.get(record_id).unwrap().as_ref(), Some(record));
+        }
+        assert_eq!(scanned(&heap), expected);
+
+        let reopened = HeapFile::open(&manager, heap.free_space_map_page_id()).unwrap();
+        assert_eq!(scanned(&reopened), expected);
+        assert!(manager.check_integrity().unwrap().is_ok());
+    }
+
+    #[test]
+    fn updates_move_records_that_outgrow_their_page() {
+        let manager = memory_manager(512);
+        let heap = HeapFile::create(&manager).unwrap();
+        let mut expected: HashMap<_, _> = (0..100)
+            .map(|i| (heap.insert(&record(i)).unwrap(), record(i)))
+            .collect();
+        let is_forwarded =
+            |record_id: RecordId| matches!(heap.slot(record_id), Ok(Slot::Forward(_)));
+
+        // Every other record gets deleted, the rest grow past what their pages can fit
+        let record_ids: Vec<_> = expected.keys().copied().collect();
+        let mut moved = Vec::new();
+        for (i, record_id) in record_ids.into_iter().enumerate() {
+            if i % 2 == 0 {
+                assert!(heap.delete(record_id).unwrap());
+                assert!(!heap.delete(record_id).unwrap());
+                expected.remove(&record_id);
+                continue;
+            }
+            let grown = vec![i as u8; 300];
+            assert_eq!(heap.update(record_id, &grown).unwrap(), Some(record_id));
+            assert_eq!(heap.get(record_id).unwrap().as_ref(), Some(&grown));
+            if is_forwarded(record_id) {
+                moved.push(record_id);
+            }
+            expected.insert(record_id, grown);
+        }
+        assert!(moved.len() > 2);
+        assert_eq!(scanned(&heap), expected);
+        assert!(manager.check_integrity().unwrap().is_ok());
+
+        // Moved records move on without a second stub, come back once they fit and can be deleted
+        let bigger = vec![1u8; 400];
+        assert_eq!(heap.update(moved[0], &bigger).unwrap(), Some(moved[0]));
+        assert!(is_forwarded(moved[0]));
+        expected.insert(moved[0], bigger);
+        assert_eq!(heap.update(moved[1], b"tiny").unwrap(), Some(moved[1]));
+        assert!(!is_forwarded(moved[1]));
+        expected.insert(moved[1], b"tiny".to_vec());
+        assert!(heap.delete(moved[2]).unwrap());
+        assert_eq!(heap.get(moved[2]).unwrap(), None);
+        expected.remove(&moved[2]);
+        assert_eq!(scanned(&heap), expected);
+        assert!(manager.check_integrity().unwrap().is_ok());
+
+        let gone = RecordId {
+            page_id: expected.keys().next().unwrap().page_id,
+            slot: 1000,
+        };
+        assert_eq!(heap.update(gone, b"nothing").unwrap(), None);
+        assert!(matches!(
+            HeapFile::open(&manager, 0),
+            Err(DbError::Corruption { .. })
+        ));
+    }
+
+    #[test]
+    fn inserts_reuse_the_room_deletes_leave() {
+        let manager = memory_manager(512);
+        let heap = HeapFile::create(&manager).unwrap();
+        let total_pages = || {
+            manager
+                .read_page_as::<MetadataPage>(0)
+                .unwrap()
+                .1
+                .total_pages
+        };
+
+        // Enough pages that the map needs more than one page too
+        let record_ids: Vec<_> = (0..300)
+            .map(|i| heap.insert(&[i as u8; 100]).unwrap())
+            .collect();
+        let map_page = heap.map.read(heap.free_space_map_page_id()).unwrap();
+        assert_ne!(map_page.next_page_id, 0);
+        let grown_to = total_pages();
+
+        for &record_id in record_ids.iter().step_by(2) {
+            assert!(heap.delete(record_id).unwrap());
+        }
+        let mut expected: HashMap<_, _> = record_ids
+            .iter()
+            .skip(1)
+            .step_by(2)
+            .map(|&record_id| (record_id, heap.get(record_id).unwrap().unwrap()))
+            .collect();
+        for i in 0..150 {
+            expected.insert(heap.insert(&[i as u8; 100]).unwrap(), vec![i as u8; 100]);
+        }
+        assert_eq!(total_pages(), grown_to);
+        assert_eq!(scanned(&heap), expected);
+        assert!(manager.check_integrity().unwrap().is_ok());
+    }
+
+    #[test]
+    fn threads_can_share_the_heap() {
+        let manager = memory_manager(512);
+        let heap = HeapFile::create(&manager).unwrap();
+        let inserted: Vec<Vec<(RecordId, Vec<u8>)>> = thread::scope(|scope| {
+            let workers: Vec<_> = (0..4)
+                .map(|worker| {
+                    let heap = &heap;
+                    scope.spawn(move || {
+                        let mut kept = Vec::new();
+                        for i in 0..150 {
+                            let bytes = record(worker * 1000 + i);
+                            let record_id = heap.insert(&bytes).unwrap();
+                            if i % 3 == 0 {
+                                assert!(heap.delete(record_id).unwrap());
+                            } else if i % 3 == 1 {
+                                let grown = vec![worker as u8; 200 + i];
+                                let record_id = heap.update(record_id, &grown).unwrap().unwrap();
+                                kept.push((record_id, grown));
+                            } else {
+                                kept.push((record_id, bytes));
+                            }
+                        }
+                        kept
+                    })
+                })
+                .collect();
+            // Scans run alongside the writers, they only have to not fail
+            for _ in 0..5 {
+                heap.scan().for_each(|record| drop(record.unwrap()));
+            }
+            workers.into_iter().map(|w| w.join().unwrap()).collect()
+        });
+
+        let expected: HashMap<_, _> = inserted.into_iter().flatten().collect();
+        assert_eq!(expected.len(), 400);
+        assert_eq!(scanned(&heap), expected);
+        assert!(manager.check_integrity().unwrap().is_ok());
+    }
+}
@@ -0,0 +1,742 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use crate::error::{DbError, Result};
+use crate::free_space_map::FreeSpaceMapPage;
+use crate::hash_index::{HashBucketPage, HashDirectoryPage, HashIndex};
+use crate::overflow::{OverflowPage, OverflowStub};
+use crate::{
+    DataPage, FreeListPage, IndexPage, MetadataPage, MyDeserialize, PageHeader, PageType,
+    PagedFileManager,
+};
+
+// Integrity checking walks everything reachable from the metadata page and checks every page it
+// finds is what the page pointing at it expects.
+//
+// Every page has to be accounted for exactly once: on the free list, part of something the
+// metadata page points at (the free list's own pages and the B+Tree), or part of something callers
+// keep track of themselves. Nothing in the file points at free space maps, data pages or hash index
+// directories, so every one of those that isn't claimed by anything else is taken as a starting
+// point too. A free space map starts at the map page no other map page links to and brings along
+// the data pages it lists, data pages bring the overflow pages their records spill into and
+// directories their buckets. Whatever is left after that has leaked.
+//
+// Free pages keep whatever they held before they were freed, so only pages in use get their
+// contents checked.
+
+/// One thing found wrong with a database file
+#[derive(Debug, Clone, PartialEq)]
+pub struct IntegrityProblem {
+    pub page_id: u64,
+    pub reason: String,
+}
+
+impl fmt::Display for IntegrityProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "page {}: {}", self.page_id, self.reason)
+    }
+}
+
+/// What `PagedFileManager::check_integrity` found
+#[derive(Debug, Default)]
+pub struct IntegrityReport {
+    pub pages_checked: u64,
+    pub problems: Vec<IntegrityProblem>,
+}
+
+impl IntegrityReport {
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// What a page turned out to be used for
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum PageUse {
+    Metadata,
+    FreeList,
+    Free,
+    BTreeNode,
+    Data,
+    Overflow,
+    HashDirectory,
+    HashBucket,
+    FreeSpaceMap,
+}
+
+struct Checker<'m> {
+    manager: &'m PagedFileManager,
+    total_pages: u64,
+    // None for pages whose header couldn't be read, that's already been reported
+    headers: Vec<Option<PageHeader>>,
+    uses: HashMap<u64, PageUse>,
+    problems: Vec<IntegrityProblem>,
+}
+
+/// A B+Tree leaf and its sibling links, kept to check the chain once all the leaves are known
+struct LeafLinks {
+    page_id: u64,
+    prev_leaf: u64,
+    next_leaf: u64,
+}
+
+impl PagedFileManager {
+    /// Checks every page of the file, returning everything found wrong with it. Damage to the file
+    /// is reported rather than returned as an error, errors are for when the file can't be read
+    /// at all.
+    ///
+    /// The check doesn't latch anything so nothing else should be changing the file while it runs
+    pub fn check_integrity(&self) -> Result<IntegrityReport> {
+        let mut checker = Checker {
+            manager: self,
+            total_pages: 0,
+            headers: Vec::new(),
+            uses: HashMap::new(),
+            problems: Vec::new(),
+        };
+        checker.check()?;
+        Ok(IntegrityReport {
+            pages_checked: checker.total_pages,
+            problems: checker.problems,
+        })
+    }
+}
+
+impl Checker<'_> {
+    fn check(&mut self) -> Result<()> {
+        let metadata_page_id = PagedFileManager::METADATA_PAGE_ID;
+        let read = self.manager.read_page_as::<MetadataPage>(metadata_page_id);
+        let Some((_, metadata)) = self.found(metadata_page_id, read)? else {
+            return Ok(());
+        };
+        self.total_pages = metadata.total_pages;
+        for page_id in 0..self.total_pages {
+            let read = self.manager.with_page(page_id, PageHeader::deserialize);
+            let header = self.found(page_id, read)?;
+            self.headers.push(header);
+        }
+        self.use_page(metadata_page_id, PageUse::Metadata, PageType::Metadata);
+
+        self.check_free_list(metadata.first_free_list_page)?;
+        self.check_btree(metadata.root_page_id)?;
+        for page_id in self.free_space_map_starts() {
+            self.check_free_space_map(page_id)?;
+        }
+        for page_id in 0..self.total_pages {
+            if self.uses.contains_key(&page_id) {
+                continue;
+            }
+            match self.page_type(page_id) {
+                Some(PageType::Data) => self.check_data_page(page_id)?,
+                Some(PageType::HashDirectory) => self.check_hash_index(page_id)?,
+                _ => {}
+            }
+        }
+
+        for page_id in 0..self.total_pages {
+            if self.uses.contains_key(&page_id) {
+                continue;
+            }
+            // Pages whose header can't be read were already reported and nothing could claim them
+            if let Some(page_type) = self.page_type(page_id) {
+                self.problem(
+                    page_id,
+                    format!(
+                        "isn't used by anything and isn't on the free list (its header says it's \
+                         a {page_type:?} page)"
+                    ),
+                );
+            }
+        }
+        Ok(())
+    }
+
+    fn problem(&mut self, page_id: u64, reason: impl Into<String>) {
+        self.problems.push(IntegrityProblem {
+            page_id,
+            reason: reason.into(),
+        });
+    }
+
+    /// Turns errors caused by what's in the page into problems. Errors that say nothing about
+    /// the page, like failing to read the file, are returned
+    fn found<T>(&mut self, page_id: u64, result: Result<T>) -> Result<Option<T>> {
+        match result {
+            Ok(value) => Ok(Some(value)),
+            Err(err @ (DbError::Io(_) | DbError::LockPoisoned | DbError::BufferPoolFull)) => {
+                Err(err)
+            }
+            Err(DbError::Corruption { reason, .. }) => {
+                self.problem(page_id, reason);
+                Ok(None)
+            }
+            Err(err) => {
+                self.problem(page_id, err.to_string());
+                Ok(None)
+            }
+        }
+    }
+
+    fn page_type(&self, page_id: u64) -> Option<PageType> {
+        self.headers
+            .get(page_id as usize)
+            .and_then(|header| header.as_ref())
+            .map(|header| header.page_type)
+    }
+
+    /// Records what the page is used for. False if it can't be used for that: it's past the end
+    /// of the file or something else already uses it
+    fn claim(&mut self, page_id: u64, page_use: PageUse) -> bool {
+        if page_id >= self.total_pages {
+            self.problem(
+                page_id,
+                format!(
+                    "is used as a {page_use:?} page but the file has {} pages",
+                    self.total_pages
+                ),
+            );
+            return false;
+        }
+        if let Some(&used_as) = self.uses.get(&page_id) {
+            self.problem(
+                page_id,
+                format!("is used as both a {used_as:?} and a {page_use:?} page"),
+            );
+            return false;
+        }
+        self.uses.insert(page_id, page_use);
+        true
+    }
+
+    /// Claims the page and checks its header. True if its contents can be read as `page_type`
+    fn use_page(&mut self, page_id: u64, page_use: PageUse, page_type: PageType) -> bool {
+        if !self.claim(page_id, page_use) {
+            return false;
+        }
+        let Some(header) = self.headers[page_id as usize].clone() else {
+            return false;
+        };
+        if header.page_id != page_id {
+            self.problem(page_id, format!("header says it's page {}", header.page_id));
+        }
+        let free_space_pointer = header.free_space_pointer as usize;
+        if free_space_pointer < PageHeader::SIZE
+            || free_space_pointer > self.manager.page_size as usize
+        {
+            self.problem(
+                page_id,
+                format!("free space pointer {free_space_pointer} is outside the page"),
+            );
+        }
+        if header.page_type != page_type {
+            self.problem(
+                page_id,
+                format!(
+                    "expected a {page_type:?} page for a {page_use:?} but found {:?}",
+                    header.page_type
+                ),
+            );
+            return false;
+        }
+        true
+    }
+
+    fn read_body<T: MyDeserialize>(&mut self, page_id: u64) -> Result<Option<T>> {
+        let read = self.manager.read_page_as::<T>(page_id);
+        Ok(self.found(page_id, read)?.map(|(_, page)| page))
+    }
+
+    fn check_free_list(&mut self, first_free_list_page: u64) -> Result<()> {
+        let mut page_id = first_free_list_page;
+        while page_id != 0 {
+            // Claiming catches a chain that loops back on itself
+            if !self.use_page(page_id, PageUse::FreeList, PageType::FreeList) {
+                break;
+            }
+            let Some(free_list) = self.read_body::<FreeListPage>(page_id)? else {
+                break;
+            };
+            for free_page_id in free_list.free_page_ids {
+                self.claim(free_page_id, PageUse::Free);
+            }
+            page_id = free_list.next_free_list;
+        }
+        Ok(())
+    }
+
+    fn check_btree(&mut self, root_page_id: u64) -> Result<()> {
+        if root_page_id == 0 {
+            return Ok(());
+        }
+        let mut leaves = Vec::new();
+        let mut leaf_depth = None;
+        self.check_node(root_page_id, (None, None), 0, &mut leaf_depth, &mut leaves)?;
+
+        for (index, leaf) in leaves.iter().enumerate() {
+            let prev_leaf = index.checked_sub(1).map_or(0, |prev| leaves[prev].page_id);
+            let next_leaf = leaves.get(index + 1).map_or(0, |next| next.page_id);
+            if leaf.prev_leaf != prev_leaf {
+                self.problem(
+                    leaf.page_id,
+                    format!(
+                        "prev_leaf is {} but the leaf before it is {prev_leaf}",
+                        leaf.prev_leaf
+                    ),
+                );
+            }
+            if leaf.next_leaf != next_leaf {
+                self.problem(
+                    leaf.page_id,
+                    format!(
+                        "next_leaf is {} but the leaf after it is {next_leaf}",
+                        leaf.next_leaf
+                    ),
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks the node and everything under it. Its keys have to be within the separators its
+    /// parent has either side of it: at least the lower one and below the upper one
+    fn check_node(
+        &mut self,
+        page_id: u64,
+        (lower, upper): (Option<&[u8]>, Option<&[u8]>),
+        depth: usize,
+        leaf_depth: &mut Option<usize>,
+        leaves: &mut Vec<LeafLinks>,
+    ) -> Result<()> {
+        if !self.use_page(page_id, PageUse::BTreeNode, PageType::Index) {
+            return Ok(());
+        }
+        let Some(node) = self.read_body::<IndexPage>(page_id)? else {
+            return Ok(());
+        };
+
+        if let Some(index) = node.keys.windows(2).position(|pair| pair[0] >= pair[1]) {
+            self.problem(
+                page_id,
+                format!("keys {index} and {} are out of order", index + 1),
+            );
+        }
+        if let (Some(lower), Some(first)) = (lower, node.keys.first()) {
+            if first.as_slice() < lower {
+                self.problem(page_id, "has keys below its parent's separator");
+            }
+        }
+        if let (Some(upper), Some(last)) = (upper, node.keys.last()) {
+            if last.as_slice() >= upper {
+                self.problem(page_id, "has keys at or above its parent's next separator");
+            }
+        }
+
+        if node.is_leaf {
+            if node.child_pointers.len() != node.keys.len() {
+                self.problem(
+                    page_id,
+                    format!(
+                        "leaf has {} keys but {} values",
+                        node.keys.len(),
+                        node.child_pointers.len()
+                    ),
+                );
+            }
+            match *leaf_depth {
+                Some(expected) if expected != depth => self.problem(
+                    page_id,
+                    format!("leaf is {depth} levels down but other leaves are {expected}"),
+                ),
+                _ => *leaf_depth = Some(depth),
+            }
+            leaves.push(LeafLinks {
+                page_id,
+                prev_leaf: node.prev_leaf,
+                next_leaf: node.next_leaf,
+            });
+            return Ok(());
+        }
+
+        if node.child_pointers.len() != node.keys.len() + 1 {
+            self.problem(
+                page_id,
+                format!(
+                    "internal node has {} keys but {} children",
+                    node.keys.len(),
+                    node.child_pointers.len()
+                ),
+            );
+            return Ok(());
+        }
+        for (index, &child) in node.child_pointers.iter().enumerate() {
+            let child_lower = index
+                .checked_sub(1)
+                .map_or(lower, |prev| Some(&node.keys[prev][..]));
+            let child_upper = node.keys.get(index).map_or(upper, |key| Some(&key[..]));
+            self.check_node(
+                child,
+                (child_lower, child_upper),
+                depth + 1,
+                leaf_depth,
+                leaves,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Map pages that no other map page links to. A map whose pages all link to each other has no
+    /// start and is left to be reported as leaked
+    fn free_space_map_starts(&self) -> Vec<u64> {
+        let map_page_ids: Vec<_> = (0..self.total_pages)
+            .filter(|page_id| self.page_type(*page_id) == Some(PageType::FreeSpaceMap))
+            .collect();
+        // Pages that can't be read are reported once their map gets to them
+        let linked: HashSet<_> = map_page_ids
+            .iter()
+            .filter_map(|&page_id| self.manager.read_page_as::<FreeSpaceMapPage>(page_id).ok())
+            .map(|(_, map_page)| map_page.next_page_id)
+            .collect();
+        map_page_ids
+            .into_iter()
+            .filter(|page_id| !linked.contains(page_id))
+            .collect()
+    }
+
+    fn check_free_space_map(&mut self, first_page_id: u64) -> Result<()> {
+        let mut page_id = first_page_id;
+        let mut last_page_id = None;
+        while page_id != 0 {
+            if !self.use_page(page_id, PageUse::FreeSpaceMap, PageType::FreeSpaceMap) {
+                break;
+            }
+            let Some(map_page) = self.read_body::<FreeSpaceMapPage>(page_id)? else {
+                break;
+            };
+            for (data_page_id, _) in map_page.entries {
+                self.check_data_page(data_page_id)?;
+            }
+            let recorded_last = *last_page_id.get_or_insert(map_page.last_page_id);
+            if map_page.next_page_id == 0 && recorded_last != page_id {
+                self.problem(
+                    first_page_id,
+                    format!(
+                        "free space map says it ends at {recorded_last} but it ends at {page_id}"
+                    ),
+                );
+            }
+            page_id = map_page.next_page_id;
+        }
+        Ok(())
+    }
+
+    fn check_data_page(&mut self, page_id: u64) -> Result<()> {
+        if !self.use_page(page_id, PageUse::Data, PageType::Data) {
+            return Ok(());
+        }
+        let read = self
+            .manager
+            .with_window::<DataPage, _, _>(page_id, |window| Ok(window.check_slots()));
+        let Some((problems, stubs)) = self.found(page_id, read)? else {
+            return Ok(());
+        };
+        for reason in problems {
+            self.problem(page_id, reason);
+        }
+        for stub in stubs {
+            self.check_overflow_chain(page_id, stub)?;
+        }
+        Ok(())
+    }
+
+    fn check_overflow_chain(&mut self, data_page_id: u64, stub: OverflowStub) -> Result<()> {
+        let mut remaining = stub.total_len;
+        let mut page_id = stub.first_page_id;
+        while remaining > 0 {
+            if page_id == 0 {
+                self.problem(
+                    data_page_id,
+                    format!("overflow chain ends {remaining} bytes short of its record"),
+                );
+                return Ok(());
+            }
+            if !self.use_page(page_id, PageUse::Overflow, PageType::Overflow) {
+                return Ok(());
+            }
+            let Some(overflow_page) = self.read_body::<OverflowPage>(page_id)? else {
+                return Ok(());
+            };
+            remaining = remaining.saturating_sub(overflow_page.data.len() as u64);
+            page_id = overflow_page.next_page_id;
+        }
+        if page_id != 0 {
+            self.problem(
+                data_page_id,
+                format!(
+                    "overflow chain goes on past the {} bytes of its record",
+                    stub.total_len
+                ),
+            );
+        }
+        Ok(())
+    }
+
+    fn check_hash_index(&mut self, directory_page_id: u64) -> Result<()> {
+        if !self.use_page(
+            directory_page_id,
+            PageUse::HashDirectory,
+            PageType::HashDirectory,
+        ) {
+            return Ok(());
+        }
+        let Some(directory) = self.read_body::<HashDirectoryPage>(directory_page_id)? else {
+            return Ok(());
+        };
+        let max_global_depth = HashDirectoryPage::max_global_depth(self.manager.page_size);
+        if directory.global_depth > max_global_depth
+            || directory.bucket_page_ids.len() as u64 != 1 << directory.global_depth
+        {
+            self.problem(
+                directory_page_id,
+                format!(
+                    "global depth {} doesn't fit a directory of {} slots",
+                    directory.global_depth,
+                    directory.bucket_page_ids.len()
+                ),
+            );
+            return Ok(());
+        }
+
+        let mut slots_by_bucket: Vec<(u64, Vec<u32>)> = Vec::new();
+        for (slot, &bucket_page_id) in directory.bucket_page_ids.iter().enumerate() {
+            match slots_by_bucket
+                .iter_mut()
+                .find(|(id, _)| *id == bucket_page_id)
+            {
+                Some((_, slots)) => slots.push(slot as u32),
+                None => slots_by_bucket.push((bucket_page_id, vec![slot as u32])),
+            }
+        }
+        for (bucket_page_id, slots) in slots_by_bucket {
+            self.check_bucket(bucket_page_id, directory.global_depth, &slots)?;
+        }
+        Ok(())
+    }
+
+    /// Checks a bucket and its overflow pages. The directory slots pointing at it have to be
+    /// every slot ending in the same local depth bits, and every key has to hash to those bits
+    fn check_bucket(&mut self, page_id: u64, global_depth: u32, slots: &[u32]) -> Result<()> {
+        let mut keys = HashSet::new();
+        let mut local_depth = None;
+        let mut next_page_id = page_id;
+        while next_page_id != 0 {
+            let bucket_page_id = next_page_id;
+            if !self.use_page(bucket_page_id, PageUse::HashBucket, PageType::HashBucket) {
+                return Ok(());
+            }
+            let Some(bucket) = self.read_body::<HashBucketPage>(bucket_page_id)? else {
+                return Ok(());
+            };
+            next_page_id = bucket.next_page_id;
+
+            let depth = *local_depth.get_or_insert(bucket.local_depth);
+            if bucket.local_depth != depth {
+                self.problem(
+                    bucket_page_id,
+                    format!(
+                        "local depth is {} but the bucket's first page says {depth}",
+                        bucket.local_depth
+                    ),
+                );
+            }
+            if depth > global_depth {
+                self.problem(
+                    bucket_page_id,
+                    format!("local depth {depth} is deeper than the directory's {global_depth}"),
+                );
+                return Ok(());
+            }
+            let mask = (1u64 << depth) - 1;
+            let low_bits = slots[0] as u64 & mask;
+            if bucket_page_id == page_id
+                && (slots.len() as u64 != 1 << (global_depth - depth)
+                    || slots.iter().any(|&slot| slot as u64 & mask != low_bits))
+            {
+                self.problem(
+                    bucket_page_id,
+                    format!(
+                        "directory slots {slots:?} can't share a bucket of local depth {depth}"
+                    ),
+                );
+            }
+            for (key, _) in bucket.entries {
+                if HashIndex::hash(&key) as u64 & mask != low_bits {
+                    self.problem(
+                        bucket_page_id,
+                        format!("key {key:?} is in the wrong bucket"),
+                    );
+                }
+                if !keys.insert(key) {
+                    self.problem(bucket_page_id, "bucket holds the same key more than once");
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{memory_manager, remove_db, temp_db_path};
+    use crate::{BTree, HeapFile, PagedFileManagerConfigBuilder};
+    use std::fs::OpenOptions;
+    use std::io::{Seek, SeekFrom, Write};
+
+    const PAGE_SIZE: u32 = 512;
+
+    /// A bit of everything: a B+Tree that's had pages freed, data pages holding inline and
+    /// overflowing records, a heap file and a hash index. Returns the id of the data page that
+    /// isn't part of the heap
+    fn fill(manager: &PagedFileManager) -> u64 {
+        let tree = BTree::open(manager).unwrap();
+        for i in 0..400u64 {
+            tree.insert(format!("key{i:04}").as_bytes(), i).unwrap();
+        }
+        for i in 100..300u64 {
+            tree.delete(format!("key{i:04}").as_bytes()).unwrap();
+        }
+
+        let data_page_id = manager.create_data_page().unwrap();
+        manager.insert_record(data_page_id, b"small").unwrap();
+        let deleted = manager
+            .insert_record(data_page_id, b"deleted")
+            .unwrap()
+            .unwrap();
+        manager
+            .insert_record(data_page_id, &vec![7u8; 3000])
+            .unwrap();
+        manager.delete_record(data_page_id, deleted).unwrap();
+
+        let heap = HeapFile::create(manager).unwrap();
+        for i in 0..200usize {
+            let record_id = heap.insert(&vec![i as u8; i * 7 % 400]).unwrap();
+            if i % 4 == 0 {
+                heap.delete(record_id).unwrap();
+            }
+        }
+
+        let index = HashIndex::create(manager).unwrap();
+        for i in 0..300u64 {
+            index.insert(format!("hash{i}").as_bytes(), i).unwrap();
+        }
+        data_page_id
+    }
+
+    fn problem_pages(report: &IntegrityReport) -> Vec<u64> {
+        report
+            .problems
+            .iter()
+            .map(|problem| problem.page_id)
+            .collect()
+    }
+
+    #[test]
+    fn healthy_files_have_no_problems() {
+        let manager = memory_manager(PAGE_SIZE);
+        assert!(manager.check_integrity().unwrap().is_ok());
+
+        fill(&manager);
+        let report = manager.check_integrity().unwrap();
+        assert!(report.is_ok(), "{:?}", report.problems);
+        let (_, metadata) = manager.read_page_as::<MetadataPage>(0).unwrap();
+        assert_eq!(report.pages_checked, metadata.total_pages);
+    }
+
+    #[test]
+    fn every_problem_is_reported() {
+        let manager = memory_manager(PAGE_SIZE);
+        let data_page_id = fill(&manager);
+        let tree = BTree::open(&manager).unwrap();
+        let mut first_leaf_id = tree.root_page_id().unwrap();
+        let mut first_leaf = tree.read_node(first_leaf_id).unwrap();
+        while !first_leaf.is_leaf {
+            first_leaf_id = first_leaf.child_pointers[0];
+            first_leaf = tree.read_node(first_leaf_id).unwrap();
+        }
+        let second_leaf_id = first_leaf.next_leaf;
+
+        let mut unsorted = first_leaf.clone();
+        unsorted.keys.swap(0, 1);
+        manager.write_index_page(first_leaf_id, &unsorted).unwrap();
+        let mut unlinked = tree.read_node(second_leaf_id).unwrap();
+        unlinked.next_leaf = 0;
+        manager.write_index_page(second_leaf_id, &unlinked).unwrap();
+        // Point the first slot past the end of the page
+        manager
+            .with_page_mut(data_page_id, |bytes| {
+                let slot = PageHeader::SIZE + DataPage::SLOT_ARRAY_FIRST_VALUE_OFFSET;
+                bytes[slot..slot + 4].copy_from_slice(&(PAGE_SIZE - 2).to_be_bytes());
+                Ok(())
+            })
+            .unwrap();
+        let leaked_page_id = manager.allocate_page().unwrap();
+
+        let report = manager.check_integrity().unwrap();
+        let pages = problem_pages(&report);
+        for page_id in [first_leaf_id, second_leaf_id, data_page_id, leaked_page_id] {
+            assert!(
+                pages.contains(&page_id),
+                "page {page_id} in {:?}",
+                report.problems
+            );
+        }
+        assert!(report
+            .problems
+            .iter()
+            .any(|p| p.reason.contains("out of order")));
+        assert!(report
+            .problems
+            .iter()
+            .any(|p| p.reason.contains("next_leaf")));
+        assert!(report
+            .problems
+            .iter()
+            .any(|p| p.reason.contains("free list")));
+    }
+
+    #[test]
+    fn checksum_failures_are_reported() {
+        let path = temp_db_path("integrity_checksums");
+        let config = || {
+            PagedFileManagerConfigBuilder::new()
+                .page_size(PAGE_SIZE)
+                .build()
+        };
+        let data_page_id = {
+            let manager = PagedFileManager::new(&path, config()).unwrap();
+            let data_page_id = fill(&manager);
+            manager.flush_all().unwrap();
+            data_page_id
+        };
+
+        let mut file = OpenOptions::new().write(true).open(&path).unwrap();
+        let offset = data_page_id * PAGE_SIZE as u64 + PAGE_SIZE as u64 - 1;
+        file.seek(SeekFrom::Start(offset)).unwrap();
+        file.write_all(&[0xAB]).unwrap();
+        drop(file);
+
+        let manager = PagedFileManager::new(&path, config()).unwrap();
+        let report = manager.check_integrity().unwrap();
+        let reasons: Vec<&str> = report
+            .problems
+            .iter()
+            .filter(|p| p.page_id == data_page_id)
+            .map(|p| p.reason.as_str())
+            .collect();
+        // Reported once, not again as a page nothing uses
+        assert_eq!(reasons.len(), 1, "{:?}", report.problems);
+        assert!(reasons[0].contains("checksum"), "{:?}", report.problems);
+        drop(manager);
+        remove_db(&path);
+    }
+}
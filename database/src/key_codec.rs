@@ -0,0 +1,384 @@
+use crate::error::{DbError, Result};
+
+// Turns typed (and composite) keys into byte strings whose plain byte comparison, which is all
+// IndexPage and BTree know about, gives the same order as comparing the values themselves.
+//
+// Every column is a tag byte followed by the value:
+//
+// - Null: just the tag. Its tag is the smallest so nulls sort first
+// - Bool: 0 or 1
+// - Int: 8 bytes big-endian with the sign bit flipped, so negatives sort below positives
+// - UInt: 8 bytes big-endian
+// - Float: the 8 bits big-endian. Positives get the sign bit set and negatives get every bit
+//   flipped, which is the same order as f64::total_cmp (-NaN < -inf < ... < -0 < 0 < ... < NaN)
+// - Bytes and Str: the bytes with every 0x00 escaped as 0x00 0xff, then a 0x00 0x01 terminator.
+//   The terminator is smaller than any byte that can follow a 0x00 inside the value so a value
+//   sorts before everything it's a prefix of, and the column after it can't leak into the order
+//
+// Descending columns are the same bytes with every bit flipped, which exactly reverses their
+// order. Since every column knows where it ends, tuples compare column by column.
+//
+// A column should always hold the same type (or null). Values of different types still compare
+// consistently but by their tag, not by value
+
+/// A single column of a key
+#[derive(Debug, Clone, PartialEq)]
+pub enum KeyValue {
+    Null,
+    Bool(bool),
+    Int(i64),
+    UInt(u64),
+    Float(f64),
+    Bytes(Vec<u8>),
+    Str(String),
+}
+
+/// Which way a column of a key sorts
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SortOrder {
+    #[default]
+    Ascending,
+    Descending,
+}
+
+impl KeyValue {
+    const NULL_TAG: u8 = 0x00;
+    const BOOL_TAG: u8 = 0x01;
+    const INT_TAG: u8 = 0x02;
+    const UINT_TAG: u8 = 0x03;
+    const FLOAT_TAG: u8 = 0x04;
+    const BYTES_TAG: u8 = 0x05;
+    const STR_TAG: u8 = 0x06;
+
+    const ESCAPE: u8 = 0x00;
+    const ESCAPED_ZERO: u8 = 0xff;
+    const TERMINATOR: u8 = 0x01;
+
+    fn encode_into(&self, out: &mut Vec<u8>) {
+        match self {
+            KeyValue::Null => out.push(Self::NULL_TAG),
+            KeyValue::Bool(value) => out.extend([Self::BOOL_TAG, *value as u8]),
+            KeyValue::Int(value) => {
+                out.push(Self::INT_TAG);
+                out.extend(((*value as u64) ^ (1 << 63)).to_be_bytes());
+            }
+            KeyValue::UInt(value) => {
+                out.push(Self::UINT_TAG);
+                out.extend(value.to_be_bytes());
+            }
+            KeyValue::Float(value) => {
+                let bits = value.to_bits();
+                let ordered = if bits >> 63 == 1 {
+                    !bits
+                } else {
+                    bits | 1 << 63
+                };
+                out.push(Self::FLOAT_TAG);
+                out.extend(ordered.to_be_bytes());
+            }
+            KeyValue::Bytes(value) => {
+                out.push(Self::BYTES_TAG);
+                Self::encode_escaped(value, out);
+            }
+            KeyValue::Str(value) => {
+                out.push(Self::STR_TAG);
+                Self::encode_escaped(value.as_bytes(), out);
+            }
+        }
+    }
+
+    fn encode_escaped(bytes: &[u8], out: &mut Vec<u8>) {
+        for &byte in bytes {
+            out.push(byte);
+            if byte == Self::ESCAPE {
+                out.push(Self::ESCAPED_ZERO);
+            }
+        }
+        out.extend([Self::ESCAPE, Self::TERMINATOR]);
+    }
+}
+
+impl From<bool> for KeyValue {
+    fn from(value: bool) -> Self {
+        KeyValue::Bool(value)
+    }
+}
+
+impl From<i64> for KeyValue {
+    fn from(value: i64) -> Self {
+        KeyValue::Int(value)
+    }
+}
+
+impl From<u64> for KeyValue {
+    fn from(value: u64) -> Self {
+        KeyValue::UInt(value)
+    }
+}
+
+impl From<f64> for KeyValue {
+    fn from(value: f64) -> Self {
+        KeyValue::Float(value)
+    }
+}
+
+impl From<&[u8]> for KeyValue {
+    fn from(value: &[u8]) -> Self {
+        KeyValue::Bytes(value.to_vec())
+    }
+}
+
+impl From<&str> for KeyValue {
+    fn from(value: &str) -> Self {
+        KeyValue::Str(value.to_string())
+    }
+}
+
+/// Encodes the columns in order into a single key. Every column sorts ascending
+pub fn encode_key(values: &[KeyValue]) -> Vec<u8> {
+    let mut out = Vec::new();
+    for value in values {
+        value.encode_into(&mut out);
+    }
+    out
+}
+
+/// Same as `encode_key` but each column picks the way it sorts
+pub fn encode_key_with_order(columns: &[(KeyValue, SortOrder)]) -> Vec<u8> {
+    let mut out = Vec::new();
+    for (value, order) in columns {
+        let start = out.len();
+        value.encode_into(&mut out);
+        if *order == SortOrder::Descending {
+            out[start..].iter_mut().for_each(|byte| *byte = !*byte);
+        }
+    }
+    out
+}
+
+/// What the encoding of every `Bytes` column whose value starts with `prefix` starts with. That's
+/// just the column's encoding without its terminator, since escaping never leaves a lone 0x00
+pub(crate) fn bytes_column_prefix(prefix: &[u8]) -> Vec<u8> {
+    let mut out = encode_key(&[KeyValue::Bytes(prefix.to_vec())]);
+    out.truncate(out.len() - 2);
+    out
+}
+
+/// Decodes a key made by `encode_key_with_order`. `orders` has to be what it was encoded with, one
+/// per column. Keys from `encode_key` are every column `Ascending`
+pub fn decode_key(bytes: &[u8], orders: &[SortOrder]) -> Result<Vec<KeyValue>> {
+    let mut reader = KeyReader { bytes, position: 0 };
+    let values = orders
+        .iter()
+        .map(|&order| reader.read_value(order))
+        .collect::<Result<Vec<_>>>()?;
+    if reader.position != bytes.len() {
+        return Err(DbError::InvalidKey(format!(
+            "{} bytes left over after {} columns",
+            bytes.len() - reader.position,
+            orders.len()
+        )));
+    }
+    Ok(values)
+}
+
+struct KeyReader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl KeyReader<'_> {
+    fn read_byte(&mut self, order: SortOrder) -> Result<u8> {
+        let byte = *self
+            .bytes
+            .get(self.position)
+            .ok_or_else(|| DbError::InvalidKey("key ends in the middle of a column".to_string()))?;
+        self.position += 1;
+        Ok(match order {
+            SortOrder::Ascending => byte,
+            SortOrder::Descending => !byte,
+        })
+    }
+
+    fn read_u64(&mut self, order: SortOrder) -> Result<u64> {
+        let mut bytes = [0u8; 8];
+        for byte in bytes.iter_mut() {
+            *byte = self.read_byte(order)?;
+        }
+        Ok(u64::from_be_bytes(bytes))
+    }
+
+    fn read_escaped(&mut self, order: SortOrder) -> Result<Vec<u8>> {
+        let mut out = Vec::new();
+        loop {
+            let byte = self.read_byte(order)?;
+            if byte != KeyValue::ESCAPE {
+                out.push(byte);
+                continue;
+            }
+            match self.read_byte(order)? {
+                KeyValue::ESCAPED_ZERO => out.push(KeyValue::ESCAPE),
+                KeyValue::TERMINATOR => return Ok(out),
+                other => {
+                    return Err(DbError::InvalidKey(format!(
+                        "unexpected byte {other:#04x} after a 0x00"
+                    )))
+                }
+            }
+        }
+    }
+
+    fn read_value(&mut self, order: SortOrder) -> Result<KeyValue> {
+        Ok(match self.read_byte(order)? {
+            KeyValue::NULL_TAG => KeyValue::Null,
+            KeyValue::BOOL_TAG => KeyValue::Bool(self.read_byte(order)? != 0),
+            KeyValue::INT_TAG => KeyValue::Int((self.read_u64(order)? ^ (1 << 63)) as i64),
+            KeyValue::UINT_TAG => KeyValue::UInt(self.read_u64(order)?),
+            KeyValue::FLOAT_TAG => {
+                let ordered = self.read_u64(order)?;
+                let bits = if ordered >> 63 == 1 {
+                    ordered & !(1 << 63)
+                } else {
+                    !ordered
+                };
+                KeyValue::Float(f64::from_bits(bits))
+            }
+            KeyValue::BYTES_TAG => KeyValue::Bytes(self.read_escaped(order)?),
+            KeyValue::STR_TAG => KeyValue::Str(
+                String::from_utf8(self.read_escaped(order)?)
+                    .map_err(|_| DbError::InvalidKey("string column isn't utf-8".to_string()))?,
+            ),
+            tag => {
+                return Err(DbError::InvalidKey(format!(
+                    "unknown column tag {tag:#04x}"
+                )))
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+    use std::cmp::Ordering;
+
+    /// How the values themselves compare, which the encoded bytes have to agree with
+    fn logical_cmp(a: &KeyValue, b: &KeyValue) -> Ordering {
+        match (a, b) {
+            (KeyValue::Null, KeyValue::Null) => Ordering::Equal,
+            (KeyValue::Null, _) => Ordering::Less,
+            (_, KeyValue::Null) => Ordering::Greater,
+            (KeyValue::Bool(a), KeyValue::Bool(b)) => a.cmp(b),
+            (KeyValue::Int(a), KeyValue::Int(b)) => a.cmp(b),
+            (KeyValue::UInt(a), KeyValue::UInt(b)) => a.cmp(b),
+            (KeyValue::Float(a), KeyValue::Float(b)) => a.total_cmp(b),
+            (KeyValue::Bytes(a), KeyValue::Bytes(b)) => a.cmp(b),
+            (KeyValue::Str(a), KeyValue::Str(b)) => a.cmp(b),
+            _ => unreachable!("columns only ever hold one type"),
+        }
+    }
+
+    // One strategy per column type so both sides of a comparison are always the same type
+    fn column_strategy(column: usize) -> BoxedStrategy<KeyValue> {
+        let value = match column % 5 {
+            0 => any::<i64>().prop_map(KeyValue::Int).boxed(),
+            1 => any::<f64>().prop_map(KeyValue::Float).boxed(),
+            2 => "[a\\x00b]{0,6}".prop_map(KeyValue::Str).boxed(),
+            3 => prop::collection::vec(prop_oneof![Just(0u8), Just(1), Just(0xff)], 0..6)
+                .prop_map(KeyValue::Bytes)
+                .boxed(),
+            _ => prop_oneof![
+                any::<bool>().prop_map(KeyValue::Bool),
+                any::<u64>().prop_map(KeyValue::UInt)
+            ]
+            .boxed(),
+        };
+        prop_oneof![1 => Just(KeyValue::Null), 8 => value].boxed()
+    }
+
+    fn orders_strategy() -> impl Strategy<Value = Vec<SortOrder>> {
+        prop::collection::vec(
+            prop_oneof![Just(SortOrder::Ascending), Just(SortOrder::Descending)],
+            1..4,
+        )
+    }
+
+    fn key_pair_strategy() -> impl Strategy<Value = (Vec<SortOrder>, Vec<KeyValue>, Vec<KeyValue>)>
+    {
+        orders_strategy().prop_flat_map(|orders| {
+            let columns = |orders: &Vec<SortOrder>| {
+                (0..orders.len()).map(column_strategy).collect::<Vec<_>>()
+            };
+            (Just(orders.clone()), columns(&orders), columns(&orders))
+        })
+    }
+
+    fn with_orders(values: &[KeyValue], orders: &[SortOrder]) -> Vec<(KeyValue, SortOrder)> {
+        values.iter().cloned().zip(orders.iter().copied()).collect()
+    }
+
+    proptest! {
+        #[test]
+        fn byte_order_matches_value_order((orders, a, b) in key_pair_strategy()) {
+            let logical = a
+                .iter()
+                .zip(&b)
+                .zip(&orders)
+                .map(|((a, b), order)| match order {
+                    SortOrder::Ascending => logical_cmp(a, b),
+                    SortOrder::Descending => logical_cmp(b, a),
+                })
+                .find(|ordering| ordering.is_ne())
+                .unwrap_or(Ordering::Equal);
+
+            let encoded_a = encode_key_with_order(&with_orders(&a, &orders));
+            let encoded_b = encode_key_with_order(&with_orders(&b, &orders));
+            prop_assert_eq!(encoded_a.cmp(&encoded_b), logical);
+        }
+
+        #[test]
+        fn keys_decode_to_what_was_encoded((orders, values, _) in key_pair_strategy()) {
+            let encoded = encode_key_with_order(&with_orders(&values, &orders));
+            let decoded = decode_key(&encoded, &orders).unwrap();
+            // Compared through Debug so NaNs count as equal to themselves
+            prop_assert_eq!(format!("{decoded:?}"), format!("{values:?}"));
+        }
+    }
+
+    #[test]
+    fn strings_sort_before_their_extensions() {
+        let key = |value: &str| encode_key(&[value.into(), KeyValue::Int(0)]);
+        assert!(key("ab") < key("ab\0"));
+        assert!(key("ab\0") < key("ab\x01"));
+        assert!(key("ab") < key("abc"));
+        assert!(encode_key(&[(-1i64).into()]) < encode_key(&[0i64.into()]));
+        assert!(encode_key(&[(-0.5f64).into()]) < encode_key(&[0.25f64.into()]));
+    }
+
+    #[test]
+    fn malformed_keys_are_errors() {
+        let orders = [SortOrder::Ascending];
+        assert!(matches!(
+            decode_key(&[], &orders),
+            Err(DbError::InvalidKey(_))
+        ));
+        assert!(matches!(
+            decode_key(&[0x02, 1, 2], &orders),
+            Err(DbError::InvalidKey(_))
+        ));
+        assert!(matches!(
+            decode_key(&[0x05, 0, 7], &orders),
+            Err(DbError::InvalidKey(_))
+        ));
+        assert!(matches!(
+            decode_key(&[0x42], &orders),
+            Err(DbError::InvalidKey(_))
+        ));
+        // Trailing bytes past the last column
+        assert!(matches!(
+            decode_key(&[0x00, 0x00], &orders),
+            Err(DbError::InvalidKey(_))
+        ));
+    }
+}
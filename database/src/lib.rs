@@ -1,46 +1,134 @@
 use std::collections::HashMap;
-use std::fs::{File, OpenOptions};
-use std::io::{Read, Result, Seek, SeekFrom, Write};
 use std::marker::PhantomData;
 use std::mem;
 use std::ops::Range;
-use std::path::Path;
-use std::sync::{Arc, Mutex};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, ThreadId};
 use struct_layout::StructLayout;
 
-/// General comment:
-/// I'm using GenAI heavily to assist in creating this. I may comment on certain decisions it makes
-/// I also may leave some be. Leaving this comment because I may not always make clear I'm
-/// explaining an AI's decision vs. a decision I made. If it is important I will attempt to make a
-/// distinction
-///
-/// TODO Callouts:
-/// * See the todo on the page cache. I think its totally busted right now and won't actually
-/// update pages
-/// * Replace all attempts to lock().unwrap() with something else cause that just seems like a
-/// catastrophe waiting to happen
-/// * Actually start doing checksumming. Right now I don't think any is happening
-
-const DB_VERSION: u32 = 1;
-
-struct PageWindow<'a, T> {
+use buffer_pool::{BufferPool, FrameId, LatchMode, PageLatch};
+use error::check_buffer_size;
+pub use error::{DbError, Result};
+use page_store::{PageStore, SharedPageStore};
+use wal::{LogRecord, TxnId, Wal};
+
+pub mod btree;
+mod btree_cursor;
+pub mod buffer_pool;
+pub mod error;
+pub mod free_space_map;
+pub mod hash_index;
+pub mod heap_file;
+pub mod integrity;
+pub mod key_codec;
+pub mod overflow;
+pub mod page_store;
+mod slotted_page;
+pub mod tuple;
+pub mod wal;
+
+pub use btree::{BTree, Uniqueness};
+pub use btree_cursor::{BTreeCursor, Backwards};
+pub use hash_index::HashIndex;
+pub use heap_file::{HeapFile, HeapScan, RecordId};
+pub use integrity::{IntegrityProblem, IntegrityReport};
+pub use key_codec::{KeyValue, SortOrder};
+pub use page_store::StorageBackend;
+pub use slotted_page::{Record, SlotId};
+pub use tuple::{Column, ColumnType, Schema, TupleRef};
+
+// General comment:
+// I'm using GenAI heavily to assist in creating this. I may comment on certain decisions it makes
+// I also may leave some be. Leaving this comment because I may not always make clear I'm
+// explaining an AI's decision vs. a decision I made. If it is important I will attempt to make a
+// distinction
+
+// Bumped whenever the on-disk format changes
+// 2: index pages store keys in a slotted key heap
+// 3: index pages store the prefix shared by their keys once
+// 4: the metadata page records whether the tree is unique
+// 5: leaves link back to the leaf before them
+// 6: hash index directory and bucket pages
+// 7: heap files list their data pages in free space map pages, and data pages can forward a
+//    record to another one
+const DB_VERSION: u32 = 7;
+
+/// Typed view over the raw bytes of a page, split into the header and the page's body. Lets page
+/// fields be read and updated in place without deserializing the whole page
+pub struct PageWindow<'a, T> {
     header_bytes: &'a mut [u8],
     page_bytes: &'a mut [u8],
     _phantom: PhantomData<T>,
 }
 
-// TODO: Have this be where a checksum is done on reads
+// Checksums are verified by the buffer pool when a page is read from disk so by the time bytes
+// make it into a window they've already been checked
 impl<'a, T> PageWindow<'a, T> {
-    fn new(bytes: &'a mut Vec<u8>) -> Self {
-        if bytes.len() < PageHeader::SIZE {
-            panic!("invalid sequence of bytes")
-        }
+    pub fn new(bytes: &'a mut [u8]) -> Result<Self> {
+        check_buffer_size(bytes, PageHeader::SIZE)?;
         let (header_bytes, page_bytes) = bytes.split_at_mut(PageHeader::SIZE);
-        Self {
+        Ok(Self {
             header_bytes,
             page_bytes,
             _phantom: PhantomData,
-        }
+        })
+    }
+
+    fn read_free_space_pointer(&self) -> u32 {
+        read_be_u32(&self.header_bytes[PageHeader::free_space_pointer_span()])
+    }
+
+    fn update_free_space_pointer(&mut self, new_free_space_pointer: u32) {
+        self.header_bytes[PageHeader::free_space_pointer_span()]
+            .copy_from_slice(&new_free_space_pointer.to_be_bytes());
+    }
+
+    pub fn read_page_type(&self) -> Result<PageType> {
+        let mut page_type_bytes = [0u8; PageHeader::PAGE_TYPE_SIZE];
+        page_type_bytes.copy_from_slice(&self.header_bytes[PageHeader::page_type_span()]);
+        PageType::from_be_bytes(page_type_bytes)
+    }
+
+    pub fn read_page_id(&self) -> u64 {
+        read_be_u64(&self.header_bytes[PageHeader::page_id_span()])
+    }
+
+    /// Lsn of the last change to the page
+    pub fn read_lsn(&self) -> u64 {
+        read_be_u64(&self.header_bytes[PageHeader::lsn_span()])
+    }
+}
+
+/// A page pinned in the buffer pool by `PagedFileManager::pin`, unpinned when dropped
+pub(crate) struct PinnedPage<'a> {
+    manager: &'a PagedFileManager,
+    page_id: u64,
+    frame_id: FrameId,
+}
+
+impl PinnedPage<'_> {
+    pub(crate) fn page_id(&self) -> u64 {
+        self.page_id
+    }
+
+    /// Same as `PagedFileManager::with_window`, without pinning the page again
+    pub(crate) fn with_window<T, F, R>(&self, f: F) -> Result<R>
+    where
+        F: FnOnce(&PageWindow<T>) -> Result<R>,
+    {
+        // PageWindow needs mutable bytes even though f can only read through it
+        let mut frame = self.manager.buffer_pool.frame_mut(self.frame_id)?;
+        f(&PageWindow::new(&mut frame)?)
+    }
+}
+
+impl Drop for PinnedPage<'_> {
+    fn drop(&mut self) {
+        // Unpinning a pinned page only fails if the pool's lock is poisoned, and then nothing
+        // else is going to work either
+        let _ = self.manager.buffer_pool.unpin_page(self.page_id, false);
     }
 }
 
@@ -52,22 +140,27 @@ pub enum PageType {
     Index = 2,
     Overflow = 3,
     FreeList = 4,
+    HashDirectory = 5,
+    HashBucket = 6,
+    FreeSpaceMap = 7,
 }
 
 impl PageType {
-    fn to_be_bytes(&self) -> [u8; 1] {
-        (*self as u8).to_be_bytes()
+    fn to_be_bytes(self) -> [u8; 1] {
+        (self as u8).to_be_bytes()
     }
 
-    fn from_be_bytes(bytes: [u8; 1]) -> Self {
+    fn from_be_bytes(bytes: [u8; 1]) -> Result<Self> {
         match u8::from_be_bytes(bytes) {
-            0 => Self::Metadata,
-            1 => Self::Data,
-            2 => Self::Index,
-            3 => Self::Overflow,
-            4 => Self::FreeList,
-            // TODO: Is this bad practice?
-            _ => panic!("Can't convert from bytes"),
+            0 => Ok(Self::Metadata),
+            1 => Ok(Self::Data),
+            2 => Ok(Self::Index),
+            3 => Ok(Self::Overflow),
+            4 => Ok(Self::FreeList),
+            5 => Ok(Self::HashDirectory),
+            6 => Ok(Self::HashBucket),
+            7 => Ok(Self::FreeSpaceMap),
+            unknown => Err(DbError::UnknownPageType(unknown)),
         }
     }
 }
@@ -80,6 +173,9 @@ impl From<u16> for PageType {
             2 => PageType::Index,
             3 => PageType::Overflow,
             4 => PageType::FreeList,
+            5 => PageType::HashDirectory,
+            6 => PageType::HashBucket,
+            7 => PageType::FreeSpaceMap,
             _ => PageType::Data, // Default
         }
     }
@@ -90,29 +186,40 @@ pub trait MySerialize {
     /// Implemntations should ensure before attempting to write that the content they're going to
     /// write will fit into the provided buffer.
     ///
-    /// The return value will be the total number of bytes written to the buffer.
+    /// The return value will be the total number of bytes written to the buffer. If the buffer is
+    /// too small nothing is written and `DbError::BufferTooSmall` is returned instead.
     /// WARNING: It will not be memory aligned. Before writing any more bytes to the buffer passed
     /// the proper alignment should first be found
-    fn serialize(&self, buffer: &mut [u8]) -> usize;
+    fn serialize(&self, buffer: &mut [u8]) -> Result<usize>;
+}
+
+pub trait MyDeserialize: Sized {
+    /// Inverse of `MySerialize::serialize`. The buffer needs to start at the same offset the value
+    /// was serialized to, any padding inside the value is skipped using the same rules serialize
+    /// used to add it.
+    ///
+    /// Lengths read from the buffer are checked against its size so a garbage length turns into
+    /// `DbError::BufferTooSmall` rather than a panic.
+    fn deserialize(buffer: &[u8]) -> Result<Self>;
 }
 
 // Common header for all pages
 #[repr(C)]
-#[derive(StructLayout)]
+#[derive(StructLayout, Debug, Clone, PartialEq)]
 pub struct PageHeader {
     pub page_id: u64,
     pub page_type: PageType,
     pub checksum: u32,
-    pub lsn: u64,                // Log Sequence Number
-    pub free_space_pointer: u32, // Pointer to start of free space in the page
+    pub lsn: u64, // Log Sequence Number
+    // Pointer to the free space in the page. Most pages fill from the front so it's the start of
+    // the free space, data pages fill records from the back so for them it's the end
+    pub free_space_pointer: u32,
 }
 
 impl MySerialize for PageHeader {
-    fn serialize(&self, buffer: &mut [u8]) -> usize {
+    fn serialize(&self, buffer: &mut [u8]) -> Result<usize> {
         let size_to_write = Self::free_space_pointer_span().end;
-        if buffer.len() < size_to_write {
-            panic!("Buffer too small for page header");
-        }
+        check_buffer_size(buffer, size_to_write)?;
 
         // Write fields in big-endian order
         // TODO: Ordering is chosen because "it makes it easier to sort keys because it maintains
@@ -126,7 +233,7 @@ impl MySerialize for PageHeader {
         buffer[Self::free_space_pointer_span()]
             .copy_from_slice(&self.free_space_pointer.to_be_bytes());
 
-        size_to_write
+        Ok(size_to_write)
     }
 }
 
@@ -146,11 +253,35 @@ impl PageHeader {
         Self::free_space_pointer_span().end
     }
 
-    pub fn deserialize(buffer: Vec<u8>) -> Self {
+    /// CRC32C over every byte of the page except the checksum field itself. That way the checksum
+    /// can be computed and stamped in place without having to zero anything first
+    pub fn compute_checksum(page: &[u8]) -> u32 {
+        let checksum_span = Self::checksum_span();
+        let crc = crc32c::crc32c(&page[..checksum_span.start]);
+        crc32c::crc32c_append(crc, &page[checksum_span.end..])
+    }
+
+    /// Computes the page's checksum and writes it into the header's checksum field
+    pub fn stamp_checksum(page: &mut [u8]) {
+        let checksum = Self::compute_checksum(page);
+        page[Self::checksum_span()].copy_from_slice(&checksum.to_be_bytes());
+    }
+
+    /// Returns the checksum stored in the header and the one computed from the page's contents
+    pub fn read_checksums(page: &[u8]) -> (u32, u32) {
+        let mut checksum_buffer = [0u8; Self::CHECKSUM_SIZE];
+        checksum_buffer.copy_from_slice(&page[Self::checksum_span()]);
+        (
+            u32::from_be_bytes(checksum_buffer),
+            Self::compute_checksum(page),
+        )
+    }
+}
+
+impl MyDeserialize for PageHeader {
+    fn deserialize(buffer: &[u8]) -> Result<Self> {
         let size_to_read = Self::free_space_pointer_span().end;
-        if buffer.len() < size_to_read {
-            panic!("Buffer too small for page header");
-        }
+        check_buffer_size(buffer, size_to_read)?;
 
         let mut page_id_buffer = [0u8; Self::PAGE_ID_SIZE];
         page_id_buffer.copy_from_slice(&buffer[Self::page_id_span()]);
@@ -158,7 +289,7 @@ impl PageHeader {
 
         let mut page_type_buffer = [0u8; Self::PAGE_TYPE_SIZE];
         page_type_buffer.copy_from_slice(&buffer[Self::page_type_span()]);
-        let page_type = PageType::from_be_bytes(page_type_buffer);
+        let page_type = PageType::from_be_bytes(page_type_buffer)?;
 
         let mut checksum_buffer = [0u8; Self::CHECKSUM_SIZE];
         checksum_buffer.copy_from_slice(&buffer[Self::checksum_span()]);
@@ -172,19 +303,19 @@ impl PageHeader {
         free_space_pointer_buffer.copy_from_slice(&buffer[Self::free_space_pointer_span()]);
         let free_space_pointer = u32::from_be_bytes(free_space_pointer_buffer);
 
-        PageHeader {
+        Ok(PageHeader {
             page_id,
             page_type,
             checksum,
             lsn,
             free_space_pointer,
-        }
+        })
     }
 }
 
 // Metadata page structure
 #[repr(C)]
-#[derive(StructLayout)]
+#[derive(StructLayout, Debug, Clone, PartialEq)]
 pub struct MetadataPage {
     pub db_version: u32,
     pub page_size: u32,
@@ -194,14 +325,14 @@ pub struct MetadataPage {
     /// to start clearing page is the index of the first page
     pub first_free_list_page: u64,
     pub total_pages: u64,
+    /// Whether the tree at root_page_id allows a key to map to more than one value
+    pub tree_is_unique: bool,
 }
 
 impl MySerialize for MetadataPage {
-    fn serialize(&self, buffer: &mut [u8]) -> usize {
-        let size_to_write = Self::total_pages_span().end;
-        if buffer.len() < size_to_write {
-            panic!("Buffer too small for page header");
-        }
+    fn serialize(&self, buffer: &mut [u8]) -> Result<usize> {
+        let size_to_write = Self::tree_is_unique_span().end;
+        check_buffer_size(buffer, size_to_write)?;
 
         buffer[Self::db_version_span()].copy_from_slice(&self.db_version.to_be_bytes());
         buffer[Self::page_size_span()].copy_from_slice(&self.page_size.to_be_bytes());
@@ -209,8 +340,24 @@ impl MySerialize for MetadataPage {
         buffer[Self::first_free_list_page_span()]
             .copy_from_slice(&self.first_free_list_page.to_be_bytes());
         buffer[Self::total_pages_span()].copy_from_slice(&self.total_pages.to_be_bytes());
+        buffer[Self::tree_is_unique_span()][0] = self.tree_is_unique as u8;
+
+        Ok(Self::tree_is_unique_span().end)
+    }
+}
 
-        Self::total_pages_span().end
+impl MyDeserialize for MetadataPage {
+    fn deserialize(buffer: &[u8]) -> Result<Self> {
+        check_buffer_size(buffer, Self::tree_is_unique_span().end)?;
+
+        Ok(MetadataPage {
+            db_version: read_be_u32(&buffer[Self::db_version_span()]),
+            page_size: read_be_u32(&buffer[Self::page_size_span()]),
+            root_page_id: read_be_u64(&buffer[Self::root_page_id_span()]),
+            first_free_list_page: read_be_u64(&buffer[Self::first_free_list_page_span()]),
+            total_pages: read_be_u64(&buffer[Self::total_pages_span()]),
+            tree_is_unique: buffer[Self::tree_is_unique_span()][0] != 0,
+        })
     }
 }
 
@@ -222,6 +369,7 @@ impl MetadataPage {
             root_page_id: 0,
             first_free_list_page: 0,
             total_pages: 1, // Just this metadata page initially
+            tree_is_unique: true,
         }
     }
 }
@@ -237,26 +385,52 @@ impl<'a> PageWindow<'a, MetadataPage> {
         self.page_bytes[MetadataPage::total_pages_span()]
             .copy_from_slice(&new_total_pages.to_be_bytes());
     }
+
+    fn read_first_free_list_page(&self) -> u64 {
+        let mut u64_bytes = [0u8; size_of::<u64>()];
+        u64_bytes.copy_from_slice(&self.page_bytes[MetadataPage::first_free_list_page_span()]);
+        u64::from_be_bytes(u64_bytes)
+    }
+
+    fn update_first_free_list_page(&mut self, new_first_free_list_page: u64) {
+        self.page_bytes[MetadataPage::first_free_list_page_span()]
+            .copy_from_slice(&new_first_free_list_page.to_be_bytes());
+    }
+
+    fn read_root_page_id(&self) -> u64 {
+        read_be_u64(&self.page_bytes[MetadataPage::root_page_id_span()])
+    }
+
+    fn update_root_page_id(&mut self, new_root_page_id: u64) {
+        self.page_bytes[MetadataPage::root_page_id_span()]
+            .copy_from_slice(&new_root_page_id.to_be_bytes());
+    }
+
+    fn read_tree_is_unique(&self) -> bool {
+        self.page_bytes[MetadataPage::tree_is_unique_span()][0] != 0
+    }
+
+    fn update_tree_is_unique(&mut self, tree_is_unique: bool) {
+        self.page_bytes[MetadataPage::tree_is_unique_span()][0] = tree_is_unique as u8;
+    }
 }
 
 // Data page structure
 #[repr(C)]
-#[derive(StructLayout)]
+#[derive(StructLayout, Debug, Clone, PartialEq)]
 pub struct DataPage {
+    // Number of live records. Deleted slots stick around in the slot array as tombstones so this
+    // can be smaller than slot_array.len()
     pub num_records: u32,
-    // Offsets to records within the page
-    // TODO: Determine from where in
-    // the page this offset should start from. For now I'm assuming its
-    // the start of the page itself
+    // Offsets to records within the page. Offsets are from the start of the page itself (so
+    // including the header). See slotted_page.rs for how records are laid out
     pub slot_array: Vec<u32>,
 }
 
 impl MySerialize for DataPage {
-    fn serialize(&self, buffer: &mut [u8]) -> usize {
+    fn serialize(&self, buffer: &mut [u8]) -> Result<usize> {
         let size = self.size();
-        if buffer.len() < size {
-            panic!("Buffer too small for data page");
-        }
+        check_buffer_size(buffer, size)?;
         // Write num_records
         buffer[Self::num_records_span()].copy_from_slice(&self.num_records.to_be_bytes());
 
@@ -273,7 +447,31 @@ impl MySerialize for DataPage {
         }
 
         assert!(size == current_offset);
-        size
+        Ok(size)
+    }
+}
+
+impl MyDeserialize for DataPage {
+    fn deserialize(buffer: &[u8]) -> Result<Self> {
+        check_buffer_size(buffer, Self::MIN_SIZE)?;
+
+        let num_records = read_be_u32(&buffer[Self::num_records_span()]);
+        let slot_array_len = read_be_u32(&buffer[Self::slot_array_length_span()]) as usize;
+        check_buffer_size(
+            buffer,
+            Self::MIN_SIZE + Self::SLOT_ARRAY_VALUE_SIZE * slot_array_len,
+        )?;
+
+        let slot_array = buffer[Self::SLOT_ARRAY_FIRST_VALUE_OFFSET..]
+            .chunks_exact(Self::SLOT_ARRAY_VALUE_SIZE)
+            .take(slot_array_len)
+            .map(read_be_u32)
+            .collect();
+
+        Ok(DataPage {
+            num_records,
+            slot_array,
+        })
     }
 }
 
@@ -307,69 +505,68 @@ impl DataPage {
     }
 }
 
+impl Default for DataPage {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 // Index page structure
+//
+// | is_leaf | next_leaf | prev_leaf | keys_len | child_pointers_len | prefix slot | key slots |
+// | child pointers | key heap |
+//
+// The prefix every key on the page shares is only stored once, at the start of the key heap. Each
+// key then gets a slot holding the offset (from the start of the body) and length of the rest of
+// its bytes in the key heap, so keys of any length can share a page. Pages are always written whole
+// so the heap is just the prefix and the key suffixes packed back to back in order, there's never
+// any free space inside it.
+//
+// None of this is visible outside of (de)serializing, `keys` always holds the full keys
 #[repr(C)]
-#[derive(StructLayout)]
+#[derive(StructLayout, Debug, Clone, PartialEq)]
 pub struct IndexPage {
     pub is_leaf: bool,
-    // Only used if is_leaf is true
+    // Only used if is_leaf is true. Leaves form a doubly linked list in key order, 0 marks either end
     pub next_leaf: u64,
-    // Variable-length keys. The variable is across index pages. One index page will have keys that
-    // all are the same size otherwise panics will occur
-    //
-    // WARNING (or TODO): Right now the code assumes that the bytes represenging the keys will be
-    // in BigEndian here. Need to confirm that somehow
+    pub prev_leaf: u64,
+    // Keys compare as plain byte strings so whatever is stored here needs to be encoded in an
+    // order preserving way, see key_codec
     pub keys: Vec<Vec<u8>>,
     // Page IDs for children
     pub child_pointers: Vec<u64>,
 }
 
 impl MySerialize for IndexPage {
-    fn serialize(&self, buffer: &mut [u8]) -> usize {
-        // TODO: Determine if these calcuations are worth it. I think they are? but need to confirm
-        // later if I even really care about checking initial buffer size
+    fn serialize(&self, buffer: &mut [u8]) -> Result<usize> {
         let size = self.calc_size();
-        if buffer.len() < size {
-            panic!("Buffer too small for index page");
-        }
+        check_buffer_size(buffer, size)?;
 
         buffer[Self::is_leaf_span()].copy_from_slice(&if self.is_leaf { [1u8] } else { [0u8] });
         buffer[Self::next_leaf_span()].copy_from_slice(&self.next_leaf.to_be_bytes());
-
-        buffer[Self::KEYS_LEN_OFFSET..Self::KEYS_LEN_SIZE]
-            .copy_from_slice(&self.keys.len().to_be_bytes());
-        let key_size_opt = self.key_value_size();
-        let mut current_key_offset = Self::KEYS_FIRST_VALUE_OFFSET_WITHOUT_PADDING;
-        for key in self.keys.iter() {
-            match key_size_opt {
-                Some(key_size) if key_size == key.len() => {
-                    // Add the padding necessary
-                    current_key_offset += padding_needed_from_size(current_key_offset, key_size);
-                    // Write to the buffer
-                    buffer[current_key_offset..current_key_offset + key_size]
-                        .copy_from_slice(&key.as_slice());
-                    // Increment by amount written to buffer. We don't do the padding after because
-                    // we don't know if we're going to write another key or we're going to write
-                    // the len of the next vec
-                    current_key_offset += key_size;
-                }
-                Some(key_size) if key_size != key.len() => {
-                    panic!("varying key sizes")
-                }
-                _ => panic!("key found but no key size found"),
-            }
+        buffer[Self::prev_leaf_span()].copy_from_slice(&self.prev_leaf.to_be_bytes());
+        buffer[Self::keys_len_span()].copy_from_slice(&(self.keys.len() as u32).to_be_bytes());
+        buffer[Self::child_pointers_len_span()]
+            .copy_from_slice(&(self.child_pointers.len() as u32).to_be_bytes());
+
+        let prefix_len = self.prefix_len();
+        let prefix = self.keys.first().map_or(&[][..], |key| &key[..prefix_len]);
+        let mut key_heap_offset = self.key_heap_offset();
+        for (slot_start, bytes) in std::iter::once((Self::PREFIX_SLOT_OFFSET, prefix)).chain(
+            self.keys
+                .iter()
+                .enumerate()
+                .map(|(index, key)| (Self::key_slot_offset(index), &key[prefix_len..])),
+        ) {
+            buffer[slot_start..slot_start + Self::KEY_OFFSET_SIZE]
+                .copy_from_slice(&(key_heap_offset as u32).to_be_bytes());
+            buffer[slot_start + Self::KEY_OFFSET_SIZE..slot_start + Self::KEY_SLOT_SIZE]
+                .copy_from_slice(&(bytes.len() as u32).to_be_bytes());
+            buffer[key_heap_offset..key_heap_offset + bytes.len()].copy_from_slice(bytes);
+            key_heap_offset += bytes.len();
         }
-        // We add the padding from the last key needed before writing the len of the child pointers
-        // vec
-        current_key_offset += padding_needed_from_type::<u32>(current_key_offset);
-        // Write the child pointer vec
-        buffer[current_key_offset..current_key_offset + Self::CHILD_POINTERS_LEN_SIZE]
-            .copy_from_slice(&self.child_pointers.len().to_be_bytes());
-        let mut current_child_pointer_offset = current_key_offset + Self::CHILD_POINTERS_LEN_SIZE;
-        // TODO: This might not be needed, its unclear to me if this can be determined statically
-        // or not
-        current_child_pointer_offset +=
-            padding_needed_from_type::<u64>(current_child_pointer_offset);
+
+        let mut current_child_pointer_offset = Self::child_pointers_offset(self.keys.len());
         for child_pointer in self.child_pointers.iter() {
             buffer[current_child_pointer_offset
                 ..current_child_pointer_offset + Self::CHILD_POINTERS_VALUE_SIZE]
@@ -377,8 +574,48 @@ impl MySerialize for IndexPage {
             current_child_pointer_offset += Self::CHILD_POINTERS_VALUE_SIZE;
         }
 
-        assert!(size == current_child_pointer_offset);
-        size
+        assert!(size == key_heap_offset);
+        Ok(size)
+    }
+}
+
+impl MyDeserialize for IndexPage {
+    fn deserialize(buffer: &[u8]) -> Result<Self> {
+        check_buffer_size(buffer, Self::EXCLUDING_VEC_SIZE)?;
+
+        let is_leaf = buffer[Self::is_leaf_span()][0] != 0;
+        let next_leaf = read_be_u64(&buffer[Self::next_leaf_span()]);
+        let prev_leaf = read_be_u64(&buffer[Self::prev_leaf_span()]);
+        let keys_len = read_be_u32(&buffer[Self::keys_len_span()]) as usize;
+        let child_pointers_len = read_be_u32(&buffer[Self::child_pointers_len_span()]) as usize;
+
+        let child_pointers_offset = Self::child_pointers_offset(keys_len);
+        check_buffer_size(
+            buffer,
+            child_pointers_offset + Self::CHILD_POINTERS_VALUE_SIZE * child_pointers_len,
+        )?;
+
+        let prefix = Self::read_heap_bytes(buffer, Self::PREFIX_SLOT_OFFSET)?;
+        let keys = (0..keys_len)
+            .map(|index| {
+                let suffix = Self::read_heap_bytes(buffer, Self::key_slot_offset(index))?;
+                Ok([prefix, suffix].concat())
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let child_pointers = buffer[child_pointers_offset..]
+            .chunks_exact(Self::CHILD_POINTERS_VALUE_SIZE)
+            .take(child_pointers_len)
+            .map(read_be_u64)
+            .collect();
+
+        Ok(IndexPage {
+            is_leaf,
+            next_leaf,
+            prev_leaf,
+            keys,
+            child_pointers,
+        })
     }
 }
 
@@ -386,78 +623,199 @@ impl IndexPage {
     const KEYS_LEN_SIZE: usize = size_of::<u32>();
     const CHILD_POINTERS_LEN_SIZE: usize = size_of::<u32>();
     const CHILD_POINTERS_VALUE_SIZE: usize = size_of::<u64>();
+    const KEY_OFFSET_SIZE: usize = size_of::<u32>();
+    /// Bytes each key takes up on top of its own length: its offset and length in the key heap
+    const KEY_SLOT_SIZE: usize = Self::KEY_OFFSET_SIZE + size_of::<u32>();
 
-    const KEYS_LEN_OFFSET: usize =
-        // Next type is the key len TODO: I think that this padding does nothing
-        padding_needed_from_type::<u32>(Self::NEXT_LEAF_OFFSET + Self::NEXT_LEAF_SIZE)
-                + Self::NEXT_LEAF_OFFSET
-                + Self::NEXT_LEAF_SIZE;
-    // Don't know how much padding is needed because we don't know how big the keys are yet
-    const KEYS_FIRST_VALUE_OFFSET_WITHOUT_PADDING: usize =
-        Self::KEYS_LEN_OFFSET + Self::KEYS_LEN_SIZE;
+    const KEYS_LEN_OFFSET: usize = Self::PREV_LEAF_OFFSET + Self::PREV_LEAF_SIZE;
+    const CHILD_POINTERS_LEN_OFFSET: usize = Self::KEYS_LEN_OFFSET + Self::KEYS_LEN_SIZE;
+    const PREFIX_SLOT_OFFSET: usize =
+        Self::CHILD_POINTERS_LEN_OFFSET + Self::CHILD_POINTERS_LEN_SIZE;
+    const KEY_SLOTS_OFFSET: usize = Self::PREFIX_SLOT_OFFSET + Self::KEY_SLOT_SIZE;
 
-    const EXCLUDING_VEC_SIZE: usize = Self::KEYS_LEN_OFFSET;
+    const EXCLUDING_VEC_SIZE: usize = Self::KEY_SLOTS_OFFSET;
 
-    // Assumes that all elements of the key vec will be the same size. That properly has to be
-    // checked when calling this method. I.E this method does not confirm that fact.
-    //
-    // Some(value) -> The key vec has a key and its size along
-    // None -> The key vec has no keys
-    fn key_value_size(&self) -> Option<usize> {
-        self.keys.first().map(|first_key| first_key.len())
+    fn keys_len_span() -> Range<usize> {
+        Self::KEYS_LEN_OFFSET..Self::KEYS_LEN_OFFSET + Self::KEYS_LEN_SIZE
     }
 
-    /// Does NOT include any padding after the final value. This is because the contract of
-    /// serialize is to not include that padding cause it doesn't know what the next value is going
-    /// to be
-    pub fn calc_size(&self) -> usize {
-        let size_of_key_vec = match self.key_value_size() {
-            Some(key_size) => {
-                let total_size_of_keys = key_size * self.keys.len();
-                let padding_to_first_key =
-                    padding_needed_from_size(Self::EXCLUDING_VEC_SIZE, key_size);
-                let total_padding_between_keys =
-                    padding_needed_from_size(key_size, key_size) * (self.keys.len() - 1);
-
-                total_size_of_keys + padding_to_first_key + total_padding_between_keys
-            }
-            None => 0,
-        };
+    fn child_pointers_len_span() -> Range<usize> {
+        Self::CHILD_POINTERS_LEN_OFFSET
+            ..Self::CHILD_POINTERS_LEN_OFFSET + Self::CHILD_POINTERS_LEN_SIZE
+    }
+
+    fn key_slot_offset(index: usize) -> usize {
+        Self::KEY_SLOTS_OFFSET + index * Self::KEY_SLOT_SIZE
+    }
+
+    // Slots are 8 bytes and start 8 byte aligned so the child pointers never need padding
+    fn child_pointers_offset(keys_len: usize) -> usize {
+        Self::key_slot_offset(keys_len)
+    }
+
+    fn key_heap_offset(&self) -> usize {
+        Self::child_pointers_offset(self.keys.len())
+            + Self::CHILD_POINTERS_VALUE_SIZE * self.child_pointers.len()
+    }
+
+    /// Bytes in the key heap that the slot at `slot_start` points at
+    fn read_heap_bytes(buffer: &[u8], slot_start: usize) -> Result<&[u8]> {
+        let offset = read_be_u32(&buffer[slot_start..slot_start + Self::KEY_OFFSET_SIZE]) as usize;
+        let len = read_be_u32(
+            &buffer[slot_start + Self::KEY_OFFSET_SIZE..slot_start + Self::KEY_SLOT_SIZE],
+        ) as usize;
+        check_buffer_size(buffer, offset + len)?;
+        Ok(&buffer[offset..offset + len])
+    }
+
+    /// Length of the prefix shared by every key on the page
+    pub fn prefix_len(&self) -> usize {
+        Self::shared_prefix_len(&self.keys)
+    }
 
-        let offset_after_key_vec = Self::EXCLUDING_VEC_SIZE + size_of_key_vec;
-        let offset_after_child_pointers_key_len =
-            padding_needed_from_type::<u32>(offset_after_key_vec)
-                + offset_after_key_vec
-                + Self::CHILD_POINTERS_LEN_SIZE;
+    fn shared_prefix_len(keys: &[Vec<u8>]) -> usize {
+        let Some((first, rest)) = keys.split_first() else {
+            return 0;
+        };
+        rest.iter().fold(first.len(), |len, key| {
+            len.min(common_prefix_len(first, key))
+        })
+    }
 
-        let padding_to_first_child_pointer =
-            padding_needed_from_type::<u64>(offset_after_child_pointers_key_len);
-        let size_of_child_pointers = Self::CHILD_POINTERS_VALUE_SIZE * self.child_pointers.len();
+    /// Bytes the page body takes up when serialized, which is exactly the bytes the keys (with
+    /// their shared prefix counted once), their slots and the child pointers use
+    pub fn calc_size(&self) -> usize {
+        Self::size_for(&self.keys, self.child_pointers.len())
+    }
 
-        offset_after_child_pointers_key_len
-            + padding_to_first_child_pointer
-            + size_of_child_pointers
+    /// What `calc_size` would be for a page with these keys and this many child pointers, without
+    /// building one
+    pub(crate) fn size_for(keys: &[Vec<u8>], child_pointers_len: usize) -> usize {
+        let key_bytes: usize = keys.iter().map(Vec::len).sum();
+        let prefix_savings = keys.len().saturating_sub(1) * Self::shared_prefix_len(keys);
+        Self::child_pointers_offset(keys.len())
+            + Self::CHILD_POINTERS_VALUE_SIZE * child_pointers_len
+            + key_bytes
+            - prefix_savings
     }
 
     pub fn new(is_leaf: bool) -> Self {
         IndexPage {
             is_leaf,
             next_leaf: 0,
+            prev_leaf: 0,
             keys: Vec::new(),
             child_pointers: Vec::new(),
         }
     }
 }
 
+// Reads single keys straight out of the page, for when deserializing every key would be wasted
+impl PageWindow<'_, IndexPage> {
+    pub fn is_leaf(&self) -> bool {
+        self.page_bytes[IndexPage::is_leaf_span()][0] != 0
+    }
+
+    pub fn next_leaf(&self) -> u64 {
+        read_be_u64(&self.page_bytes[IndexPage::next_leaf_span()])
+    }
+
+    pub fn prev_leaf(&self) -> u64 {
+        read_be_u64(&self.page_bytes[IndexPage::prev_leaf_span()])
+    }
+
+    pub fn keys_len(&self) -> usize {
+        read_be_u32(&self.page_bytes[IndexPage::keys_len_span()]) as usize
+    }
+
+    /// Copy of the key at `index`
+    pub fn key(&self, index: usize) -> Result<Vec<u8>> {
+        let (prefix, suffix) = self.key_parts(index)?;
+        Ok([prefix, suffix].concat())
+    }
+
+    /// How the key at `index` compares to `other`, without copying it out
+    pub fn compare_key(&self, index: usize, other: &[u8]) -> Result<std::cmp::Ordering> {
+        let (prefix, suffix) = self.key_parts(index)?;
+        Ok(prefix.iter().chain(suffix).cmp(other))
+    }
+
+    pub fn child_pointer(&self, index: usize) -> Result<u64> {
+        let offset = IndexPage::child_pointers_offset(self.keys_len())
+            + index * IndexPage::CHILD_POINTERS_VALUE_SIZE;
+        check_buffer_size(
+            self.page_bytes,
+            offset + IndexPage::CHILD_POINTERS_VALUE_SIZE,
+        )?;
+        Ok(read_be_u64(
+            &self.page_bytes[offset..offset + IndexPage::CHILD_POINTERS_VALUE_SIZE],
+        ))
+    }
+
+    /// The prefix every key on the page shares and the rest of the key at `index`
+    fn key_parts(&self, index: usize) -> Result<(&[u8], &[u8])> {
+        check_buffer_size(self.page_bytes, IndexPage::key_slot_offset(index + 1))?;
+        let prefix = IndexPage::read_heap_bytes(self.page_bytes, IndexPage::PREFIX_SLOT_OFFSET)?;
+        let suffix =
+            IndexPage::read_heap_bytes(self.page_bytes, IndexPage::key_slot_offset(index))?;
+        Ok((prefix, suffix))
+    }
+}
+
 // FreeList page structure
 // TODO: Confirm this pages impl looks correct
 #[repr(C)]
-#[derive(StructLayout)]
+#[derive(StructLayout, Debug, Clone, PartialEq)]
 pub struct FreeListPage {
     pub next_free_list: u64,
     pub free_page_ids: Vec<u64>,
 }
 
+impl MySerialize for FreeListPage {
+    fn serialize(&self, buffer: &mut [u8]) -> Result<usize> {
+        let size_to_write =
+            Self::MIN_SIZE + (Self::FREE_PAGE_IDS_VALUE_SIZE * self.free_page_ids.len());
+        check_buffer_size(buffer, size_to_write)?;
+
+        buffer[Self::next_free_list_span()].copy_from_slice(&self.next_free_list.to_be_bytes());
+        buffer[Self::free_page_ids_len_span()]
+            .copy_from_slice(&(self.free_page_ids.len() as u32).to_be_bytes());
+        let mut free_page_id_offset = Self::FREE_PAGE_IDS_FIRST_VALUE_OFFSET;
+        for free_page_id in self.free_page_ids.iter() {
+            buffer[free_page_id_offset..free_page_id_offset + Self::FREE_PAGE_IDS_VALUE_SIZE]
+                .copy_from_slice(&free_page_id.to_be_bytes());
+            free_page_id_offset += Self::FREE_PAGE_IDS_VALUE_SIZE;
+        }
+
+        assert!(size_to_write == free_page_id_offset);
+        Ok(size_to_write)
+    }
+}
+
+impl MyDeserialize for FreeListPage {
+    fn deserialize(buffer: &[u8]) -> Result<Self> {
+        check_buffer_size(buffer, Self::MIN_SIZE)?;
+
+        let next_free_list = read_be_u64(&buffer[Self::next_free_list_span()]);
+        let free_page_ids_len = read_be_u32(&buffer[Self::free_page_ids_len_span()]) as usize;
+        check_buffer_size(
+            buffer,
+            Self::MIN_SIZE + Self::FREE_PAGE_IDS_VALUE_SIZE * free_page_ids_len,
+        )?;
+
+        let free_page_ids = buffer[Self::FREE_PAGE_IDS_FIRST_VALUE_OFFSET..]
+            .chunks_exact(Self::FREE_PAGE_IDS_VALUE_SIZE)
+            .take(free_page_ids_len)
+            .map(read_be_u64)
+            .collect();
+
+        Ok(FreeListPage {
+            next_free_list,
+            free_page_ids,
+        })
+    }
+}
+
 impl FreeListPage {
     const FREE_PAGE_IDS_LEN_SIZE: usize = size_of::<u32>();
     const FREE_PAGE_IDS_VALUE_SIZE: usize = size_of::<u64>();
@@ -477,37 +835,82 @@ impl FreeListPage {
         }
     }
 
-    pub fn serialize(&self, buffer: &mut [u8]) -> usize {
-        let size_to_write =
-            Self::MIN_SIZE + (Self::FREE_PAGE_IDS_VALUE_SIZE * self.free_page_ids.len());
-        if buffer.len() < size_to_write {
-            panic!("Buffer too small for index page");
-        }
+    /// How many free page ids fit on one free list page for the given page size
+    pub fn capacity(page_size: u32) -> usize {
+        (page_size as usize - PageHeader::SIZE - Self::MIN_SIZE) / Self::FREE_PAGE_IDS_VALUE_SIZE
+    }
 
-        buffer[Self::next_free_list_span()].copy_from_slice(&self.next_free_list.to_be_bytes());
-        buffer[Self::FREE_PAGE_IDS_LEN_OFFSET
-            ..Self::FREE_PAGE_IDS_LEN_OFFSET + Self::FREE_PAGE_IDS_LEN_SIZE]
-            .copy_from_slice(&self.free_page_ids.len().to_be_bytes());
-        let mut free_page_id_offset = Self::FREE_PAGE_IDS_FIRST_VALUE_OFFSET;
-        for free_page_id in self.free_page_ids.iter() {
-            buffer[free_page_id_offset..free_page_id_offset + Self::FREE_PAGE_IDS_VALUE_SIZE]
-                .copy_from_slice(&free_page_id.to_be_bytes());
-            free_page_id_offset += Self::FREE_PAGE_IDS_VALUE_SIZE;
-        }
+    fn free_page_ids_len_span() -> Range<usize> {
+        Self::FREE_PAGE_IDS_LEN_OFFSET
+            ..Self::FREE_PAGE_IDS_LEN_OFFSET + Self::FREE_PAGE_IDS_LEN_SIZE
+    }
 
-        assert!(size_to_write == free_page_id_offset);
-        size_to_write
+    fn free_page_id_span(index: usize) -> Range<usize> {
+        let start = Self::FREE_PAGE_IDS_FIRST_VALUE_OFFSET + index * Self::FREE_PAGE_IDS_VALUE_SIZE;
+        start..start + Self::FREE_PAGE_IDS_VALUE_SIZE
+    }
+}
+
+impl Default for FreeListPage {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'a> PageWindow<'a, FreeListPage> {
+    fn read_next_free_list(&self) -> u64 {
+        let mut u64_bytes = [0u8; size_of::<u64>()];
+        u64_bytes.copy_from_slice(&self.page_bytes[FreeListPage::next_free_list_span()]);
+        u64::from_be_bytes(u64_bytes)
+    }
+
+    fn read_free_page_ids_len(&self) -> usize {
+        let mut u32_bytes = [0u8; size_of::<u32>()];
+        u32_bytes.copy_from_slice(&self.page_bytes[FreeListPage::free_page_ids_len_span()]);
+        u32::from_be_bytes(u32_bytes) as usize
+    }
+
+    fn update_free_page_ids_len(&mut self, new_len: usize) {
+        self.page_bytes[FreeListPage::free_page_ids_len_span()]
+            .copy_from_slice(&(new_len as u32).to_be_bytes());
+        // Keep the header in sync with where the id array ends
+        let end_of_ids = PageHeader::SIZE + FreeListPage::free_page_id_span(new_len).start;
+        self.update_free_space_pointer(end_of_ids as u32);
+    }
+
+    /// Caller is responsible for checking there is room using `FreeListPage::capacity`
+    fn push_free_page_id(&mut self, page_id: u64) {
+        let len = self.read_free_page_ids_len();
+        self.page_bytes[FreeListPage::free_page_id_span(len)]
+            .copy_from_slice(&page_id.to_be_bytes());
+        self.update_free_page_ids_len(len + 1);
+    }
+
+    fn pop_free_page_id(&mut self) -> Option<u64> {
+        let len = self.read_free_page_ids_len();
+        if len == 0 {
+            return None;
+        }
+        let mut u64_bytes = [0u8; size_of::<u64>()];
+        u64_bytes.copy_from_slice(&self.page_bytes[FreeListPage::free_page_id_span(len - 1)]);
+        self.update_free_page_ids_len(len - 1);
+        Some(u64::from_be_bytes(u64_bytes))
     }
 }
 pub struct PagedFileManagerConfig {
-    page_size: u32,
+    // None means use whatever an existing file was created with, or the default for new files
+    page_size: Option<u32>,
     max_cache_size: usize,
+    checksums: bool,
+    storage: StorageBackend,
 }
 
 #[derive(Default)]
 pub struct PagedFileManagerConfigBuilder {
     page_size: Option<u32>,
     max_cache_size: Option<usize>,
+    checksums: Option<bool>,
+    storage: Option<StorageBackend>,
 }
 
 impl PagedFileManagerConfigBuilder {
@@ -518,6 +921,9 @@ impl PagedFileManagerConfigBuilder {
         Self::default()
     }
 
+    /// Page size for new files, at least `PagedFileManager::MIN_PAGE_SIZE`. When reopening an
+    /// existing file this has to match the size the file was created with, leave it unset to adopt
+    /// whatever the file uses
     pub fn page_size(mut self, size: u32) -> Self {
         self.page_size = Some(size);
         self
@@ -528,216 +934,748 @@ impl PagedFileManagerConfigBuilder {
         self
     }
 
+    /// Turns page checksums on or off. On by default.
+    ///
+    /// Only meant for benchmarking. Pages written with checksums off are stamped with whatever
+    /// happened to be in the checksum field so reopening the file with checksums on will report
+    /// them as corrupt.
+    pub fn checksums(mut self, enabled: bool) -> Self {
+        self.checksums = Some(enabled);
+        self
+    }
+
+    /// Where pages actually get stored. Defaults to a plain file
+    pub fn storage(mut self, storage: StorageBackend) -> Self {
+        self.storage = Some(storage);
+        self
+    }
+
     pub fn build(self) -> PagedFileManagerConfig {
         PagedFileManagerConfig {
-            page_size: self.page_size.unwrap_or(Self::DEFAULT_PAGE_SIZE),
+            page_size: self.page_size,
             max_cache_size: self.max_cache_size.unwrap_or(Self::DEFAULT_MAX_CACHE_SIZE),
+            checksums: self.checksums.unwrap_or(true),
+            storage: self.storage.unwrap_or_default(),
         }
     }
 }
 
-// File manager to handle page operations
+// File manager to handle page operations.
+//
+// Every change to a page is logged to the write-ahead log next to the database file (same path
+// with ".wal" tacked on) and happens inside a transaction, see `atomically`. The log gets replayed
+// when the file is reopened after a crash and emptied on every `flush_all`, which waits for running
+// transactions to finish and keeps new ones from starting until it's done.
+//
+// The manager can be shared between threads, every method only needs `&self`. Each thread runs its
+// own transaction, so two threads changing the same pages at the same time need to keep out of
+// each other's way with page latches (`latch_page`) held until their transactions end. Rolling
+// back puts back the old bytes of every page the transaction changed, which would wipe out
+// anything another transaction did to those pages in the meantime. `allocate_page` and
+// `free_page` take care of that for the metadata page and the free list, the metadata page's latch
+// is held from the first one until the transaction ends
 pub struct PagedFileManager {
-    file: Arc<Mutex<File>>,
+    store: SharedPageStore,
     page_size: u32,
-    buffer_pool: HashMap<u64, Vec<u8>>, // pageId -> raw page data
-    max_cache_size: usize,
+    buffer_pool: BufferPool,
+    wal: Arc<Mutex<Wal>>,
+    // Transaction each thread is currently running, if any. Also works as the checkpoint lock,
+    // every transaction holds a place in it and `flush_all` holds the whole map
+    active_txns: Mutex<HashMap<ThreadId, ActiveTxn>>,
+    // Signalled whenever a transaction ends, for `flush_all` waiting on them
+    txn_finished: Condvar,
+    next_txn_id: AtomicU64,
+}
+
+struct ActiveTxn {
+    txn_id: TxnId,
+    // Updates made by the transaction, kept around so they can be rolled back
+    updates: Vec<LogRecord>,
+    // Whether the transaction holds the metadata page's latch, see `latch_metadata_until_end`
+    metadata_latched: bool,
 }
 
 impl PagedFileManager {
     const METADATA_PAGE_ID: u64 = 0;
+    /// Smallest page size a file can be created with. Every page type's capacity is worked out
+    /// from the page size and B+Tree nodes are the first to run out of room
+    pub const MIN_PAGE_SIZE: u32 = 256;
 
     pub fn new<P: AsRef<Path>>(path: P, config: PagedFileManagerConfig) -> Result<Self> {
-        let file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(path)?;
+        let path = path.as_ref();
+        if let Some(configured) = config.page_size {
+            if configured < Self::MIN_PAGE_SIZE {
+                return Err(DbError::PageSizeTooSmall {
+                    configured,
+                    min: Self::MIN_PAGE_SIZE,
+                });
+            }
+        }
+        let mut store = config.storage.open_pages(path)?;
+        let mut log_store = config.storage.open_log(&Self::wal_path(path))?;
+
+        // Initialize the file if it's new (create metadata page), otherwise recover whatever the
+        // log holds and let the file decide the page size
+        let is_new_file = store.is_empty()?;
+        let (page_size, start_lsn) = if is_new_file {
+            let page_size = config
+                .page_size
+                .unwrap_or(PagedFileManagerConfigBuilder::DEFAULT_PAGE_SIZE);
+            (page_size, 1)
+        } else {
+            let start_lsn = wal::recover(store.as_mut(), log_store.as_mut())?;
+            let page_size = Self::validate_existing_file(store.as_mut(), &config)?;
+            (page_size, start_lsn)
+        };
+        // Has to happen before a new file is initialized, otherwise a crash in between could leave
+        // an old log lying around that would get replayed onto the new file
+        let wal = Arc::new(Mutex::new(Wal::create(log_store, page_size, start_lsn)?));
 
+        let store = Arc::new(Mutex::new(store));
+        let buffer_pool = BufferPool::new(
+            store.clone(),
+            page_size,
+            config.max_cache_size,
+            config.checksums,
+        )
+        .with_wal(wal.clone());
         let manager = PagedFileManager {
-            file: Arc::new(Mutex::new(file)),
-            page_size: config.page_size,
-            buffer_pool: HashMap::new(),
-            max_cache_size: config.max_cache_size,
+            store,
+            page_size,
+            buffer_pool,
+            wal,
+            active_txns: Mutex::new(HashMap::new()),
+            txn_finished: Condvar::new(),
+            next_txn_id: AtomicU64::new(1),
         };
-
-        // Initialize the file if it's new (create metadata page)
-        let file_len = manager.file.lock().unwrap().metadata()?.len();
-        if file_len == 0 {
+        if is_new_file {
             manager.initialize_file()?;
         }
 
         Ok(manager)
     }
 
+    fn wal_path(path: &Path) -> PathBuf {
+        let mut wal_path = path.as_os_str().to_owned();
+        wal_path.push(".wal");
+        PathBuf::from(wal_path)
+    }
+
+    /// Reads the metadata page straight off disk and makes sure the file is something this
+    /// version of the crate can open. Returns the page size the file was created with
+    fn validate_existing_file(
+        store: &mut dyn PageStore,
+        config: &PagedFileManagerConfig,
+    ) -> Result<u32> {
+        let file_len = store.len()?;
+        let metadata_end = PageHeader::SIZE + MetadataPage::SIZE;
+        if file_len < metadata_end as u64 {
+            return Err(DbError::NotADatabase(format!(
+                "file is only {file_len} bytes, too small to hold a metadata page"
+            )));
+        }
+        let mut metadata_bytes = vec![0u8; metadata_end];
+        store.read_at(0, &mut metadata_bytes)?;
+
+        // Nothing's been verified yet so any failure to even parse the header means it's not ours
+        let header = PageHeader::deserialize(&metadata_bytes)
+            .map_err(|err| DbError::NotADatabase(format!("unreadable first page: {err}")))?;
+        if header.page_id != Self::METADATA_PAGE_ID || header.page_type != PageType::Metadata {
+            return Err(DbError::NotADatabase(format!(
+                "first page is {:?} page {} rather than the metadata page",
+                header.page_type, header.page_id
+            )));
+        }
+        let metadata_page = MetadataPage::deserialize(&metadata_bytes[PageHeader::SIZE..])?;
+
+        if metadata_page.db_version != DB_VERSION {
+            return Err(DbError::UnsupportedVersion {
+                expected: DB_VERSION,
+                found: metadata_page.db_version,
+            });
+        }
+
+        let page_size = metadata_page.page_size;
+        if page_size < Self::MIN_PAGE_SIZE || file_len < page_size as u64 {
+            return Err(DbError::NotADatabase(format!(
+                "stored page size {page_size} is too small or bigger than the file"
+            )));
+        }
+        if let Some(configured) = config.page_size {
+            if configured != page_size {
+                return Err(DbError::PageSizeMismatch {
+                    configured,
+                    stored: page_size,
+                });
+            }
+        }
+
+        // Only now that the page size is known can the whole metadata page be checked
+        if config.checksums {
+            let mut page_bytes = vec![0u8; page_size as usize];
+            store.read_at(0, &mut page_bytes)?;
+            let (stored, computed) = PageHeader::read_checksums(&page_bytes);
+            if stored != computed {
+                return Err(DbError::Corruption {
+                    page_id: Self::METADATA_PAGE_ID,
+                    reason: format!("stored checksum {stored:#010x} but computed {computed:#010x}"),
+                });
+            }
+        }
+
+        // Every allocated page gets written back by the time the file is closed so the file
+        // should hold exactly total_pages pages
+        let expected_len = metadata_page.total_pages * page_size as u64;
+        if file_len != expected_len {
+            return Err(DbError::Corruption {
+                page_id: Self::METADATA_PAGE_ID,
+                reason: format!(
+                    "metadata says {} pages of {page_size} bytes ({expected_len} bytes) but the \
+                     file is {file_len} bytes",
+                    metadata_page.total_pages
+                ),
+            });
+        }
+
+        Ok(page_size)
+    }
+
     fn initialize_file(&self) -> Result<()> {
         // Create a buffer for the metadata page
         let mut page_buffer = vec![0u8; self.page_size as usize];
 
         // Create and write header
         let header = PageHeader::new(Self::METADATA_PAGE_ID, PageType::Metadata);
-        let end_of_header = header.serialize(&mut page_buffer);
+        let end_of_header = header.serialize(&mut page_buffer)?;
         // TODO: I think padding the whole MetadataPage is fine? Rather than just its first value
-        let metadata_offset = padding_needed_from_type::<MetadataPage>(end_of_header);
+        let metadata_offset =
+            end_of_header + padding_needed_from_type::<MetadataPage>(end_of_header);
 
         // TODO: This can probably be a debug_assert
         assert!(metadata_offset == PageHeader::SIZE);
 
         let metadata_page = MetadataPage::intial_page(self.page_size);
-        metadata_page.serialize(&mut page_buffer[metadata_offset..]);
+        metadata_page.serialize(&mut page_buffer[metadata_offset..])?;
+        PageHeader::stamp_checksum(&mut page_buffer);
 
-        // Write to file
-        let mut file = self.file.lock().unwrap();
-        file.seek(SeekFrom::Start(0))?;
-        file.write_all(&page_buffer)?;
-        file.sync_all()?;
+        // Write to file. This skips the buffer pool on purpose, nothing can be cached yet and the
+        // file needs to be valid on disk before anything else happens
+        let mut store = self.store.lock()?;
+        store.write_at(0, &page_buffer)?;
+        store.sync()?;
 
         Ok(())
     }
 
-    pub fn allocate_page(&mut self) -> Result<u64> {
-        // Read metadata to get next page ID
-        let cache = &mut self.buffer_pool;
-        let mut page_bytes = Self::load_into_buffer_pool(
-            cache,
-            self.max_cache_size,
-            Self::METADATA_PAGE_ID,
-            // TODO: Does self.file.clone() do anything weird here. It ~feels~ wrong.
-            || Self::read_page(self.file.clone(), Self::METADATA_PAGE_ID, self.page_size),
-        )?;
-        let mut metadata_page_window = PageWindow::<MetadataPage>::new(&mut page_bytes);
+    /// Hands out a page id that's free to use. Pages that were freed through `free_page` are reused
+    /// before the file is grown. The returned page is always zeroed
+    pub fn allocate_page(&self) -> Result<u64> {
+        self.atomically(|manager| {
+            manager.latch_metadata_until_end()?;
+            if let Some(page_id) = manager.pop_free_list()? {
+                manager.modify_page(page_id, false, |page_bytes| {
+                    page_bytes.fill(0);
+                    Ok(())
+                })?;
+                return Ok(page_id);
+            }
+
+            // Read metadata to get next page ID
+            let new_page_id = manager.with_page_mut(Self::METADATA_PAGE_ID, |page_bytes| {
+                let mut metadata_page_window = PageWindow::<MetadataPage>::new(page_bytes)?;
+                // Page ids are 0 indexed so the current total is the id of the next page
+                let new_page_id = metadata_page_window.read_total_pages();
+                metadata_page_window.update_total_pages(new_page_id + 1);
+                Ok(new_page_id)
+            })?;
+
+            // Create empty page. Nothing changes but it still gets logged so recovery knows to
+            // write the page out
+            manager.modify_page(new_page_id, true, |_| Ok(()))?;
+
+            Ok(new_page_id)
+        })
+    }
+
+    /// Marks the page as free so a later `allocate_page` can reuse it.
+    ///
+    /// Freed ids get recorded on the FreeListPage at the head of the chain. When that page is full
+    /// (or there is no chain yet) the freed page itself becomes the new head, so freeing never needs
+    /// to allocate.
+    ///
+    /// Either way the freed page is overwritten with an empty FreeListPage. That's how freeing a
+    /// page twice gets caught, and it means anyone holding on to what the page used to be (like a
+    /// cursor on a leaf that got merged away) sees it change
+    pub fn free_page(&self, page_id: u64) -> Result<()> {
+        self.atomically(|manager| {
+            manager.latch_metadata_until_end()?;
+            let (first_free_list_page, total_pages) = manager.with_window::<MetadataPage, _, _>(
+                Self::METADATA_PAGE_ID,
+                |metadata_page_window| {
+                    Ok((
+                        metadata_page_window.read_first_free_list_page(),
+                        metadata_page_window.read_total_pages(),
+                    ))
+                },
+            )?;
+            if page_id == Self::METADATA_PAGE_ID || page_id >= total_pages {
+                return Err(DbError::InvalidPageId(page_id));
+            }
+            // Already free, or part of the chain itself
+            let page_type = manager
+                .with_window::<FreeListPage, _, _>(page_id, |window| window.read_page_type())?;
+            if page_type == PageType::FreeList {
+                return Err(DbError::InvalidPageId(page_id));
+            }
+
+            let mut pushed = false;
+            if first_free_list_page != 0 {
+                let capacity = FreeListPage::capacity(manager.page_size);
+                pushed = manager.with_page_mut(first_free_list_page, |page_bytes| {
+                    let mut free_list_window = PageWindow::<FreeListPage>::new(page_bytes)?;
+                    if free_list_window.read_free_page_ids_len() < capacity {
+                        free_list_window.push_free_page_id(page_id);
+                        Ok(true)
+                    } else {
+                        Ok(false)
+                    }
+                })?;
+            }
+
+            // Only a page that's the new head of the chain links on to the rest of it
+            let mut page_buffer = vec![0u8; manager.page_size as usize];
+            let mut header = PageHeader::new(page_id, PageType::FreeList);
+            let free_list_page = FreeListPage {
+                next_free_list: if pushed { 0 } else { first_free_list_page },
+                free_page_ids: Vec::new(),
+            };
+            let free_list_offset =
+                PageHeader::size() + padding_needed_from_type::<u64>(PageHeader::size());
+            assert!(free_list_offset == PageHeader::SIZE);
+            header.free_space_pointer = (free_list_offset + FreeListPage::MIN_SIZE) as u32;
+            header.serialize(&mut page_buffer)?;
+            free_list_page.serialize(&mut page_buffer[free_list_offset..])?;
+            manager.write_page(page_id, page_buffer)?;
+            if pushed {
+                return Ok(());
+            }
+
+            manager.with_page_mut(Self::METADATA_PAGE_ID, |page_bytes| {
+                PageWindow::<MetadataPage>::new(page_bytes)?.update_first_free_list_page(page_id);
+                Ok(())
+            })
+        })
+    }
+
+    /// Latches the metadata page exclusively until the running transaction ends. Rolling back puts
+    /// back the page's old bytes, so no other transaction can be let near it until this one is
+    /// done. Taking the latch again is fine if the caller already holds it
+    fn latch_metadata_until_end(&self) -> Result<()> {
+        let thread_id = thread::current().id();
+        let latched = self
+            .active_txns
+            .lock()?
+            .get(&thread_id)
+            .ok_or(DbError::NotInTransaction)?
+            .metadata_latched;
+        if latched {
+            return Ok(());
+        }
 
-        let new_page_id = metadata_page_window.read_total_pages() + 1;
-        metadata_page_window.update_total_pages(new_page_id);
+        self.latch_page(Self::METADATA_PAGE_ID, LatchMode::Exclusive)?
+            .keep();
+        if let Some(txn) = self.active_txns.lock()?.get_mut(&thread_id) {
+            txn.metadata_latched = true;
+        }
+        Ok(())
+    }
 
-        // Write updated metadata page
-        let to_write = mem::take(page_bytes);
-        self.write_page(Self::METADATA_PAGE_ID, to_write)?;
+    /// Takes a page id off the free list chain if there is one. When the head FreeListPage has no
+    /// ids left the head page itself is handed out and the chain moves on to the next page
+    fn pop_free_list(&self) -> Result<Option<u64>> {
+        let first_free_list_page = self
+            .with_window::<MetadataPage, _, _>(Self::METADATA_PAGE_ID, |metadata_page_window| {
+                Ok(metadata_page_window.read_first_free_list_page())
+            })?;
+        if first_free_list_page == 0 {
+            return Ok(None);
+        }
+
+        let (popped, next_free_list) = self.with_page_mut(first_free_list_page, |page_bytes| {
+            let mut free_list_window = PageWindow::<FreeListPage>::new(page_bytes)?;
+            Ok((
+                free_list_window.pop_free_page_id(),
+                free_list_window.read_next_free_list(),
+            ))
+        })?;
+        if popped.is_some() {
+            return Ok(popped);
+        }
 
-        // Create empty page
-        let empty_page = vec![0u8; self.page_size as usize];
-        self.write_page(new_page_id, empty_page)?;
+        self.with_page_mut(Self::METADATA_PAGE_ID, |page_bytes| {
+            PageWindow::<MetadataPage>::new(page_bytes)?
+                .update_first_free_list_page(next_free_list);
+            Ok(())
+        })?;
+        Ok(Some(first_free_list_page))
+    }
 
-        Ok(new_page_id)
+    /// Returns a copy of the page's current bytes, going through the buffer pool
+    pub fn read_page(&self, page_id: u64) -> Result<Vec<u8>> {
+        self.with_page(page_id, |page_bytes| Ok(page_bytes.to_vec()))
     }
 
-    fn read_page(file: Arc<Mutex<File>>, page_id: u64, page_size: u32) -> Result<Vec<u8>> {
-        // Read from disk
-        let mut page_data = vec![0u8; page_size as usize];
-        let mut file = file.lock().unwrap();
-        file.seek(SeekFrom::Start(page_id * page_size as u64))?;
-        file.read_exact(&mut page_data)?;
+    /// Reads the page's header and deserializes its body as `T`. Doesn't check that the header's
+    /// page_type matches `T`, that's left to the caller
+    pub fn read_page_as<T: MyDeserialize>(&self, page_id: u64) -> Result<(PageHeader, T)> {
+        self.with_page(page_id, |page_bytes| {
+            let header = PageHeader::deserialize(page_bytes)?;
+            let page = T::deserialize(&page_bytes[PageHeader::SIZE..])?;
+            Ok((header, page))
+        })
+    }
 
-        Ok(page_data)
+    /// Runs `f` over the bytes of the page while it is pinned in the buffer pool. The page is
+    /// unpinned whether or not `f` fails
+    pub fn with_page<F, R>(&self, page_id: u64, f: F) -> Result<R>
+    where
+        F: FnOnce(&[u8]) -> Result<R>,
+    {
+        let frame_id = self.buffer_pool.pin_page(page_id)?;
+        let result = self.buffer_pool.frame(frame_id).and_then(|frame| f(&frame));
+        self.buffer_pool.unpin_page(page_id, false)?;
+        result
     }
 
-    pub fn load_into_buffer_pool<F>(
-        cache: &mut HashMap<u64, Vec<u8>>,
-        max_cache_size: usize,
-        page_id: u64,
-        loader: F,
-    ) -> Result<&mut Vec<u8>>
+    /// Runs `f` over the bytes of the page while it is pinned in the buffer pool. The page is
+    /// marked dirty afterwards so whatever `f` changed gets written back on eviction or flush
+    pub fn with_page_mut<F, R>(&self, page_id: u64, f: F) -> Result<R>
     where
-        F: FnOnce() -> Result<Vec<u8>>,
+        F: FnOnce(&mut Vec<u8>) -> Result<R>,
     {
-        // Check cache first
-        if !cache.contains_key(&page_id) {
-            let page_data = loader()?;
-            // Update cache TODO: This might be needed elsewhere too, but I need to implement some sort of
-            // mechanism for locking cache keys so that I can guarantee this won't break things
-            if cache.len() >= max_cache_size {
-                // Simple eviction - remove first key
-                if let Some(key) = cache.keys().next().cloned() {
-                    cache.remove(&key);
+        self.atomically(|manager| manager.modify_page(page_id, false, f))
+    }
+
+    /// Keeps the page pinned in the buffer pool until the returned guard is dropped, for pages
+    /// that get read over and over. Reads through the guard don't go through the page table and
+    /// never find the page evicted
+    pub(crate) fn pin(&self, page_id: u64) -> Result<PinnedPage<'_>> {
+        Ok(PinnedPage {
+            manager: self,
+            page_id,
+            frame_id: self.buffer_pool.pin_page(page_id)?,
+        })
+    }
+
+    /// Same as `with_page` but hands `f` a `PageWindow` over the page. Useful for reading single
+    /// fields without deserializing the whole page. The page is not marked dirty
+    pub fn with_window<T, F, R>(&self, page_id: u64, f: F) -> Result<R>
+    where
+        F: FnOnce(&PageWindow<T>) -> Result<R>,
+    {
+        let frame_id = self.buffer_pool.pin_page(page_id)?;
+        // PageWindow needs mutable bytes even though f can only read through it
+        let result = self
+            .buffer_pool
+            .frame_mut(frame_id)
+            .and_then(|mut frame| PageWindow::new(&mut frame).and_then(|w| f(&w)));
+        self.buffer_pool.unpin_page(page_id, false)?;
+        result
+    }
+
+    /// Same as `with_page_mut` but hands `f` a `PageWindow` over the page
+    pub fn with_window_mut<T, F, R>(&self, page_id: u64, f: F) -> Result<R>
+    where
+        F: FnOnce(&mut PageWindow<T>) -> Result<R>,
+    {
+        self.with_page_mut(page_id, |page_bytes| f(&mut PageWindow::new(page_bytes)?))
+    }
+
+    /// Replaces the contents of the page in the buffer pool. Nothing hits disk until the page is
+    /// evicted or flushed
+    pub fn write_page(&self, page_id: u64, data: Vec<u8>) -> Result<()> {
+        self.with_page_mut(page_id, |frame| {
+            let len = data.len().min(frame.len());
+            frame[..len].copy_from_slice(&data[..len]);
+            frame[len..].fill(0);
+            Ok(())
+        })
+    }
+
+    pub fn flush_page(&self, page_id: u64) -> Result<()> {
+        self.buffer_pool.flush_page(page_id)?;
+        self.store.lock()?.sync()?;
+        Ok(())
+    }
+
+    /// Writes every dirty page in the buffer pool to disk and syncs the file. This is also a
+    /// checkpoint, once everything is on disk the write-ahead log is emptied.
+    ///
+    /// Pages changed by a running transaction could still need undoing, so this waits for every
+    /// transaction to end first. Called from inside a transaction it can't wait for its own, the
+    /// pages are written but the log is left alone
+    pub fn flush_all(&self) -> Result<()> {
+        let mut active_txns = self.active_txns.lock()?;
+        if active_txns.contains_key(&thread::current().id()) {
+            drop(active_txns);
+            return self.buffer_pool.flush_all();
+        }
+        while !active_txns.is_empty() {
+            active_txns = self.txn_finished.wait(active_txns)?;
+        }
+
+        // No transaction can start until `active_txns` is let go, otherwise one could change a page
+        // after it was written and have the update dropped from the log with the rest
+        self.buffer_pool.flush_all()?;
+        self.wal.lock()?.truncate()?;
+        Ok(())
+    }
+
+    /// Waits for the page's latch and takes it in `mode`. It's released when the returned guard is
+    /// dropped, see `PageLatches` for what latches do and don't do
+    pub fn latch_page(&self, page_id: u64, mode: LatchMode) -> Result<PageLatch<'_>> {
+        self.buffer_pool.latches().acquire(page_id, mode)
+    }
+
+    /// Same as `latch_page` but None rather than waiting if the latch is taken
+    pub fn try_latch_page(&self, page_id: u64, mode: LatchMode) -> Result<Option<PageLatch<'_>>> {
+        self.buffer_pool.latches().try_acquire(page_id, mode)
+    }
+
+    //
+    // Transactions
+    //
+
+    /// Runs `f` as a single atomic change: after a crash either everything it did to pages is
+    /// there or none of it is. If `f` returns an error its changes are rolled back before the error
+    /// is passed on.
+    ///
+    /// Calls nest, only the outermost call commits. Every public method that changes pages already
+    /// runs itself through this so callers only need it to group several calls together.
+    ///
+    /// Transactions belong to the thread running them, calls from different threads are separate
+    /// transactions even when they overlap. A `flush_all` waits for every running transaction
+    pub fn atomically<F, R>(&self, f: F) -> Result<R>
+    where
+        F: FnOnce(&Self) -> Result<R>,
+    {
+        let thread_id = thread::current().id();
+        if self.active_txns.lock()?.contains_key(&thread_id) {
+            return f(self);
+        }
+
+        let txn_id = self.next_txn_id.fetch_add(1, Ordering::Relaxed);
+        self.active_txns.lock()?.insert(
+            thread_id,
+            ActiveTxn {
+                txn_id,
+                updates: Vec::new(),
+                metadata_latched: false,
+            },
+        );
+
+        let result = f(self);
+        let finished = match result {
+            Ok(_) => self.commit(txn_id),
+            Err(_) => self.rollback(txn_id),
+        };
+
+        let txn = self.active_txns.lock()?.remove(&thread_id);
+        if txn.is_some_and(|txn| txn.metadata_latched) {
+            self.buffer_pool
+                .latches()
+                .release(Self::METADATA_PAGE_ID, LatchMode::Exclusive);
+        }
+        self.txn_finished.notify_all();
+        finished?;
+        result
+    }
+
+    fn commit(&self, txn_id: TxnId) -> Result<()> {
+        let mut wal = self.wal.lock()?;
+        let lsn = wal.append(&LogRecord::Commit { txn_id });
+        wal.flush_to(lsn)
+    }
+
+    /// Puts back the before image of every update the transaction made, newest first. The undo
+    /// writes are logged like any other update so recovery can replay history as it happened
+    /// without having to treat aborted transactions specially
+    fn rollback(&self, txn_id: TxnId) -> Result<()> {
+        let updates = self
+            .active_txns
+            .lock()?
+            .get_mut(&thread::current().id())
+            .map(|txn| std::mem::take(&mut txn.updates))
+            .unwrap_or_default();
+        for update in updates.iter().rev() {
+            if let LogRecord::Update {
+                page_id,
+                offset,
+                before,
+                ..
+            } = update
+            {
+                let start = *offset as usize;
+                self.modify_page(*page_id, false, |page_bytes| {
+                    page_bytes[start..start + before.len()].copy_from_slice(before);
+                    Ok(())
+                })?;
+            }
+        }
+
+        // Pages the transaction allocated past the end of the file shouldn't ever reach it
+        let total_pages = self
+            .with_window::<MetadataPage, _, _>(Self::METADATA_PAGE_ID, |metadata_page_window| {
+                Ok(metadata_page_window.read_total_pages())
+            })?;
+        for update in &updates {
+            if let LogRecord::Update { page_id, .. } = update {
+                if *page_id >= total_pages {
+                    self.buffer_pool.discard_page(*page_id)?;
                 }
             }
-            cache.insert(page_id, page_data);
         }
 
-        // TODO: Find a way to make it guaranteed this will be in the cache at this point
-        // I think the best way is to find a locking mechanism based on key
-        Ok(cache.get_mut(&page_id).unwrap())
+        self.wal.lock()?.append(&LogRecord::Abort { txn_id });
+        Ok(())
     }
 
-    pub fn write_page(&self, page_id: u64, data: Vec<u8>) -> Result<()> {
-        // Write to disk
-        let mut file = self.file.lock().unwrap();
-        file.seek(SeekFrom::Start(page_id * self.page_size as u64))?;
-        file.write_all(&data)?;
-        file.sync_all()?;
-        Ok(())
+    /// Every change to a page goes through here so it gets logged. `fresh` pages have never been
+    /// written so they start out zeroed rather than being read from disk
+    fn modify_page<F, R>(&self, page_id: u64, fresh: bool, f: F) -> Result<R>
+    where
+        F: FnOnce(&mut Vec<u8>) -> Result<R>,
+    {
+        let txn_id = self
+            .active_txns
+            .lock()?
+            .get(&thread::current().id())
+            .ok_or(DbError::NotInTransaction)?
+            .txn_id;
+        let frame_id = if fresh {
+            self.buffer_pool.pin_new_page(page_id)?
+        } else {
+            self.buffer_pool.pin_page(page_id)?
+        };
+
+        // The frame stays locked until the change is logged so nobody sees it half done
+        let result = self.buffer_pool.frame_mut(frame_id).and_then(|mut frame| {
+            let before = frame.clone();
+            let result = f(&mut frame).and_then(|r| {
+                // The lsn and checksum are stamped separately, keep them out of the logged change
+                for span in [PageHeader::checksum_span(), PageHeader::lsn_span()] {
+                    frame[span.clone()].copy_from_slice(&before[span]);
+                }
+                let (offset, changed) = changed_range(&before, &frame);
+                let update = LogRecord::Update {
+                    txn_id,
+                    page_id,
+                    offset: offset as u32,
+                    before: before[changed.clone()].to_vec(),
+                    after: frame[changed].to_vec(),
+                };
+                let lsn = self.wal.lock()?.append(&update);
+                frame[PageHeader::lsn_span()].copy_from_slice(&lsn.to_be_bytes());
+                if let Some(txn) = self.active_txns.lock()?.get_mut(&thread::current().id()) {
+                    txn.updates.push(update);
+                }
+                Ok(r)
+            });
+            if result.is_err() {
+                frame.copy_from_slice(&before);
+            }
+            result
+        });
+
+        self.buffer_pool.unpin_page(page_id, result.is_ok())?;
+        result
     }
 
     //
     // Creating specific page types
     //
 
-    pub fn create_data_page(&mut self) -> Result<u64> {
-        let page_id = self.allocate_page()?;
-        let mut page_buffer = vec![0u8; self.page_size as usize];
+    pub fn create_data_page(&self) -> Result<u64> {
+        self.atomically(|manager| {
+            let page_id = manager.allocate_page()?;
+            let mut page_buffer = vec![0u8; manager.page_size as usize];
 
-        let mut header = PageHeader::new(page_id, PageType::Data);
-        let data_page = DataPage::new();
+            let mut header = PageHeader::new(page_id, PageType::Data);
+            let data_page = DataPage::new();
 
-        // u32 is the first datatype of DataPage
-        let data_page_offset =
-            PageHeader::size() + padding_needed_from_type::<u32>(PageHeader::size());
-        // TODO: This is dangerous I think but realistically it should never panic
-        header.free_space_pointer = (data_page_offset + data_page.size()) as u32;
+            // Every page body starts at PageHeader::SIZE (the header plus its trailing padding) so
+            // PageWindow and read_page_as can split any page the same way
+            let data_page_offset = PageHeader::SIZE;
+            // Records are added from the end of the page backwards so there are none yet
+            header.free_space_pointer = manager.page_size;
 
-        let initial_offset = header.serialize(&mut page_buffer);
-        let offset_with_padding = initial_offset + padding_needed_from_type::<u32>(initial_offset);
-        assert!(offset_with_padding == data_page_offset);
-        let final_offset = data_page.serialize(&mut page_buffer[offset_with_padding..]);
+            header.serialize(&mut page_buffer)?;
+            data_page.serialize(&mut page_buffer[data_page_offset..])?;
 
-        assert!(final_offset == header.free_space_pointer as usize);
+            manager.write_page(page_id, page_buffer)?;
 
-        self.write_page(page_id, page_buffer)?;
+            Ok(page_id)
+        })
+    }
 
-        Ok(page_id)
+    pub fn create_index_page(&self, is_leaf: bool) -> Result<u64> {
+        self.atomically(|manager| {
+            let page_id = manager.allocate_page()?;
+            manager.write_index_page(page_id, &IndexPage::new(is_leaf))?;
+            Ok(page_id)
+        })
     }
 
-    pub fn create_index_page(&mut self, is_leaf: bool) -> Result<u64> {
-        let page_id = self.allocate_page()?;
+    /// Replaces the page with the given index page. Errors with `BufferTooSmall` if it doesn't fit
+    pub fn write_index_page(&self, page_id: u64, index_page: &IndexPage) -> Result<()> {
         let mut page_buffer = vec![0u8; self.page_size as usize];
 
         // Initialize header
         let mut header = PageHeader::new(page_id, PageType::Index);
-        let index_page = IndexPage::new(is_leaf);
-
-        let index_page_offset =
-            PageHeader::size() + padding_needed_from_type::<bool>(PageHeader::size());
+        let index_page_offset = PageHeader::SIZE;
         // TODO: This is dangerous I think but realistically it should never panic
         header.free_space_pointer = (index_page_offset + index_page.calc_size()) as u32;
 
-        let initial_offset = header.serialize(&mut page_buffer);
-        let offset_with_padding = initial_offset + padding_needed_from_type::<bool>(initial_offset);
-        assert!(offset_with_padding == index_page_offset);
-        let final_size = index_page.serialize(&mut page_buffer[offset_with_padding..]);
+        header.serialize(&mut page_buffer)?;
+        let index_page_size = index_page.serialize(&mut page_buffer[index_page_offset..])?;
 
-        assert!(final_size == header.free_space_pointer as usize);
+        assert!(index_page_offset + index_page_size == header.free_space_pointer as usize);
 
-        self.write_page(page_id, page_buffer)?;
+        self.write_page(page_id, page_buffer)
+    }
+}
 
-        Ok(page_id)
+impl Drop for PagedFileManager {
+    fn drop(&mut self) {
+        // Best effort. Anyone who cares about the result should call flush_all themselves
+        let _ = self.flush_all();
     }
 }
 
-const fn padding_needed_from_size(offset: usize, next_size: usize) -> usize {
-    // For most primitive types, alignment equals size
-    // But we cap at common max alignments and handle special cases
-    let alignment = match next_size {
-        0 => 1,       // Zero-sized types still need 1-byte alignment
-        1 => 1,       // u8/i8 need 1-byte alignment
-        2 => 2,       // u16/i16 need 2-byte alignment
-        3..=4 => 4,   // u32/i32/f32 need 4-byte alignment
-        5..=8 => 8,   // u64/i64/f64 need 8-byte alignment
-        9..=16 => 16, // Large types like u128/i128 often need 16-byte alignment
-        _ => 16,      // Default to conservative alignment for larger types
-    };
-    let remainder = offset % alignment;
+/// Smallest range covering every byte that differs between the two pages, returned along with its
+/// start. Empty when the pages are identical
+fn changed_range(before: &[u8], after: &[u8]) -> (usize, Range<usize>) {
+    let first = before.iter().zip(after).position(|(b, a)| b != a);
+    let last = before.iter().zip(after).rposition(|(b, a)| b != a);
+    match (first, last) {
+        (Some(first), Some(last)) => (first, first..last + 1),
+        _ => (0, 0..0),
+    }
+}
 
-    gen_padding(alignment, remainder)
+// Callers are expected to pass exactly the field's span, anything else is a bug so panicking on a
+// length mismatch is fine here
+fn read_be_u32(bytes: &[u8]) -> u32 {
+    u32::from_be_bytes(bytes.try_into().expect("u32 field must be 4 bytes"))
+}
+
+fn read_be_u64(bytes: &[u8]) -> u64 {
+    u64::from_be_bytes(bytes.try_into().expect("u64 field must be 8 bytes"))
+}
+
+/// Number of leading bytes the two have in common
+fn common_prefix_len(a: &[u8], b: &[u8]) -> usize {
+    a.iter().zip(b).take_while(|(a, b)| a == b).count()
 }
 
 const fn padding_needed_from_type<T>(offset: usize) -> usize {
@@ -762,10 +1700,491 @@ pub fn add(left: u64, right: u64) -> u64 {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use proptest::prelude::*;
+
+    pub(crate) fn temp_db_path(name: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("{name}_{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(PagedFileManager::wal_path(&path));
+        path
+    }
+
+    /// Removes the database file along with its write-ahead log
+    pub(crate) fn remove_db(path: &Path) {
+        std::fs::remove_file(path).unwrap();
+        let _ = std::fs::remove_file(PagedFileManager::wal_path(path));
+    }
+
+    /// A database that only lives in memory, for tests that don't care about files
+    pub(crate) fn memory_manager(page_size: u32) -> PagedFileManager {
+        let config = PagedFileManagerConfigBuilder::new()
+            .page_size(page_size)
+            .storage(StorageBackend::Memory)
+            .build();
+        PagedFileManager::new("in_memory", config).unwrap()
+    }
+
+    #[test]
+    fn checkpoints_wait_for_running_transactions() {
+        let manager = &memory_manager(256);
+        let page_id = manager.allocate_page().unwrap();
+        let (started_tx, started) = std::sync::mpsc::channel();
+        let (finish, finish_rx) = std::sync::mpsc::channel();
+        let flushed = std::sync::atomic::AtomicBool::new(false);
+
+        thread::scope(|scope| {
+            scope.spawn(move || {
+                manager
+                    .atomically(|manager| {
+                        manager.with_page_mut(page_id, |page_bytes| {
+                            page_bytes[PageHeader::SIZE] = 1;
+                            Ok(())
+                        })?;
+                        started_tx.send(()).unwrap();
+                        finish_rx.recv().unwrap();
+                        Ok(())
+                    })
+                    .unwrap()
+            });
+            started.recv().unwrap();
+            let flusher = scope.spawn(|| {
+                manager.flush_all().unwrap();
+                flushed.store(true, Ordering::SeqCst);
+            });
+            thread::sleep(std::time::Duration::from_millis(50));
+            assert!(!flushed.load(Ordering::SeqCst));
+            finish.send(()).unwrap();
+            flusher.join().unwrap();
+        });
+
+        assert!(flushed.load(Ordering::SeqCst));
+        manager
+            .with_page(page_id, |page_bytes| {
+                assert_eq!(page_bytes[PageHeader::SIZE], 1);
+                Ok(())
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn rolled_back_allocations_dont_undo_other_transactions() {
+        let manager = &memory_manager(256);
+        let (allocated_tx, allocated) = std::sync::mpsc::channel();
+        let (finish, finish_rx) = std::sync::mpsc::channel();
+
+        thread::scope(|scope| {
+            let failing = scope.spawn(move || {
+                manager.atomically(|manager| {
+                    allocated_tx.send(manager.allocate_page()?).unwrap();
+                    finish_rx.recv().unwrap();
+                    Err::<(), _>(DbError::InvalidPageId(0))
+                })
+            });
+            let rolled_back_page_id = allocated.recv().unwrap();
+            let other = scope.spawn(move || manager.allocate_page().unwrap());
+            thread::sleep(std::time::Duration::from_millis(50));
+            finish.send(()).unwrap();
+            assert!(failing.join().unwrap().is_err());
+
+            // The other allocation had to wait for the rollback, so it gets the same page
+            let page_id = other.join().unwrap();
+            assert_eq!(page_id, rolled_back_page_id);
+            let total_pages = manager
+                .with_window::<MetadataPage, _, _>(
+                    PagedFileManager::METADATA_PAGE_ID,
+                    |metadata_page_window| Ok(metadata_page_window.read_total_pages()),
+                )
+                .unwrap();
+            assert_eq!(total_pages, page_id + 1);
+        });
+    }
 
     #[test]
     fn it_works() {
         let result = add(2, 2);
         assert_eq!(result, 4);
     }
+
+    #[test]
+    fn page_edits_persist_through_buffer_pool() {
+        let path = temp_db_path("persist_edits");
+        let config = || {
+            PagedFileManagerConfigBuilder::new()
+                .max_cache_size(2)
+                .build()
+        };
+
+        let page_id = {
+            let manager = PagedFileManager::new(&path, config()).unwrap();
+            let page_id = manager.allocate_page().unwrap();
+            manager
+                .with_page_mut(page_id, |bytes| {
+                    bytes[100] = 42;
+                    Ok(())
+                })
+                .unwrap();
+            // Allocate enough pages that the edited one has to be evicted
+            for _ in 0..4 {
+                manager.allocate_page().unwrap();
+            }
+            manager.flush_all().unwrap();
+            page_id
+        };
+
+        let manager = PagedFileManager::new(&path, config()).unwrap();
+        assert_eq!(manager.read_page(page_id).unwrap()[100], 42);
+        let total_pages = manager
+            .with_page_mut(0, |bytes| {
+                Ok(PageWindow::<MetadataPage>::new(bytes)?.read_total_pages())
+            })
+            .unwrap();
+        assert_eq!(total_pages, 6);
+
+        drop(manager);
+        remove_db(&path);
+    }
+
+    #[test]
+    fn page_logic_runs_on_every_storage_backend() {
+        for (name, storage) in [
+            ("backend_file", StorageBackend::File),
+            ("backend_memory", StorageBackend::Memory),
+            ("backend_mmap", StorageBackend::Mmap),
+        ] {
+            let path = temp_db_path(name);
+            let config = || {
+                PagedFileManagerConfigBuilder::new()
+                    .page_size(256)
+                    .max_cache_size(2)
+                    .storage(storage)
+                    .build()
+            };
+
+            let manager = PagedFileManager::new(&path, config()).unwrap();
+            let page_id = manager.create_data_page().unwrap();
+            let slot = manager.insert_record(page_id, b"stored").unwrap().unwrap();
+            // Enough pages to push the data page out of the pool and back in
+            for _ in 0..3 {
+                manager.allocate_page().unwrap();
+            }
+            assert_eq!(
+                manager.get_record(page_id, slot).unwrap().unwrap(),
+                b"stored"
+            );
+            drop(manager);
+
+            if storage == StorageBackend::Memory {
+                assert!(!path.exists());
+                continue;
+            }
+            let manager = PagedFileManager::new(&path, config()).unwrap();
+            assert_eq!(
+                manager.get_record(page_id, slot).unwrap().unwrap(),
+                b"stored"
+            );
+            drop(manager);
+            remove_db(&path);
+        }
+    }
+
+    #[test]
+    fn reopen_adopts_stored_page_size() {
+        let path = temp_db_path("reopen_page_size");
+        {
+            let config = PagedFileManagerConfigBuilder::new().page_size(256).build();
+            let manager = PagedFileManager::new(&path, config).unwrap();
+            manager.create_data_page().unwrap();
+        }
+
+        let manager = PagedFileManager::new(&path, PagedFileManagerConfigBuilder::new().build());
+        assert_eq!(manager.unwrap().page_size, 256);
+
+        let config = PagedFileManagerConfigBuilder::new().page_size(4096).build();
+        assert!(matches!(
+            PagedFileManager::new(&path, config),
+            Err(DbError::PageSizeMismatch {
+                configured: 4096,
+                stored: 256
+            })
+        ));
+        remove_db(&path);
+
+        // Too small for a new file, nothing gets created
+        let config = PagedFileManagerConfigBuilder::new().page_size(128).build();
+        assert!(matches!(
+            PagedFileManager::new(&path, config),
+            Err(DbError::PageSizeTooSmall {
+                configured: 128,
+                min: PagedFileManager::MIN_PAGE_SIZE
+            })
+        ));
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn reopen_rejects_files_it_cant_trust() {
+        let path = temp_db_path("reopen_rejects");
+        let open = |path: &std::path::Path| {
+            PagedFileManager::new(path, PagedFileManagerConfigBuilder::new().build())
+        };
+
+        std::fs::write(&path, b"definitely not a database, just some text").unwrap();
+        assert!(matches!(open(&path), Err(DbError::NotADatabase(_))));
+        std::fs::remove_file(&path).unwrap();
+
+        drop(open(&path).unwrap());
+        let valid_file = std::fs::read(&path).unwrap();
+
+        // Version from the future
+        let mut bytes = valid_file.clone();
+        let version_offset = PageHeader::SIZE + MetadataPage::db_version_span().start;
+        bytes[version_offset..version_offset + 4].copy_from_slice(&(DB_VERSION + 1).to_be_bytes());
+        std::fs::write(&path, &bytes).unwrap();
+        assert!(matches!(
+            open(&path),
+            Err(DbError::UnsupportedVersion { found, .. }) if found == DB_VERSION + 1
+        ));
+
+        // File length doesn't line up with total_pages
+        let mut bytes = valid_file;
+        bytes.extend_from_slice(&[0u8; 100]);
+        std::fs::write(&path, &bytes).unwrap();
+        assert!(matches!(
+            open(&path),
+            Err(DbError::Corruption { page_id: 0, .. })
+        ));
+
+        remove_db(&path);
+    }
+
+    #[test]
+    fn freed_pages_are_reused_before_growing_file() {
+        let path = temp_db_path("free_list_reuse");
+        // Small pages so the free list chain needs more than one page
+        let config = PagedFileManagerConfigBuilder::new().page_size(256).build();
+        let capacity = FreeListPage::capacity(256);
+        let manager = PagedFileManager::new(&path, config).unwrap();
+
+        let page_ids: Vec<u64> = (0..capacity * 2 + 3)
+            .map(|_| manager.allocate_page().unwrap())
+            .collect();
+        let total_pages = |manager: &PagedFileManager| {
+            manager
+                .with_page_mut(0, |bytes| {
+                    Ok(PageWindow::<MetadataPage>::new(bytes)?.read_total_pages())
+                })
+                .unwrap()
+        };
+        let total_before = total_pages(&manager);
+
+        for &page_id in page_ids.iter() {
+            manager.free_page(page_id).unwrap();
+        }
+        let mut reallocated: Vec<u64> = (0..page_ids.len())
+            .map(|_| manager.allocate_page().unwrap())
+            .collect();
+        reallocated.sort();
+
+        assert_eq!(reallocated, page_ids);
+        assert_eq!(total_pages(&manager), total_before);
+        // Chain is empty again so the next allocation has to grow the file
+        assert_eq!(manager.allocate_page().unwrap(), total_before);
+
+        assert!(manager.free_page(0).is_err());
+        assert!(manager.free_page(total_before + 10).is_err());
+
+        // Whether the id went on a FreeListPage or the page became the head of the chain
+        let freed = [
+            manager.allocate_page().unwrap(),
+            manager.allocate_page().unwrap(),
+        ];
+        for page_id in freed {
+            manager.free_page(page_id).unwrap();
+            assert!(matches!(
+                manager.free_page(page_id),
+                Err(DbError::InvalidPageId(id)) if id == page_id
+            ));
+        }
+        let reallocated = [
+            manager.allocate_page().unwrap(),
+            manager.allocate_page().unwrap(),
+        ];
+        assert_ne!(reallocated[0], reallocated[1]);
+
+        drop(manager);
+        remove_db(&path);
+    }
+
+    #[test]
+    fn bad_input_is_an_error_not_a_panic() {
+        let mut small_buffer = [0u8; 4];
+        assert!(matches!(
+            PageHeader::new(1, PageType::Data).serialize(&mut small_buffer),
+            Err(DbError::BufferTooSmall { .. })
+        ));
+
+        // Key slot pointing past the end of the page
+        let mut index_page = IndexPage::new(true);
+        index_page.keys = vec![vec![1, 2, 3]];
+        index_page.child_pointers = vec![1];
+        let mut buffer = vec![0u8; index_page.calc_size()];
+        index_page.serialize(&mut buffer).unwrap();
+        let key_len_offset = IndexPage::key_slot_offset(0) + IndexPage::KEY_OFFSET_SIZE;
+        buffer[key_len_offset..key_len_offset + 4].copy_from_slice(&100u32.to_be_bytes());
+        assert!(matches!(
+            IndexPage::deserialize(&buffer),
+            Err(DbError::BufferTooSmall { .. })
+        ));
+
+        let mut header_bytes = vec![0u8; PageHeader::SIZE];
+        header_bytes[PageHeader::page_type_span()][0] = 200;
+        assert!(matches!(
+            PageHeader::deserialize(&header_bytes),
+            Err(DbError::UnknownPageType(200))
+        ));
+    }
+
+    #[test]
+    fn index_pages_store_the_prefix_their_keys_share_once() {
+        let prefix = b"tenant-0042/2024-06-01T".to_vec();
+        let index_page = IndexPage {
+            is_leaf: true,
+            next_leaf: 0,
+            prev_leaf: 0,
+            keys: (0..20u8)
+                .map(|i| [prefix.clone(), vec![b'a' + i]].concat())
+                .collect(),
+            child_pointers: (0..20).collect(),
+        };
+        assert_eq!(index_page.prefix_len(), prefix.len());
+
+        let mut buffer = vec![0u8; index_page.calc_size()];
+        index_page.serialize(&mut buffer).unwrap();
+        // The prefix shows up exactly once in the serialized page
+        let occurrences = buffer
+            .windows(prefix.len())
+            .filter(|window| *window == prefix.as_slice())
+            .count();
+        assert_eq!(occurrences, 1);
+        assert_eq!(
+            buffer.len(),
+            index_page.key_heap_offset() + prefix.len() + 20
+        );
+        assert_eq!(IndexPage::deserialize(&buffer).unwrap(), index_page);
+    }
+
+    fn serialize_then_deserialize<T: MySerialize + MyDeserialize>(value: &T) -> T {
+        let mut buffer = vec![0u8; 8192];
+        let written = value.serialize(&mut buffer).unwrap();
+        // Only hand back what was written so reading past the end gets caught
+        T::deserialize(&buffer[..written]).unwrap()
+    }
+
+    fn page_type_strategy() -> impl Strategy<Value = PageType> {
+        prop_oneof![
+            Just(PageType::Metadata),
+            Just(PageType::Data),
+            Just(PageType::Index),
+            Just(PageType::Overflow),
+            Just(PageType::FreeList),
+            Just(PageType::HashDirectory),
+            Just(PageType::HashBucket),
+            Just(PageType::FreeSpaceMap),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn page_header_round_trips(
+            page_id: u64,
+            page_type in page_type_strategy(),
+            checksum: u32,
+            lsn: u64,
+            free_space_pointer: u32,
+        ) {
+            let header = PageHeader { page_id, page_type, checksum, lsn, free_space_pointer };
+            prop_assert_eq!(serialize_then_deserialize(&header), header);
+        }
+
+        #[test]
+        fn metadata_page_round_trips(
+            db_version: u32,
+            page_size: u32,
+            root_page_id: u64,
+            first_free_list_page: u64,
+            total_pages: u64,
+            tree_is_unique: bool,
+        ) {
+            let metadata_page = MetadataPage {
+                db_version,
+                page_size,
+                root_page_id,
+                first_free_list_page,
+                total_pages,
+                tree_is_unique,
+            };
+            prop_assert_eq!(serialize_then_deserialize(&metadata_page), metadata_page);
+        }
+
+        #[test]
+        fn data_page_round_trips(
+            num_records: u32,
+            slot_array in prop::collection::vec(any::<u32>(), 0..64),
+        ) {
+            let data_page = DataPage { num_records, slot_array };
+            prop_assert_eq!(serialize_then_deserialize(&data_page), data_page);
+        }
+
+        #[test]
+        fn index_page_round_trips(
+            is_leaf: bool,
+            next_leaf: u64,
+            prev_leaf: u64,
+            shared_prefix in prop::collection::vec(any::<u8>(), 0..16),
+            keys in prop::collection::vec(prop::collection::vec(any::<u8>(), 0..24), 0..32),
+            child_pointers in prop::collection::vec(any::<u64>(), 0..33),
+        ) {
+            // Some shared prefix so prefix compression actually gets exercised
+            let keys = keys.into_iter().map(|key| [shared_prefix.clone(), key].concat()).collect();
+            let index_page = IndexPage { is_leaf, next_leaf, prev_leaf, keys, child_pointers };
+            // Size calculation has to agree with what actually gets written
+            let mut buffer = vec![0u8; 8192];
+            prop_assert_eq!(index_page.serialize(&mut buffer).unwrap(), index_page.calc_size());
+            prop_assert_eq!(serialize_then_deserialize(&index_page), index_page);
+        }
+
+        #[test]
+        fn free_list_page_round_trips(
+            next_free_list: u64,
+            free_page_ids in prop::collection::vec(any::<u64>(), 0..64),
+        ) {
+            let free_list_page = FreeListPage { next_free_list, free_page_ids };
+            prop_assert_eq!(serialize_then_deserialize(&free_list_page), free_list_page);
+        }
+    }
+
+    #[test]
+    fn created_pages_can_be_read_back_as_typed_values() {
+        let path = temp_db_path("read_page_as");
+        let manager =
+            PagedFileManager::new(&path, PagedFileManagerConfigBuilder::new().build()).unwrap();
+
+        let data_page_id = manager.create_data_page().unwrap();
+        let (header, data_page) = manager.read_page_as::<DataPage>(data_page_id).unwrap();
+        assert_eq!(header.page_id, data_page_id);
+        assert_eq!(header.page_type, PageType::Data);
+        assert_eq!(data_page, DataPage::new());
+
+        let index_page_id = manager.create_index_page(true).unwrap();
+        let (header, index_page) = manager.read_page_as::<IndexPage>(index_page_id).unwrap();
+        assert_eq!(header.page_type, PageType::Index);
+        assert_eq!(index_page, IndexPage::new(true));
+
+        let (header, metadata_page) = manager.read_page_as::<MetadataPage>(0).unwrap();
+        assert_eq!(header.page_type, PageType::Metadata);
+        assert_eq!(metadata_page.total_pages, 3);
+
+        drop(manager);
+        remove_db(&path);
+    }
 }
@@ -0,0 +1,585 @@
+use std::ops::Range;
+
+use struct_layout::StructLayout;
+
+use crate::error::{check_buffer_size, DbError, Result};
+use crate::slotted_page::{Record, SlotId};
+use crate::{
+    read_be_u32, read_be_u64, DataPage, MetadataPage, MyDeserialize, MySerialize, PageHeader,
+    PageType, PageWindow, PagedFileManager,
+};
+
+/// What a data page slot holds in place of a record that was too big to fit on the page. Points
+/// at the first page of the chain of overflow pages holding the record's bytes
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OverflowStub {
+    pub total_len: u64,
+    pub first_page_id: u64,
+}
+
+impl OverflowStub {
+    pub const SIZE: usize = 2 * size_of::<u64>();
+
+    pub fn to_bytes(&self) -> [u8; Self::SIZE] {
+        let mut bytes = [0u8; Self::SIZE];
+        bytes[..8].copy_from_slice(&self.total_len.to_be_bytes());
+        bytes[8..].copy_from_slice(&self.first_page_id.to_be_bytes());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != Self::SIZE {
+            return None;
+        }
+        Some(OverflowStub {
+            total_len: read_be_u64(&bytes[..8]),
+            first_page_id: read_be_u64(&bytes[8..]),
+        })
+    }
+}
+
+// Overflow page structure. One link in the chain holding a piece of a big record
+#[repr(C)]
+#[derive(StructLayout, Debug, Clone, PartialEq)]
+pub struct OverflowPage {
+    // 0 for the last page in the chain. Page 0 is always the metadata page so it can't be a link
+    pub next_page_id: u64,
+    pub data: Vec<u8>,
+}
+
+impl MySerialize for OverflowPage {
+    fn serialize(&self, buffer: &mut [u8]) -> Result<usize> {
+        let size_to_write = self.size();
+        check_buffer_size(buffer, size_to_write)?;
+
+        buffer[Self::next_page_id_span()].copy_from_slice(&self.next_page_id.to_be_bytes());
+        buffer[Self::data_len_span()].copy_from_slice(&(self.data.len() as u32).to_be_bytes());
+        // u8s never need padding
+        buffer[Self::DATA_FIRST_VALUE_OFFSET..size_to_write].copy_from_slice(&self.data);
+
+        Ok(size_to_write)
+    }
+}
+
+impl MyDeserialize for OverflowPage {
+    fn deserialize(buffer: &[u8]) -> Result<Self> {
+        check_buffer_size(buffer, Self::MIN_SIZE)?;
+
+        let next_page_id = read_be_u64(&buffer[Self::next_page_id_span()]);
+        let data_len = read_be_u32(&buffer[Self::data_len_span()]) as usize;
+        check_buffer_size(buffer, Self::MIN_SIZE + data_len)?;
+
+        Ok(OverflowPage {
+            next_page_id,
+            data: buffer[Self::DATA_FIRST_VALUE_OFFSET..Self::DATA_FIRST_VALUE_OFFSET + data_len]
+                .to_vec(),
+        })
+    }
+}
+
+impl OverflowPage {
+    const DATA_LEN_SIZE: usize = size_of::<u32>();
+
+    const DATA_LEN_OFFSET: usize = Self::NEXT_PAGE_ID_OFFSET + Self::NEXT_PAGE_ID_SIZE;
+    const DATA_FIRST_VALUE_OFFSET: usize = Self::DATA_LEN_OFFSET + Self::DATA_LEN_SIZE;
+
+    const MIN_SIZE: usize = Self::DATA_FIRST_VALUE_OFFSET;
+
+    fn data_len_span() -> Range<usize> {
+        Self::DATA_LEN_OFFSET..Self::DATA_LEN_OFFSET + Self::DATA_LEN_SIZE
+    }
+
+    pub fn size(&self) -> usize {
+        Self::MIN_SIZE + self.data.len()
+    }
+
+    /// How many bytes of a record fit on one overflow page
+    pub fn capacity(page_size: u32) -> usize {
+        page_size as usize - PageHeader::SIZE - Self::MIN_SIZE
+    }
+}
+
+/// A slot's record copied out of its page, so it can be followed once the page's been let go
+enum StoredRecord {
+    Inline(Vec<u8>),
+    Overflow(OverflowStub),
+    Forward,
+}
+
+impl From<Record<'_>> for StoredRecord {
+    fn from(record: Record<'_>) -> Self {
+        match record {
+            Record::Inline(bytes) => StoredRecord::Inline(bytes.to_vec()),
+            Record::Overflow(stub) => StoredRecord::Overflow(stub),
+            Record::Forward { .. } => StoredRecord::Forward,
+        }
+    }
+}
+
+impl PagedFileManager {
+    /// Writes the bytes out over as many overflow pages as needed and returns the stub pointing at
+    /// them
+    pub fn write_overflow_chain(&self, bytes: &[u8]) -> Result<OverflowStub> {
+        self.atomically(|manager| {
+            let chunks: Vec<&[u8]> = bytes
+                .chunks(OverflowPage::capacity(manager.page_size))
+                .collect();
+            let page_ids = chunks
+                .iter()
+                .map(|_| manager.allocate_page())
+                .collect::<Result<Vec<u64>>>()?;
+
+            for (i, chunk) in chunks.iter().enumerate() {
+                let overflow_page = OverflowPage {
+                    next_page_id: page_ids.get(i + 1).copied().unwrap_or(0),
+                    data: chunk.to_vec(),
+                };
+                let mut header = PageHeader::new(page_ids[i], PageType::Overflow);
+                header.free_space_pointer = (PageHeader::SIZE + overflow_page.size()) as u32;
+
+                let mut page_buffer = vec![0u8; manager.page_size as usize];
+                header.serialize(&mut page_buffer)?;
+                overflow_page.serialize(&mut page_buffer[PageHeader::SIZE..])?;
+                manager.write_page(page_ids[i], page_buffer)?;
+            }
+
+            Ok(OverflowStub {
+                total_len: bytes.len() as u64,
+                first_page_id: page_ids.first().copied().unwrap_or(0),
+            })
+        })
+    }
+
+    /// Follows the chain the stub points at and stitches the record back together
+    pub fn read_overflow_chain(&self, stub: OverflowStub) -> Result<Vec<u8>> {
+        // The length comes off disk, so it's checked against what the file could possibly hold
+        // before anything gets allocated for it
+        let total_pages = self
+            .with_window::<MetadataPage, _, _>(Self::METADATA_PAGE_ID, |metadata_page_window| {
+                Ok(metadata_page_window.read_total_pages())
+            })?;
+        let max_len = total_pages.saturating_sub(1) * OverflowPage::capacity(self.page_size) as u64;
+        if stub.total_len > max_len {
+            return Err(DbError::Corruption {
+                page_id: stub.first_page_id,
+                reason: format!(
+                    "overflow record of {} bytes is bigger than the whole file",
+                    stub.total_len
+                ),
+            });
+        }
+        // Every page but the last is full, so the length says exactly how many pages there are and
+        // how much each holds. A chain that doesn't match is corrupt, even one that loops back on
+        // itself
+        let capacity = OverflowPage::capacity(self.page_size);
+        let pages = stub.total_len.div_ceil(capacity as u64);
+        let mut bytes = Vec::with_capacity(stub.total_len as usize);
+        let mut page_id = stub.first_page_id;
+        for _ in 0..pages {
+            if page_id == 0 {
+                return Err(DbError::Corruption {
+                    page_id: stub.first_page_id,
+                    reason: format!(
+                        "overflow chain ended after {} of {} bytes",
+                        bytes.len(),
+                        stub.total_len
+                    ),
+                });
+            }
+            let overflow_page = self.read_overflow_page(page_id)?;
+            let expected = capacity.min(stub.total_len as usize - bytes.len());
+            if overflow_page.data.len() != expected {
+                return Err(DbError::Corruption {
+                    page_id,
+                    reason: format!(
+                        "overflow page holds {} bytes but should hold {expected}",
+                        overflow_page.data.len()
+                    ),
+                });
+            }
+            bytes.extend_from_slice(&overflow_page.data);
+            page_id = overflow_page.next_page_id;
+        }
+
+        if page_id != 0 {
+            return Err(DbError::Corruption {
+                page_id: stub.first_page_id,
+                reason: format!(
+                    "overflow chain holds more than the expected {} bytes",
+                    stub.total_len
+                ),
+            });
+        }
+        Ok(bytes)
+    }
+
+    /// Returns every page in the chain to the free list
+    pub fn free_overflow_chain(&self, stub: OverflowStub) -> Result<()> {
+        self.atomically(|manager| {
+            let mut page_id = stub.first_page_id;
+            let mut pages_left = stub
+                .total_len
+                .div_ceil(OverflowPage::capacity(manager.page_size) as u64);
+            while page_id != 0 && pages_left > 0 {
+                // Grab the next link before freeing, the free list is allowed to reuse the page
+                let next_page_id = manager.read_overflow_page(page_id)?.next_page_id;
+                manager.free_page(page_id)?;
+                page_id = next_page_id;
+                pages_left -= 1;
+            }
+            Ok(())
+        })
+    }
+
+    fn read_overflow_page(&self, page_id: u64) -> Result<OverflowPage> {
+        let (header, overflow_page) = self.read_page_as::<OverflowPage>(page_id)?;
+        if header.page_type != PageType::Overflow {
+            return Err(DbError::Corruption {
+                page_id,
+                reason: format!("expected an overflow page but found {:?}", header.page_type),
+            });
+        }
+        Ok(overflow_page)
+    }
+
+    //
+    // Record level API on data pages. Records too big for a data page are spilled into overflow
+    // pages and put back together on read so callers never see stubs
+    //
+
+    /// Stores the record on the data page. Returns None if the page doesn't have room, even for a
+    /// stub
+    pub fn insert_record(&self, page_id: u64, record: &[u8]) -> Result<Option<SlotId>> {
+        self.atomically(|manager| {
+            if record.len() <= DataPage::max_record_size(manager.page_size) {
+                return manager
+                    .with_data_window_mut(page_id, |window| window.insert_record(record));
+            }
+
+            // Make sure the stub has somewhere to go before writing out the whole chain
+            let stub_fits = manager
+                .with_data_window(page_id, |window| window.has_room_for(OverflowStub::SIZE))?;
+            if !stub_fits {
+                return Ok(None);
+            }
+
+            let stub = manager.write_overflow_chain(record)?;
+            let slot = manager
+                .with_data_window_mut(page_id, |window| window.insert_overflow_stub(stub))?;
+            if slot.is_none() {
+                manager.free_overflow_chain(stub)?;
+            }
+            Ok(slot)
+        })
+    }
+
+    /// Reads the record back. Forwarding stubs can't be followed from here, they're an error
+    pub fn get_record(&self, page_id: u64, slot: SlotId) -> Result<Option<Vec<u8>>> {
+        let record = self.with_data_window(page_id, |window| {
+            Ok(window.get_record(slot)?.map(StoredRecord::from))
+        })?;
+        match record {
+            None => Ok(None),
+            Some(StoredRecord::Inline(bytes)) => Ok(Some(bytes)),
+            Some(StoredRecord::Overflow(stub)) => self.read_overflow_chain(stub).map(Some),
+            Some(StoredRecord::Forward) => Err(DbError::Forwarded { page_id, slot }),
+        }
+    }
+
+    /// Replaces the record in the slot, spilling to or coming back from overflow pages as needed.
+    /// Returns false if the slot is empty or the new record doesn't fit on the page
+    pub fn update_record(&self, page_id: u64, slot: SlotId, record: &[u8]) -> Result<bool> {
+        self.atomically(|manager| {
+            let old_stub = match manager.existing_stub(page_id, slot)? {
+                Some(old_stub) => old_stub,
+                None => return Ok(false),
+            };
+
+            let updated = if record.len() <= DataPage::max_record_size(manager.page_size) {
+                manager
+                    .with_data_window_mut(page_id, |window| window.update_record(slot, record))?
+            } else {
+                let stub = manager.write_overflow_chain(record)?;
+                let updated = manager.with_data_window_mut(page_id, |window| {
+                    window.update_overflow_stub(slot, stub)
+                })?;
+                if !updated {
+                    manager.free_overflow_chain(stub)?;
+                }
+                updated
+            };
+
+            if let (true, Some(old_stub)) = (updated, old_stub) {
+                manager.free_overflow_chain(old_stub)?;
+            }
+            Ok(updated)
+        })
+    }
+
+    /// Replaces the record in the slot with a forwarding stub pointing at where it moved to,
+    /// freeing any overflow pages it was using. Returns false if the slot is empty
+    pub fn forward_record(
+        &self,
+        page_id: u64,
+        slot: SlotId,
+        to_page_id: u64,
+        to_slot: SlotId,
+    ) -> Result<bool> {
+        self.atomically(|manager| {
+            let old_stub = match manager.existing_stub(page_id, slot)? {
+                Some(old_stub) => old_stub,
+                None => return Ok(false),
+            };
+            manager.with_data_window_mut(page_id, |window| {
+                window.update_forward(slot, to_page_id, to_slot)
+            })?;
+            if let Some(old_stub) = old_stub {
+                manager.free_overflow_chain(old_stub)?;
+            }
+            Ok(true)
+        })
+    }
+
+    /// Deletes the record along with any overflow pages it was using
+    pub fn delete_record(&self, page_id: u64, slot: SlotId) -> Result<bool> {
+        self.atomically(|manager| {
+            let old_stub = match manager.existing_stub(page_id, slot)? {
+                Some(old_stub) => old_stub,
+                None => return Ok(false),
+            };
+            if !manager.with_data_window_mut(page_id, |window| window.delete_record(slot))? {
+                return Ok(false);
+            }
+            if let Some(old_stub) = old_stub {
+                manager.free_overflow_chain(old_stub)?;
+            }
+            Ok(true)
+        })
+    }
+
+    /// None if the slot is empty, Some(None) for inline records and forwarding stubs and
+    /// Some(Some(stub)) for records in overflow pages
+    fn existing_stub(&self, page_id: u64, slot: SlotId) -> Result<Option<Option<OverflowStub>>> {
+        self.with_data_window(page_id, |window| {
+            Ok(window.get_record(slot)?.map(|record| match record {
+                Record::Inline(_) | Record::Forward { .. } => None,
+                Record::Overflow(stub) => Some(stub),
+            }))
+        })
+    }
+
+    /// `with_window` for a data page, an error if the page is anything else so a wrong page id
+    /// can't have its bytes read or overwritten as slots
+    pub(crate) fn with_data_window<F, R>(&self, page_id: u64, f: F) -> Result<R>
+    where
+        F: FnOnce(&PageWindow<DataPage>) -> Result<R>,
+    {
+        self.with_window::<DataPage, _, _>(page_id, |window| {
+            Self::check_data_page(page_id, window.read_page_type()?)?;
+            f(window)
+        })
+    }
+
+    /// `with_window_mut` for a data page, see `with_data_window`
+    pub(crate) fn with_data_window_mut<F, R>(&self, page_id: u64, f: F) -> Result<R>
+    where
+        F: FnOnce(&mut PageWindow<DataPage>) -> Result<R>,
+    {
+        self.with_window_mut::<DataPage, _, _>(page_id, |window| {
+            Self::check_data_page(page_id, window.read_page_type()?)?;
+            f(window)
+        })
+    }
+
+    fn check_data_page(page_id: u64, page_type: PageType) -> Result<()> {
+        if page_type != PageType::Data {
+            return Err(DbError::Corruption {
+                page_id,
+                reason: format!("expected a data page but found {page_type:?}"),
+            });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{remove_db, temp_db_path};
+    use crate::{BTree, PagedFileManagerConfigBuilder};
+
+    fn temp_manager(name: &str) -> (std::path::PathBuf, PagedFileManager) {
+        let path = temp_db_path(name);
+        let config = PagedFileManagerConfigBuilder::new().page_size(512).build();
+        let manager = PagedFileManager::new(&path, config).unwrap();
+        (path, manager)
+    }
+
+    fn total_pages(manager: &PagedFileManager) -> u64 {
+        manager
+            .with_window::<MetadataPage, _, _>(0, |window: &PageWindow<MetadataPage>| {
+                Ok(window.read_total_pages())
+            })
+            .unwrap()
+    }
+
+    fn big_record(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[test]
+    fn big_records_round_trip_through_overflow_pages() {
+        let (path, manager) = temp_manager("overflow_round_trip");
+        let data_page_id = manager.create_data_page().unwrap();
+
+        let small = manager
+            .insert_record(data_page_id, b"small")
+            .unwrap()
+            .unwrap();
+        let big = big_record(5000);
+        let big_slot = manager.insert_record(data_page_id, &big).unwrap().unwrap();
+
+        assert_eq!(
+            manager.get_record(data_page_id, small).unwrap().unwrap(),
+            b"small"
+        );
+        assert_eq!(
+            manager.get_record(data_page_id, big_slot).unwrap().unwrap(),
+            big
+        );
+
+        // Shrinking back to inline hands the overflow pages to the free list so the next big
+        // record reuses them rather than growing the file
+        let pages_with_chain = total_pages(&manager);
+        assert!(manager
+            .update_record(data_page_id, big_slot, b"tiny")
+            .unwrap());
+        assert_eq!(
+            manager.get_record(data_page_id, big_slot).unwrap().unwrap(),
+            b"tiny"
+        );
+        assert!(manager.update_record(data_page_id, big_slot, &big).unwrap());
+        assert_eq!(total_pages(&manager), pages_with_chain);
+        assert_eq!(
+            manager.get_record(data_page_id, big_slot).unwrap().unwrap(),
+            big
+        );
+
+        assert!(manager.delete_record(data_page_id, big_slot).unwrap());
+        assert_eq!(manager.get_record(data_page_id, big_slot).unwrap(), None);
+        let other_slot = manager.insert_record(data_page_id, &big).unwrap().unwrap();
+        assert_eq!(total_pages(&manager), pages_with_chain);
+        assert_eq!(
+            manager
+                .get_record(data_page_id, other_slot)
+                .unwrap()
+                .unwrap(),
+            big
+        );
+
+        drop(manager);
+        remove_db(&path);
+    }
+
+    #[test]
+    fn broken_chain_is_reported_as_corruption() {
+        let (path, manager) = temp_manager("overflow_broken_chain");
+        let stub = manager.write_overflow_chain(&big_record(2000)).unwrap();
+
+        // Point the stub somewhere that isn't an overflow page
+        let data_page_id = manager.create_data_page().unwrap();
+        let bad_stub = OverflowStub {
+            first_page_id: data_page_id,
+            ..stub
+        };
+        assert!(matches!(
+            manager.read_overflow_chain(bad_stub),
+            Err(DbError::Corruption { .. })
+        ));
+
+        // Or claim the record is longer than the chain
+        let long_stub = OverflowStub {
+            total_len: stub.total_len + 10_000,
+            ..stub
+        };
+        assert!(matches!(
+            manager.read_overflow_chain(long_stub),
+            Err(DbError::Corruption { .. })
+        ));
+
+        // A length no file could hold is an error rather than a huge allocation
+        let huge_stub = OverflowStub {
+            total_len: u64::MAX,
+            ..stub
+        };
+        assert!(matches!(
+            manager.read_overflow_chain(huge_stub),
+            Err(DbError::Corruption { .. })
+        ));
+
+        // A chain of empty pages that loops back on itself ends rather than going round forever
+        let looped_page_id = manager.allocate_page().unwrap();
+        let looped_page = OverflowPage {
+            next_page_id: looped_page_id,
+            data: Vec::new(),
+        };
+        let mut page_buffer = vec![0u8; manager.page_size as usize];
+        PageHeader::new(looped_page_id, PageType::Overflow)
+            .serialize(&mut page_buffer)
+            .unwrap();
+        looped_page
+            .serialize(&mut page_buffer[PageHeader::SIZE..])
+            .unwrap();
+        manager.write_page(looped_page_id, page_buffer).unwrap();
+        let looped_stub = OverflowStub {
+            total_len: 10,
+            first_page_id: looped_page_id,
+        };
+        assert!(matches!(
+            manager.read_overflow_chain(looped_stub),
+            Err(DbError::Corruption { .. })
+        ));
+
+        drop(manager);
+        remove_db(&path);
+    }
+
+    #[test]
+    fn records_are_only_read_and_written_on_data_pages() {
+        let (path, manager) = temp_manager("records_on_data_pages_only");
+        let tree = BTree::open(&manager).unwrap();
+        tree.insert(b"key", 1).unwrap();
+        let root_page_id = tree.root_page_id().unwrap();
+        let root_before = manager.read_page(root_page_id).unwrap();
+
+        let not_a_data_page = |result: Result<_>| matches!(result, Err(DbError::Corruption { page_id, .. }) if page_id == root_page_id);
+        assert!(not_a_data_page(
+            manager.insert_record(root_page_id, b"record").map(|_| ())
+        ));
+        assert!(not_a_data_page(
+            manager
+                .insert_record(root_page_id, &big_record(5000))
+                .map(|_| ())
+        ));
+        assert!(not_a_data_page(
+            manager.get_record(root_page_id, 0).map(|_| ())
+        ));
+        assert!(not_a_data_page(
+            manager
+                .update_record(root_page_id, 0, b"record")
+                .map(|_| ())
+        ));
+        assert!(not_a_data_page(
+            manager.forward_record(root_page_id, 0, 1, 0).map(|_| ())
+        ));
+        assert!(not_a_data_page(
+            manager.delete_record(root_page_id, 0).map(|_| ())
+        ));
+
+        assert_eq!(manager.read_page(root_page_id).unwrap(), root_before);
+        assert_eq!(tree.get(b"key").unwrap(), Some(1));
+
+        drop(manager);
+        remove_db(&path);
+    }
+}
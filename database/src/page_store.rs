@@ -0,0 +1,344 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use memmap2::{MmapMut, MmapOptions};
+
+use crate::error::Result;
+
+/// Whatever the pages (and the write-ahead log) actually live in. Everything above this only deals
+/// in byte offsets so the same page logic runs the same over a file, plain memory or an mmap
+pub trait PageStore: Send {
+    /// Number of bytes currently in the store
+    fn len(&self) -> Result<u64>;
+
+    fn is_empty(&self) -> Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Fills the buffer with the bytes starting at `offset`. Reading past the end of the store is
+    /// an `UnexpectedEof` io error, same as a short file read
+    fn read_at(&mut self, offset: u64, buffer: &mut [u8]) -> Result<()>;
+
+    /// Writes the bytes starting at `offset`, growing the store if they go past the end. Any gap
+    /// left between the old end and `offset` reads as zeros
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> Result<()>;
+
+    /// Grows (with zeros) or shrinks the store to exactly `len` bytes
+    fn set_len(&mut self, len: u64) -> Result<()>;
+
+    /// Makes everything written so far durable. A no-op for stores with nothing to be durable on
+    fn sync(&mut self) -> Result<()>;
+}
+
+/// A store shared between the `PagedFileManager` and its buffer pool
+pub type SharedPageStore = Arc<Mutex<Box<dyn PageStore>>>;
+
+/// Which `PageStore` a `PagedFileManager` should use
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum StorageBackend {
+    /// Plain seek + read/write on the file
+    #[default]
+    File,
+    /// Nothing ever touches disk and everything is gone once the manager is dropped. Meant for
+    /// tests
+    Memory,
+    /// The file is memory mapped. The write-ahead log still goes through a plain file since it's
+    /// only ever appended to
+    Mmap,
+}
+
+impl StorageBackend {
+    /// Opens (creating if needed) the store holding the pages of the database at `path`
+    pub fn open_pages(self, path: &Path) -> Result<Box<dyn PageStore>> {
+        Ok(match self {
+            StorageBackend::File => Box::new(FileStore::open(path)?),
+            StorageBackend::Memory => Box::new(MemoryStore::new()),
+            StorageBackend::Mmap => Box::new(MmapStore::open(path)?),
+        })
+    }
+
+    /// Opens (creating if needed) the store holding the write-ahead log at `path`
+    pub fn open_log(self, path: &Path) -> Result<Box<dyn PageStore>> {
+        Ok(match self {
+            StorageBackend::File | StorageBackend::Mmap => Box::new(FileStore::open(path)?),
+            StorageBackend::Memory => Box::new(MemoryStore::new()),
+        })
+    }
+}
+
+fn open_file(path: &Path) -> io::Result<File> {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+}
+
+fn unexpected_eof(offset: u64, len: usize, store_len: u64) -> io::Error {
+    io::Error::new(
+        ErrorKind::UnexpectedEof,
+        format!("read of {len} bytes at {offset} is past the end of the {store_len} byte store"),
+    )
+}
+
+pub struct FileStore {
+    file: File,
+}
+
+impl FileStore {
+    pub fn open(path: &Path) -> Result<Self> {
+        Ok(FileStore {
+            file: open_file(path)?,
+        })
+    }
+}
+
+impl PageStore for FileStore {
+    fn len(&self) -> Result<u64> {
+        Ok(self.file.metadata()?.len())
+    }
+
+    fn read_at(&mut self, offset: u64, buffer: &mut [u8]) -> Result<()> {
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.read_exact(buffer)?;
+        Ok(())
+    }
+
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> Result<()> {
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.write_all(data)?;
+        Ok(())
+    }
+
+    fn set_len(&mut self, len: u64) -> Result<()> {
+        self.file.set_len(len)?;
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        self.file.sync_all()?;
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+pub struct MemoryStore {
+    bytes: Vec<u8>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl PageStore for MemoryStore {
+    fn len(&self) -> Result<u64> {
+        Ok(self.bytes.len() as u64)
+    }
+
+    fn read_at(&mut self, offset: u64, buffer: &mut [u8]) -> Result<()> {
+        let start = offset as usize;
+        let bytes = self
+            .bytes
+            .get(start..start + buffer.len())
+            .ok_or_else(|| unexpected_eof(offset, buffer.len(), self.bytes.len() as u64))?;
+        buffer.copy_from_slice(bytes);
+        Ok(())
+    }
+
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> Result<()> {
+        let start = offset as usize;
+        if self.bytes.len() < start + data.len() {
+            self.bytes.resize(start + data.len(), 0);
+        }
+        self.bytes[start..start + data.len()].copy_from_slice(data);
+        Ok(())
+    }
+
+    fn set_len(&mut self, len: u64) -> Result<()> {
+        self.bytes.resize(len as usize, 0);
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Keeps the whole file mapped into memory. Growing the mapping means remapping it, so the mapping
+/// grows by doubling and can run past the end of the file. Only the file itself grows with every
+/// write, so it's always exactly the store even if the process dies without dropping it. Nothing
+/// past the end of the file is ever touched, which would fault
+pub struct MmapStore {
+    file: File,
+    // None until something's mapped, zero length mappings aren't allowed everywhere
+    mmap: Option<MmapMut>,
+    // Length of the file, the mapping can be longer
+    len: u64,
+}
+
+impl MmapStore {
+    // Smallest the mapping grows to so the first few pages don't each remap it
+    const MIN_MAPPED_LEN: u64 = 64 * 1024;
+
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = open_file(path)?;
+        let len = file.metadata()?.len();
+        let mut store = MmapStore {
+            file,
+            mmap: None,
+            len,
+        };
+        store.reserve(len)?;
+        Ok(store)
+    }
+
+    fn mapped(&self) -> &[u8] {
+        self.mmap.as_deref().unwrap_or(&[])
+    }
+
+    /// Makes sure at least `len` bytes are mapped
+    fn reserve(&mut self, len: u64) -> Result<()> {
+        let mapped_len = self.mapped().len() as u64;
+        if len == 0 || mapped_len >= len {
+            return Ok(());
+        }
+        // Changes sitting in the old mapping have to be flushed before it goes away
+        if let Some(mmap) = self.mmap.take() {
+            mmap.flush()?;
+        }
+        let mapped_len = len.max(mapped_len * 2).max(Self::MIN_MAPPED_LEN);
+        // SAFETY: The file is only ever changed through this store while it's open. Another
+        // process changing it underneath the mapping is no different from it scribbling over the
+        // file for any other backend, except here it'd be UB rather than garbage
+        self.mmap = Some(unsafe {
+            MmapOptions::new()
+                .len(mapped_len as usize)
+                .map_mut(&self.file)?
+        });
+        Ok(())
+    }
+}
+
+impl PageStore for MmapStore {
+    fn len(&self) -> Result<u64> {
+        Ok(self.len)
+    }
+
+    fn read_at(&mut self, offset: u64, buffer: &mut [u8]) -> Result<()> {
+        let start = offset as usize;
+        let bytes = self.mapped()[..self.len as usize]
+            .get(start..start + buffer.len())
+            .ok_or_else(|| unexpected_eof(offset, buffer.len(), self.len))?;
+        buffer.copy_from_slice(bytes);
+        Ok(())
+    }
+
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> Result<()> {
+        let start = offset as usize;
+        let end = start + data.len();
+        if end as u64 > self.len {
+            self.set_len(end as u64)?;
+        }
+        if let Some(mmap) = self.mmap.as_mut() {
+            mmap[start..end].copy_from_slice(data);
+        }
+        Ok(())
+    }
+
+    fn set_len(&mut self, len: u64) -> Result<()> {
+        // Growing the file zero fills it, and whatever a shrink cuts off reads as zeros if it grows
+        // back
+        self.file.set_len(len)?;
+        self.len = len;
+        self.reserve(len)
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        if let Some(mmap) = &self.mmap {
+            mmap.flush()?;
+        }
+        self.file.sync_all()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::DbError;
+    use crate::tests::temp_db_path;
+
+    fn exercise(store: &mut dyn PageStore) {
+        assert!(store.is_empty().unwrap());
+
+        // Writing past the end grows the store and zero fills the gap
+        store.write_at(8, b"pages").unwrap();
+        assert_eq!(store.len().unwrap(), 13);
+        let mut buffer = [0xffu8; 13];
+        store.read_at(0, &mut buffer).unwrap();
+        assert_eq!(&buffer, b"\0\0\0\0\0\0\0\0pages");
+
+        store.write_at(0, b"some").unwrap();
+        let mut buffer = [0u8; 4];
+        store.read_at(0, &mut buffer).unwrap();
+        assert_eq!(&buffer, b"some");
+
+        assert!(matches!(
+            store.read_at(10, &mut [0u8; 4]),
+            Err(DbError::Io(err)) if err.kind() == ErrorKind::UnexpectedEof
+        ));
+
+        store.set_len(4).unwrap();
+        assert_eq!(store.len().unwrap(), 4);
+        store.sync().unwrap();
+    }
+
+    #[test]
+    fn every_backend_behaves_the_same() {
+        exercise(&mut MemoryStore::new());
+
+        for (name, backend) in [
+            ("store_file", StorageBackend::File),
+            ("store_mmap", StorageBackend::Mmap),
+        ] {
+            let path = temp_db_path(name);
+            exercise(backend.open_pages(&path).unwrap().as_mut());
+            // The mmap store's spare mapping doesn't show up in the file
+            assert_eq!(std::fs::metadata(&path).unwrap().len(), 4);
+
+            // Whatever was written is in the file itself
+            let mut reopened = backend.open_pages(&path).unwrap();
+            let mut buffer = [0u8; 4];
+            reopened.read_at(0, &mut buffer).unwrap();
+            assert_eq!(&buffer, b"some");
+
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn mmap_store_file_is_right_after_a_crash() {
+        let path = temp_db_path("store_mmap_crash");
+        let mut store = MmapStore::open(&path).unwrap();
+        store.write_at(0, &[7u8; 300]).unwrap();
+        store.set_len(100).unwrap();
+        store.write_at(200, b"end").unwrap();
+        // Crash: the store is never synced or dropped
+        std::mem::forget(store);
+
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 203);
+        let mut reopened = MmapStore::open(&path).unwrap();
+        let mut buffer = [0xffu8; 203];
+        reopened.read_at(0, &mut buffer).unwrap();
+        assert_eq!(buffer[..100], [7u8; 100]);
+        assert_eq!(buffer[100..200], [0u8; 100]);
+        assert_eq!(&buffer[200..], b"end");
+        drop(reopened);
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,714 @@
+use std::ops::Range;
+
+use crate::error::{DbError, Result};
+use crate::overflow::OverflowStub;
+use crate::{read_be_u32, read_be_u64, DataPage, PageHeader, PageWindow};
+
+/// Index into a data page's slot array. Stays the same for a record for as long as it lives, even
+/// if the record gets moved around inside the page
+pub type SlotId = u32;
+
+/// What a slot holds. Records too big to fit on a data page live in a chain of overflow pages and
+/// the slot only holds a stub pointing at the start of the chain. A heap record that outgrew its
+/// page leaves a forwarding stub behind pointing at the slot it moved to, see `HeapFile::update`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Record<'a> {
+    Inline(&'a [u8]),
+    Overflow(OverflowStub),
+    Forward { page_id: u64, slot: SlotId },
+}
+
+// Slotted page layout (offsets are from the start of the page):
+//
+// | header | num_records | slot_array_len | slot 0 | slot 1 | ... free space ... | records |
+//                                                            ^                  ^
+//                                                     slot array end    free_space_pointer
+//
+// The slot array grows forwards and records grow backwards from the end of the page. Each record
+// is stored as a u32 length followed by its bytes and its slot holds the offset of the length.
+// The top bit of the length is set when the bytes are an overflow stub rather than the record, the
+// next one down when they're a forwarding stub (page id then slot) and the one after that when the
+// record was moved here from another page and is only reached through its forwarding stub. Every
+// record takes up at least as much room as a forwarding stub so it can always be replaced by one.
+//
+// Deleting a record just turns its slot into a tombstone so every other SlotId stays valid. The
+// bytes it used are only reclaimed when the page gets compacted, which happens automatically once
+// an insert or update doesn't fit in the contiguous free space but would fit in the total.
+impl DataPage {
+    // Nothing can live at offset 0 because that's where the header is
+    const TOMBSTONE: u32 = 0;
+    const RECORD_LEN_SIZE: usize = size_of::<u32>();
+    // Pages are never anywhere near 512MiB so the top three bits of a record's length are free
+    const OVERFLOW_FLAG: u32 = 1 << 31;
+    const FORWARD_FLAG: u32 = 1 << 30;
+    const MOVED_FLAG: u32 = 1 << 29;
+    const FLAGS: u32 = Self::OVERFLOW_FLAG | Self::FORWARD_FLAG | Self::MOVED_FLAG;
+    const FORWARD_SIZE: usize = size_of::<u64>() + size_of::<SlotId>();
+
+    /// Largest record that fits on an empty data page of the given size
+    pub fn max_record_size(page_size: u32) -> usize {
+        (page_size as usize)
+            .saturating_sub(PageHeader::SIZE + Self::MIN_SIZE + Self::SLOT_ARRAY_VALUE_SIZE)
+            .saturating_sub(Self::RECORD_LEN_SIZE)
+    }
+
+    /// Most room a record of the given length can take up on a page, counting its length prefix
+    /// and a new slot for it
+    pub fn max_space_needed(record_len: usize) -> usize {
+        Self::RECORD_LEN_SIZE + Self::room_for(record_len) + Self::SLOT_ARRAY_VALUE_SIZE
+    }
+
+    /// Bytes a record of the given length takes up after its length prefix
+    fn room_for(record_len: usize) -> usize {
+        record_len.max(Self::FORWARD_SIZE)
+    }
+
+    fn forward_to_bytes(page_id: u64, slot: SlotId) -> [u8; Self::FORWARD_SIZE] {
+        let mut bytes = [0u8; Self::FORWARD_SIZE];
+        bytes[..8].copy_from_slice(&page_id.to_be_bytes());
+        bytes[8..].copy_from_slice(&slot.to_be_bytes());
+        bytes
+    }
+
+    fn forward_from_bytes(bytes: &[u8]) -> Option<Record<'static>> {
+        (bytes.len() == Self::FORWARD_SIZE).then(|| Record::Forward {
+            page_id: read_be_u64(&bytes[..8]),
+            slot: read_be_u32(&bytes[8..]),
+        })
+    }
+
+    fn slot_span(slot: SlotId) -> Range<usize> {
+        let start =
+            Self::SLOT_ARRAY_FIRST_VALUE_OFFSET + slot as usize * Self::SLOT_ARRAY_VALUE_SIZE;
+        start..start + Self::SLOT_ARRAY_VALUE_SIZE
+    }
+}
+
+impl<'a> PageWindow<'a, DataPage> {
+    pub fn num_records(&self) -> u32 {
+        read_be_u32(&self.page_bytes[DataPage::num_records_span()])
+    }
+
+    fn update_num_records(&mut self, num_records: u32) {
+        self.page_bytes[DataPage::num_records_span()].copy_from_slice(&num_records.to_be_bytes());
+    }
+
+    /// Number of entries in the slot array, including tombstones
+    pub fn num_slots(&self) -> u32 {
+        read_be_u32(&self.page_bytes[DataPage::slot_array_length_span()])
+    }
+
+    fn update_num_slots(&mut self, num_slots: u32) {
+        self.page_bytes[DataPage::slot_array_length_span()]
+            .copy_from_slice(&num_slots.to_be_bytes());
+    }
+
+    /// Number of slots, or a corruption error if that many don't fit on the page. Everything that
+    /// reads the slot array goes through this first so `read_slot` never runs past the page
+    fn checked_num_slots(&self) -> Result<u32> {
+        let num_slots = self.num_slots();
+        if DataPage::slot_span(num_slots).start > self.page_bytes.len() {
+            return Err(self.corruption(format!(
+                "slot array of {num_slots} slots runs past the end of the page"
+            )));
+        }
+        Ok(num_slots)
+    }
+
+    fn read_slot(&self, slot: SlotId) -> u32 {
+        read_be_u32(&self.page_bytes[DataPage::slot_span(slot)])
+    }
+
+    fn update_slot(&mut self, slot: SlotId, offset: u32) {
+        self.page_bytes[DataPage::slot_span(slot)].copy_from_slice(&offset.to_be_bytes());
+    }
+
+    fn page_len(&self) -> usize {
+        PageHeader::SIZE + self.page_bytes.len()
+    }
+
+    fn slot_array_end(&self) -> usize {
+        PageHeader::SIZE + DataPage::slot_span(self.num_slots()).start
+    }
+
+    /// Start of the record area, everything from here to the end of the page is records
+    fn records_start(&self) -> usize {
+        self.read_free_space_pointer() as usize
+    }
+
+    /// Byte range (relative to the page body) of the record stored at the given page offset,
+    /// including its length prefix and any room past its end. None if the offset or length points
+    /// outside the record area
+    fn record_span(&self, offset: u32) -> Option<Range<usize>> {
+        let offset = offset as usize;
+        if offset < self.slot_array_end() || offset + DataPage::RECORD_LEN_SIZE > self.page_len() {
+            return None;
+        }
+        let start = offset - PageHeader::SIZE;
+        let len = self.read_record_len(start);
+        let end = start + DataPage::RECORD_LEN_SIZE + DataPage::room_for(len);
+        (end <= self.page_bytes.len()).then_some(start..end)
+    }
+
+    fn read_record_len(&self, start: usize) -> usize {
+        (self.read_length_prefix(start) & !DataPage::FLAGS) as usize
+    }
+
+    fn read_flags(&self, start: usize) -> u32 {
+        self.read_length_prefix(start) & DataPage::FLAGS
+    }
+
+    fn read_length_prefix(&self, start: usize) -> u32 {
+        read_be_u32(&self.page_bytes[start..start + DataPage::RECORD_LEN_SIZE])
+    }
+
+    /// The bytes of the record whose span starts at `start`, without the length prefix
+    fn record_bytes(&self, start: usize) -> &[u8] {
+        let record_start = start + DataPage::RECORD_LEN_SIZE;
+        &self.page_bytes[record_start..record_start + self.read_record_len(start)]
+    }
+
+    fn live_slots(&self) -> Result<impl Iterator<Item = (SlotId, Range<usize>)> + '_> {
+        Ok((0..self.checked_num_slots()?).filter_map(|slot| {
+            let offset = self.read_slot(slot);
+            if offset == DataPage::TOMBSTONE {
+                return None;
+            }
+            self.record_span(offset).map(|span| (slot, span))
+        }))
+    }
+
+    /// Bytes available without compacting
+    pub fn contiguous_free_space(&self) -> usize {
+        self.records_start().saturating_sub(self.slot_array_end())
+    }
+
+    /// Bytes available if the page were compacted. Includes space left behind by deleted and
+    /// shrunk records
+    pub fn free_space(&self) -> Result<usize> {
+        let live_bytes: usize = self.live_slots()?.map(|(_, span)| span.len()).sum();
+        Ok(self
+            .page_len()
+            .saturating_sub(self.slot_array_end())
+            .saturating_sub(live_bytes))
+    }
+
+    /// Span of the record in the slot, None for tombstones and slots past the end of the array
+    fn slot_record_span(&self, slot: SlotId) -> Result<Option<Range<usize>>> {
+        if slot >= self.checked_num_slots()? {
+            return Ok(None);
+        }
+        let offset = self.read_slot(slot);
+        if offset == DataPage::TOMBSTONE {
+            return Ok(None);
+        }
+        Ok(self.record_span(offset))
+    }
+
+    pub fn get_record(&self, slot: SlotId) -> Result<Option<Record<'_>>> {
+        let Some(span) = self.slot_record_span(slot)? else {
+            return Ok(None);
+        };
+        let flags = self.read_flags(span.start);
+        let bytes = self.record_bytes(span.start);
+        Ok(if flags & DataPage::FORWARD_FLAG != 0 {
+            DataPage::forward_from_bytes(bytes)
+        } else if flags & DataPage::OVERFLOW_FLAG != 0 {
+            OverflowStub::from_bytes(bytes).map(Record::Overflow)
+        } else {
+            Some(Record::Inline(bytes))
+        })
+    }
+
+    /// Whether the record in the slot was moved here from another page, see `mark_moved`
+    pub fn is_moved(&self, slot: SlotId) -> Result<bool> {
+        Ok(self
+            .slot_record_span(slot)?
+            .is_some_and(|span| self.read_flags(span.start) & DataPage::MOVED_FLAG != 0))
+    }
+
+    /// Marks the record in the slot as moved here from another page, which has a forwarding stub
+    /// pointing at it. Stays marked through updates until it's deleted. Returns false if the slot
+    /// is empty
+    pub fn mark_moved(&mut self, slot: SlotId) -> Result<bool> {
+        let Some(span) = self.slot_record_span(slot)? else {
+            return Ok(false);
+        };
+        let prefix = self.read_length_prefix(span.start) | DataPage::MOVED_FLAG;
+        self.page_bytes[span.start..span.start + DataPage::RECORD_LEN_SIZE]
+            .copy_from_slice(&prefix.to_be_bytes());
+        Ok(true)
+    }
+
+    /// Everything wrong with the slot array and the records it points at, along with the overflow
+    /// stubs of the records that look fine. The rest of the window's methods only check enough to
+    /// not read past the page, this is for `check_integrity`
+    pub(crate) fn check_slots(&self) -> (Vec<String>, Vec<OverflowStub>) {
+        let mut problems = Vec::new();
+        let mut stubs = Vec::new();
+        let num_slots = self.num_slots();
+        let slot_array_end = PageHeader::SIZE as u64
+            + DataPage::SLOT_ARRAY_FIRST_VALUE_OFFSET as u64
+            + num_slots as u64 * DataPage::SLOT_ARRAY_VALUE_SIZE as u64;
+        if slot_array_end > self.page_len() as u64 {
+            problems.push(format!(
+                "slot array of {num_slots} slots runs past the end of the page"
+            ));
+            return (problems, stubs);
+        }
+        let records_start = self.records_start();
+        if records_start < self.slot_array_end() || records_start > self.page_len() {
+            problems.push(format!(
+                "free space pointer {records_start} isn't between the end of the slot array ({}) \
+                 and the end of the page",
+                self.slot_array_end()
+            ));
+        }
+
+        let mut spans = Vec::new();
+        for slot in 0..num_slots {
+            let offset = self.read_slot(slot);
+            if offset == DataPage::TOMBSTONE {
+                continue;
+            }
+            if (offset as usize) < records_start {
+                problems.push(format!(
+                    "slot {slot} points at offset {offset} which is before the records start"
+                ));
+                continue;
+            }
+            let Some(span) = self.record_span(offset) else {
+                problems.push(format!(
+                    "record in slot {slot} runs past the end of the page"
+                ));
+                continue;
+            };
+            let flags = self.read_flags(span.start);
+            let bytes = self.record_bytes(span.start);
+            if flags & DataPage::FORWARD_FLAG != 0 {
+                if DataPage::forward_from_bytes(bytes).is_none() {
+                    problems.push(format!(
+                        "slot {slot} is marked as a forwarding stub but isn't the size of one"
+                    ));
+                }
+            } else if flags & DataPage::OVERFLOW_FLAG != 0 {
+                match OverflowStub::from_bytes(bytes) {
+                    Some(stub) => stubs.push(stub),
+                    None => problems.push(format!(
+                        "slot {slot} is marked as an overflow stub but isn't the size of one"
+                    )),
+                }
+            }
+            spans.push((slot, span));
+        }
+
+        if spans.len() != self.num_records() as usize {
+            problems.push(format!(
+                "num_records is {} but {} slots hold records",
+                self.num_records(),
+                spans.len()
+            ));
+        }
+        spans.sort_by_key(|(_, span)| span.start);
+        for pair in spans.windows(2) {
+            let ((first_slot, first), (second_slot, second)) = (&pair[0], &pair[1]);
+            if first.end > second.start {
+                problems.push(format!(
+                    "records in slots {first_slot} and {second_slot} overlap"
+                ));
+            }
+        }
+        (problems, stubs)
+    }
+
+    /// Stores the record on the page returning the slot it lives in, or None if it doesn't fit.
+    /// Tombstoned slots are reused before the slot array is grown
+    pub fn insert_record(&mut self, record: &[u8]) -> Result<Option<SlotId>> {
+        self.insert_raw(record, 0)
+    }
+
+    /// Same as `insert_record` but for a stub pointing at a record in overflow pages
+    pub fn insert_overflow_stub(&mut self, stub: OverflowStub) -> Result<Option<SlotId>> {
+        self.insert_raw(&stub.to_bytes(), DataPage::OVERFLOW_FLAG)
+    }
+
+    /// Whether an insert of `record_len` bytes would succeed (possibly after compacting)
+    pub fn has_room_for(&self, record_len: usize) -> Result<bool> {
+        Ok(self.free_space()? >= self.space_needed(record_len, self.reusable_slot()?))
+    }
+
+    fn reusable_slot(&self) -> Result<Option<SlotId>> {
+        Ok(
+            (0..self.checked_num_slots()?)
+                .find(|&slot| self.read_slot(slot) == DataPage::TOMBSTONE),
+        )
+    }
+
+    fn space_needed(&self, record_len: usize, reusable_slot: Option<SlotId>) -> usize {
+        let slot_cost = match reusable_slot {
+            Some(_) => 0,
+            None => DataPage::SLOT_ARRAY_VALUE_SIZE,
+        };
+        DataPage::RECORD_LEN_SIZE + DataPage::room_for(record_len) + slot_cost
+    }
+
+    fn insert_raw(&mut self, record: &[u8], flags: u32) -> Result<Option<SlotId>> {
+        let reusable_slot = self.reusable_slot()?;
+        let needed = self.space_needed(record.len(), reusable_slot);
+        if self.free_space()? < needed {
+            return Ok(None);
+        }
+        if self.contiguous_free_space() < needed {
+            self.compact()?;
+        }
+
+        let offset = self.write_record_at_end(record, flags);
+        let slot = match reusable_slot {
+            Some(slot) => slot,
+            None => {
+                let slot = self.num_slots();
+                self.update_num_slots(slot + 1);
+                slot
+            }
+        };
+        self.update_slot(slot, offset);
+        self.update_num_records(self.num_records() + 1);
+
+        Ok(Some(slot))
+    }
+
+    /// Replaces the record in the slot. Records that shrink (or stay the same size) are updated in
+    /// place, anything bigger gets moved to a new spot in the page. Returns false if the slot is
+    /// empty or the new record doesn't fit, in which case the page is left unchanged
+    pub fn update_record(&mut self, slot: SlotId, record: &[u8]) -> Result<bool> {
+        self.update_raw(slot, record, 0)
+    }
+
+    /// Same as `update_record` but replaces whatever is in the slot with an overflow stub
+    pub fn update_overflow_stub(&mut self, slot: SlotId, stub: OverflowStub) -> Result<bool> {
+        self.update_raw(slot, &stub.to_bytes(), DataPage::OVERFLOW_FLAG)
+    }
+
+    /// Replaces whatever is in the slot with a forwarding stub pointing at `to_slot` on page
+    /// `to_page_id`. Always fits, every record has room for one
+    pub fn update_forward(
+        &mut self,
+        slot: SlotId,
+        to_page_id: u64,
+        to_slot: SlotId,
+    ) -> Result<bool> {
+        let forward = DataPage::forward_to_bytes(to_page_id, to_slot);
+        self.update_raw(slot, &forward, DataPage::FORWARD_FLAG)
+    }
+
+    fn update_raw(&mut self, slot: SlotId, record: &[u8], flags: u32) -> Result<bool> {
+        let old_span = match self.slot_record_span(slot)? {
+            Some(span) => span,
+            None => return Ok(false),
+        };
+        let flags = flags | self.read_flags(old_span.start) & DataPage::MOVED_FLAG;
+
+        let needed = DataPage::RECORD_LEN_SIZE + DataPage::room_for(record.len());
+        if needed <= old_span.len() {
+            self.write_record(old_span.start, record, flags);
+            return Ok(true);
+        }
+
+        // The old copy is going away so its bytes count as free
+        if self.free_space()? + old_span.len() < needed {
+            return Ok(false);
+        }
+        // Tombstone the slot first so compaction doesn't bother keeping the old copy around
+        self.update_slot(slot, DataPage::TOMBSTONE);
+        if self.contiguous_free_space() < needed {
+            self.compact()?;
+        }
+        let offset = self.write_record_at_end(record, flags);
+        self.update_slot(slot, offset);
+
+        Ok(true)
+    }
+
+    /// Removes the record leaving a tombstone in its slot. Returns false if there was nothing to
+    /// delete
+    pub fn delete_record(&mut self, slot: SlotId) -> Result<bool> {
+        if slot >= self.checked_num_slots()? {
+            return Ok(false);
+        }
+        let offset = self.read_slot(slot);
+        if offset == DataPage::TOMBSTONE {
+            return Ok(false);
+        }
+        if self.record_span(offset).is_none() {
+            return Err(self.corruption(format!(
+                "record in slot {slot} runs past the end of the page"
+            )));
+        }
+        let Some(num_records) = self.num_records().checked_sub(1) else {
+            return Err(self.corruption(format!(
+                "slot {slot} holds a record but num_records is already 0"
+            )));
+        };
+        self.update_slot(slot, DataPage::TOMBSTONE);
+        self.update_num_records(num_records);
+
+        // Trailing tombstones can be dropped entirely. Nobody can be holding on to them since
+        // there's no record behind them
+        let mut num_slots = self.num_slots();
+        while num_slots > 0 && self.read_slot(num_slots - 1) == DataPage::TOMBSTONE {
+            num_slots -= 1;
+        }
+        self.update_num_slots(num_slots);
+
+        Ok(true)
+    }
+
+    fn corruption(&self, reason: String) -> DbError {
+        DbError::Corruption {
+            page_id: self.read_page_id(),
+            reason,
+        }
+    }
+
+    /// Packs every live record against the end of the page so all free space is contiguous.
+    /// SlotIds don't change, only the offsets stored in them
+    pub fn compact(&mut self) -> Result<()> {
+        let mut live: Vec<(SlotId, Range<usize>)> = self.live_slots()?.collect();
+        // Moving the record closest to the end first means a record is never copied over one
+        // that hasn't been moved yet
+        live.sort_by_key(|(_, span)| std::cmp::Reverse(span.start));
+
+        let mut records_start = self.page_bytes.len();
+        for (slot, span) in live {
+            let new_start = records_start - span.len();
+            self.page_bytes.copy_within(span, new_start);
+            self.update_slot(slot, (PageHeader::SIZE + new_start) as u32);
+            records_start = new_start;
+        }
+        self.update_free_space_pointer((PageHeader::SIZE + records_start) as u32);
+        Ok(())
+    }
+
+    /// Writes the record just before the current start of the record area. Caller has to make
+    /// sure there's enough contiguous space
+    fn write_record_at_end(&mut self, record: &[u8], flags: u32) -> u32 {
+        let offset =
+            self.records_start() - DataPage::RECORD_LEN_SIZE - DataPage::room_for(record.len());
+        self.write_record(offset - PageHeader::SIZE, record, flags);
+        self.update_free_space_pointer(offset as u32);
+        offset as u32
+    }
+
+    /// Writes the length prefix and the record's bytes starting at `start` in the page body
+    fn write_record(&mut self, start: usize, record: &[u8], flags: u32) {
+        let len = record.len() as u32 | flags;
+        let record_start = start + DataPage::RECORD_LEN_SIZE;
+        self.page_bytes[start..record_start].copy_from_slice(&len.to_be_bytes());
+        self.page_bytes[record_start..record_start + record.len()].copy_from_slice(record);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MySerialize, PageType};
+
+    const PAGE_SIZE: u32 = 256;
+
+    fn empty_data_page() -> Vec<u8> {
+        let mut page = vec![0u8; PAGE_SIZE as usize];
+        let mut header = PageHeader::new(1, PageType::Data);
+        header.free_space_pointer = PAGE_SIZE;
+        header.serialize(&mut page).unwrap();
+        DataPage::new()
+            .serialize(&mut page[PageHeader::SIZE..])
+            .unwrap();
+        page
+    }
+
+    #[test]
+    fn insert_get_update_delete() {
+        let mut page = empty_data_page();
+        let mut window = PageWindow::<DataPage>::new(&mut page).unwrap();
+
+        let a = window.insert_record(b"hello").unwrap().unwrap();
+        let b = window.insert_record(b"world!").unwrap().unwrap();
+        assert_eq!(
+            window.get_record(a).unwrap(),
+            Some(Record::Inline(b"hello"))
+        );
+        assert_eq!(
+            window.get_record(b).unwrap(),
+            Some(Record::Inline(b"world!"))
+        );
+        assert_eq!(window.num_records(), 2);
+
+        // Shrinks in place, grows by relocating
+        assert!(window.update_record(a, b"hi").unwrap());
+        assert_eq!(window.get_record(a).unwrap(), Some(Record::Inline(b"hi")));
+        assert!(window.update_record(a, b"a much longer value").unwrap());
+        assert_eq!(
+            window.get_record(a).unwrap(),
+            Some(Record::Inline(b"a much longer value"))
+        );
+        assert_eq!(
+            window.get_record(b).unwrap(),
+            Some(Record::Inline(b"world!"))
+        );
+
+        assert!(window.delete_record(a).unwrap());
+        assert!(!window.delete_record(a).unwrap());
+        assert_eq!(window.get_record(a).unwrap(), None);
+        assert_eq!(window.num_records(), 1);
+
+        // Slot a is a tombstone (not trailing) so it gets reused
+        let c = window.insert_record(b"again").unwrap().unwrap();
+        assert_eq!(c, a);
+        assert_eq!(
+            window.get_record(b).unwrap(),
+            Some(Record::Inline(b"world!"))
+        );
+    }
+
+    #[test]
+    fn corrupt_slots_are_errors_on_delete() {
+        let mut page = empty_data_page();
+        let mut window = PageWindow::<DataPage>::new(&mut page).unwrap();
+        let a = window.insert_record(b"hello").unwrap().unwrap();
+        let b = window.insert_record(b"world").unwrap().unwrap();
+
+        // A record the count doesn't know about
+        window.update_num_records(0);
+        assert!(matches!(
+            window.delete_record(a),
+            Err(DbError::Corruption { page_id: 1, .. })
+        ));
+        window.update_num_records(2);
+
+        window.update_slot(b, PAGE_SIZE - 2);
+        assert!(matches!(
+            window.delete_record(b),
+            Err(DbError::Corruption { page_id: 1, .. })
+        ));
+        // A slot array longer than the page
+        window.update_num_slots(PAGE_SIZE);
+        assert!(matches!(
+            window.delete_record(PAGE_SIZE - 1),
+            Err(DbError::Corruption { page_id: 1, .. })
+        ));
+        assert!(window.get_record(a).is_err());
+        assert!(window.insert_record(b"again").is_err());
+        assert!(window.update_record(a, b"hi").is_err());
+        assert!(window.free_space().is_err());
+        assert!(window.compact().is_err());
+    }
+
+    #[test]
+    fn fragmented_space_is_reclaimed_by_compaction() {
+        let mut page = empty_data_page();
+        let mut window = PageWindow::<DataPage>::new(&mut page).unwrap();
+        let record = [7u8; 40];
+
+        let mut slots = Vec::new();
+        while let Some(slot) = window.insert_record(&record).unwrap() {
+            slots.push(slot);
+        }
+        assert!(slots.len() > 2);
+        assert!(window.insert_record(&record).unwrap().is_none());
+
+        // Free every other record. Contiguous space doesn't change but total free space does
+        let contiguous_before = window.contiguous_free_space();
+        for &slot in slots.iter().step_by(2) {
+            assert!(window.delete_record(slot).unwrap());
+        }
+        assert_eq!(window.contiguous_free_space(), contiguous_before);
+
+        let slot = window.insert_record(&[9u8; 60]).unwrap().unwrap();
+        assert_eq!(
+            window.get_record(slot).unwrap(),
+            Some(Record::Inline(&[9u8; 60]))
+        );
+        for &slot in slots.iter().skip(1).step_by(2) {
+            assert_eq!(
+                window.get_record(slot).unwrap(),
+                Some(Record::Inline(&record))
+            );
+        }
+    }
+
+    #[test]
+    fn records_that_dont_fit_are_rejected() {
+        let mut page = empty_data_page();
+        let mut window = PageWindow::<DataPage>::new(&mut page).unwrap();
+        let max = DataPage::max_record_size(PAGE_SIZE);
+
+        assert!(window.insert_record(&vec![1u8; max + 1]).unwrap().is_none());
+        let slot = window.insert_record(&vec![1u8; max]).unwrap().unwrap();
+        assert_eq!(window.free_space().unwrap(), 0);
+        assert!(!window.update_record(slot, &vec![1u8; max + 1]).unwrap());
+        assert!(matches!(
+            window.get_record(slot).unwrap(),
+            Some(Record::Inline(bytes)) if bytes.len() == max
+        ));
+    }
+
+    #[test]
+    fn overflow_stubs_are_kept_apart_from_inline_records() {
+        let mut page = empty_data_page();
+        let mut window = PageWindow::<DataPage>::new(&mut page).unwrap();
+        let stub = OverflowStub {
+            total_len: 10_000,
+            first_page_id: 7,
+        };
+
+        let slot = window.insert_overflow_stub(stub).unwrap().unwrap();
+        assert_eq!(
+            window.get_record(slot).unwrap(),
+            Some(Record::Overflow(stub))
+        );
+
+        assert!(window.update_record(slot, b"small now").unwrap());
+        assert_eq!(
+            window.get_record(slot).unwrap(),
+            Some(Record::Inline(b"small now"))
+        );
+        assert!(window.update_overflow_stub(slot, stub).unwrap());
+        assert_eq!(
+            window.get_record(slot).unwrap(),
+            Some(Record::Overflow(stub))
+        );
+    }
+
+    #[test]
+    fn tiny_records_leave_room_for_a_forwarding_stub() {
+        let mut page = empty_data_page();
+        let mut window = PageWindow::<DataPage>::new(&mut page).unwrap();
+        let mut slots = Vec::new();
+        while let Some(slot) = window.insert_record(b"x").unwrap() {
+            slots.push(slot);
+        }
+
+        assert!(window.update_forward(slots[0], 9, 3).unwrap());
+        assert_eq!(
+            window.get_record(slots[0]).unwrap(),
+            Some(Record::Forward {
+                page_id: 9,
+                slot: 3
+            })
+        );
+        assert_eq!(
+            window.get_record(slots[1]).unwrap(),
+            Some(Record::Inline(b"x"))
+        );
+
+        assert!(!window.is_moved(slots[1]).unwrap());
+        assert!(window.mark_moved(slots[1]).unwrap());
+        assert!(window.update_record(slots[1], b"y").unwrap());
+        assert!(window.is_moved(slots[1]).unwrap());
+        assert_eq!(
+            window.get_record(slots[1]).unwrap(),
+            Some(Record::Inline(b"y"))
+        );
+        assert!(window.check_slots().0.is_empty());
+    }
+}
@@ -0,0 +1,613 @@
+use std::mem;
+
+use crate::error::{DbError, Result};
+use crate::key_codec::KeyValue;
+use crate::padding_needed_from_type;
+
+// Turns a row of values into the bytes stored in a data page slot, and reads columns back out of
+// those bytes without copying them. Every offset is from the start of the tuple:
+//
+// | num_columns | null bitmap | fixed-width columns | var ends | var data |
+//
+// - num_columns: u16, so bytes written with a different schema are caught rather than misread
+// - null bitmap: one bit per column, lowest bit of the first byte is column 0. Set means null
+// - fixed-width columns: Bool, Int, UInt and Float columns in schema order. Each is aligned for
+//   its type the same way page fields are, a null one still takes up its space (as zeroes)
+// - var ends: a u32 per Str/Bytes column, in schema order, of where its bytes end. Each starts
+//   where the one before it ends, the first at the start of the var data. Null ones are empty
+// - var data: the Str/Bytes columns' bytes one after the other
+//
+// Everything but the var data is the same size for every tuple of a schema so where a column
+// lives is worked out once, when the schema is made. Numbers are big-endian like everywhere else.
+//
+// Rows are the same `KeyValue`s the key codec takes so a row's columns can go straight into a key.
+
+/// What a column of a table holds. `Str` is TEXT and `Bytes` is BLOB
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColumnType {
+    Bool,
+    Int,
+    UInt,
+    Float,
+    Bytes,
+    Str,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Column {
+    pub name: String,
+    pub column_type: ColumnType,
+    pub nullable: bool,
+}
+
+impl Column {
+    pub fn new(name: &str, column_type: ColumnType) -> Self {
+        Column {
+            name: name.to_string(),
+            column_type,
+            nullable: false,
+        }
+    }
+
+    pub fn nullable(name: &str, column_type: ColumnType) -> Self {
+        Column {
+            nullable: true,
+            ..Column::new(name, column_type)
+        }
+    }
+}
+
+/// Where a column's value lives in the tuple
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ColumnSlot {
+    /// Offset of the value
+    Fixed(usize),
+    /// Position among the var columns
+    Var(usize),
+}
+
+/// The columns of a table, in order, and where each one goes in a tuple
+#[derive(Debug, Clone, PartialEq)]
+pub struct Schema {
+    columns: Vec<Column>,
+    slots: Vec<ColumnSlot>,
+    var_ends_offset: usize,
+    var_data_offset: usize,
+}
+
+impl Schema {
+    const NUM_COLUMNS_SIZE: usize = mem::size_of::<u16>();
+    const NULL_BITMAP_OFFSET: usize = Self::NUM_COLUMNS_SIZE;
+    const VAR_END_SIZE: usize = mem::size_of::<u32>();
+
+    /// Panics with more than `u16::MAX` columns
+    pub fn new(columns: Vec<Column>) -> Self {
+        assert!(
+            columns.len() <= u16::MAX as usize,
+            "a schema can have at most {} columns",
+            u16::MAX
+        );
+        let mut offset = Self::NULL_BITMAP_OFFSET + columns.len().div_ceil(8);
+        let mut num_var = 0;
+        let slots = columns
+            .iter()
+            .map(|column| {
+                let (padding, size) = match column.column_type {
+                    ColumnType::Bool => (padding_needed_from_type::<u8>(offset), 1),
+                    ColumnType::Int => (padding_needed_from_type::<i64>(offset), 8),
+                    ColumnType::UInt => (padding_needed_from_type::<u64>(offset), 8),
+                    ColumnType::Float => (padding_needed_from_type::<f64>(offset), 8),
+                    ColumnType::Bytes | ColumnType::Str => {
+                        num_var += 1;
+                        return ColumnSlot::Var(num_var - 1);
+                    }
+                };
+                offset += padding;
+                let slot = ColumnSlot::Fixed(offset);
+                offset += size;
+                slot
+            })
+            .collect();
+        let var_ends_offset = offset + padding_needed_from_type::<u32>(offset);
+        Schema {
+            columns,
+            slots,
+            var_ends_offset,
+            var_data_offset: var_ends_offset + num_var * Self::VAR_END_SIZE,
+        }
+    }
+
+    pub fn columns(&self) -> &[Column] {
+        &self.columns
+    }
+
+    /// Position of the column with the given name
+    pub fn column_index(&self, name: &str) -> Option<usize> {
+        self.columns.iter().position(|column| column.name == name)
+    }
+
+    /// Size of a tuple with every Str/Bytes column empty
+    pub fn min_tuple_size(&self) -> usize {
+        self.var_data_offset
+    }
+
+    /// Encodes a row, one value per column. Values have to match their column's type, or be
+    /// `Null` if the column is nullable
+    pub fn encode(&self, row: &[KeyValue]) -> Result<Vec<u8>> {
+        if row.len() != self.columns.len() {
+            return Err(DbError::SchemaMismatch(format!(
+                "row has {} values but the schema has {} columns",
+                row.len(),
+                self.columns.len()
+            )));
+        }
+        let mut tuple = vec![0u8; self.var_data_offset];
+        tuple[..Self::NUM_COLUMNS_SIZE].copy_from_slice(&(row.len() as u16).to_be_bytes());
+
+        for (index, value) in row.iter().enumerate() {
+            let column = &self.columns[index];
+            let bytes: &[u8] = match (column.column_type, value, self.slots[index]) {
+                (_, KeyValue::Null, _) if column.nullable => {
+                    tuple[Self::NULL_BITMAP_OFFSET + index / 8] |= 1 << (index % 8);
+                    &[]
+                }
+                (ColumnType::Bool, KeyValue::Bool(value), ColumnSlot::Fixed(offset)) => {
+                    tuple[offset] = *value as u8;
+                    continue;
+                }
+                (ColumnType::Int, KeyValue::Int(value), ColumnSlot::Fixed(offset)) => {
+                    tuple[offset..offset + 8].copy_from_slice(&value.to_be_bytes());
+                    continue;
+                }
+                (ColumnType::UInt, KeyValue::UInt(value), ColumnSlot::Fixed(offset)) => {
+                    tuple[offset..offset + 8].copy_from_slice(&value.to_be_bytes());
+                    continue;
+                }
+                (ColumnType::Float, KeyValue::Float(value), ColumnSlot::Fixed(offset)) => {
+                    tuple[offset..offset + 8].copy_from_slice(&value.to_be_bytes());
+                    continue;
+                }
+                (ColumnType::Bytes, KeyValue::Bytes(value), _) => value,
+                (ColumnType::Str, KeyValue::Str(value), _) => value.as_bytes(),
+                _ => {
+                    return Err(DbError::SchemaMismatch(format!(
+                        "column {:?} is a {}{:?} column but was given {value:?}",
+                        column.name,
+                        if column.nullable { "nullable " } else { "" },
+                        column.column_type
+                    )))
+                }
+            };
+            // Null fixed-width columns are left as zeroes, var ones still need their end written
+            if let ColumnSlot::Var(var_index) = self.slots[index] {
+                tuple.extend_from_slice(bytes);
+                let end = u32::try_from(tuple.len()).map_err(|_| {
+                    DbError::SchemaMismatch(format!("row is over {} bytes", u32::MAX))
+                })?;
+                let end_offset = self.var_ends_offset + var_index * Self::VAR_END_SIZE;
+                tuple[end_offset..end_offset + Self::VAR_END_SIZE]
+                    .copy_from_slice(&end.to_be_bytes());
+            }
+        }
+        Ok(tuple)
+    }
+
+    /// Decodes every column of a tuple made by `encode`
+    pub fn decode(&self, tuple: &[u8]) -> Result<Vec<KeyValue>> {
+        let tuple = self.read(tuple)?;
+        (0..self.columns.len())
+            .map(|index| tuple.value(index))
+            .collect()
+    }
+
+    /// A view of a tuple made by `encode` that reads columns straight out of its bytes. The
+    /// tuple's layout and null bitmap are checked up front so reading its columns can't go out of
+    /// bounds or find a null where there can't be one
+    pub fn read<'a>(&'a self, tuple: &'a [u8]) -> Result<TupleRef<'a>> {
+        if tuple.len() < self.var_data_offset {
+            return Err(DbError::InvalidTuple(format!(
+                "tuple is {} bytes but the schema needs at least {}",
+                tuple.len(),
+                self.var_data_offset
+            )));
+        }
+        let num_columns = u16::from_be_bytes([tuple[0], tuple[1]]) as usize;
+        if num_columns != self.columns.len() {
+            return Err(DbError::InvalidTuple(format!(
+                "tuple has {num_columns} columns but the schema has {}",
+                self.columns.len()
+            )));
+        }
+        // The last byte of the bitmap is padded out with bits `encode` never sets
+        let unused_bits = num_columns % 8;
+        if unused_bits != 0 && tuple[Self::NULL_BITMAP_OFFSET + num_columns / 8] >> unused_bits != 0
+        {
+            return Err(DbError::InvalidTuple(format!(
+                "null bitmap has bits set past its {num_columns} columns"
+            )));
+        }
+        let tuple = TupleRef {
+            schema: self,
+            bytes: tuple,
+        };
+        let mut start = self.var_data_offset;
+        for (index, slot) in self.slots.iter().enumerate() {
+            let column = &self.columns[index];
+            let is_null = tuple.null_bit(index);
+            if is_null && !column.nullable {
+                return Err(DbError::InvalidTuple(format!(
+                    "column {:?} is null but isn't nullable",
+                    column.name
+                )));
+            }
+            if let ColumnSlot::Var(var_index) = *slot {
+                let end = tuple.var_end(var_index);
+                if end < start || end > tuple.bytes.len() {
+                    return Err(DbError::InvalidTuple(format!(
+                        "column {:?} ends at {end} which isn't between {start} and the end of the \
+                         tuple",
+                        column.name
+                    )));
+                }
+                if is_null && end != start {
+                    return Err(DbError::InvalidTuple(format!(
+                        "column {:?} is null but has {} bytes",
+                        column.name,
+                        end - start
+                    )));
+                }
+                start = end;
+            }
+        }
+        if start != tuple.bytes.len() {
+            return Err(DbError::InvalidTuple(format!(
+                "{} bytes left over after the last column",
+                tuple.bytes.len() - start
+            )));
+        }
+        Ok(tuple)
+    }
+}
+
+/// Columns of a tuple, read straight from its bytes. Made by `Schema::read`
+#[derive(Debug, Clone, Copy)]
+pub struct TupleRef<'a> {
+    schema: &'a Schema,
+    bytes: &'a [u8],
+}
+
+impl<'a> TupleRef<'a> {
+    pub fn is_null(&self, column: usize) -> Result<bool> {
+        self.column(column)?;
+        Ok(self.null_bit(column))
+    }
+
+    pub fn get_bool(&self, column: usize) -> Result<Option<bool>> {
+        Ok(self
+            .fixed(column, ColumnType::Bool)?
+            .map(|offset| self.bytes[offset] != 0))
+    }
+
+    pub fn get_int(&self, column: usize) -> Result<Option<i64>> {
+        Ok(self
+            .fixed(column, ColumnType::Int)?
+            .map(|offset| i64::from_be_bytes(self.eight_bytes(offset))))
+    }
+
+    pub fn get_uint(&self, column: usize) -> Result<Option<u64>> {
+        Ok(self
+            .fixed(column, ColumnType::UInt)?
+            .map(|offset| u64::from_be_bytes(self.eight_bytes(offset))))
+    }
+
+    pub fn get_float(&self, column: usize) -> Result<Option<f64>> {
+        Ok(self
+            .fixed(column, ColumnType::Float)?
+            .map(|offset| f64::from_be_bytes(self.eight_bytes(offset))))
+    }
+
+    pub fn get_bytes(&self, column: usize) -> Result<Option<&'a [u8]>> {
+        self.var(column, ColumnType::Bytes)
+    }
+
+    pub fn get_str(&self, column: usize) -> Result<Option<&'a str>> {
+        self.var(column, ColumnType::Str)?
+            .map(|bytes| {
+                std::str::from_utf8(bytes).map_err(|_| {
+                    DbError::InvalidTuple(format!(
+                        "column {:?} isn't utf-8",
+                        self.schema.columns[column].name
+                    ))
+                })
+            })
+            .transpose()
+    }
+
+    /// Copies the column out as whatever `KeyValue` its type is
+    pub fn value(&self, column: usize) -> Result<KeyValue> {
+        let column_type = self.column(column)?.column_type;
+        let value = match column_type {
+            ColumnType::Bool => self.get_bool(column)?.map(KeyValue::Bool),
+            ColumnType::Int => self.get_int(column)?.map(KeyValue::Int),
+            ColumnType::UInt => self.get_uint(column)?.map(KeyValue::UInt),
+            ColumnType::Float => self.get_float(column)?.map(KeyValue::Float),
+            ColumnType::Bytes => self.get_bytes(column)?.map(KeyValue::from),
+            ColumnType::Str => self.get_str(column)?.map(KeyValue::from),
+        };
+        Ok(value.unwrap_or(KeyValue::Null))
+    }
+
+    fn column(&self, column: usize) -> Result<&'a Column> {
+        self.schema.columns.get(column).ok_or_else(|| {
+            DbError::SchemaMismatch(format!(
+                "no column {column}, the schema has {}",
+                self.schema.columns.len()
+            ))
+        })
+    }
+
+    fn check_type(&self, column: usize, column_type: ColumnType) -> Result<()> {
+        let found = self.column(column)?;
+        if found.column_type != column_type {
+            return Err(DbError::SchemaMismatch(format!(
+                "column {:?} is a {:?} column, not {column_type:?}",
+                found.name, found.column_type
+            )));
+        }
+        Ok(())
+    }
+
+    /// Offset of a fixed-width column's value, None if it's null
+    fn fixed(&self, column: usize, column_type: ColumnType) -> Result<Option<usize>> {
+        self.check_type(column, column_type)?;
+        match self.schema.slots[column] {
+            ColumnSlot::Fixed(offset) => Ok((!self.null_bit(column)).then_some(offset)),
+            ColumnSlot::Var(_) => unreachable!("only Str and Bytes columns are var columns"),
+        }
+    }
+
+    fn var(&self, column: usize, column_type: ColumnType) -> Result<Option<&'a [u8]>> {
+        self.check_type(column, column_type)?;
+        let ColumnSlot::Var(var_index) = self.schema.slots[column] else {
+            unreachable!("Str and Bytes columns are always var columns");
+        };
+        if self.null_bit(column) {
+            return Ok(None);
+        }
+        let start = match var_index {
+            0 => self.schema.var_data_offset,
+            _ => self.var_end(var_index - 1),
+        };
+        Ok(Some(&self.bytes[start..self.var_end(var_index)]))
+    }
+
+    /// Whether the column's bit is set in the null bitmap, the column has to exist
+    fn null_bit(&self, column: usize) -> bool {
+        self.bytes[Schema::NULL_BITMAP_OFFSET + column / 8] & (1 << (column % 8)) != 0
+    }
+
+    fn var_end(&self, var_index: usize) -> usize {
+        let offset = self.schema.var_ends_offset + var_index * Schema::VAR_END_SIZE;
+        let mut end = [0u8; Schema::VAR_END_SIZE];
+        end.copy_from_slice(&self.bytes[offset..offset + Schema::VAR_END_SIZE]);
+        u32::from_be_bytes(end) as usize
+    }
+
+    fn eight_bytes(&self, offset: usize) -> [u8; 8] {
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(&self.bytes[offset..offset + 8]);
+        bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::memory_manager;
+    use crate::Record;
+    use proptest::prelude::*;
+
+    fn people() -> Schema {
+        Schema::new(vec![
+            Column::new("id", ColumnType::UInt),
+            Column::nullable("name", ColumnType::Str),
+            Column::new("active", ColumnType::Bool),
+            Column::nullable("score", ColumnType::Float),
+            Column::new("balance", ColumnType::Int),
+            Column::nullable("photo", ColumnType::Bytes),
+        ])
+    }
+
+    #[test]
+    fn fixed_columns_are_aligned_after_the_null_bitmap() {
+        let schema = people();
+        // num_columns, one byte of bitmap, padding to 8 for id
+        assert_eq!(
+            schema.slots,
+            vec![
+                ColumnSlot::Fixed(8),
+                ColumnSlot::Var(0),
+                ColumnSlot::Fixed(16),
+                ColumnSlot::Fixed(24),
+                ColumnSlot::Fixed(32),
+                ColumnSlot::Var(1),
+            ]
+        );
+        assert_eq!(schema.min_tuple_size(), 48);
+    }
+
+    #[test]
+    fn columns_are_read_in_place_from_a_data_page() {
+        let manager = memory_manager(4096);
+        let schema = people();
+        let row = vec![
+            KeyValue::UInt(7),
+            "ada".into(),
+            KeyValue::Bool(true),
+            KeyValue::Null,
+            KeyValue::Int(-42),
+            KeyValue::Bytes(vec![1, 2, 3]),
+        ];
+        let page_id = manager.create_data_page().unwrap();
+        let slot = manager
+            .insert_record(page_id, &schema.encode(&row).unwrap())
+            .unwrap()
+            .unwrap();
+
+        manager
+            .with_window(page_id, |window| {
+                let Some(Record::Inline(bytes)) = window.get_record(slot)? else {
+                    panic!("record should be inline");
+                };
+                let tuple = schema.read(bytes)?;
+                assert_eq!(tuple.get_uint(0)?, Some(7));
+                assert_eq!(tuple.get_str(1)?, Some("ada"));
+                assert_eq!(tuple.get_bool(2)?, Some(true));
+                assert_eq!(tuple.get_float(3)?, None);
+                assert_eq!(tuple.get_int(4)?, Some(-42));
+                assert_eq!(tuple.get_bytes(5)?, Some(&[1u8, 2, 3][..]));
+                assert!(matches!(tuple.get_int(0), Err(DbError::SchemaMismatch(_))));
+                assert!(matches!(tuple.value(6), Err(DbError::SchemaMismatch(_))));
+                Ok(())
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn rows_and_tuples_that_dont_fit_the_schema_are_errors() {
+        let schema = people();
+        let row = |id: KeyValue, active: KeyValue| {
+            vec![
+                id,
+                KeyValue::Null,
+                active,
+                KeyValue::Null,
+                KeyValue::Int(0),
+                KeyValue::Null,
+            ]
+        };
+        assert!(matches!(
+            schema.encode(&row(KeyValue::Int(1), KeyValue::Bool(false))),
+            Err(DbError::SchemaMismatch(_))
+        ));
+        assert!(matches!(
+            schema.encode(&row(KeyValue::UInt(1), KeyValue::Null)),
+            Err(DbError::SchemaMismatch(_))
+        ));
+        assert!(matches!(
+            schema.encode(&[KeyValue::UInt(1)]),
+            Err(DbError::SchemaMismatch(_))
+        ));
+
+        let tuple = schema
+            .encode(&row(KeyValue::UInt(1), KeyValue::Bool(false)))
+            .unwrap();
+        assert!(matches!(
+            schema.read(&tuple[..tuple.len() - 1]),
+            Err(DbError::InvalidTuple(_))
+        ));
+        let mut extra = tuple.clone();
+        extra.push(0);
+        assert!(matches!(schema.read(&extra), Err(DbError::InvalidTuple(_))));
+        // Point the name column's end past the end of the tuple
+        let mut bad_end = tuple.clone();
+        bad_end[40..44].copy_from_slice(&1000u32.to_be_bytes());
+        assert!(matches!(
+            schema.read(&bad_end),
+            Err(DbError::InvalidTuple(_))
+        ));
+        let other = Schema::new(vec![Column::new("id", ColumnType::UInt)]);
+        assert!(matches!(other.read(&tuple), Err(DbError::InvalidTuple(_))));
+
+        // Null bits on the id column, and on the name column while it still has bytes
+        let mut null_id = tuple.clone();
+        null_id[2] |= 1;
+        assert!(matches!(
+            schema.read(&null_id),
+            Err(DbError::InvalidTuple(_))
+        ));
+        let named = schema
+            .encode(&[
+                KeyValue::UInt(1),
+                "ada".into(),
+                KeyValue::Bool(false),
+                KeyValue::Null,
+                KeyValue::Int(0),
+                KeyValue::Null,
+            ])
+            .unwrap();
+        let mut null_name = named.clone();
+        null_name[2] |= 1 << 1;
+        assert!(matches!(
+            schema.read(&null_name),
+            Err(DbError::InvalidTuple(_))
+        ));
+
+        // A null bit past the last column
+        let mut null_padding = tuple.clone();
+        null_padding[2] |= 1 << 6;
+        assert!(matches!(
+            schema.read(&null_padding),
+            Err(DbError::InvalidTuple(_))
+        ));
+
+        let tuple = schema.read(&tuple).unwrap();
+        assert!(tuple.is_null(1).unwrap());
+        assert!(!tuple.is_null(2).unwrap());
+        assert!(matches!(tuple.is_null(6), Err(DbError::SchemaMismatch(_))));
+    }
+
+    fn value_strategy(column: &Column) -> BoxedStrategy<KeyValue> {
+        let value = match column.column_type {
+            ColumnType::Bool => any::<bool>().prop_map(KeyValue::Bool).boxed(),
+            ColumnType::Int => any::<i64>().prop_map(KeyValue::Int).boxed(),
+            ColumnType::UInt => any::<u64>().prop_map(KeyValue::UInt).boxed(),
+            ColumnType::Float => any::<f64>().prop_map(KeyValue::Float).boxed(),
+            ColumnType::Bytes => prop::collection::vec(any::<u8>(), 0..20)
+                .prop_map(KeyValue::Bytes)
+                .boxed(),
+            ColumnType::Str => ".{0,10}".prop_map(KeyValue::Str).boxed(),
+        };
+        if column.nullable {
+            prop_oneof![1 => Just(KeyValue::Null), 4 => value].boxed()
+        } else {
+            value
+        }
+    }
+
+    fn schema_and_row_strategy() -> impl Strategy<Value = (Schema, Vec<KeyValue>)> {
+        let column_type = prop_oneof![
+            Just(ColumnType::Bool),
+            Just(ColumnType::Int),
+            Just(ColumnType::UInt),
+            Just(ColumnType::Float),
+            Just(ColumnType::Bytes),
+            Just(ColumnType::Str),
+        ];
+        prop::collection::vec((column_type, any::<bool>()), 0..12).prop_flat_map(|columns| {
+            let schema = Schema::new(
+                columns
+                    .into_iter()
+                    .enumerate()
+                    .map(|(index, (column_type, nullable))| Column {
+                        name: format!("c{index}"),
+                        column_type,
+                        nullable,
+                    })
+                    .collect(),
+            );
+            let row: Vec<_> = schema.columns().iter().map(value_strategy).collect();
+            (Just(schema), row)
+        })
+    }
+
+    proptest! {
+        #[test]
+        fn tuples_decode_to_what_was_encoded((schema, row) in schema_and_row_strategy()) {
+            let tuple = schema.encode(&row).unwrap();
+            let decoded = schema.decode(&tuple).unwrap();
+            // Compared through Debug so NaNs count as equal to themselves
+            prop_assert_eq!(format!("{decoded:?}"), format!("{row:?}"));
+        }
+    }
+}
@@ -0,0 +1,511 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::error::{DbError, Result};
+use crate::page_store::PageStore;
+use crate::{read_be_u32, read_be_u64, MetadataPage, PageHeader};
+
+/// Log sequence number. Every record appended to the log gets the next one and the lsn of the
+/// last change made to a page is stamped into its header
+pub type Lsn = u64;
+pub type TxnId = u64;
+
+// Write-ahead log layout:
+//
+// | magic | page_size | start_lsn | record | record | ...
+//
+// Each record is
+//
+// | body_len (u32) | crc32c of body (u32) | body: lsn | kind | txn_id | kind specific fields |
+//
+// Records are only ever appended. A crash can leave a partially written record at the end, the
+// crc catches that and everything from the torn record on is ignored. The log is emptied at every
+// checkpoint (`PagedFileManager::flush_all`) once all the pages it covers are safely on disk, so
+// start_lsn is kept in the header to keep lsns increasing across checkpoints.
+
+/// A single entry in the log
+#[derive(Debug, Clone, PartialEq)]
+pub enum LogRecord {
+    /// Bytes `offset..offset + after.len()` of the page went from `before` to `after`. Having both
+    /// means the change can be redone or undone without looking at anything else
+    Update {
+        txn_id: TxnId,
+        page_id: u64,
+        offset: u32,
+        before: Vec<u8>,
+        after: Vec<u8>,
+    },
+    Commit {
+        txn_id: TxnId,
+    },
+    /// Every change the transaction made has already been reverted by updates logged after it
+    Abort {
+        txn_id: TxnId,
+    },
+}
+
+impl LogRecord {
+    const UPDATE: u8 = 1;
+    const COMMIT: u8 = 2;
+    const ABORT: u8 = 3;
+
+    const PREFIX_SIZE: usize = 2 * size_of::<u32>();
+    // lsn + kind + txn_id
+    const BODY_MIN_SIZE: usize = size_of::<u64>() + size_of::<u8>() + size_of::<u64>();
+    // page_id + offset + len
+    const UPDATE_FIELDS_SIZE: usize = size_of::<u64>() + 2 * size_of::<u32>();
+
+    pub fn txn_id(&self) -> TxnId {
+        match self {
+            LogRecord::Update { txn_id, .. }
+            | LogRecord::Commit { txn_id }
+            | LogRecord::Abort { txn_id } => *txn_id,
+        }
+    }
+
+    fn encode(&self, lsn: Lsn, buffer: &mut Vec<u8>) {
+        let mut body = Vec::with_capacity(Self::BODY_MIN_SIZE);
+        body.extend_from_slice(&lsn.to_be_bytes());
+        match self {
+            LogRecord::Update {
+                txn_id,
+                page_id,
+                offset,
+                before,
+                after,
+            } => {
+                debug_assert_eq!(before.len(), after.len());
+                body.push(Self::UPDATE);
+                body.extend_from_slice(&txn_id.to_be_bytes());
+                body.extend_from_slice(&page_id.to_be_bytes());
+                body.extend_from_slice(&offset.to_be_bytes());
+                body.extend_from_slice(&(after.len() as u32).to_be_bytes());
+                body.extend_from_slice(before);
+                body.extend_from_slice(after);
+            }
+            LogRecord::Commit { txn_id } => {
+                body.push(Self::COMMIT);
+                body.extend_from_slice(&txn_id.to_be_bytes());
+            }
+            LogRecord::Abort { txn_id } => {
+                body.push(Self::ABORT);
+                body.extend_from_slice(&txn_id.to_be_bytes());
+            }
+        }
+
+        buffer.extend_from_slice(&(body.len() as u32).to_be_bytes());
+        buffer.extend_from_slice(&crc32c::crc32c(&body).to_be_bytes());
+        buffer.extend_from_slice(&body);
+    }
+
+    /// Decodes the record at the start of `bytes`, returning it along with how many bytes it took
+    /// up. None if the bytes are torn or don't hold a valid record
+    fn decode(bytes: &[u8]) -> Option<(Lsn, LogRecord, usize)> {
+        if bytes.len() < Self::PREFIX_SIZE {
+            return None;
+        }
+        let body_len = read_be_u32(&bytes[0..4]) as usize;
+        let crc = read_be_u32(&bytes[4..8]);
+        let body = bytes.get(Self::PREFIX_SIZE..Self::PREFIX_SIZE + body_len)?;
+        if body_len < Self::BODY_MIN_SIZE || crc32c::crc32c(body) != crc {
+            return None;
+        }
+
+        let lsn = read_be_u64(&body[0..8]);
+        let txn_id = read_be_u64(&body[9..17]);
+        let fields = &body[Self::BODY_MIN_SIZE..];
+        let record = match body[8] {
+            Self::UPDATE => {
+                if fields.len() < Self::UPDATE_FIELDS_SIZE {
+                    return None;
+                }
+                let page_id = read_be_u64(&fields[0..8]);
+                let offset = read_be_u32(&fields[8..12]);
+                let len = read_be_u32(&fields[12..16]) as usize;
+                let images = &fields[Self::UPDATE_FIELDS_SIZE..];
+                if images.len() != 2 * len {
+                    return None;
+                }
+                LogRecord::Update {
+                    txn_id,
+                    page_id,
+                    offset,
+                    before: images[..len].to_vec(),
+                    after: images[len..].to_vec(),
+                }
+            }
+            Self::COMMIT => LogRecord::Commit { txn_id },
+            Self::ABORT => LogRecord::Abort { txn_id },
+            _ => return None,
+        };
+        Some((lsn, record, Self::PREFIX_SIZE + body_len))
+    }
+}
+
+/// Append only log of every change made to a page. Changes are appended to an in memory buffer and
+/// only hit disk when something forces them to: a commit, or the buffer pool about to write back a
+/// page whose lsn hasn't been made durable yet (the WAL-before-data rule)
+pub struct Wal {
+    store: Box<dyn PageStore>,
+    page_size: u32,
+    next_lsn: Lsn,
+    // Every record with an lsn below this is on disk
+    durable_lsn: Lsn,
+    pending: Vec<u8>,
+}
+
+impl Wal {
+    const MAGIC: [u8; 8] = *b"relwal01";
+    const MAGIC_SIZE: usize = Self::MAGIC.len();
+    const HEADER_SIZE: usize = Self::MAGIC_SIZE + size_of::<u32>() + size_of::<u64>();
+
+    /// Starts an empty log in the store, throwing away anything that was already there. Anything
+    /// that mattered in an existing log has to be recovered first
+    pub fn create(store: Box<dyn PageStore>, page_size: u32, start_lsn: Lsn) -> Result<Self> {
+        let mut wal = Wal {
+            store,
+            page_size,
+            next_lsn: start_lsn,
+            durable_lsn: start_lsn,
+            pending: Vec::new(),
+        };
+        wal.truncate()?;
+        Ok(wal)
+    }
+
+    /// Adds the record to the log returning its lsn. It isn't durable until `flush_to` is called
+    /// with an lsn at least this big
+    pub fn append(&mut self, record: &LogRecord) -> Lsn {
+        let lsn = self.next_lsn;
+        record.encode(lsn, &mut self.pending);
+        self.next_lsn += 1;
+        lsn
+    }
+
+    /// Makes sure every record up to and including `lsn` is on disk
+    pub fn flush_to(&mut self, lsn: Lsn) -> Result<()> {
+        if lsn < self.durable_lsn || self.pending.is_empty() {
+            return Ok(());
+        }
+        let end = self.store.len()?;
+        self.store.write_at(end, &self.pending)?;
+        self.store.sync()?;
+        self.pending.clear();
+        self.durable_lsn = self.next_lsn;
+        Ok(())
+    }
+
+    pub fn next_lsn(&self) -> Lsn {
+        self.next_lsn
+    }
+
+    /// Drops every record in the log. Only safe once every page the log covers has been written
+    /// back and synced
+    pub fn truncate(&mut self) -> Result<()> {
+        let mut header = Vec::with_capacity(Self::HEADER_SIZE);
+        header.extend_from_slice(&Self::MAGIC);
+        header.extend_from_slice(&self.page_size.to_be_bytes());
+        header.extend_from_slice(&self.next_lsn.to_be_bytes());
+
+        self.pending.clear();
+        self.store.set_len(0)?;
+        self.store.write_at(0, &header)?;
+        self.store.sync()?;
+        self.durable_lsn = self.next_lsn;
+        Ok(())
+    }
+}
+
+/// Brings the database back to a consistent state using whatever is in the log. Returns the lsn the
+/// next log should start from.
+///
+/// Every update in the log is redone in order, which gets every page to the state it was in at the
+/// moment of the crash. Then the updates of every transaction that never finished are undone, newest
+/// first across all of them. Transactions running at the same time keep off each other's pages
+/// until they end (see `PagedFileManager`) so that's the same as undoing each one on its own. Both
+/// steps only ever write logged bytes to logged offsets so running recovery again after crashing
+/// part way through it is harmless.
+pub fn recover(db_store: &mut dyn PageStore, log_store: &mut dyn PageStore) -> Result<Lsn> {
+    let mut wal_bytes = vec![0u8; log_store.len()? as usize];
+    log_store.read_at(0, &mut wal_bytes)?;
+    // A missing log, or a crash while the log was being created, leaves nothing worth recovering
+    if wal_bytes.len() < Wal::HEADER_SIZE {
+        return Ok(1);
+    }
+    if wal_bytes[..Wal::MAGIC_SIZE] != Wal::MAGIC {
+        return Err(DbError::CorruptLog(
+            "write-ahead log has an unrecognized header".to_string(),
+        ));
+    }
+    let page_size = read_be_u32(&wal_bytes[Wal::MAGIC_SIZE..Wal::MAGIC_SIZE + 4]) as usize;
+    let start_lsn = read_be_u64(&wal_bytes[Wal::MAGIC_SIZE + 4..Wal::HEADER_SIZE]);
+    if page_size < PageHeader::SIZE + MetadataPage::SIZE {
+        return Err(DbError::CorruptLog(format!(
+            "write-ahead log has an impossible page size {page_size}"
+        )));
+    }
+
+    let mut records = Vec::new();
+    let mut position = Wal::HEADER_SIZE;
+    while let Some((lsn, record, len)) = LogRecord::decode(&wal_bytes[position..]) {
+        records.push((lsn, record));
+        position += len;
+    }
+    let next_lsn = records.last().map_or(start_lsn, |(lsn, _)| lsn + 1);
+    if records.is_empty() {
+        return Ok(next_lsn);
+    }
+
+    let mut pages = RecoveredPages::new(db_store, page_size)?;
+
+    for (lsn, record) in &records {
+        if let LogRecord::Update {
+            page_id,
+            offset,
+            after,
+            ..
+        } = record
+        {
+            pages.apply(*page_id, *offset, after, *lsn)?;
+        }
+    }
+
+    let finished: HashSet<TxnId> = records
+        .iter()
+        .filter(|(_, record)| !matches!(record, LogRecord::Update { .. }))
+        .map(|(_, record)| record.txn_id())
+        .collect();
+    for (lsn, record) in records.iter().rev() {
+        if let LogRecord::Update {
+            txn_id,
+            page_id,
+            offset,
+            before,
+            ..
+        } = record
+        {
+            if !finished.contains(txn_id) {
+                pages.apply(*page_id, *offset, before, *lsn)?;
+            }
+        }
+    }
+
+    pages.write_back()?;
+    Ok(next_lsn)
+}
+
+/// Pages touched during recovery. Recovery works on the raw store (the buffer pool would reject the
+/// half written pages it's there to fix) so this keeps them in memory until they're all done
+struct RecoveredPages<'a> {
+    db_store: &'a mut dyn PageStore,
+    store_len: u64,
+    page_size: usize,
+    pages: HashMap<u64, Vec<u8>>,
+}
+
+impl<'a> RecoveredPages<'a> {
+    fn new(db_store: &'a mut dyn PageStore, page_size: usize) -> Result<Self> {
+        let store_len = db_store.len()?;
+        Ok(RecoveredPages {
+            db_store,
+            store_len,
+            page_size,
+            pages: HashMap::new(),
+        })
+    }
+
+    fn page(&mut self, page_id: u64) -> Result<&mut Vec<u8>> {
+        if !self.pages.contains_key(&page_id) {
+            // Pages past the end of the store were allocated but never written before the crash
+            let mut page = vec![0u8; self.page_size];
+            let start = page_id * self.page_size as u64;
+            let stored = self
+                .store_len
+                .saturating_sub(start)
+                .min(self.page_size as u64);
+            if stored > 0 {
+                self.db_store.read_at(start, &mut page[..stored as usize])?;
+            }
+            self.pages.insert(page_id, page);
+        }
+        Ok(self
+            .pages
+            .get_mut(&page_id)
+            .expect("page was just inserted"))
+    }
+
+    fn apply(&mut self, page_id: u64, offset: u32, bytes: &[u8], lsn: Lsn) -> Result<()> {
+        let page_size = self.page_size;
+        let start = offset as usize;
+        if start + bytes.len() > page_size {
+            return Err(DbError::CorruptLog(format!(
+                "update to page {page_id} runs past the end of the page"
+            )));
+        }
+        let page = self.page(page_id)?;
+        page[start..start + bytes.len()].copy_from_slice(bytes);
+        page[PageHeader::lsn_span()].copy_from_slice(&lsn.to_be_bytes());
+        Ok(())
+    }
+
+    /// Writes every recovered page back and trims off any pages that were allocated by the
+    /// transaction that got undone
+    fn write_back(mut self) -> Result<()> {
+        let metadata_page = self.page(0)?;
+        let total_pages =
+            read_be_u64(&metadata_page[PageHeader::SIZE..][MetadataPage::total_pages_span()]);
+
+        for (page_id, page) in self.pages.iter_mut() {
+            if *page_id >= total_pages {
+                continue;
+            }
+            PageHeader::stamp_checksum(page);
+            self.db_store
+                .write_at(page_id * self.page_size as u64, page)?;
+        }
+        let expected_len = total_pages * self.page_size as u64;
+        if self.db_store.len()? > expected_len {
+            self.db_store.set_len(expected_len)?;
+        }
+        self.db_store.sync()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{remove_db, temp_db_path};
+    use crate::{
+        DataPage, MetadataPage, PageWindow, PagedFileManager, PagedFileManagerConfigBuilder,
+    };
+    use std::path::Path;
+
+    fn open(path: &Path) -> PagedFileManager {
+        PagedFileManager::new(
+            path,
+            PagedFileManagerConfigBuilder::new().page_size(256).build(),
+        )
+        .unwrap()
+    }
+
+    fn total_pages(manager: &PagedFileManager) -> u64 {
+        manager
+            .with_window::<MetadataPage, _, _>(0, |window| Ok(window.read_total_pages()))
+            .unwrap()
+    }
+
+    #[test]
+    fn committed_changes_survive_a_crash() {
+        let path = temp_db_path("wal_committed");
+        let manager = open(&path);
+        let page_id = manager.create_data_page().unwrap();
+        let slot = manager.insert_record(page_id, b"durable").unwrap().unwrap();
+        // Crash: nothing but the log made it to disk
+        std::mem::forget(manager);
+
+        let manager = open(&path);
+        assert_eq!(total_pages(&manager), 2);
+        assert_eq!(
+            manager.get_record(page_id, slot).unwrap().unwrap(),
+            b"durable"
+        );
+        let (header, _) = manager.read_page_as::<DataPage>(page_id).unwrap();
+        assert!(header.lsn > 0);
+
+        drop(manager);
+        remove_db(&path);
+    }
+
+    #[test]
+    fn unfinished_changes_are_undone_after_a_crash() {
+        let path = temp_db_path("wal_unfinished");
+        let crash_path = temp_db_path("wal_unfinished_crash");
+        let manager = open(&path);
+        let page_id = manager.create_data_page().unwrap();
+        manager.insert_record(page_id, b"before").unwrap().unwrap();
+
+        manager
+            .atomically(|manager| {
+                manager.create_data_page()?;
+                manager.with_window_mut::<DataPage, _, _>(page_id, |window| {
+                    window.update_record(0, b"after!")
+                })?;
+                // Push the half done changes all the way to disk and snapshot the files as if the
+                // process died right here
+                manager.buffer_pool.flush_all()?;
+                std::fs::copy(&path, &crash_path)?;
+                std::fs::copy(
+                    PagedFileManager::wal_path(&path),
+                    PagedFileManager::wal_path(&crash_path),
+                )?;
+                Ok(())
+            })
+            .unwrap();
+        drop(manager);
+
+        let manager = open(&crash_path);
+        assert_eq!(total_pages(&manager), 2);
+        assert_eq!(manager.get_record(page_id, 0).unwrap().unwrap(), b"before");
+
+        drop(manager);
+        remove_db(&path);
+        remove_db(&crash_path);
+    }
+
+    #[test]
+    fn failed_atomic_changes_are_rolled_back() {
+        let path = temp_db_path("wal_rollback");
+        let manager = open(&path);
+        let page_id = manager.create_data_page().unwrap();
+        manager.insert_record(page_id, b"kept").unwrap().unwrap();
+
+        let result: Result<()> = manager.atomically(|manager| {
+            manager.allocate_page()?;
+            manager.insert_record(page_id, b"dropped")?;
+            Err(DbError::InvalidPageId(42))
+        });
+        assert!(matches!(result, Err(DbError::InvalidPageId(42))));
+        assert_eq!(total_pages(&manager), 2);
+        manager
+            .with_window::<DataPage, _, _>(page_id, |window: &PageWindow<DataPage>| {
+                assert_eq!(window.num_records(), 1);
+                Ok(())
+            })
+            .unwrap();
+
+        // And the file is still consistent once reopened
+        drop(manager);
+        let manager = open(&path);
+        assert_eq!(total_pages(&manager), 2);
+        assert_eq!(manager.get_record(page_id, 0).unwrap().unwrap(), b"kept");
+
+        drop(manager);
+        remove_db(&path);
+    }
+
+    #[test]
+    fn torn_records_at_the_end_of_the_log_are_ignored() {
+        let mut bytes = Vec::new();
+        let commit = LogRecord::Commit { txn_id: 7 };
+        commit.encode(3, &mut bytes);
+        let update = LogRecord::Update {
+            txn_id: 8,
+            page_id: 1,
+            offset: 40,
+            before: vec![1, 2, 3],
+            after: vec![4, 5, 6],
+        };
+        update.encode(4, &mut bytes);
+
+        let (lsn, record, len) = LogRecord::decode(&bytes).unwrap();
+        assert_eq!((lsn, &record), (3, &commit));
+        assert_eq!(LogRecord::decode(&bytes[len..]).unwrap().1, update);
+
+        bytes.pop();
+        assert!(LogRecord::decode(&bytes[len..]).is_none());
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xff;
+        bytes.push(6);
+        assert!(LogRecord::decode(&bytes[len..]).is_none());
+    }
+}